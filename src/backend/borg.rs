@@ -1,12 +1,117 @@
 use crate::{borrg::*, util::resolve_path};
 use log::{debug, trace, warn, Level};
 use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    ffi::{OsStr, OsString},
     io::{BufRead, BufReader, Lines, Read},
-    ops::{Deref, DerefMut},
-    path::PathBuf,
-    process::{Command, Stdio},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, ExitStatus, Output, Stdio},
+    sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
+use thiserror::Error;
+
+#[cfg(feature = "async")]
+mod tokio_wrapper;
+#[cfg(feature = "async")]
+pub use tokio_wrapper::AsyncBorgWrapper;
+
+#[derive(Error, Debug, Clone)]
+pub enum BorgError {
+    #[error("borg terminated with error status (rc {0})")]
+    Failed(i32),
+    #[error("borg was terminated by a signal")]
+    Killed,
+    #[error("borg was terminated by signal {0}")]
+    Signal(i32),
+    #[error("repository error (rc {0})")]
+    Repository(i32),
+    #[error("archive error (rc {0})")]
+    Archive(i32),
+    #[error("key error (rc {0})")]
+    Key(i32),
+    #[error("passphrase missing or wrong (rc {0})")]
+    Passphrase(i32),
+    #[error("cache error (rc {0})")]
+    Cache(i32),
+    #[error("repository is locked (rc {0})")]
+    Lock(i32),
+    #[error("connection to the repository failed (rc {0})")]
+    Connection(i32),
+    #[error("borg didn't finish within {}", humantime::format_duration(*.0))]
+    Timeout(Duration),
+    #[error("cancelled")]
+    Cancelled,
+    #[error(
+        "{} not found, install borg or set BORG_PATH to its location",
+        .attempted.display()
+    )]
+    BinaryNotFound { attempted: PathBuf },
+}
+
+impl BorgError {
+    /// Interpret the exit status of a borg process
+    ///
+    /// Borg exits with 0 on success, 1 on warnings and 2 on errors. With modern exit codes
+    /// errors have codes from 3 to 99 and warnings from 100 to 127.
+    fn check(status: ExitStatus, modern: bool) -> std::result::Result<(), BorgError> {
+        match status.code() {
+            Some(rc) => Self::check_rc(rc, modern),
+            None => Err(BorgError::Killed),
+        }
+    }
+
+    /// Like [`Self::check`], but trusting the rc borg reported in its last message
+    ///
+    /// Wrappers around borg, e.g. ssh or sudo, can change its exit code, the message has the
+    /// real one.
+    fn check_reported(
+        status: ExitStatus,
+        reported: Option<i32>,
+        modern: bool,
+    ) -> std::result::Result<(), BorgError> {
+        match reported {
+            Some(rc) if status.code() != Some(rc) => {
+                warn!("borg reported rc {rc}, but exited with {status}");
+                Self::check_rc(rc, modern)
+            }
+            _ => Self::check(status, modern),
+        }
+    }
+
+    fn check_rc(rc: i32, modern: bool) -> std::result::Result<(), BorgError> {
+        match (rc, modern) {
+            (0, _) => Ok(()),
+            (1, _) | (100..=127, true) => {
+                warn!("borg terminated with warning status (rc {rc})");
+                Ok(())
+            }
+            (_, false) => Err(BorgError::Failed(rc)),
+            (10..=21, true) => Err(BorgError::Repository(rc)),
+            (30..=32, true) => Err(BorgError::Archive(rc)),
+            (40..=48, true) => Err(BorgError::Key(rc)),
+            (50..=53, true) => Err(BorgError::Passphrase(rc)),
+            (60..=64, true) => Err(BorgError::Cache(rc)),
+            (70..=75, true) => Err(BorgError::Lock(rc)),
+            (80..=86, true) => Err(BorgError::Connection(rc)),
+            (128.., true) => Err(BorgError::Signal(rc - 128)),
+            (_, true) => Err(BorgError::Failed(rc)),
+        }
+    }
+}
+
+/// Failure to start borg, [`BorgError::BinaryNotFound`] if there is no such program
+fn spawn_error(program: &OsStr, e: std::io::Error) -> Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        return BorgError::BinaryNotFound {
+            attempted: program.into(),
+        }
+        .into();
+    }
+    e.into()
+}
 
 impl TryFrom<serde_json::Value> for Event {
     type Error = Error;
@@ -27,17 +132,12 @@ impl TryFrom<serde_json::Value> for Event {
         let compressed_size = || value.get("compressed_size").and_then(|s| s.as_u64());
         let deduplicated_size = || value.get("deduplicated_size").and_then(|s| s.as_u64());
         let original_size = || value.get("original_size").and_then(|s| s.as_u64());
-        let path = || {
-            value
-                .get("path")
-                .and_then(|p| p.as_str())
-                .map(PathBuf::from)
-        };
+        let path = || value.get("path").and_then(|p| p.as_str()).map(decode_path);
         let message = || {
             value
                 .get("message")
                 .and_then(|m| m.as_str())
-                .map(|m| m.to_owned())
+                .map(decode_lossy)
         };
         let finished = || value.get("finished").and_then(|f| f.as_bool());
         let msgid = || {
@@ -121,13 +221,29 @@ impl TryFrom<serde_json::Value> for Event {
                 operation: operation(),
                 time: time(),
             },
-            "log_message" => Self::LogMessage {
-                name: name(),
-                level: level(),
-                message: message().unwrap_or_default(),
-                msgid: msgid(),
-                time: time(),
-            },
+            "log_message" if name().as_deref() == Some("borg.output.list") => {
+                let message = message().unwrap_or_default();
+                parse_prune_decision(&message).unwrap_or(Self::LogMessage {
+                    name: name(),
+                    level: level(),
+                    message,
+                    msgid: msgid(),
+                    time: time(),
+                })
+            }
+            "log_message" => {
+                let message = message().unwrap_or_default();
+                match parse_termination(&message) {
+                    Some(rc) => Self::Termination { rc, level: level() },
+                    None => Self::LogMessage {
+                        name: name(),
+                        level: level(),
+                        message,
+                        msgid: msgid(),
+                        time: time(),
+                    },
+                }
+            }
             "file_status" => Self::FileStatus {
                 path: path().unwrap_or_default(),
                 status: status().unwrap_or_default(),
@@ -159,6 +275,43 @@ impl TryFrom<serde_json::Value> for Event {
     }
 }
 
+/// The rc of the last message of borg, e.g. 1 for "terminating with warning status, rc 1"
+fn parse_termination(message: &str) -> Option<i32> {
+    let status = message.strip_prefix("terminating with ")?;
+    let (_, rc) = status.split_once(" status, rc ")?;
+    rc.trim().parse().ok()
+}
+
+/// Parse a line of `borg prune --list`, e.g.
+/// "Keeping archive (rule: daily #1):  host-2024-05-10  Fri, 2024-05-10 03:00:02 [7b0b…]"
+///
+/// borg 1.1 prints "Keeping archive:" without the rule and "Would prune:" in dry runs.
+fn parse_prune_decision(message: &str) -> Option<Event> {
+    let pruned = if message.starts_with("Keeping") {
+        false
+    } else if message.starts_with("Pruning") || message.starts_with("Would prune") {
+        true
+    } else {
+        return None;
+    };
+
+    // The label can contain colons itself, like in "(rule: daily #1):"
+    let (label, rest) = match message.find("):") {
+        Some(end) => (&message[..=end], &message[end + 2..]),
+        None => message.split_once(':')?,
+    };
+    let archive = rest.split_whitespace().next()?.to_owned();
+    let rule = label
+        .split_once("(rule: ")
+        .map(|(_, rule)| rule.trim_end_matches(')').to_owned());
+
+    Some(Event::PruneDecision {
+        archive,
+        pruned,
+        rule,
+    })
+}
+
 pub struct Events<R: Read> {
     lines: Lines<BufReader<R>>,
 }
@@ -175,48 +328,441 @@ impl<R: Read> Iterator for Events<R> {
     type Item = Event;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let line = self.lines.next()?;
-        let line = match line {
-            Ok(line) => line,
-            Err(err) => return Some(Event::Error(Box::new(err))),
-        };
+        match self.lines.next()? {
+            Ok(line) => Some(parse_event(line)),
+            Err(err) => Some(Event::Error(Box::new(err))),
+        }
+    }
+}
+
+/// Lines borg prints to stdout, read while it is running
+///
+/// Stderr is drained on its own thread, otherwise borg could block on a full stderr pipe
+/// while we wait for its next line on stdout. If borg fails, the last item is the error.
+/// Dropping the iterator early kills borg.
+pub struct OutputLines {
+    lines: Lines<BufReader<ChildStdout>>,
+    child: Child,
+    stderr: Option<std::thread::JoinHandle<VecDeque<String>>>,
+    modern_exit_codes: bool,
+    finished: bool,
+}
+
+impl OutputLines {
+    /// How many lines of stderr are kept for the error message
+    const STDERR_TAIL: usize = 20;
 
-        trace!("[borg] {:#?}", line);
+    fn spawn(cmd: &mut Command, modern_exit_codes: bool) -> Result<Self> {
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd.spawn().map_err(|e| spawn_error(cmd.get_program(), e))?;
+
+        let stdout = child.stdout.take().ok_or("No stdout")?;
+        let stderr = child.stderr.take().ok_or("No stderr")?;
+
+        let stderr = std::thread::spawn(move || {
+            let mut tail = VecDeque::with_capacity(Self::STDERR_TAIL);
+            for line in BufReader::new(stderr).lines() {
+                let Ok(line) = line else { break };
+                debug!("[borg] {line}");
+                if tail.len() == Self::STDERR_TAIL {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            tail
+        });
+
+        Ok(OutputLines {
+            lines: BufReader::new(stdout).lines(),
+            child,
+            stderr: Some(stderr),
+            modern_exit_codes,
+            finished: false,
+        })
+    }
 
-        let json: std::result::Result<serde_json::Value, _> = serde_json::from_str(&line);
-        let json = match json {
-            Ok(json) => json,
-            Err(e) => {
-                warn!("Failed to parse borg log event: {line:?} ({e})");
-                return Some(Event::Other(line));
+    /// Wait for borg to exit, fails with the end of what it logged if it failed
+    fn finish(&mut self) -> Result<()> {
+        let status = self.child.wait()?;
+        let tail = match self.stderr.take() {
+            Some(stderr) => stderr.join().map_err(|_| "Failed to read stderr")?,
+            None => VecDeque::new(),
+        };
+        if let Err(e) = BorgError::check(status, self.modern_exit_codes) {
+            if tail.is_empty() {
+                return Err(e.into());
             }
+            return Err(Vec::from(tail).join("\n").into());
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for OutputLines {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match self.lines.next() {
+            Some(Ok(line)) => return Some(Ok(line)),
+            Some(Err(e)) => return Some(Err(e.into())),
+            None => {}
+        }
+        self.finished = true;
+        self.finish().err().map(Err)
+    }
+}
+
+impl Drop for OutputLines {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.child.kill().ok();
+            self.child.wait().ok();
+        }
+    }
+}
+
+/// Parse a line borg logged with `--log-json`
+fn parse_event(line: String) -> Event {
+    trace!("[borg] {:#?}", line);
+
+    let json: std::result::Result<serde_json::Value, _> =
+        serde_json::from_str(&escape_surrogates(&line));
+    let json = match json {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("Failed to parse borg log event: {line:?} ({e})");
+            return Event::Other(line);
+        }
+    };
+
+    debug!("{:#?}", json);
+
+    match Event::try_from(json) {
+        Ok(event) => {
+            debug!("{:#?}", event);
+            event
+        }
+        Err(e) => {
+            warn!("Unknown borg log event: {line:?} ({e})");
+            Event::Other(line)
+        }
+    }
+}
+
+/// Marks a byte of a filename which isn't valid UTF-8, see [`escape_surrogates`]
+const RAW_BYTE: char = '\0';
+
+/// Make the surrogate escapes of borg's JSON parseable
+///
+/// Python decodes filenames with the "surrogateescape" error handler, so a byte that isn't
+/// valid UTF-8 becomes a lone surrogate U+DC80 to U+DCFF, which `json.dumps` writes as e.g.
+/// `\udce9`. serde_json rejects lone surrogates, so they are rewritten to [`RAW_BYTE`]
+/// followed by the byte as a char (`\u0000\u00e9`). Paths can't contain a NUL byte, so
+/// [`decode_path`] can tell them apart from the rest of the path.
+fn escape_surrogates(line: &str) -> Cow<'_, str> {
+    if !line.contains("\\udc") && !line.contains("\\uDC") {
+        return Cow::Borrowed(line);
+    }
+
+    let mut escaped = String::with_capacity(line.len());
+    let mut rest = line;
+    // The previous escape was a high surrogate, the next one completes the pair
+    let mut high_surrogate = false;
+    while let Some(i) = rest.find('\\') {
+        escaped.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let len = if rest[1..].starts_with('u') { 6 } else { 2 };
+        let Some(escape) = rest.get(..len) else {
+            break;
         };
+        let code = escape
+            .strip_prefix("\\u")
+            .and_then(|hex| u16::from_str_radix(hex, 16).ok());
+        match code {
+            Some(code @ 0xdc80..=0xdcff) if !high_surrogate => {
+                escaped.push_str(&format!("\\u0000\\u00{:02x}", code & 0xff));
+            }
+            _ => escaped.push_str(escape),
+        }
+        high_surrogate = matches!(code, Some(0xd800..=0xdbff));
+        rest = &rest[len..];
+    }
+    escaped.push_str(rest);
+    Cow::Owned(escaped)
+}
+
+/// A path borg logged, with the bytes marked by [`escape_surrogates`] restored
+///
+/// Other platforms than unix can't have such paths, the bytes are replaced by U+FFFD.
+fn decode_path(path: &str) -> PathBuf {
+    if !path.contains(RAW_BYTE) {
+        return PathBuf::from(path);
+    }
 
-        debug!("{:#?}", json);
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
 
-        match Event::try_from(json) {
-            Ok(event) => {
-                debug!("{:#?}", event);
-                Some(event)
+        let mut bytes = Vec::with_capacity(path.len());
+        let mut chars = path.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                RAW_BYTE => bytes.extend(chars.next().map(|b| b as u8)),
+                c => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
             }
-            Err(e) => {
-                warn!("Unknown borg log event: {line:?} ({e})");
-                Some(Event::Other(line))
+        }
+        PathBuf::from(OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(decode_lossy(path))
+    }
+}
+
+/// A message borg logged, with the bytes marked by [`escape_surrogates`] replaced by U+FFFD
+fn decode_lossy(message: &str) -> String {
+    let mut decoded = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            RAW_BYTE => {
+                chars.next();
+                decoded.push(char::REPLACEMENT_CHARACTER);
             }
+            c => decoded.push(c),
         }
     }
+    decoded
+}
+
+/// Archives in the output of `borg list --json`
+fn parse_archive_list(json: serde_json::Value) -> Result<Vec<ArchiveInfo>> {
+    match json.get("archives") {
+        Some(serde_json::Value::Array(archives)) => archives
+            .iter()
+            .cloned()
+            .map(ArchiveInfo::try_from)
+            .collect(),
+        _ => Err("missing key: \"archives\"".into()),
+    }
 }
 
 fn log_command(cmd: &Command) {
-    let command = format!(
-        "{} {}",
-        cmd.get_program().to_string_lossy(),
-        cmd.get_args()
-            .map(|a| format!("\"{}\"", a.to_string_lossy()))
-            .collect::<Vec<_>>()
-            .join(" ")
+    debug!(
+        "Executing command: {}",
+        format_command(cmd.get_program(), cmd.get_args())
     );
-    debug!("Executing command: {}", command);
+}
+
+/// A borg command as it would be executed
+///
+/// Values of secret environment variables are left out so that passphrases can't leak.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    pub env: Vec<(OsString, Option<OsString>)>,
+    /// Where borg runs, the working directory of borrg if `None`
+    pub current_dir: Option<PathBuf>,
+}
+
+impl Invocation {
+    /// The command line with secret arguments replaced by `***`
+    pub fn command_line(&self) -> String {
+        format_command(&self.program, self.args.iter().map(OsString::as_os_str))
+    }
+}
+
+impl std::fmt::Display for Invocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.command_line())?;
+        if !self.env.is_empty() {
+            let env = self
+                .env
+                .iter()
+                .map(|(name, _)| name.to_string_lossy())
+                .collect::<Vec<_>>();
+            write!(f, " (env: {})", env.join(", "))?;
+        }
+        if let Some(dir) = &self.current_dir {
+            write!(f, " (in {})", dir.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Environment variables whose values must never show up in logs or explanations
+const SECRET_ENV: &[&str] = &["BORG_PASSPHRASE"];
+
+/// Variables borg reads the passphrase from, in the order of precedence
+const PASSPHRASE_ENV: &[&str] = &["BORG_PASSPHRASE", "BORG_PASSCOMMAND", "BORG_PASSPHRASE_FD"];
+
+/// Run a [`Passphrase::CommandArgs`] command and return the first line it prints
+///
+/// Stdin and stderr are inherited, e.g. for the PIN prompt of a password manager. In `batch`
+/// mode stdin is closed instead.
+fn run_passcommand(command: &[String], batch: bool) -> Result<String> {
+    let (program, args) = command.split_first().ok_or("Empty passcommand")?;
+    let output = Command::new(program)
+        .args(args)
+        .stdin(match batch {
+            true => Stdio::null(),
+            false => Stdio::inherit(),
+        })
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to run passcommand {program}: {e}"))?;
+    if !output.status.success() {
+        return Err(format!("Passcommand {program} failed ({})", output.status).into());
+    }
+    let stdout = String::from_utf8(output.stdout).map_err(|_| "Passphrase isn't UTF-8")?;
+    let line = stdout.lines().next().unwrap_or_default();
+    Ok(line.to_owned())
+}
+
+/// Passphrases printed by [`Passphrase::CommandArgs`] commands, shared by the clones of a
+/// [`Borg`], so a command runs only once even for several borg calls on the repository
+#[derive(Default)]
+pub(crate) struct Passphrases(Mutex<HashMap<Vec<String>, String>>);
+
+impl Passphrases {
+    /// The passphrase `command` printed before, or run it now
+    ///
+    /// Other commands wait meanwhile, so parallel backups don't prompt at the same time.
+    fn get_or_run(&self, command: &[String], batch: bool) -> Result<String> {
+        let mut passphrases = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(passphrase) = passphrases.get(command) {
+            return Ok(passphrase.to_owned());
+        }
+        let passphrase = run_passcommand(command, batch)?;
+        passphrases.insert(command.to_owned(), passphrase.clone());
+        Ok(passphrase)
+    }
+}
+
+/// Without the passphrases
+impl std::fmt::Debug for Passphrases {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = self.0.lock().map_or(0, |p| p.len());
+        write!(f, "Passphrases({count})")
+    }
+}
+
+/// Where borg gets the passphrase from, e.g. "passcommand from the config"
+///
+/// `is_set` tells whether a variable is set in borrg's environment.
+pub(crate) fn passphrase_source(
+    passphrase: Option<&Passphrase>,
+    is_set: impl Fn(&str) -> bool,
+) -> String {
+    let configured = match passphrase {
+        Some(Passphrase::Passphrase(_)) => "passphrase from the config".to_owned(),
+        Some(Passphrase::Command(_)) => "passcommand from the config".to_owned(),
+        Some(Passphrase::CommandArgs(_)) => "passcommand from the config, run by borrg".to_owned(),
+        Some(Passphrase::FileDescriptor(fd)) => format!("file descriptor {fd} from the config"),
+        Some(Passphrase::Environment(var)) if is_set(var) => format!("{var} from the environment"),
+        Some(Passphrase::Environment(var)) => return format!("none, {var} isn't set"),
+        None => {
+            return match PASSPHRASE_ENV.iter().find(|&&var| is_set(var)) {
+                Some(var) => format!("{var} from the environment"),
+                None => "none".to_owned(),
+            }
+        }
+    };
+    let used = match passphrase {
+        Some(Passphrase::Environment(var)) => Some(var.as_str()),
+        _ => None,
+    };
+    let overridden: Vec<_> = PASSPHRASE_ENV
+        .iter()
+        .filter(|&&var| is_set(var) && Some(var) != used)
+        .copied()
+        .collect();
+    match overridden[..] {
+        [] => configured,
+        _ => format!("{configured}, overriding {}", overridden.join(" and ")),
+    }
+}
+
+/// Whether borg gets a passphrase from somewhere other than its prompt, see
+/// [`passphrase_source`]
+pub(crate) fn has_passphrase_source(
+    passphrase: Option<&Passphrase>,
+    is_set: impl Fn(&str) -> bool,
+) -> bool {
+    match passphrase {
+        Some(Passphrase::Environment(var)) => is_set(var),
+        None => PASSPHRASE_ENV.iter().any(|var| is_set(var)),
+        Some(_) => true,
+    }
+}
+
+/// Arguments whose values must never show up in logs or explanations
+const REDACTED_ARGS: &[&str] = &["--passphrase", "--passcommand", "--encryption-passphrase"];
+
+/// Format a command line so it can be pasted into a shell, with secrets replaced by `***`
+fn format_command<'a>(program: &OsStr, args: impl Iterator<Item = &'a OsStr>) -> String {
+    let mut line = shell_quote(&program.to_string_lossy()).into_owned();
+    let mut redact_next = false;
+    for arg in args {
+        let arg = arg.to_string_lossy();
+        line.push(' ');
+        if redact_next {
+            line.push_str("***");
+            redact_next = false;
+            continue;
+        }
+        match arg.split_once('=') {
+            Some((name, _)) if REDACTED_ARGS.contains(&name) => {
+                line.push_str(&shell_quote(name));
+                line.push_str("=***");
+            }
+            _ => {
+                redact_next = REDACTED_ARGS.contains(&arg.as_ref());
+                line.push_str(&shell_quote(&arg));
+            }
+        }
+    }
+    line
+}
+
+/// Quote a word for a POSIX shell, leaving it untouched if that's not needed
+pub fn shell_quote(word: &str) -> std::borrow::Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.into()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''")).into()
+    }
+}
+
+/// `rsh` with `-o BatchMode=yes` added if it runs ssh, `None` for other remote shells and
+/// if it sets `BatchMode` itself
+///
+/// The program is the first word of `rsh`, e.g. `ssh -J bastion` or `'/opt/my tools/ssh' -i key`.
+fn ssh_batch_mode(rsh: &str) -> Option<String> {
+    let rsh = rsh.trim_start();
+    let program = match rsh.chars().next()? {
+        quote @ ('\'' | '"') => rsh[1..].split(quote).next()?,
+        _ => rsh.split_whitespace().next()?,
+    };
+    let is_ssh = std::path::Path::new(program)
+        .file_name()
+        .is_some_and(|name| name == "ssh");
+    (is_ssh && !rsh.contains("BatchMode")).then(|| format!("{rsh} -o BatchMode=yes"))
+}
+
+/// Parse a timestamp like "2022-04-07T15:44:37.000000" as borg prints them in local time
+fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    use chrono::TimeZone;
+
+    let naive = chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f").ok()?;
+    let local = chrono::Local.from_local_datetime(&naive).earliest()?;
+    Some(local.into())
 }
 
 impl TryFrom<serde_json::Value> for RepoInfo {
@@ -264,20 +810,16 @@ impl TryFrom<serde_json::Value> for RepoInfo {
             .and_then(|e| e.as_object())
             .ok_or("missing key: \"encryption\"")?;
 
-        let encryption = match encryption
+        // Only reported for keyfile modes
+        let keyfile = encryption
+            .get("keyfile")
+            .and_then(|k| k.as_str())
+            .map(PathBuf::from);
+        let encryption = encryption
             .get("mode")
             .and_then(|m| m.as_str())
             .ok_or("missing key: \"encryption.mode\"")?
-        {
-            "none" => Encryption::None,
-            "repokey" => Encryption::RepoKey,
-            "repokey-blake2" => Encryption::RepoKeyBlake2,
-            "keyfile" => Encryption::KeyFile,
-            "keyfile-blake2" => Encryption::KeyFileBlake2,
-            "authenticated" => Encryption::Authenticated,
-            "authenticated-blake2" => Encryption::AuthenticatedBlake2,
-            _ => return Err("unsupported encryption mode".into()),
-        };
+            .parse()?;
 
         let id = value
             .get("repository")
@@ -291,6 +833,13 @@ impl TryFrom<serde_json::Value> for RepoInfo {
             .and_then(|l| l.as_str())
             .ok_or("missing key: \"repository.location\"")?
             .to_owned();
+        let last_modified = value
+            .get("repository")
+            .and_then(|r| r.get("last_modified"))
+            .and_then(|l| l.as_str())
+            .ok_or("missing key: \"repository.last_modified\"")?;
+        let last_modified = parse_timestamp(last_modified)
+            .ok_or("invalid timestamp: \"repository.last_modified\"")?;
         let security_dir = value
             .get("security_dir")
             .and_then(|s| s.as_str())
@@ -308,15 +857,284 @@ impl TryFrom<serde_json::Value> for RepoInfo {
             encryption,
             id,
             location,
+            last_modified,
+            keyfile,
             security_dir,
         })
     }
 }
 
-struct BorgCommand(Command);
+impl TryFrom<serde_json::Value> for ArchiveInfo {
+    type Error = Error;
+    fn try_from(value: serde_json::Value) -> Result<Self> {
+        let name = value
+            .get("name")
+            .and_then(|n| n.as_str())
+            .ok_or("missing key: \"name\"")?
+            .to_owned();
+        let id = value
+            .get("id")
+            .and_then(|i| i.as_str())
+            .ok_or("missing key: \"id\"")?
+            .to_owned();
+        let time = value
+            .get("time")
+            .or_else(|| value.get("start"))
+            .and_then(|t| t.as_str())
+            .ok_or("missing key: \"time\"")?;
+        let time = parse_timestamp(time).ok_or("invalid timestamp: \"time\"")?;
+
+        Ok(ArchiveInfo { name, id, time })
+    }
+}
+
+impl TryFrom<serde_json::Value> for CreateStats {
+    type Error = Error;
+    fn try_from(value: serde_json::Value) -> Result<Self> {
+        let archive = value.get("archive").ok_or("missing key: \"archive\"")?;
+        let string = |key: &'static str| {
+            archive
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(ToOwned::to_owned)
+                .ok_or(format!("missing key: \"archive.{key}\""))
+        };
+        let stats = archive
+            .get("stats")
+            .ok_or("missing key: \"archive.stats\"")?;
+        let stat = |key: &'static str| {
+            stats
+                .get(key)
+                .and_then(|v| v.as_u64())
+                .ok_or(format!("missing key: \"archive.stats.{key}\""))
+        };
+        let duration = archive
+            .get("duration")
+            .and_then(|d| d.as_f64())
+            .map(Duration::from_secs_f64)
+            .unwrap_or_default();
+
+        Ok(CreateStats {
+            name: string("name")?,
+            id: string("id")?,
+            duration,
+            original_size: stat("original_size")?,
+            compressed_size: stat("compressed_size")?,
+            deduplicated_size: stat("deduplicated_size")?,
+            nfiles: stat("nfiles")?,
+        })
+    }
+}
+
+impl TryFrom<serde_json::Value> for ArchiveListEntry {
+    type Error = Error;
+    fn try_from(value: serde_json::Value) -> Result<Self> {
+        let string = |key: &'static str| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .ok_or(format!("missing key: \"{key}\""))
+        };
+
+        let path = decode_path(string("path")?);
+        let kind = string("type")?
+            .chars()
+            .next()
+            .ok_or("empty key: \"type\"")?
+            .into();
+        let size = value.get("size").and_then(|s| s.as_u64()).unwrap_or(0);
+        let mtime = parse_timestamp(string("mtime")?).ok_or("invalid timestamp: \"mtime\"")?;
+        let link_target = value
+            .get("linktarget")
+            .or_else(|| value.get("source"))
+            .and_then(|l| l.as_str())
+            .filter(|l| !l.is_empty())
+            .map(decode_path);
+
+        Ok(ArchiveListEntry {
+            path,
+            kind,
+            size,
+            mtime,
+            mode: string("mode")?.to_owned(),
+            user: string("user")?.to_owned(),
+            group: string("group")?.to_owned(),
+            link_target,
+        })
+    }
+}
+
+/// Builds and runs borg commands with borrg's configuration applied
+///
+/// This is what [`BorgWrapper`] uses for every operation, and can be used to run borg
+/// subcommands it doesn't wrap yet. The API is semi-stable: it may change in minor versions
+/// as borrg learns about more of borg.
+///
+/// Common options go before the subcommand, its own options after it:
+///
+/// ```no_run
+/// use borrg::{backend::borg::CommandBuilder, Borg, Repo};
+/// use std::ops::ControlFlow;
+///
+/// let borg = Borg::default();
+/// let repo: Repo = "/srv/backup".parse()?;
+///
+/// let mut cmd = CommandBuilder::new(&borg);
+/// cmd.arg("recreate").progress().log_json();
+/// cmd.args(["--recompress", "always", "--compression", "zstd,10"]);
+/// cmd.repo(&repo);
+/// cmd.run_with_events(|event| {
+///     println!("{event}");
+///     ControlFlow::Continue(())
+/// })?;
+///
+/// let mut cmd = CommandBuilder::new(&borg);
+/// cmd.arg("config").repo(&repo).arg("additional_free_space");
+/// let output = cmd.output()?;
+/// println!("{}", String::from_utf8_lossy(&output).trim());
+/// # Ok::<(), borrg::Error>(())
+/// ```
+pub struct CommandBuilder {
+    cmd: Command,
+    modern_exit_codes: bool,
+    timeout: Option<Duration>,
+    /// [`Passphrase::CommandArgs`] to run before borg
+    passcommand: Option<Vec<String>>,
+    /// Where the output of `passcommand` is kept, see [`Borg`]
+    passphrases: Option<Arc<Passphrases>>,
+    /// Interrupts borg once cancelled, see [`Borg::cancellation`]
+    cancellation: Option<CancellationToken>,
+    /// Borg mustn't wait for input, see [`Self::batch`]
+    batch: bool,
+}
+
+impl CommandBuilder {
+    /// Borg with the cache, exit codes, log level, `--show-rc` and batch mode of `borg`
+    pub fn new(borg: &Borg) -> Self {
+        let mut cmd = Self::from_command(Command::new(borg_program()));
+        if let Some(level) = borg.log_level {
+            cmd.log_level(level);
+        }
+        if borg.show_rc {
+            cmd.arg("--show-rc");
+        }
+        cmd.cache(&borg.cache);
+        cmd.exit_codes(borg);
+        cmd.cancellation = borg.cancellation.clone();
+        cmd.passphrases = Some(Arc::clone(&borg.passphrases));
+        if borg.interaction.is_batch() {
+            cmd.batch();
+        }
+        cmd
+    }
+
+    fn from_command(cmd: Command) -> Self {
+        Self {
+            cmd,
+            modern_exit_codes: false,
+            timeout: None,
+            passcommand: None,
+            passphrases: None,
+            cancellation: None,
+            batch: false,
+        }
+    }
+
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.cmd.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.cmd.args(args);
+        self
+    }
+
+    pub fn env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
+        self.cmd.env(key, value);
+        self
+    }
+
+    /// Run borg in `dir`, relative paths in the arguments are relative to it
+    pub fn current_dir(&mut self, dir: impl AsRef<std::path::Path>) -> &mut Self {
+        self.cmd.current_dir(dir);
+        self
+    }
+
+    /// Operate on `repository`: adds its location and sets its passphrase and environment
+    pub fn repo(&mut self, repository: &Repo) -> &mut Self {
+        self.repo_env(repository);
+        self.arg(repository.borg_location())
+    }
+
+    /// Operate on an archive of `repository`, like [`Self::repo`]
+    pub fn archive(&mut self, repository: &Repo, archive: &str) -> &mut Self {
+        self.repo_env(repository);
+        self.arg(format!("{}::{archive}", repository.borg_location()))
+    }
+
+    /// Set the environment and passphrase of `repository` without adding its location
+    ///
+    /// In batch mode a repository without a passphrase gets an empty one, borg fails then
+    /// instead of asking if it is encrypted.
+    pub fn repo_env(&mut self, repository: &Repo) -> &mut Self {
+        self.cmd.envs(&repository.env);
+        let is_set =
+            |var: &str| std::env::var_os(var).is_some() || repository.env.contains_key(var);
+        debug!(
+            "Passphrase for {repository}: {}",
+            passphrase_source(repository.passphrase.as_ref(), is_set)
+        );
+        if let Some(pass) = &repository.passphrase {
+            self.passphrase(pass);
+        }
+        if self.batch && !has_passphrase_source(repository.passphrase.as_ref(), is_set) {
+            self.env("BORG_PASSPHRASE", "");
+        }
+        // The `rsh` of the repository replaced the one `batch` set up
+        if self.batch && repository.env.contains_key("BORG_RSH") {
+            self.rsh_batch_mode();
+        }
+        self
+    }
+
+    /// Never let borg wait for input, see [`Interaction::Batch`](crate::Interaction::Batch)
+    ///
+    /// Borg gets no stdin, and "no" as the answer to the questions of [`BORG_QUESTIONS`] unless
+    /// borrg's environment answers them. If the remote shell is ssh, it runs with `BatchMode`, so
+    /// it fails instead of asking for a password or about an unknown host key.
+    pub fn batch(&mut self) -> &mut Self {
+        self.batch = true;
+        self.cmd.stdin(Stdio::null());
+        for var in BORG_QUESTIONS {
+            if std::env::var_os(var).is_none() {
+                self.env(var, "no");
+            }
+        }
+        self.rsh_batch_mode();
+        self
+    }
+
+    /// Add `-o BatchMode=yes` to the `BORG_RSH` borg will use, if it is ssh
+    fn rsh_batch_mode(&mut self) {
+        let rsh = match self.cmd.get_envs().find(|(key, _)| *key == "BORG_RSH") {
+            Some((_, value)) => value.map(OsStr::to_os_string),
+            None => std::env::var_os("BORG_RSH"),
+        };
+        let rsh = match &rsh {
+            Some(rsh) => rsh.to_string_lossy(),
+            None => "ssh".into(),
+        };
+        if let Some(rsh) = ssh_batch_mode(&rsh) {
+            self.env("BORG_RSH", rsh);
+        }
+    }
 
-impl BorgCommand {
-    pub(self) fn rate_limit(&mut self, rate_limit: &RateLimit) -> &mut Self {
+    pub fn rate_limit(&mut self, rate_limit: &RateLimit) -> &mut Self {
         match rate_limit {
             RateLimit {
                 up: Some(up),
@@ -346,27 +1164,115 @@ impl BorgCommand {
         self
     }
 
-    pub(self) fn passphrase(&mut self, passphrase: &Passphrase) -> &mut Self {
+    /// Pass the passphrase to borg
+    ///
+    /// Borg prefers `BORG_PASSPHRASE` over `BORG_PASSCOMMAND` over `BORG_PASSPHRASE_FD`, so the
+    /// other two are removed from the inherited environment, also for
+    /// [`Passphrase::Environment`].
+    pub fn passphrase(&mut self, passphrase: &Passphrase) -> &mut Self {
+        for var in PASSPHRASE_ENV {
+            self.cmd.env_remove(var);
+        }
+        self.passcommand = None;
         match passphrase {
-            Passphrase::Passphrase(ref passphrase) => {
-                self.env("BORG_PASSPHRASE", passphrase);
-            }
-            Passphrase::Command(ref command) => {
-                self.env("BORG_PASSCOMMAND", command);
-            }
-            Passphrase::FileDescriptor(fd) => {
-                self.env("BORG_PASSPHRASE_FD", fd.to_string());
+            Passphrase::Passphrase(passphrase) => self.env("BORG_PASSPHRASE", passphrase),
+            Passphrase::Command(command) => self.env("BORG_PASSCOMMAND", command),
+            Passphrase::FileDescriptor(fd) => self.env("BORG_PASSPHRASE_FD", fd.to_string()),
+            // BORG_PASSPHRASE is set once the command ran, see `prepare`
+            Passphrase::CommandArgs(command) => {
+                self.passcommand = Some(command.to_owned());
+                self
             }
+            Passphrase::Environment(var) => match std::env::var_os(var) {
+                Some(value) if PASSPHRASE_ENV.contains(&var.as_str()) => self.env(var, value),
+                Some(value) => self.env("BORG_PASSPHRASE", value),
+                None => self,
+            },
+        }
+    }
+
+    /// Log the command and run the passcommand of a [`Passphrase::CommandArgs`], once per
+    /// [`Borg`]
+    ///
+    /// Fails if the command was already cancelled, borg isn't started then.
+    fn prepare(&mut self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(BorgError::Cancelled.into());
+        }
+        log_command(&self.cmd);
+        if let Some(command) = self.passcommand.take() {
+            let passphrase = match &self.passphrases {
+                Some(passphrases) => passphrases.get_or_run(&command, self.batch)?,
+                None => run_passcommand(&command, self.batch)?,
+            };
+            self.env("BORG_PASSPHRASE", passphrase);
+        }
+        Ok(())
+    }
+
+    /// Interrupt borg once the token is cancelled, unless it already got SIGINT
+    fn interrupt_cancelled(&self, child: &mut std::process::Child) {
+        if self
+            .cancellation
+            .as_ref()
+            .is_none_or(CancellationToken::interrupts)
+        {
+            interrupt(child);
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    pub fn cache(&mut self, cache: &Cache) -> &mut Self {
+        self.cmd.envs(cache.env());
+        self
+    }
+
+    pub fn exit_codes(&mut self, borg: &Borg) -> &mut Self {
+        self.modern_exit_codes = borg.modern_exit_codes;
+        if borg.modern_exit_codes {
+            self.env("BORG_EXIT_CODES", "modern");
+        } else {
+            self.cmd.env_remove("BORG_EXIT_CODES");
+        }
+        self
+    }
+
+    /// Interpret the exit status according to the configured exit codes
+    fn check(&self, status: ExitStatus) -> std::result::Result<(), BorgError> {
+        BorgError::check(status, self.modern_exit_codes)
+    }
+
+    pub fn lock_wait(&mut self, seconds: Option<u32>) -> &mut Self {
+        if let Some(seconds) = seconds {
+            self.arg("--lock-wait").arg(seconds.to_string());
         }
         self
     }
 
-    pub(self) fn progress(&mut self) -> &mut Self {
+    pub fn progress(&mut self) -> &mut Self {
         self.arg("--progress");
         self
     }
 
-    pub(self) fn log_level(&mut self, level: log::Level) -> &mut Self {
+    /// Log as JSON, which [`Self::run_with_events`] parses into events
+    pub fn log_json(&mut self) -> &mut Self {
+        self.arg("--log-json");
+        self
+    }
+
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        if dry_run {
+            self.arg("--dry-run");
+        }
+        self
+    }
+
+    pub fn log_level(&mut self, level: log::Level) -> &mut Self {
         match level {
             log::Level::Error => self.arg("--error"),
             log::Level::Warn => self.arg("--warning"),
@@ -376,55 +1282,297 @@ impl BorgCommand {
         };
         self
     }
-}
 
-impl Default for BorgCommand {
-    fn default() -> Self {
-        let borg_path = std::env::var("BORG_PATH").unwrap_or_else(|_| "borg".to_owned());
-
-        let mut cmd = Self(Command::new(borg_path));
-
-        if let Some(level) = log::max_level().to_level() {
-            cmd.log_level(level);
-        };
-
-        cmd
+    /// Kill borg if [`Self::output`] takes longer than `timeout`
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
     }
-}
 
-impl Deref for BorgCommand {
-    type Target = Command;
+    fn spawn(&mut self) -> Result<Child> {
+        self.cmd
+            .spawn()
+            .map_err(|e| spawn_error(self.cmd.get_program(), e))
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Describe the command without running it
+    pub fn invocation(&self) -> Invocation {
+        Invocation {
+            program: self.cmd.get_program().to_owned(),
+            args: self.cmd.get_args().map(ToOwned::to_owned).collect(),
+            env: self
+                .cmd
+                .get_envs()
+                .filter_map(|(key, value)| {
+                    let secret = SECRET_ENV.iter().any(|s| key == *s);
+                    let value = value?.to_owned();
+                    Some((key.to_owned(), (!secret).then_some(value)))
+                })
+                // borrg runs the command itself, borg can run the same one, e.g. in a script
+                .chain(self.passcommand.as_ref().map(|command| {
+                    let words: Vec<_> = command.iter().map(|word| shell_quote(word)).collect();
+                    ("BORG_PASSCOMMAND".into(), Some(words.join(" ").into()))
+                }))
+                .collect(),
+            current_dir: self.cmd.get_current_dir().map(ToOwned::to_owned),
+        }
     }
-}
 
-impl DerefMut for BorgCommand {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+    /// Run borg and collect what it prints to stdout
+    ///
+    /// If borg fails, the error is what it logged, or the [`BorgError`] if it logged nothing.
+    pub fn output(&mut self) -> Result<Vec<u8>> {
+        self.prepare()?;
+
+        let output = self.output_with_timeout(self.timeout)?;
+        if let Err(e) = self.check(output.status) {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.trim().is_empty() {
+                return Err(e.into());
+            }
+            return Err(stderr.into());
+        }
+        Ok(output.stdout)
     }
-}
 
-pub struct BorgWrapper {}
+    /// Like [`Command::output`], but kill borg if it takes longer than `timeout` and interrupt
+    /// it once cancelled
+    fn output_with_timeout(&mut self, timeout: Option<Duration>) -> Result<Output> {
+        if timeout.is_none() && self.cancellation.is_none() {
+            return self
+                .cmd
+                .output()
+                .map_err(|e| spawn_error(self.cmd.get_program(), e));
+        }
 
-impl Backend for BorgWrapper {
-    type Update = Event;
+        self.cmd.stdout(Stdio::piped());
+        self.cmd.stderr(Stdio::piped());
+        let mut child = self.spawn()?;
+
+        // Drain both pipes on their own threads, a full pipe would block borg
+        let read = |pipe: Option<Box<dyn Read + Send>>| {
+            std::thread::spawn(move || {
+                let mut output = vec![];
+                if let Some(mut pipe) = pipe {
+                    pipe.read_to_end(&mut output).ok();
+                }
+                output
+            })
+        };
+        let stdout = read(child.stdout.take().map(|p| Box::new(p) as _));
+        let stderr = read(child.stderr.take().map(|p| Box::new(p) as _));
 
-    fn init_repository(
+        let deadline = timeout.map(|timeout| (std::time::Instant::now() + timeout, timeout));
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if let Some((deadline, timeout)) = deadline {
+                if std::time::Instant::now() >= deadline {
+                    child.kill().ok();
+                    child.wait().ok();
+                    return Err(BorgError::Timeout(timeout).into());
+                }
+            }
+            if self.is_cancelled() {
+                self.interrupt_cancelled(&mut child);
+                child.wait().ok();
+                return Err(BorgError::Cancelled.into());
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().map_err(|_| "Failed to read stdout")?,
+            stderr: stderr.join().map_err(|_| "Failed to read stderr")?,
+        })
+    }
+
+    /// Run borg attached to the terminal and wait for it to exit
+    ///
+    /// Stdin, stdout and stderr are inherited, so borg can ask questions and print whatever it
+    /// likes, except that stdin stays closed in [batch mode](Self::batch). There are no
+    /// events, don't combine this with [`Self::log_json`]. The exit status is returned as is,
+    /// interpreting it is up to the caller.
+    pub fn interactive(&mut self) -> Result<ExitStatus> {
+        self.prepare()?;
+
+        if !self.batch {
+            self.cmd.stdin(Stdio::inherit());
+        }
+        self.cmd.stdout(Stdio::inherit());
+        self.cmd.stderr(Stdio::inherit());
+        self.cmd
+            .status()
+            .map_err(|e| spawn_error(self.cmd.get_program(), e))
+    }
+
+    /// Run borg and read its stdout line by line, for output too large to collect
+    ///
+    /// The [timeout](Self::timeout) doesn't apply, the caller decides how long to read.
+    pub fn output_lines(&mut self) -> Result<OutputLines> {
+        self.prepare()?;
+        OutputLines::spawn(&mut self.cmd, self.modern_exit_codes)
+    }
+
+    /// Spawn the command, pass every event from stderr to `on_update` and wait for it to exit
+    ///
+    /// Borg is interrupted if `on_update` breaks, the result is then [`BorgError::Cancelled`].
+    pub fn run_with_events(
+        &mut self,
+        on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<()> {
+        self.run(false, on_update).map(drop)
+    }
+
+    /// Same as [`Self::run_with_events`], but also collect stdout
+    fn run_with_events_and_output(
+        &mut self,
+        on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<Vec<u8>> {
+        self.run(true, on_update)
+    }
+
+    /// Run borg with stderr and, if `collect_stdout` is set, stdout piped
+    ///
+    /// Every pipe is drained on its own thread, stderr into a channel of events and stdout into
+    /// a buffer. Borg can't block on a full pipe that way, no matter how slow `on_update` is or
+    /// how much it writes to either pipe. Once both are closed borg is reaped, and the result
+    /// is assembled from the exit status and what was collected.
+    fn run(
+        &mut self,
+        collect_stdout: bool,
+        mut on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<Vec<u8>> {
+        self.prepare()?;
+
+        if collect_stdout {
+            self.cmd.stdout(Stdio::piped());
+        }
+        self.cmd.stderr(Stdio::piped());
+        let mut child = self.spawn()?;
+
+        let stdout = child.stdout.take().map(|mut stdout| {
+            std::thread::spawn(move || {
+                let mut output = vec![];
+                stdout.read_to_end(&mut output).map(|_| output)
+            })
+        });
+
+        let stderr = child.stderr.take().ok_or("No stderr")?;
+        let (tx, events) = std::sync::mpsc::channel();
+        let stderr = std::thread::spawn(move || {
+            for event in Events::from(stderr) {
+                // Keep draining after the receiver is gone, borg is shutting down then
+                tx.send(event).ok();
+            }
+        });
+
+        // The callback isn't Send, so the events come back to this thread. The token is polled
+        // in between, borg may not report anything for a long time.
+        let mut cancelled = false;
+        let mut reported = None;
+        loop {
+            let event = match events.recv_timeout(CANCEL_POLL) {
+                Ok(event) => Some(event),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => None,
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            if !cancelled && self.is_cancelled() {
+                cancelled = true;
+                self.interrupt_cancelled(&mut child);
+            }
+            let Some(event) = event else {
+                continue;
+            };
+            if let Event::Termination { rc, .. } = event {
+                reported = Some(rc);
+            }
+            if !cancelled && on_update(event).is_break() {
+                cancelled = true;
+                interrupt(&mut child);
+            }
+        }
+
+        stderr.join().map_err(|_| "Failed to read stderr")?;
+        let output = match stdout {
+            Some(stdout) => stdout.join().map_err(|_| "Failed to read stdout")??,
+            None => vec![],
+        };
+        let status = child.wait()?;
+        if cancelled {
+            return Err(BorgError::Cancelled.into());
+        }
+        BorgError::check_reported(status, reported, self.modern_exit_codes)?;
+
+        Ok(output)
+    }
+}
+
+/// How often a running command checks whether it was cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// Ask borg to stop like Ctrl+C would, so it can release locks and clean up
+fn interrupt(child: &mut std::process::Child) {
+    #[cfg(unix)]
+    if sigint(child.id()) {
+        return;
+    }
+    child.kill().ok();
+}
+
+/// Send SIGINT to a child which wasn't reaped yet, returns whether that worked
+#[cfg(unix)]
+fn sigint(pid: u32) -> bool {
+    // SAFETY: kill has no memory safety requirements
+    unsafe { libc::kill(pid as libc::pid_t, libc::SIGINT) == 0 }
+}
+
+/// `$BORG_PATH` if set, otherwise borg from `$PATH`
+///
+/// On Windows `borg.exe` is looked up explicitly, pip installs it next to a `borg` script
+/// which can't be executed directly.
+fn borg_program() -> OsString {
+    if let Some(path) = std::env::var_os("BORG_PATH") {
+        return path;
+    }
+
+    #[cfg(windows)]
+    if let Some(exe) = std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join("borg.exe"))
+            .find(|exe| exe.is_file())
+    }) {
+        return exe.into_os_string();
+    }
+
+    "borg".into()
+}
+
+/// Borg without any configuration, `$BORG_PATH` if set
+impl Default for CommandBuilder {
+    fn default() -> Self {
+        Self::from_command(Command::new(borg_program()))
+    }
+}
+
+pub struct BorgWrapper {}
+
+impl BorgWrapper {
+    fn init_command(
         borg: &Borg,
-        repository: &mut Repo,
-        encryption: Encryption,
+        repository: &Repo,
+        encryption: &Encryption,
         append_only: bool,
         storage_quota: Option<usize>,
         make_parent_dirs: bool,
-        on_update: impl Fn(Event),
-    ) -> Result<()> {
-        let mut cmd = BorgCommand::default();
+    ) -> Result<CommandBuilder> {
+        let mut cmd = CommandBuilder::new(borg);
 
         cmd.arg("init");
 
-        cmd.arg("--log-json");
+        cmd.log_json();
 
         cmd.rate_limit(&borg.rate_limit);
 
@@ -444,68 +1592,62 @@ impl Backend for BorgWrapper {
         cmd.arg("--encryption");
         cmd.arg(encryption.to_string());
 
-        cmd.arg(repository.to_string());
-
-        if let Some(ref pass) = repository.passphrase {
-            cmd.passphrase(pass);
-        }
+        cmd.repo(repository);
 
         // Don't let borg ask if the passphrase should be displayed
         cmd.env("BORG_DISPLAY_PASSPHRASE", "no");
 
-        log_command(&cmd);
-
-        cmd.stderr(Stdio::piped());
-        let mut child = cmd.spawn()?;
-
-        let stderr = child.stderr.take();
-
-        let stderr = match stderr {
-            Some(stderr) => stderr,
-            None => return Err("No stderr".into()),
-        };
-
-        for event in Events::from(stderr) {
-            on_update(event);
-        }
-
-        Ok(())
+        Ok(cmd)
     }
 
-    fn create_archive(
-        borg: &Borg,
-        repository: &Repo,
-        archive: &Archive,
-        on_update: impl Fn(Event),
-    ) -> Result<()> {
+    fn create_command(borg: &Borg, repository: &Repo, archive: &Archive) -> Result<CommandBuilder> {
         if archive.paths.is_empty() {
             return Err("No paths specified".into());
         }
 
-        let mut cmd = BorgCommand::default();
+        let mut cmd = CommandBuilder::new(borg);
 
         cmd.rate_limit(&borg.rate_limit);
 
-        if let Some(pass) = &repository.passphrase {
-            cmd.passphrase(pass);
-        }
-
         cmd.arg("create");
 
         // TODO: make this configurable
         cmd.progress();
         cmd.arg("--stats");
         // cmd.arg("--list");
-        cmd.arg("--log-json");
+        cmd.log_json();
+
+        let listed = match (&borg.list_files, borg.list_excluded) {
+            (Some(filter), true) => Some(filter.clone().with(FileChangeKind::Excluded)),
+            (Some(filter), false) => Some(filter.clone()),
+            (None, true) => Some(FileChangeKind::Excluded.into()),
+            (None, false) => None,
+        };
+        if let Some(filter) = listed {
+            cmd.arg("--list");
+            if !filter.is_all() {
+                cmd.arg("--filter").arg(filter.to_string());
+            }
+        }
 
         if borg.dry_run {
-            cmd.arg("--dry-run");
+            cmd.dry_run(true);
+        } else {
+            // Stats of the new archive on stdout
+            cmd.arg("--json");
         }
 
         if let Some(comment) = &archive.comment {
             cmd.arg("--comment").arg(comment);
         }
 
+        if let Some(timestamp) = archive.timestamp {
+            // Borg 1.x reads the timestamp as UTC and doesn't accept an offset
+            let utc = chrono::DateTime::<chrono::Utc>::from(timestamp);
+            cmd.arg("--timestamp")
+                .arg(utc.format("%Y-%m-%dT%H:%M:%S").to_string());
+        }
+
         if let Some(compression) = &archive.compression {
             cmd.arg("--compression").arg(compression.to_string());
         }
@@ -514,7 +1656,7 @@ impl Backend for BorgWrapper {
             let pattern_file = if pattern_file.is_absolute() {
                 pattern_file.to_owned()
             } else if let Some(path) = archive.paths.first() {
-                resolve_path(&path.join(pattern_file))
+                archive.locate(&path.join(pattern_file))
             } else {
                 return Err("relative pattern file for multiple paths".into());
             };
@@ -527,67 +1669,1923 @@ impl Backend for BorgWrapper {
             cmd.arg(pattern_file);
         }
 
-        if let Some(exclude_file) = &archive.exclude_file {
-            let exclude_file = if exclude_file.is_absolute() {
-                exclude_file.to_owned()
-            } else if let Some(path) = archive.paths.first() {
-                resolve_path(&path.join(exclude_file))
-            } else {
-                return Err("relative exclude file for multiple paths".into());
-            };
-            if !exclude_file.is_file() {
-                return Err(
-                    format!("exclude file does not exist: {}", exclude_file.display()).into(),
-                );
+        match archive.exclude_file_status() {
+            Some(ExcludeFile::Applied(exclude_file)) => {
+                cmd.arg("--exclude-from");
+                cmd.arg(exclude_file);
             }
-            cmd.arg("--exclude-from");
-            cmd.arg(exclude_file);
+            Some(status @ ExcludeFile::Skipped(_, MissingFile::Warn)) => warn!("{status}"),
+            Some(status @ ExcludeFile::Skipped(..)) => debug!("{status}"),
+            Some(status @ ExcludeFile::Missing(_)) => return Err(status.to_string().into()),
+            None => {}
         }
 
-        cmd.arg(format!("{}::{}", repository, archive.name));
-        cmd.args(archive.paths.iter().map(resolve_path));
+        for name in &archive.exclude_if_present {
+            cmd.arg("--exclude-if-present").arg(name);
+        }
+        if archive.keep_exclude_tags {
+            cmd.arg("--keep-exclude-tags");
+        }
 
-        log_command(&cmd);
+        cmd.archive(repository, &archive.name);
+        if let Some(dir) = &archive.relative_to {
+            cmd.current_dir(resolve_path(dir));
+        }
+        cmd.args(archive.borg_paths()?);
 
-        cmd.stderr(Stdio::piped());
-        let mut child = cmd.spawn()?;
+        Ok(cmd)
+    }
 
-        let stderr = child.stderr.take();
+    fn check_command(
+        borg: &Borg,
+        repository: &Repo,
+        options: &CheckOptions,
+    ) -> Result<CommandBuilder> {
+        let mut cmd = CommandBuilder::new(borg);
 
-        let stderr = match stderr {
-            Some(stderr) => stderr,
-            None => return Err("No stderr".into()),
-        };
+        cmd.rate_limit(&borg.rate_limit);
+
+        cmd.arg("check");
 
-        for event in Events::from(stderr) {
-            on_update(event);
+        cmd.progress();
+        cmd.log_json();
+
+        if options.repository_only {
+            cmd.arg("--repository-only");
         }
 
-        Ok(())
+        if options.archives_only {
+            cmd.arg("--archives-only");
+        }
+
+        if let Some(last) = options.last {
+            cmd.arg("--last").arg(last.to_string());
+        }
+
+        if options.verify_data {
+            cmd.arg("--verify-data");
+        }
+
+        cmd.repo(repository);
+
+        Ok(cmd)
     }
 
-    fn repo_info(repository: &Repo) -> Result<RepoInfo> {
-        let mut cmd = BorgCommand::default();
+    fn prune_command(
+        borg: &Borg,
+        repository: &Repo,
+        policy: &PrunePolicy,
+    ) -> Result<CommandBuilder> {
+        if policy.is_empty() {
+            return Err("No prune policy specified".into());
+        }
+
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.rate_limit(&borg.rate_limit);
+
+        cmd.arg("prune");
+
+        cmd.progress();
+        cmd.log_json();
+        // Lets the caller count kept and pruned archives
+        cmd.arg("--list");
+
+        cmd.dry_run(borg.dry_run);
+
+        if let Some(within) = &policy.keep_within {
+            cmd.arg("--keep-within").arg(within);
+        }
+
+        let rules = [
+            ("--keep-last", policy.keep_last),
+            ("--keep-hourly", policy.keep_hourly),
+            ("--keep-daily", policy.keep_daily),
+            ("--keep-weekly", policy.keep_weekly),
+            ("--keep-monthly", policy.keep_monthly),
+            ("--keep-yearly", policy.keep_yearly),
+        ];
+        for (flag, keep) in rules {
+            if let Some(keep) = keep {
+                cmd.arg(flag).arg(keep.to_string());
+            }
+        }
+
+        if let Some(glob) = &policy.glob_archives {
+            cmd.arg("--glob-archives").arg(glob);
+        }
+
+        cmd.repo(repository);
+
+        Ok(cmd)
+    }
+
+    fn compact_command(borg: &Borg, repository: &Repo) -> Result<CommandBuilder> {
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.rate_limit(&borg.rate_limit);
+
+        cmd.arg("compact");
+
+        cmd.progress();
+        cmd.log_json();
+
+        cmd.repo(repository);
+
+        Ok(cmd)
+    }
+
+    fn delete_command(
+        borg: &Borg,
+        repository: &Repo,
+        cache_only: bool,
+        keep_security_info: bool,
+    ) -> Result<CommandBuilder> {
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.rate_limit(&borg.rate_limit);
+
+        // The caller is responsible for asking the user
+        cmd.env("BORG_DELETE_I_KNOW_WHAT_I_AM_DOING", "YES");
+
+        cmd.arg("delete");
+
+        cmd.progress();
+        cmd.log_json();
+
+        cmd.dry_run(borg.dry_run);
+
+        if cache_only {
+            cmd.arg("--cache-only");
+        }
+
+        if keep_security_info {
+            cmd.arg("--keep-security-info");
+        }
+
+        cmd.repo(repository);
+
+        Ok(cmd)
+    }
+
+    fn version_command(borg: &Borg) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new(borg);
+        cmd.arg("--version");
+        cmd
+    }
+
+    fn info_command(borg: &Borg, repository: &Repo) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new(borg);
 
         cmd.arg("info");
+        cmd.lock_wait(borg.lock_wait);
+        cmd.arg("--json");
+        cmd.repo(repository);
+        cmd.timeout(borg.info_timeout);
 
-        if let Some(pass) = &repository.passphrase {
-            cmd.passphrase(pass);
+        cmd
+    }
+
+    /// `borg --version` on the host of a remote repository
+    fn remote_version_command(borg: &Borg, repository: &Repo) -> Result<CommandBuilder> {
+        // The remote shell splits BORG_REMOTE_PATH into words, borg does the same
+        Self::remote_shell_command(borg, repository, "\"${BORG_REMOTE_PATH:-borg} --version\"")
+    }
+
+    /// `true` on the host of a remote repository, only to find out whether it is reachable
+    fn probe_command(borg: &Borg, repository: &Repo) -> Result<CommandBuilder> {
+        Self::remote_shell_command(borg, repository, "true")
+    }
+
+    /// `command`, a shell word, on the host of a remote repository through `$BORG_RSH` like
+    /// borg connects to it
+    ///
+    /// Only a probe, so ssh never asks: it gets no stdin and runs with `BatchMode`, a host
+    /// which wants a password or has an unknown key fails right away. Other remote shells only
+    /// get the port and destination, like borg runs them.
+    fn remote_shell_command(
+        borg: &Borg,
+        repository: &Repo,
+        command: &str,
+    ) -> Result<CommandBuilder> {
+        let (destination, port) = repository
+            .ssh_destination()
+            .ok_or("not a remote repository")?;
+
+        // Borg splits BORG_RSH into words like a shell, quoted words stay together
+        let mut script = "eval \"set -- ${BORG_RSH:-ssh}\" && \"$@\"".to_owned();
+        if let Some(port) = port {
+            script.push_str(&format!(" -p {port}"));
         }
+        script.push_str(&format!(" {} {command}", shell_quote(&destination)));
 
+        let mut cmd = CommandBuilder::from_command(Command::new("sh"));
+        cmd.arg("-c").arg(script);
+        cmd.cmd.stdin(Stdio::null());
+        if borg.interaction.is_batch() {
+            cmd.batch();
+        }
+        for (key, value) in &repository.env {
+            cmd.env(key, value);
+        }
+        cmd.rsh_batch_mode();
+        cmd.timeout(borg.info_timeout);
+
+        Ok(cmd)
+    }
+
+    fn config_command(borg: &Borg, repository: &Repo, key: &str) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.arg("config");
+        cmd.lock_wait(borg.lock_wait);
+        cmd.repo(repository);
+        cmd.arg(key);
+        cmd.timeout(borg.info_timeout);
+
+        cmd
+    }
+
+    fn list_archives_command(borg: &Borg, repository: &Repo) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.arg("list");
+        cmd.lock_wait(borg.lock_wait);
         cmd.arg("--json");
-        cmd.arg(&repository.to_string());
+        cmd.repo(repository);
+        cmd.timeout(borg.info_timeout);
+
+        cmd
+    }
 
-        log_command(&cmd);
+    fn list_archive_command(borg: &Borg, repository: &Repo, archive: &str) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new(borg);
 
-        let output = cmd.output()?;
+        cmd.arg("list");
+        cmd.arg("--json-lines");
+        cmd.archive(repository, archive);
+
+        cmd
+    }
 
+    fn extract_command(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &str,
+        path: &Path,
+        strip_components: usize,
+    ) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.arg("extract");
+        cmd.lock_wait(borg.lock_wait);
+        if strip_components > 0 {
+            cmd.arg("--strip-components");
+            cmd.arg(strip_components.to_string());
+        }
+        cmd.archive(repository, archive);
+        cmd.arg(path);
+
+        cmd
+    }
+
+    fn key_export_command(borg: &Borg, repository: &Repo, path: &Path) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.arg("key");
+        cmd.arg("export");
+        cmd.repo(repository);
+        cmd.arg(path);
+
+        cmd
+    }
+
+    fn raw_command(
+        borg: &Borg,
+        repository: Option<&Repo>,
+        args: &[OsString],
+    ) -> Result<CommandBuilder> {
+        // Everything after a separator is a path or pattern, not an option
+        let options = args.iter().take_while(|arg| *arg != "--");
+        if options.into_iter().any(|arg| arg == "--log-json") {
+            return Err("--log-json is not supported, borg's output goes to the terminal".into());
+        }
+
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.rate_limit(&borg.rate_limit);
+
+        // Lets the arguments refer to the repository as `::` or `::archive`
+        if let Some(repository) = repository {
+            cmd.repo_env(repository);
+            cmd.env("BORG_REPO", repository.borg_location());
+        }
+
+        cmd.args(args);
+
+        Ok(cmd)
+    }
+
+    fn with_lock_command(
+        borg: &Borg,
+        repository: &Repo,
+        command: &[OsString],
+    ) -> Result<CommandBuilder> {
+        let (program, args) = command.split_first().ok_or("No command specified")?;
+
+        let mut cmd = CommandBuilder::new(borg);
+
+        cmd.arg("with-lock");
+        cmd.lock_wait(borg.lock_wait);
+        cmd.repo(repository);
+        cmd.arg(program);
+        cmd.args(args);
+
+        Ok(cmd)
+    }
+
+    /// Version of borg on the host of a remote repository
+    ///
+    /// Fails for local repositories, and if ssh or borg can't be run on the host.
+    pub fn remote_version(borg: &Borg, repository: &Repo) -> Result<Version> {
+        let output = Self::remote_version_command(borg, repository)?.output()?;
+        Ok(String::from_utf8_lossy(&output).trim().parse()?)
+    }
+
+    /// Fails if the host of a remote repository can't be reached through `$BORG_RSH`, e.g.
+    /// through a jump host or a wrapper script, without needing borg there
+    pub fn probe_remote(borg: &Borg, repository: &Repo) -> Result<()> {
+        let mut cmd = Self::probe_command(borg, repository)?;
+        cmd.prepare()?;
+        let output = cmd.output_with_timeout(cmd.timeout)?;
+        // Not borg, any other status than 0 is a failure
         if !output.status.success() {
-            return Err(String::from_utf8_lossy(&output.stderr).into());
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(match stderr.trim() {
+                "" => format!("remote shell failed with {}", output.status).into(),
+                stderr => stderr.into(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Run `command` while borg holds the lock of the repository
+    ///
+    /// Stdin, stdout and stderr are inherited. Borg exits with the status of the command,
+    /// unless it failed itself. Fails for a dry run, the command would run anyway, see
+    /// [`explain_with_lock`](Self::explain_with_lock).
+    pub fn with_lock(borg: &Borg, repository: &Repo, command: &[OsString]) -> Result<ExitStatus> {
+        if borg.dry_run {
+            return Err("borrg can't dry-run a command under the lock".into());
         }
+        Self::with_lock_command(borg, repository, command)?.interactive()
+    }
+
+    /// Extract `path` of an archive into `dest`, without its first `strip_components` directories
+    ///
+    /// `path` is as listed by [`Backend::list_archive`], e.g. "home/seb/notes.txt".
+    pub fn extract(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &str,
+        path: &Path,
+        dest: &Path,
+        strip_components: usize,
+    ) -> Result<()> {
+        let mut cmd = Self::extract_command(borg, repository, archive, path, strip_components);
+        cmd.current_dir(dest);
+        cmd.output()?;
+        Ok(())
+    }
+
+    /// Export the key of a repository to `path`, replacing an existing export
+    ///
+    /// Borg writes to a temporary file next to `path` first, so a failed export leaves the
+    /// previous one intact. The export is protected by the passphrase like the key itself.
+    pub fn export_key(borg: &Borg, repository: &Repo, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        // Left behind by an earlier attempt
+        let _ = std::fs::remove_file(&partial);
+        // Borg would create the file with the umask, it keeps the mode when overwriting it. So
+        // the key is only readable by the owner from the start.
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(&partial)
+            .map_err(|e| format!("Failed to create {}: {e}", partial.display()))?;
+        if let Err(e) = Self::key_export_command(borg, repository, &partial).output() {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+        std::fs::rename(&partial, path)
+            .map_err(|e| format!("Failed to move the key to {}: {e}", path.display()))?;
+        Ok(())
+    }
+
+    /// Run borg with `args` attached to the terminal, returns its exit status
+    ///
+    /// The passphrase of `repository` is set and its location is in `$BORG_REPO`. Fails
+    /// without running borg if the arguments ask for `--log-json`, or for a dry run: borrg
+    /// can't tell whether the arguments change anything, see [`explain_raw`](Self::explain_raw).
+    pub fn raw(borg: &Borg, repository: Option<&Repo>, args: &[OsString]) -> Result<ExitStatus> {
+        if borg.dry_run {
+            return Err("borrg can't dry-run arbitrary borg commands".into());
+        }
+        Self::raw_command(borg, repository, args)?.interactive()
+    }
+
+    /// The command `init_repository` would execute
+    pub fn explain_init(
+        borg: &Borg,
+        repository: &Repo,
+        encryption: &Encryption,
+        append_only: bool,
+        storage_quota: Option<usize>,
+        make_parent_dirs: bool,
+    ) -> Result<Invocation> {
+        Self::init_command(
+            borg,
+            repository,
+            encryption,
+            append_only,
+            storage_quota,
+            make_parent_dirs,
+        )
+        .map(|cmd| cmd.invocation())
+    }
+
+    /// The command `create_archive` would execute
+    pub fn explain_create(borg: &Borg, repository: &Repo, archive: &Archive) -> Result<Invocation> {
+        Self::create_command(borg, repository, archive).map(|cmd| cmd.invocation())
+    }
+
+    /// The command `check` would execute
+    pub fn explain_check(
+        borg: &Borg,
+        repository: &Repo,
+        options: &CheckOptions,
+    ) -> Result<Invocation> {
+        Self::check_command(borg, repository, options).map(|cmd| cmd.invocation())
+    }
+
+    /// The command `prune` would execute
+    pub fn explain_prune(
+        borg: &Borg,
+        repository: &Repo,
+        policy: &PrunePolicy,
+    ) -> Result<Invocation> {
+        Self::prune_command(borg, repository, policy).map(|cmd| cmd.invocation())
+    }
+
+    /// The command `compact` would execute
+    pub fn explain_compact(borg: &Borg, repository: &Repo) -> Result<Invocation> {
+        Self::compact_command(borg, repository).map(|cmd| cmd.invocation())
+    }
+
+    /// The command `raw` would execute
+    pub fn explain_raw(
+        borg: &Borg,
+        repository: Option<&Repo>,
+        args: &[OsString],
+    ) -> Result<Invocation> {
+        Self::raw_command(borg, repository, args).map(|cmd| cmd.invocation())
+    }
+
+    /// The command `with_lock` would execute
+    pub fn explain_with_lock(
+        borg: &Borg,
+        repository: &Repo,
+        command: &[OsString],
+    ) -> Result<Invocation> {
+        Self::with_lock_command(borg, repository, command).map(|cmd| cmd.invocation())
+    }
+}
+
+impl Backend for BorgWrapper {
+    type Update = Event;
+
+    fn init_repository(
+        borg: &Borg,
+        repository: &mut Repo,
+        encryption: Encryption,
+        append_only: bool,
+        storage_quota: Option<usize>,
+        make_parent_dirs: bool,
+        on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Self::init_command(
+            borg,
+            repository,
+            &encryption,
+            append_only,
+            storage_quota,
+            make_parent_dirs,
+        )?
+        .run_with_events(on_update)
+    }
+
+    fn create_archive(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &Archive,
+        on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<Option<CreateStats>> {
+        let output = Self::create_command(borg, repository, archive)?
+            .run_with_events_and_output(on_update)?;
+
+        // There are no stats for dry runs
+        if borg.dry_run {
+            return Ok(None);
+        }
+
+        let json = serde_json::from_slice::<serde_json::Value>(&output)?;
+        json.try_into().map(Some)
+    }
+
+    fn check(
+        borg: &Borg,
+        repository: &Repo,
+        options: &CheckOptions,
+        on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Self::check_command(borg, repository, options)?.run_with_events(on_update)
+    }
+
+    fn prune(
+        borg: &Borg,
+        repository: &Repo,
+        policy: &PrunePolicy,
+        on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Self::prune_command(borg, repository, policy)?.run_with_events(on_update)
+    }
+
+    fn compact(
+        borg: &Borg,
+        repository: &Repo,
+        on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Self::compact_command(borg, repository)?.run_with_events(on_update)
+    }
+
+    fn delete_repository(
+        borg: &Borg,
+        repository: &Repo,
+        cache_only: bool,
+        keep_security_info: bool,
+        on_update: impl FnMut(Event) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Self::delete_command(borg, repository, cache_only, keep_security_info)?
+            .run_with_events(on_update)
+    }
+
+    fn version(borg: &Borg) -> Result<Version> {
+        let output = Self::version_command(borg).output()?;
+        Ok(String::from_utf8_lossy(&output).trim().parse()?)
+    }
+
+    fn repo_info(borg: &Borg, repository: &Repo) -> Result<RepoInfo> {
+        let output = Self::info_command(borg, repository).output()?;
+        serde_json::from_slice::<serde_json::Value>(&output)?.try_into()
+    }
+
+    fn repo_config(borg: &Borg, repository: &Repo, key: &str) -> Result<String> {
+        let output = Self::config_command(borg, repository, key).output()?;
+        Ok(String::from_utf8_lossy(&output).trim().to_owned())
+    }
+
+    fn list_archives(borg: &Borg, repository: &Repo) -> Result<Vec<ArchiveInfo>> {
+        let output = Self::list_archives_command(borg, repository).output()?;
+        parse_archive_list(serde_json::from_slice(&output)?)
+    }
+
+    fn list_archive(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &str,
+        mut on_entry: impl FnMut(ArchiveListEntry),
+    ) -> Result<()> {
+        let lines = Self::list_archive_command(borg, repository, archive).output_lines()?;
+
+        for line in lines {
+            let line = line?;
+            let json = match serde_json::from_str::<serde_json::Value>(&escape_surrogates(&line)) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Failed to parse archive entry: {line:?} ({e})");
+                    continue;
+                }
+            };
+            match ArchiveListEntry::try_from(json) {
+                Ok(entry) => on_entry(entry),
+                Err(e) => warn!("Invalid archive entry: {line:?} ({e})"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // borg 1.1.x
+    const INFO_1_1: &str = r#"{
+        "cache": {
+            "path": "/home/user/.cache/borg/0a1b",
+            "stats": {
+                "total_chunks": 2,
+                "total_csize": 30,
+                "total_size": 40,
+                "total_unique_chunks": 1,
+                "unique_csize": 10,
+                "unique_size": 20
+            }
+        },
+        "encryption": {
+            "mode": "repokey"
+        },
+        "repository": {
+            "id": "0a1b",
+            "last_modified": "2019-03-01T12:00:00.000000",
+            "location": "/backup/repo"
+        },
+        "security_dir": "/home/user/.config/borg/security/0a1b"
+    }"#;
+
+    // borg 1.2.x
+    const INFO_1_2: &str = r#"{
+        "cache": {
+            "path": "/home/user/.cache/borg/dd06",
+            "stats": {
+                "total_chunks": 236619767,
+                "total_csize": 26289835627221,
+                "total_size": 38449962381221,
+                "total_unique_chunks": 1621026,
+                "unique_csize": 300958014008,
+                "unique_size": 477242905022
+            }
+        },
+        "encryption": {
+            "mode": "repokey-blake2"
+        },
+        "repository": {
+            "id": "dd06",
+            "last_modified": "2022-04-07T15:44:37.000000",
+            "location": "ssh://borg.backup/~/sagittarius"
+        },
+        "security_dir": "/home/user/.config/borg/security/dd06"
+    }"#;
+
+    // borg 1.4.x, with the archives `borg info` adds for archive selections
+    const INFO_1_4: &str = r#"{
+        "archives": [
+            {
+                "name": "2024-05-01",
+                "id": "f00d",
+                "start": "2024-05-01T03:00:00.000000",
+                "end": "2024-05-01T03:12:00.000000"
+            },
+            {
+                "name": "2024-05-02",
+                "id": "beef",
+                "start": "2024-05-02T03:00:00.000000",
+                "end": "2024-05-02T03:11:00.000000"
+            }
+        ],
+        "cache": {
+            "path": "/home/user/.cache/borg/77aa",
+            "stats": {
+                "total_chunks": 10,
+                "total_csize": 1000,
+                "total_size": 2000,
+                "total_unique_chunks": 5,
+                "unique_csize": 500,
+                "unique_size": 1000
+            }
+        },
+        "encryption": {
+            "keyfile": "/home/user/.config/borg/keys/backup_repo",
+            "mode": "keyfile-blake2"
+        },
+        "repository": {
+            "id": "77aa",
+            "last_modified": "2024-05-02T03:11:05.123456",
+            "location": "/backup/repo"
+        },
+        "security_dir": "/home/user/.config/borg/security/77aa"
+    }"#;
+
+    fn parse(json: &str) -> Result<RepoInfo> {
+        serde_json::from_str::<serde_json::Value>(json)?.try_into()
+    }
+
+    #[test]
+    fn test_repo_info() {
+        let info = parse(INFO_1_1).unwrap();
+        assert!(matches!(info.encryption, Encryption::RepoKey));
+        assert_eq!(info.location, "/backup/repo");
+        assert_eq!(info.unique_csize, 10);
+        assert_eq!(
+            info.last_modified,
+            parse_timestamp("2019-03-01T12:00:00.000000").unwrap()
+        );
+
+        let info = parse(INFO_1_2).unwrap();
+        assert!(matches!(info.encryption, Encryption::RepoKeyBlake2));
+        assert_eq!(info.keyfile, None);
+        assert_eq!(info.total_size, 38449962381221);
+
+        let info = parse(INFO_1_4).unwrap();
+        assert!(matches!(info.encryption, Encryption::KeyFileBlake2));
+        assert_eq!(
+            info.keyfile.as_deref(),
+            Some(Path::new("/home/user/.config/borg/keys/backup_repo"))
+        );
+        assert!(info.last_modified > parse_timestamp("2024-05-02T03:11:05").unwrap());
+    }
+
+    #[test]
+    fn test_repo_info_missing_keys() {
+        let mut json: serde_json::Value = serde_json::from_str(INFO_1_2).unwrap();
+        json["repository"]
+            .as_object_mut()
+            .unwrap()
+            .remove("last_modified");
+        assert!(RepoInfo::try_from(json).is_err());
+
+        let mut json: serde_json::Value = serde_json::from_str(INFO_1_2).unwrap();
+        json["repository"]["last_modified"] = "yesterday".into();
+        assert!(RepoInfo::try_from(json).is_err());
+    }
+
+    #[test]
+    fn test_archive_list_entry() {
+        let line = r#"{"type": "-", "mode": "-rw-r--r--", "user": "seb", "group": "users", "uid": 1000, "gid": 100, "path": "home/seb/notes.txt", "healthy": true, "source": "", "linktarget": "", "flags": null, "mtime": "2022-04-07T15:44:37.000000", "size": 1234}"#;
+        let entry =
+            ArchiveListEntry::try_from(serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .unwrap();
+        assert_eq!(entry.path, PathBuf::from("home/seb/notes.txt"));
+        assert_eq!(entry.kind, EntryKind::File);
+        assert_eq!(entry.size, 1234);
+        assert_eq!(entry.user, "seb");
+        assert_eq!(entry.link_target, None);
+
+        let line = r#"{"type": "l", "mode": "lrwxrwxrwx", "user": "seb", "group": "users", "path": "home/seb/link", "linktarget": "notes.txt", "mtime": "2022-04-07T15:44:37.000000", "size": 0}"#;
+        let entry =
+            ArchiveListEntry::try_from(serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .unwrap();
+        assert_eq!(entry.kind, EntryKind::Symlink);
+        assert_eq!(entry.link_target, Some(PathBuf::from("notes.txt")));
+    }
+
+    // borg 1.2.x, `borg create --json`
+    const CREATE_1_2: &str = r#"{
+        "archive": {
+            "command_line": ["borg", "create", "--json", "/backup/repo::2024-05-10", "/home"],
+            "duration": 12.5,
+            "end": "2024-05-10T03:00:12.500000",
+            "id": "9d4d1cd3e0c7bd5bf7f12f1ba9e6c1c53e1b4fe4b1cbb77e1c2d1aa0ec3b45e1",
+            "limits": {
+                "max_archive_size": 0.0001
+            },
+            "name": "2024-05-10",
+            "start": "2024-05-10T03:00:00.000000",
+            "stats": {
+                "compressed_size": 1024,
+                "deduplicated_size": 512,
+                "nfiles": 3,
+                "original_size": 2048
+            }
+        },
+        "cache": {
+            "path": "/home/user/.cache/borg/77aa",
+            "stats": {
+                "total_chunks": 10,
+                "total_csize": 1000,
+                "total_size": 2000,
+                "total_unique_chunks": 5,
+                "unique_csize": 500,
+                "unique_size": 1000
+            }
+        },
+        "encryption": {
+            "mode": "repokey"
+        },
+        "repository": {
+            "id": "77aa",
+            "last_modified": "2024-05-10T03:00:13.000000",
+            "location": "/backup/repo"
+        }
+    }"#;
+
+    #[test]
+    fn test_create_stats() {
+        let json: serde_json::Value = serde_json::from_str(CREATE_1_2).unwrap();
+        let stats = CreateStats::try_from(json.clone()).unwrap();
+        assert_eq!(stats.name, "2024-05-10");
+        assert_eq!(
+            stats.id,
+            "9d4d1cd3e0c7bd5bf7f12f1ba9e6c1c53e1b4fe4b1cbb77e1c2d1aa0ec3b45e1"
+        );
+        assert_eq!(stats.duration, Duration::from_millis(12500));
+        assert_eq!(stats.original_size, 2048);
+        assert_eq!(stats.deduplicated_size, 512);
+        assert_eq!(stats.nfiles, 3);
+
+        let mut json = json;
+        json["archive"].as_object_mut().unwrap().remove("id");
+        assert!(CreateStats::try_from(json).is_err());
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("create"), "create");
+        assert_eq!(
+            shell_quote("/srv/repo::2024-01-01"),
+            "/srv/repo::2024-01-01"
+        );
+        assert_eq!(shell_quote("my docs"), "'my docs'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("say \"hi\""), "'say \"hi\"'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("Übersicht"), "'Übersicht'");
+        assert_eq!(shell_quote("naïve café"), "'naïve café'");
+    }
+
+    #[test]
+    fn test_format_command() {
+        let format =
+            |args: &[&str]| format_command(OsStr::new("borg"), args.iter().map(OsStr::new));
+        assert_eq!(format(&[]), "borg");
+        assert_eq!(format(&["create", ""]), "borg create ''");
+        assert_eq!(
+            format(&["--comment", "it's \"done\""]),
+            r#"borg --comment 'it'\''s "done"'"#
+        );
+        assert_eq!(
+            format(&["--passphrase", "hunter 2", "repo"]),
+            "borg --passphrase *** repo"
+        );
+        assert_eq!(
+            format(&["--passcommand=pass show borg"]),
+            "borg --passcommand=***"
+        );
+    }
+
+    #[test]
+    fn test_create_invocation() {
+        let mut repo: Repo = "/srv/backup".parse().unwrap();
+        repo.passphrase(Passphrase::Passphrase("hunter2".to_owned()));
+        let mut archive = Archive::new("docs");
+        archive.path("/home/seb/my docs");
+        archive.set_comment("nightly");
+        archive.set_timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_532_400));
+        archive.exclude_if_present(".nobackup");
+        archive.set_keep_exclude_tags(true);
+
+        let mut borg = Borg::default();
+        borg.dry_run();
+        let listed = BorgWrapper::explain_create(&borg, &repo, &archive).unwrap();
+        assert!(!listed.args.iter().any(|a| a == "--list"));
+        borg.list_excluded();
+
+        let invocation = BorgWrapper::explain_create(&borg, &repo, &archive).unwrap();
+        let args: Vec<_> = invocation
+            .args
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(args.first().map(String::as_str), Some("create"));
+        assert!(args.contains(&"--dry-run".to_owned()));
+        assert!(args.windows(3).any(|w| w == ["--list", "--filter", "x"]));
+        assert!(args.windows(2).any(|w| w == ["--comment", "nightly"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--timestamp", "2024-05-01T03:00:00"]));
+        assert!(args
+            .windows(3)
+            .any(|w| w == ["--exclude-if-present", ".nobackup", "--keep-exclude-tags"]));
+        assert_eq!(args.last().map(String::as_str), Some("/home/seb/my docs"));
+        assert_eq!(
+            invocation.env,
+            vec![(OsString::from("BORG_PASSPHRASE"), None)]
+        );
+
+        let explained = invocation.to_string();
+        assert!(explained.contains("'/home/seb/my docs'"));
+        assert!(explained.contains("BORG_PASSPHRASE"));
+        assert!(!explained.contains("hunter2"));
+        assert_eq!(invocation.current_dir, None);
+
+        // Listed files are filtered by the union of both filters
+        borg.list_files("AM".parse().unwrap());
+        let invocation = BorgWrapper::explain_create(&borg, &repo, &archive).unwrap();
+        assert!(invocation
+            .args
+            .windows(3)
+            .any(|w| w == ["--list", "--filter", "AMx"]));
+        borg.list_excluded = false;
+        borg.list_files(FileFilter::default());
+        let invocation = BorgWrapper::explain_create(&borg, &repo, &archive).unwrap();
+        assert!(invocation.args.iter().any(|a| a == "--list"));
+        assert!(!invocation.args.iter().any(|a| a == "--filter"));
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let paths = |invocation: &Invocation| -> Vec<String> {
+            let args = &invocation.args;
+            let archive = args.iter().position(|a| a == "/srv/backup::www").unwrap();
+            args[archive + 1..]
+                .iter()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect()
+        };
+
+        let mut archive = Archive::new("www");
+        archive.extend_paths(["www", "/srv/cgi-bin"]);
+        let invocation = BorgWrapper::explain_create(&Borg::default(), &repo, &archive).unwrap();
+        let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert_eq!(
+            paths(&invocation),
+            [
+                cwd.join("www").display().to_string(),
+                "/srv/cgi-bin".to_owned()
+            ]
+        );
+        assert_eq!(invocation.current_dir, None);
+
+        archive.set_relative_to("/srv");
+        archive.path("/srv");
+        let invocation = BorgWrapper::explain_create(&Borg::default(), &repo, &archive).unwrap();
+        assert_eq!(paths(&invocation), ["www", "cgi-bin", "."]);
+        assert_eq!(
+            invocation.current_dir.as_deref(),
+            Some(std::path::Path::new("/srv"))
+        );
+        assert!(invocation.to_string().ends_with(" (in /srv)"));
+
+        archive.path("/etc");
+        let err = BorgWrapper::explain_create(&Borg::default(), &repo, &archive).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "/etc is outside of /srv, the directory borg runs in"
+        );
+    }
+
+    #[test]
+    fn test_with_lock_command() {
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let command: Vec<OsString> = ["rsync", "-a", "--", "/srv/backup/", "/mnt/copy"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+
+        let cmd = BorgWrapper::with_lock_command(&Borg::default(), &repo, &command).unwrap();
+        let args = cmd.invocation().args;
+        let start = args.iter().position(|a| *a == "with-lock").unwrap();
+        assert_eq!(
+            args[start..],
+            [
+                "with-lock",
+                "/srv/backup",
+                "rsync",
+                "-a",
+                "--",
+                "/srv/backup/",
+                "/mnt/copy"
+            ]
+        );
+
+        assert!(BorgWrapper::with_lock_command(&Borg::default(), &repo, &[]).is_err());
+
+        // Only explained, the command would run without --dry-run
+        let mut borg = Borg::default();
+        borg.dry_run();
+        assert!(BorgWrapper::with_lock(&borg, &repo, &command).is_err());
+        let invocation = BorgWrapper::explain_with_lock(&borg, &repo, &command).unwrap();
+        assert!(invocation.args.contains(&"with-lock".into()));
+    }
+
+    #[test]
+    fn test_prune_command() {
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let policy = PrunePolicy {
+            keep_monthly: Some(12),
+            glob_archives: Some("monthly-*".to_owned()),
+            ..Default::default()
+        };
+
+        let cmd = BorgWrapper::prune_command(&Borg::default(), &repo, &policy).unwrap();
+        let args = cmd.invocation().args;
+        let start = args.iter().position(|a| *a == "--keep-monthly").unwrap();
+        assert_eq!(
+            args[start..],
+            [
+                "--keep-monthly",
+                "12",
+                "--glob-archives",
+                "monthly-*",
+                "/srv/backup"
+            ]
+        );
+
+        // A pattern alone keeps nothing
+        let policy = PrunePolicy {
+            glob_archives: Some("monthly-*".to_owned()),
+            ..Default::default()
+        };
+        assert!(policy.is_empty());
+        assert!(BorgWrapper::prune_command(&Borg::default(), &repo, &policy).is_err());
+    }
+
+    #[test]
+    fn test_key_export_command() {
+        let repo: Repo = "ssh://nas/./backup".parse().unwrap();
+        let path = Path::new("/mnt/keys/dd06.key");
+
+        let cmd = BorgWrapper::key_export_command(&Borg::default(), &repo, path);
+        let args = cmd.invocation().args;
+        let start = args.iter().position(|a| *a == "key").unwrap();
+        assert_eq!(
+            args[start..],
+            ["key", "export", "ssh://nas/./backup", "/mnt/keys/dd06.key"]
+        );
+    }
+
+    #[test]
+    fn test_extract_command() {
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let path = Path::new("home/seb/notes.txt");
+
+        let cmd = BorgWrapper::extract_command(&Borg::default(), &repo, "docs", path, 2);
+        let args = cmd.invocation().args;
+        let start = args.iter().position(|a| *a == "extract").unwrap();
+        assert_eq!(
+            args[start..],
+            [
+                "extract",
+                "--strip-components",
+                "2",
+                "/srv/backup::docs",
+                "home/seb/notes.txt"
+            ]
+        );
+
+        let cmd = BorgWrapper::extract_command(&Borg::default(), &repo, "docs", path, 0);
+        assert!(!cmd
+            .invocation()
+            .args
+            .iter()
+            .any(|a| a == "--strip-components"));
+    }
+
+    #[test]
+    fn test_repository_in_home() {
+        let home = dirs::home_dir().unwrap();
+        for location in ["~/backup", "file://~/backup"] {
+            let repo: Repo = location.parse().unwrap();
+            assert_eq!(repo.to_string(), "~/backup");
+
+            let mut cmd = CommandBuilder::new(&Borg::default());
+            cmd.arg("list").archive(&repo, "docs");
+            let expected = format!("{}::docs", home.join("backup").display());
+            assert_eq!(cmd.invocation().args, ["list", expected.as_str()]);
+        }
+
+        let repo: Repo = "~/backup".parse().unwrap();
+        let borg = Borg::default();
+        let init = BorgWrapper::init_command(&borg, &repo, &Encryption::None, false, None, false)
+            .unwrap()
+            .invocation();
+        assert_eq!(init.args.last().unwrap(), home.join("backup").as_os_str());
+
+        let archive = Archive::builder("docs").path("/srv/docs").build().unwrap();
+        let create = BorgWrapper::create_command(&borg, &repo, &archive)
+            .unwrap()
+            .invocation();
+        let expected = format!("{}::docs", home.join("backup").display());
+        assert!(create.args.contains(&expected.into()), "{:?}", create.args);
+        // Displayed as configured, the config stays portable
+        assert_eq!(repo.to_string(), "~/backup");
+
+        // Expanded on the server, by its shell
+        let remote: Repo = "ssh://nas/~/backup".parse().unwrap();
+        let init = BorgWrapper::init_command(&borg, &remote, &Encryption::None, false, None, false)
+            .unwrap()
+            .invocation();
+        assert_eq!(init.args.last().unwrap(), "ssh://nas/~/backup");
+    }
+
+    #[test]
+    fn test_config_invocation() {
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let cmd = BorgWrapper::config_command(&Borg::default(), &repo, "append_only");
+        assert_eq!(
+            cmd.invocation().args,
+            ["config", "/srv/backup", "append_only"]
+        );
+    }
+
+    #[test]
+    fn test_remote_version_command() {
+        let borg = Borg::default();
+        let mut repo: Repo = "ssh://seb@nas:2222/./backup".parse().unwrap();
+        repo.set_env("BORG_RSH", "ssh -i key");
+        let invocation = BorgWrapper::remote_version_command(&borg, &repo)
+            .unwrap()
+            .invocation();
+        assert_eq!(invocation.program, "sh");
+        assert_eq!(
+            invocation.args,
+            [
+                "-c",
+                "eval \"set -- ${BORG_RSH:-ssh}\" && \"$@\" -p 2222 seb@nas \
+                \"${BORG_REMOTE_PATH:-borg} --version\""
+            ]
+        );
+        assert!(invocation.env.contains(&(
+            "BORG_RSH".into(),
+            Some("ssh -i key -o BatchMode=yes".into())
+        )));
+
+        let local: Repo = "/srv/backup".parse().unwrap();
+        assert!(BorgWrapper::remote_version_command(&borg, &local).is_err());
+    }
+
+    #[test]
+    fn test_ssh_batch_mode() {
+        assert_eq!(
+            ssh_batch_mode("ssh").as_deref(),
+            Some("ssh -o BatchMode=yes")
+        );
+        assert_eq!(
+            ssh_batch_mode("'/opt/my tools/ssh' -i '/my keys/backup'").as_deref(),
+            Some("'/opt/my tools/ssh' -i '/my keys/backup' -o BatchMode=yes")
+        );
+        // Wrappers may not take ssh's options, and what the user set stays
+        assert_eq!(ssh_batch_mode("/usr/local/bin/borg-rsh -J bastion"), None);
+        assert_eq!(ssh_batch_mode("sshpass -f pw ssh"), None);
+        assert_eq!(ssh_batch_mode("ssh -o BatchMode=no"), None);
+        assert_eq!(ssh_batch_mode(""), None);
+    }
+
+    #[test]
+    fn test_batch_command() {
+        let unset = |var: &str| std::env::var_os(var).is_none();
+        let env = |cmd: &CommandBuilder, var: &str| {
+            cmd.cmd
+                .get_envs()
+                .find(|(key, _)| *key == var)
+                .and_then(|(_, value)| value)
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+        let mut borg = Borg::default();
+        borg.interaction(Interaction::Batch);
+        let mut repo: Repo = "/srv/backup".parse().unwrap();
+
+        let mut cmd = CommandBuilder::new(&borg);
+        cmd.repo(&repo);
+        for var in BORG_QUESTIONS.iter().filter(|var| unset(var)) {
+            assert_eq!(env(&cmd, var).as_deref(), Some("no"), "{var}");
+        }
+        // Borg fails instead of asking for a passphrase
+        if PASSPHRASE_ENV.iter().all(|var| unset(var)) {
+            assert_eq!(env(&cmd, "BORG_PASSPHRASE").as_deref(), Some(""));
+        }
+        if unset("BORG_RSH") {
+            assert_eq!(
+                env(&cmd, "BORG_RSH").as_deref(),
+                Some("ssh -o BatchMode=yes")
+            );
+        }
+
+        // What the config sets wins, a configured `rsh` only gets BatchMode added if it is ssh
+        repo.passphrase(Passphrase::Command("pass borg".to_owned()));
+        repo.set_env("BORG_RELOCATED_REPO_ACCESS_IS_OK", "yes");
+        repo.set_env("BORG_RSH", "ssh -J bastion");
+        let mut cmd = CommandBuilder::new(&borg);
+        cmd.repo(&repo);
+        assert_eq!(env(&cmd, "BORG_PASSPHRASE"), None);
+        assert_eq!(
+            env(&cmd, "BORG_RELOCATED_REPO_ACCESS_IS_OK").as_deref(),
+            Some("yes")
+        );
+        assert_eq!(
+            env(&cmd, "BORG_RSH").as_deref(),
+            Some("ssh -J bastion -o BatchMode=yes")
+        );
+        repo.set_env("BORG_RSH", "'/opt/borg rsh' --host-key=ask");
+        let mut cmd = CommandBuilder::new(&borg);
+        cmd.repo(&repo);
+        assert_eq!(
+            env(&cmd, "BORG_RSH").as_deref(),
+            Some("'/opt/borg rsh' --host-key=ask")
+        );
+
+        // The remote shell can't ask either
+        let remote: Repo = "ssh://nas/./backup".parse().unwrap();
+        let cmd = BorgWrapper::remote_version_command(&borg, &remote).unwrap();
+        if unset("BORG_RSH") {
+            assert_eq!(
+                env(&cmd, "BORG_RSH").as_deref(),
+                Some("ssh -o BatchMode=yes")
+            );
+        }
+
+        let mut cmd = CommandBuilder::new(&Borg::default());
+        cmd.repo(&"/srv/backup".parse().unwrap());
+        assert!(BORG_QUESTIONS.iter().all(|var| env(&cmd, var).is_none()));
+        assert_eq!(env(&cmd, "BORG_PASSPHRASE"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_batch_passcommand() {
+        let command = ["sh", "-c", "read -r line && echo got || echo closed"];
+        let command: Vec<_> = command.iter().map(|a| a.to_string()).collect();
+        assert_eq!(run_passcommand(&command, true).unwrap(), "closed");
+    }
+
+    #[test]
+    fn test_raw_command() {
+        let mut repo: Repo = "/srv/backup".parse().unwrap();
+        repo.passphrase(Passphrase::Passphrase("hunter2".to_owned()));
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+
+        let cmd = BorgWrapper::raw_command(
+            &Borg::default(),
+            Some(&repo),
+            &args(&["key", "export", "--paper", "::"]),
+        )
+        .unwrap();
+        let invocation = cmd.invocation();
+        assert_eq!(invocation.args, ["key", "export", "--paper", "::"]);
+        assert!(invocation
+            .env
+            .contains(&("BORG_REPO".into(), Some("/srv/backup".into()))));
+        assert!(invocation.env.contains(&("BORG_PASSPHRASE".into(), None)));
+
+        let borg = Borg::default();
+        assert!(BorgWrapper::raw_command(&borg, None, &args(&["list", "--log-json"])).is_err());
+        // A file may be called like that
+        let args = args(&["create", "::docs", "--", "--log-json"]);
+        assert!(BorgWrapper::raw_command(&borg, None, &args).is_ok());
+
+        // Only explained, borg would run without --dry-run
+        let mut borg = Borg::default();
+        borg.dry_run();
+        assert!(BorgWrapper::raw(&borg, None, &args).is_err());
+        let invocation = BorgWrapper::explain_raw(&borg, None, &args).unwrap();
+        assert_eq!(invocation.args, args);
+    }
+
+    #[test]
+    fn test_create_invocation_without_paths() {
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let archive = Archive::new("empty");
+        assert!(BorgWrapper::explain_create(&Borg::default(), &repo, &archive).is_err());
+    }
+
+    // borg 1.2.x: borg prune --list --progress --log-json --keep-daily 2
+    const PRUNE_1_2: &str = r#"{"type": "log_message", "time": 1715310183.4424438, "message": "Keeping archive (rule: daily #1):        sagittarius-2024-05-10T03:00:01      Fri, 2024-05-10 03:00:02 [5d1e1c2b8e0b7f3a2c4d6e8f0a1b3c5d7e9f1a2b3c4d5e6f7a8b9c0d1e2f3a4b]", "levelname": "INFO", "name": "borg.output.list"}
+{"type": "log_message", "time": 1715310183.4425547, "message": "Keeping archive (rule: daily #2):        sagittarius-2024-05-09T03:00:01      Thu, 2024-05-09 03:00:02 [0f9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e0d9c8b7a6f5e4d3c2b1a0f9e]", "levelname": "INFO", "name": "borg.output.list"}
+{"type": "progress_percent", "operation": 1, "msgid": "prune", "finished": false, "message": "Pruning archives   0.0% (0/2)", "current": 0, "total": 2, "time": 1715310183.4431782}
+{"type": "log_message", "time": 1715310183.5512214, "message": "Pruning archive (1/2):                   sagittarius-2024-05-08T03:00:01      Wed, 2024-05-08 03:00:02 [8a7b6c5d4e3f2a1b0c9d8e7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d0e9f8a7b]", "levelname": "INFO", "name": "borg.output.list"}
+{"type": "log_message", "time": 1715310183.6620735, "message": "Pruning archive (2/2):                   sagittarius-2024-05-07T03:00:01      Tue, 2024-05-07 03:00:02 [1b2c3d4e5f6a7b8c9d0e1f2a3b4c5d6e7f8a9b0c1d2e3f4a5b6c7d8e9f0a1b2c]", "levelname": "INFO", "name": "borg.output.list"}
+{"type": "progress_percent", "operation": 1, "msgid": "prune", "finished": true, "time": 1715310183.7738411}
+{"type": "log_message", "time": 1715310183.9104921, "message": "Done.", "levelname": "INFO", "name": "borg.archiver"}
+"#;
+
+    // borg 1.1.x: borg prune --list --dry-run --log-json --keep-daily 1
+    const PRUNE_1_1: &str = r#"{"type": "log_message", "time": 1652000000.1, "message": "Keeping archive: sagittarius-2022-05-08 Sun, 2022-05-08 03:00:02 [5d1e1c2b8e0b7f3a]", "levelname": "INFO", "name": "borg.output.list"}
+{"type": "log_message", "time": 1652000000.2, "message": "Would prune:     sagittarius-2022-05-07 Sat, 2022-05-07 03:00:02 [0f9e8d7c6b5a4f3e]", "levelname": "INFO", "name": "borg.output.list"}
+"#;
+
+    // borg 1.2.x: borg create --list --progress --log-json, for "café.txt" in Latin-1 next to
+    // "😀.txt" and a file literally called "\udce9.txt"
+    const CREATE_NON_UTF8: &str = r#"{"type": "file_status", "status": "A", "path": "home/seb/caf\udce9.txt"}
+{"type": "archive_progress", "compressed_size": 1291, "deduplicated_size": 1291, "nfiles": 1, "original_size": 2048, "path": "home/seb/caf\udce9.txt", "time": 1715310183.4424438}
+{"type": "file_status", "status": "A", "path": "home/seb/\ud83d\ude00.txt"}
+{"type": "file_status", "status": "A", "path": "home/seb/\\udce9.txt"}
+{"type": "log_message", "time": 1715310183.5512214, "message": "home/seb/caf\udce9.txt: file changed while we backed it up", "levelname": "WARNING", "name": "borg.archiver"}
+"#;
+
+    #[test]
+    fn test_non_utf8_paths() {
+        let events: Vec<_> = Events::from(CREATE_NON_UTF8.as_bytes()).collect();
+        assert!(!events.iter().any(|e| matches!(e, Event::Other(_))));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let cafe = std::ffi::OsStr::from_bytes(b"home/seb/caf\xe9.txt");
+            assert!(matches!(
+                &events[0],
+                Event::FileStatus { status, path } if status == "A" && path == cafe
+            ));
+            assert!(matches!(
+                &events[1],
+                Event::ArchiveProgress { nfiles: 1, path, .. } if path == cafe
+            ));
+        }
+        assert!(matches!(
+            &events[2],
+            Event::FileStatus { path, .. } if path == std::path::Path::new("home/seb/😀.txt")
+        ));
+        assert!(matches!(
+            &events[3],
+            Event::FileStatus { path, .. } if path == std::path::Path::new("home/seb/\\udce9.txt")
+        ));
+        assert!(matches!(
+            &events[4],
+            Event::LogMessage { message, .. }
+                if message == "home/seb/caf\u{fffd}.txt: file changed while we backed it up"
+        ));
+
+        let line = r#"{"path": "caf\udce9\\udce9\udc41"}"#;
+        assert_eq!(
+            escape_surrogates(line),
+            r#"{"path": "caf\u0000\u00e9\\udce9\udc41"}"#
+        );
+    }
+
+    #[test]
+    fn test_prune_events() {
+        let events: Vec<_> = Events::from(PRUNE_1_2.as_bytes()).collect();
+        let mut stats = PruneStats::default();
+        events.iter().for_each(|e| stats.update(e));
+        assert_eq!(stats, PruneStats { kept: 2, pruned: 2 });
+
+        assert!(matches!(
+            &events[0],
+            Event::PruneDecision { archive, pruned: false, rule: Some(rule) }
+                if archive == "sagittarius-2024-05-10T03:00:01" && rule == "daily #1"
+        ));
+        assert_eq!(
+            events[3].to_string(),
+            "Pruning archive sagittarius-2024-05-08T03:00:01"
+        );
+        assert!(matches!(&events[6], Event::LogMessage { message, .. } if message == "Done."));
+
+        let events: Vec<_> = Events::from(PRUNE_1_1.as_bytes()).collect();
+        let mut stats = PruneStats::default();
+        events.iter().for_each(|e| stats.update(e));
+        assert_eq!(stats, PruneStats { kept: 1, pruned: 1 });
+        assert_eq!(
+            events[0].to_string(),
+            "Keeping archive sagittarius-2022-05-08"
+        );
+    }
+
+    /// A borg exiting with $FAKE_BORG_RC, collapsed to 0/1/2 like borg does for legacy exit codes
+    #[cfg(unix)]
+    fn fake_borg(name: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("borrg-fake-borg-{name}-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"#!/bin/sh
+case "$*" in *--version*) echo "borg 1.4.0"; exit 0;; esac
+rc=$FAKE_BORG_RC
+if [ "$BORG_EXIT_CODES" != modern ]; then
+    if [ "$rc" -ge 100 ] && [ "$rc" -lt 128 ]; then rc=1; elif [ "$rc" -ge 3 ] && [ "$rc" -lt 100 ]; then rc=2; fi
+fi
+exit $rc
+"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_version() {
+        let fake = fake_borg("remote-version");
+        let mut repo: Repo = "ssh://nas/./backup".parse().unwrap();
+        repo.set_env("BORG_RSH", fake.display().to_string());
+        let version = BorgWrapper::remote_version(&Borg::default(), &repo).unwrap();
+        assert_eq!(version.to_string(), "1.4.0");
+
+        repo.set_env("BORG_RSH", "false");
+        assert!(BorgWrapper::remote_version(&Borg::default(), &repo).is_err());
+        std::fs::remove_file(fake).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_probe_remote() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A wrapper in a directory with a space, recording what it was run with
+        let dir = std::env::temp_dir().join(format!("borrg rsh {}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rsh = dir.join("rsh");
+        let log = dir.join("args");
+        std::fs::write(&rsh, "#!/bin/sh\necho \"$*\" > \"$RSH_LOG\"\n").unwrap();
+        std::fs::set_permissions(&rsh, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut repo: Repo = "ssh://seb@nas:2222/./backup".parse().unwrap();
+        let words = [
+            rsh.to_str().unwrap(),
+            "-J",
+            "seb@bastion",
+            "-o",
+            "SendEnv=A B",
+        ];
+        let words: Vec<_> = words.iter().map(|w| shell_quote(w)).collect();
+        repo.set_env("BORG_RSH", words.join(" "));
+        repo.set_env("RSH_LOG", log.display().to_string());
+        BorgWrapper::probe_remote(&Borg::default(), &repo).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "-J seb@bastion -o SendEnv=A B -p 2222 seb@nas true\n"
+        );
+
+        // A wrapper which rejects options it doesn't know only gets what borg passes
+        let strict = dir.join("strict-rsh");
+        std::fs::write(
+            &strict,
+            "#!/bin/sh\n[ \"$1\" = -p ] && [ $# -eq 4 ] || exit 2\necho \"$*\" > \"$RSH_LOG\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&strict, std::fs::Permissions::from_mode(0o755)).unwrap();
+        repo.set_env("BORG_RSH", shell_quote(strict.to_str().unwrap()));
+        BorgWrapper::probe_remote(&Borg::default(), &repo).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "-p 2222 seb@nas true\n"
+        );
+
+        // ssh never asks
+        let ssh = dir.join("ssh");
+        std::fs::copy(&rsh, &ssh).unwrap();
+        repo.set_env(
+            "BORG_RSH",
+            format!("{} -J seb@bastion", shell_quote(ssh.to_str().unwrap())),
+        );
+        BorgWrapper::probe_remote(&Borg::default(), &repo).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "-J seb@bastion -o BatchMode=yes -p 2222 seb@nas true\n"
+        );
+
+        repo.set_env("BORG_RSH", "false");
+        assert!(BorgWrapper::probe_remote(&Borg::default(), &repo).is_err());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_codes() {
+        let fake = fake_borg("exit-codes");
+        let run = |modern: bool, rc: i32| {
+            let borg = Borg {
+                modern_exit_codes: modern,
+                ..Default::default()
+            };
+            let mut cmd = CommandBuilder::from_command(Command::new(&fake));
+            cmd.exit_codes(&borg).env("FAKE_BORG_RC", rc.to_string());
+            let status = cmd.cmd.status().unwrap();
+            cmd.check(status)
+        };
+
+        assert!(run(false, 0).is_ok());
+        assert!(run(false, 1).is_ok());
+        assert!(run(false, 105).is_ok());
+        assert!(matches!(run(false, 52), Err(BorgError::Failed(2))));
+
+        assert!(run(true, 0).is_ok());
+        assert!(run(true, 105).is_ok());
+        assert!(matches!(run(true, 2), Err(BorgError::Failed(2))));
+        assert!(matches!(run(true, 3), Err(BorgError::Failed(3))));
+        assert!(matches!(run(true, 13), Err(BorgError::Repository(13))));
+        assert!(matches!(run(true, 31), Err(BorgError::Archive(31))));
+        assert!(matches!(run(true, 42), Err(BorgError::Key(42))));
+        assert!(matches!(run(true, 52), Err(BorgError::Passphrase(52))));
+        assert!(matches!(run(true, 62), Err(BorgError::Cache(62))));
+        assert!(matches!(run(true, 73), Err(BorgError::Lock(73))));
+        assert!(matches!(run(true, 80), Err(BorgError::Connection(80))));
+        assert!(matches!(run(true, 143), Err(BorgError::Signal(15))));
+
+        std::fs::remove_file(&fake).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_termination() {
+        let line = r#"{"type": "log_message", "time": 1715000000.0, "levelname": "WARNING", "name": "borg.archiver", "message": "terminating with warning status, rc 1"}"#;
+        let event = parse_event(line.to_owned());
+        assert!(matches!(
+            event,
+            Event::Termination {
+                rc: 1,
+                level: Some(Level::Warn)
+            }
+        ));
+        assert_eq!(
+            event.to_string(),
+            "borg terminated with warning status (rc 1)"
+        );
+        assert_eq!(
+            parse_termination("terminating with error status, rc 74"),
+            Some(74)
+        );
+        assert_eq!(parse_termination("terminating soon"), None);
+
+        let exit = |rc: i32| {
+            Command::new("sh")
+                .args(["-c", &format!("exit {rc}")])
+                .status()
+                .unwrap()
+        };
+        // A wrapper turned the error into success
+        assert!(matches!(
+            BorgError::check_reported(exit(0), Some(2), false),
+            Err(BorgError::Failed(2))
+        ));
+        assert!(BorgError::check_reported(exit(255), Some(1), false).is_ok());
+        assert!(BorgError::check_reported(exit(0), Some(0), false).is_ok());
+        assert!(BorgError::check_reported(exit(2), None, false).is_err());
+    }
+
+    #[cfg(unix)]
+    fn sh(script: &str) -> CommandBuilder {
+        let mut cmd = CommandBuilder::from_command(Command::new("sh"));
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_with_timeout() {
+        let output = sh("echo archives")
+            .output_with_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"archives\n");
+
+        let start = std::time::Instant::now();
+        let err = sh("exec sleep 10")
+            .output_with_timeout(Some(Duration::from_millis(200)))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(BorgError::Timeout(t)) if *t == Duration::from_millis(200)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_command_builder() {
+        let mut repo: Repo = "ssh://backup@host/./borg".parse().unwrap();
+        repo.passphrase(Passphrase::Command("pass borg".to_owned()));
+        repo.set_env("SSH_AUTH_SOCK", "/run/agent.sock")
+            .set_env("BORG_PASSCOMMAND", "cat secret");
+
+        let mut cmd = CommandBuilder::new(&Borg::default());
+        cmd.arg("recreate").log_json().dry_run(true).dry_run(false);
+        cmd.archive(&repo, "docs").args(["--exclude", "*.tmp"]);
+
+        let invocation = cmd.invocation();
+        assert_eq!(
+            invocation.args,
+            [
+                "recreate",
+                "--log-json",
+                "--dry-run",
+                "ssh://backup@host/./borg::docs",
+                "--exclude",
+                "*.tmp"
+            ]
+        );
+        // The passphrase wins over the environment of the repository
+        assert!(invocation
+            .env
+            .contains(&("BORG_PASSCOMMAND".into(), Some("pass borg".into()))));
+        assert!(invocation
+            .env
+            .contains(&("SSH_AUTH_SOCK".into(), Some("/run/agent.sock".into()))));
+
+        // The log level of borg doesn't follow the one of borrg
+        log::set_max_level(log::LevelFilter::Trace);
+        let cmd = CommandBuilder::new(&Borg::default());
+        assert!(cmd.invocation().args.is_empty());
+
+        let mut borg = Borg::default();
+        borg.log_level(log::Level::Info);
+        let mut cmd = CommandBuilder::new(&borg);
+        cmd.arg("create");
+        assert_eq!(cmd.invocation().args, ["--info", "create"]);
+
+        borg.show_rc();
+        let cmd = CommandBuilder::new(&borg);
+        assert_eq!(cmd.invocation().args, ["--info", "--show-rc"]);
+    }
+
+    #[test]
+    fn test_passphrase_precedence() {
+        let envs = |cmd: &CommandBuilder| -> Vec<(String, Option<String>)> {
+            let mut envs: Vec<_> = cmd
+                .cmd
+                .get_envs()
+                .filter(|(key, _)| key.to_string_lossy().contains("PASS"))
+                .map(|(key, value)| {
+                    let value = value.map(|v| v.to_string_lossy().into_owned());
+                    (key.to_string_lossy().into_owned(), value)
+                })
+                .collect();
+            envs.sort();
+            envs
+        };
+
+        let mut cmd = CommandBuilder::from_command(Command::new("borg"));
+        cmd.passphrase(&Passphrase::Command("pass borg".to_owned()));
+        assert_eq!(
+            envs(&cmd),
+            [
+                ("BORG_PASSCOMMAND".to_owned(), Some("pass borg".to_owned())),
+                ("BORG_PASSPHRASE".to_owned(), None),
+                ("BORG_PASSPHRASE_FD".to_owned(), None),
+            ]
+        );
+
+        let mut cmd = CommandBuilder::from_command(Command::new("borg"));
+        cmd.passphrase(&Passphrase::Environment("BORRG_TEST_UNSET".to_owned()));
+        assert_eq!(
+            envs(&cmd),
+            [
+                ("BORG_PASSCOMMAND".to_owned(), None),
+                ("BORG_PASSPHRASE".to_owned(), None),
+                ("BORG_PASSPHRASE_FD".to_owned(), None),
+            ]
+        );
+
+        let exported = |var: &str| var == "BORG_PASSPHRASE";
+        let command = Passphrase::Command("pass borg".to_owned());
+        assert_eq!(
+            passphrase_source(Some(&command), exported),
+            "passcommand from the config, overriding BORG_PASSPHRASE"
+        );
+        assert_eq!(
+            passphrase_source(Some(&command), |_| false),
+            "passcommand from the config"
+        );
+        let environment = Passphrase::Environment("BORG_PASSPHRASE".to_owned());
+        assert_eq!(
+            passphrase_source(Some(&environment), exported),
+            "BORG_PASSPHRASE from the environment"
+        );
+        assert_eq!(
+            passphrase_source(Some(&environment), |_| false),
+            "none, BORG_PASSPHRASE isn't set"
+        );
+        let other = Passphrase::Environment("BACKUP_PASS".to_owned());
+        assert_eq!(
+            passphrase_source(Some(&other), |var| var == "BACKUP_PASS" || exported(var)),
+            "BACKUP_PASS from the environment, overriding BORG_PASSPHRASE"
+        );
+        assert_eq!(passphrase_source(None, |_| false), "none");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_passcommand_args() {
+        let passcommand = |args: &[&str]| {
+            let mut cmd = CommandBuilder::from_command(Command::new("borg"));
+            cmd.passphrase(&Passphrase::CommandArgs(
+                args.iter().map(|a| a.to_string()).collect(),
+            ));
+            cmd
+        };
+
+        let mut cmd = passcommand(&["printf", "my secret\\nsecond line\\n"]);
+        // Not run before borg runs, the command is no secret
+        assert_eq!(
+            cmd.invocation().env,
+            [(
+                "BORG_PASSCOMMAND".into(),
+                Some("printf 'my secret\\nsecond line\\n'".into())
+            )]
+        );
+        cmd.prepare().unwrap();
+        let passphrase = cmd
+            .cmd
+            .get_envs()
+            .find(|(key, _)| *key == "BORG_PASSPHRASE")
+            .and_then(|(_, value)| value);
+        assert_eq!(passphrase, Some(OsStr::new("my secret")));
+
+        assert!(passcommand(&["false"]).prepare().is_err());
+        assert!(passcommand(&["borrg-test-missing-passcommand"])
+            .prepare()
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_passcommand_once() {
+        let runs =
+            std::env::temp_dir().join(format!("borrg-test-passcommand-{}", std::process::id()));
+        let script = format!("echo run >> '{}' && echo secret", runs.display());
+        let command = Passphrase::CommandArgs(vec!["sh".into(), "-c".into(), script]);
+        let borg = Borg::default();
+        let clone = borg.clone();
+        for borg in [&borg, &clone, &borg] {
+            let mut cmd = CommandBuilder::new(borg);
+            cmd.passphrase(&command);
+            cmd.prepare().unwrap();
+        }
+        // Another Borg runs it again
+        let mut cmd = CommandBuilder::new(&Borg::default());
+        cmd.passphrase(&command);
+        cmd.prepare().unwrap();
+
+        let count = std::fs::read_to_string(&runs).unwrap().lines().count();
+        std::fs::remove_file(&runs).unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output() {
+        assert_eq!(sh("echo '{}'").output().unwrap(), b"{}\n");
+
+        let err = sh("echo 'Repository does not exist.' >&2; exit 2")
+            .output()
+            .unwrap_err();
+        assert_eq!(err.to_string().trim(), "Repository does not exist.");
+
+        let err = sh("exit 2").output().unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BorgError::Failed(2))));
+    }
+
+    #[test]
+    fn test_binary_not_found() {
+        let missing = || CommandBuilder::from_command(Command::new("/nonexistent/borg"));
+        let not_found = |err: Error| {
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(BorgError::BinaryNotFound { attempted }) if attempted == std::path::Path::new("/nonexistent/borg")
+            ));
+            assert!(err.to_string().contains("BORG_PATH"));
+        };
+
+        not_found(missing().output().unwrap_err());
+        not_found(
+            missing()
+                .timeout(Some(Duration::from_secs(1)))
+                .output()
+                .unwrap_err(),
+        );
+        not_found(missing().output_lines().err().unwrap());
+        not_found(
+            missing()
+                .run_with_events(|_| ControlFlow::Continue(()))
+                .unwrap_err(),
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_lines() {
+        // Far more than fits into the pipe buffers, on both pipes at once
+        let script = r#"i=0; while [ $i -lt 20000 ]; do echo "line $i"; echo "noise $i" >&2; i=$((i+1)); done"#;
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let lines = sh(script).output_lines().unwrap();
+            tx.send(lines.collect::<Result<Vec<_>>>()).unwrap();
+        });
+        let lines = rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("borg blocked on a full pipe")
+            .unwrap();
+        assert_eq!(lines.len(), 20000);
+        assert_eq!(lines.last().map(String::as_str), Some("line 19999"));
+
+        let mut lines = sh("echo entry; echo 'Archive docs does not exist.' >&2; exit 2")
+            .output_lines()
+            .unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "entry");
+        let err = lines.next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Archive docs does not exist.");
+        assert!(lines.next().is_none());
+
+        // Stopping early kills borg instead of waiting for it
+        let start = std::time::Instant::now();
+        let lines = sh("while true; do echo entry; done")
+            .output_lines()
+            .unwrap();
+        assert_eq!(lines.take(3).count(), 3);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_with_large_output() {
+        // Megabytes on both pipes, interleaved, from a borg which doesn't care who reads them
+        let script = r#"
+            i=0
+            while [ $i -lt 4 ]; do
+                head -c 1000000 /dev/zero | tr '\0' 'o'
+                echo
+                yes '{"type": "log_message", "levelname": "INFO", "message": "noise"}' | head -n 20000 >&2
+                i=$((i+1))
+            done
+        "#;
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut events = 0;
+            let output = sh(script).run_with_events_and_output(|_| {
+                events += 1;
+                ControlFlow::Continue(())
+            });
+            tx.send(output.map(|output| (output.len(), events)))
+                .unwrap();
+        });
+        let (stdout, events) = rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("borg blocked on a full pipe")
+            .unwrap();
+        assert_eq!(stdout, 4 * 1_000_001);
+        assert_eq!(events, 4 * 20000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancel() {
+        let mut cmd = CommandBuilder::from_command(Command::new("sh"));
+        cmd.args(["-c", "echo started >&2; echo ignored >&2; exec sleep 10"]);
+
+        let start = std::time::Instant::now();
+        let mut events = vec![];
+        let err = cmd
+            .run_with_events(|event| {
+                events.push(event.to_string());
+                ControlFlow::Break(())
+            })
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BorgError::Cancelled)));
+        assert_eq!(events, ["started"]);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let sleeping = || {
+            let mut cmd = CommandBuilder::from_command(Command::new("sh"));
+            cmd.args(["-c", "echo started >&2; exec sleep 10"]);
+            cmd.cancellation = Some(token.clone());
+            cmd
+        };
+        let cancel_soon = || {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(200));
+                token.cancel();
+            })
+        };
+
+        let start = std::time::Instant::now();
+        let canceller = cancel_soon();
+        let err = sleeping()
+            .run_with_events(|_| ControlFlow::Continue(()))
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BorgError::Cancelled)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+
+        // Once cancelled nothing starts anymore
+        let err = sleeping().output().unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BorgError::Cancelled)));
+    }
 
-        let json = serde_json::from_slice::<serde_json::Value>(&output.stdout)?;
+    #[cfg(unix)]
+    #[test]
+    fn test_version() {
+        let fake = fake_borg("version");
+        let mut cmd = CommandBuilder::from_command(Command::new(&fake));
+        let output = cmd.arg("--version").output().unwrap();
+        let version: Version = String::from_utf8_lossy(&output).trim().parse().unwrap();
+        assert!(version >= Version::MODERN_EXIT_CODES);
 
-        json.try_into()
+        std::fs::remove_file(&fake).ok();
     }
 }
@@ -0,0 +1,274 @@
+use super::*;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt},
+    sync::mpsc::UnboundedSender,
+};
+
+/// [`BorgWrapper`] for tokio, running borg with the same arguments
+pub struct AsyncBorgWrapper {}
+
+impl CommandBuilder {
+    /// Runs the passcommand of a [`Passphrase::CommandArgs`], blocking
+    fn into_tokio(mut self) -> Result<tokio::process::Command> {
+        self.prepare()?;
+        let mut cmd = tokio::process::Command::from(self.cmd);
+        cmd.kill_on_drop(true);
+        Ok(cmd)
+    }
+
+    /// Run borg, send every event from stderr to `updates` and collect stdout
+    ///
+    /// Borg is interrupted like with Ctrl+C once the command is cancelled.
+    async fn run_with_updates(self, updates: &UnboundedSender<Event>) -> Result<Vec<u8>> {
+        let modern_exit_codes = self.modern_exit_codes;
+        let cancellation = self.cancellation.clone();
+        let mut cmd = self.into_tokio()?;
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| spawn_error(cmd.as_std().get_program(), e))?;
+
+        let mut stdout = child.stdout.take().ok_or("No stdout")?;
+        let stderr = child.stderr.take().ok_or("No stderr")?;
+
+        let read_stdout = async {
+            let mut output = vec![];
+            stdout.read_to_end(&mut output).await.map(|_| output)
+        };
+        let forward_events = async {
+            let mut lines = tokio::io::BufReader::new(stderr).lines();
+            let mut reported = None;
+            while let Some(line) = lines.next_line().await? {
+                let event = parse_event(line);
+                if let Event::Termination { rc, .. } = event {
+                    reported = Some(rc);
+                }
+                // Nobody listening is no reason to stop the backup
+                updates.send(event).ok();
+            }
+            Ok::<_, std::io::Error>(reported)
+        };
+        let finished = async { tokio::join!(read_stdout, forward_events) };
+        tokio::pin!(finished);
+        let (output, reported) = tokio::select! {
+            finished = &mut finished => finished,
+            () = cancelled(cancellation.as_ref()) => {
+                if cancellation.as_ref().is_none_or(CancellationToken::interrupts) {
+                    interrupt(&mut child);
+                }
+                // Borg still reports while cleaning up
+                let _ = finished.await;
+                child.wait().await?;
+                return Err(BorgError::Cancelled.into());
+            }
+        };
+        let reported = reported?;
+        let output = output?;
+
+        BorgError::check_reported(child.wait().await?, reported, modern_exit_codes)?;
+
+        Ok(output)
+    }
+
+    /// Run borg and parse the JSON it prints, killing it after the timeout or once cancelled
+    async fn json(self) -> Result<serde_json::Value> {
+        let modern_exit_codes = self.modern_exit_codes;
+        let timeout = self.timeout;
+        let cancellation = self.cancellation.clone();
+        let mut cmd = self.into_tokio()?;
+
+        // Dropping the output kills borg
+        let output = async {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, cmd.output())
+                    .await
+                    .map_err(|_| BorgError::Timeout(timeout)),
+                None => Ok(cmd.output().await),
+            }
+        };
+        let output = tokio::select! {
+            output = output => output?,
+            () = cancelled(cancellation.as_ref()) => return Err(BorgError::Cancelled.into()),
+        };
+        let output = output.map_err(|e| spawn_error(cmd.as_std().get_program(), e))?;
+
+        if BorgError::check(output.status, modern_exit_codes).is_err() {
+            return Err(String::from_utf8_lossy(&output.stderr).into());
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+}
+
+/// Resolves once `token` is cancelled, never without a token
+async fn cancelled(token: Option<&CancellationToken>) {
+    match token {
+        Some(token) => {
+            while !token.is_cancelled() {
+                tokio::time::sleep(CANCEL_POLL).await;
+            }
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Ask borg to stop like Ctrl+C would, see [`super::interrupt`]
+fn interrupt(child: &mut tokio::process::Child) {
+    #[cfg(unix)]
+    if child.id().is_some_and(sigint) {
+        return;
+    }
+    child.start_kill().ok();
+}
+
+impl AsyncBackend for AsyncBorgWrapper {
+    type Update = Event;
+
+    async fn create_archive(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &Archive,
+        updates: &UnboundedSender<Event>,
+    ) -> Result<Option<CreateStats>> {
+        let output = BorgWrapper::create_command(borg, repository, archive)?
+            .run_with_updates(updates)
+            .await?;
+
+        // There are no stats for dry runs
+        if borg.dry_run {
+            return Ok(None);
+        }
+
+        let json = serde_json::from_slice::<serde_json::Value>(&output)?;
+        json.try_into().map(Some)
+    }
+
+    async fn check(
+        borg: &Borg,
+        repository: &Repo,
+        options: &CheckOptions,
+        updates: &UnboundedSender<Event>,
+    ) -> Result<()> {
+        BorgWrapper::check_command(borg, repository, options)?
+            .run_with_updates(updates)
+            .await
+            .map(drop)
+    }
+
+    async fn prune(
+        borg: &Borg,
+        repository: &Repo,
+        policy: &PrunePolicy,
+        updates: &UnboundedSender<Event>,
+    ) -> Result<()> {
+        BorgWrapper::prune_command(borg, repository, policy)?
+            .run_with_updates(updates)
+            .await
+            .map(drop)
+    }
+
+    async fn compact(
+        borg: &Borg,
+        repository: &Repo,
+        updates: &UnboundedSender<Event>,
+    ) -> Result<()> {
+        BorgWrapper::compact_command(borg, repository)?
+            .run_with_updates(updates)
+            .await
+            .map(drop)
+    }
+
+    async fn version(borg: &Borg) -> Result<Version> {
+        let cmd = BorgWrapper::version_command(borg);
+        let modern_exit_codes = cmd.modern_exit_codes;
+
+        let mut cmd = cmd.into_tokio()?;
+        let output = cmd
+            .output()
+            .await
+            .map_err(|e| spawn_error(cmd.as_std().get_program(), e))?;
+        BorgError::check(output.status, modern_exit_codes)?;
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().parse()?)
+    }
+
+    async fn repo_info(borg: &Borg, repository: &Repo) -> Result<RepoInfo> {
+        BorgWrapper::info_command(borg, repository)
+            .json()
+            .await?
+            .try_into()
+    }
+
+    async fn list_archives(borg: &Borg, repository: &Repo) -> Result<Vec<ArchiveInfo>> {
+        let json = BorgWrapper::list_archives_command(borg, repository)
+            .json()
+            .await?;
+        parse_archive_list(json)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn sh(script: &str) -> CommandBuilder {
+        let mut cmd = CommandBuilder::from_command(Command::new("sh"));
+        cmd.args(["-c", script]);
+        cmd
+    }
+
+    #[test]
+    fn test_run_with_updates() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let script = r#"echo '{"type": "log_message", "levelname": "WARNING", "message": "slow"}' >&2; echo done"#;
+        let output = block_on(sh(script).run_with_updates(&tx)).unwrap();
+        assert_eq!(output, b"done\n");
+        assert_eq!(rx.try_recv().unwrap().to_string(), "slow");
+        assert!(rx.try_recv().is_err());
+
+        let err = block_on(sh("exit 2").run_with_updates(&tx)).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BorgError::Failed(2))));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let token = CancellationToken::new();
+        let mut cmd = sh("exec sleep 10");
+        cmd.cancellation = Some(token.clone());
+
+        let start = std::time::Instant::now();
+        let err = block_on(async {
+            let cancel = async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                token.cancel();
+            };
+            tokio::join!(cmd.run_with_updates(&tx), cancel).0
+        })
+        .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BorgError::Cancelled)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_json() {
+        let json = block_on(sh("echo '{\"archives\": []}'").json()).unwrap();
+        assert!(parse_archive_list(json).unwrap().is_empty());
+
+        let timeout = Duration::from_millis(200);
+        let start = std::time::Instant::now();
+        let mut cmd = sh("exec sleep 10");
+        cmd.timeout(Some(timeout));
+        let err = block_on(cmd.json()).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BorgError::Timeout(t)) if *t == timeout));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,213 @@
+//! Backend for tests, which answers without running borg
+
+use crate::{
+    Archive, ArchiveInfo, ArchiveListEntry, Backend, Borg, CheckOptions, CreateStats, Encryption,
+    PrunePolicy, Repo, RepoInfo, Result, Version,
+};
+use std::fmt::Display;
+use std::ops::ControlFlow;
+use std::time::SystemTime;
+
+/// [`Backend`] whose operations succeed without doing anything, tests override the ones they
+/// look at
+///
+/// Every `Mock` is a [`Backend`]. There are no stats for new archives, no archives, and
+/// repositories are unencrypted with an empty config.
+pub trait Mock {
+    type Update: Display;
+
+    fn init_repository(
+        _borg: &Borg,
+        _repository: &mut Repo,
+        _encryption: Encryption,
+        _append_only: bool,
+        _storage_quota: Option<usize>,
+        _make_parent_dirs: bool,
+        _on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn create_archive(
+        _borg: &Borg,
+        _repository: &Repo,
+        _archive: &Archive,
+        _on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<Option<CreateStats>> {
+        Ok(None)
+    }
+
+    fn check(
+        _borg: &Borg,
+        _repository: &Repo,
+        _options: &CheckOptions,
+        _on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn prune(
+        _borg: &Borg,
+        _repository: &Repo,
+        _policy: &PrunePolicy,
+        _on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn compact(
+        _borg: &Borg,
+        _repository: &Repo,
+        _on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete_repository(
+        _borg: &Borg,
+        _repository: &Repo,
+        _cache_only: bool,
+        _keep_security_info: bool,
+        _on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn version(_borg: &Borg) -> Result<Version> {
+        Ok("borg 1.4.0".parse()?)
+    }
+
+    fn repo_info(_borg: &Borg, repository: &Repo) -> Result<RepoInfo> {
+        Ok(repo_info(repository))
+    }
+
+    fn repo_config(_borg: &Borg, _repository: &Repo, _key: &str) -> Result<String> {
+        Ok(String::new())
+    }
+
+    fn list_archives(_borg: &Borg, _repository: &Repo) -> Result<Vec<ArchiveInfo>> {
+        Ok(vec![])
+    }
+
+    fn list_archive(
+        _borg: &Borg,
+        _repository: &Repo,
+        _archive: &str,
+        _on_entry: impl FnMut(ArchiveListEntry),
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Info of an empty, unencrypted repository
+pub fn repo_info(repository: &Repo) -> RepoInfo {
+    RepoInfo {
+        cache_path: "/root/.cache/borg/dd06".into(),
+        total_chunks: 0,
+        total_csize: 0,
+        total_size: 0,
+        total_unique_chunks: 0,
+        unique_csize: 0,
+        unique_size: 0,
+        encryption: Encryption::None,
+        id: "dd06".to_owned(),
+        location: repository.to_string(),
+        last_modified: SystemTime::UNIX_EPOCH,
+        keyfile: None,
+        security_dir: "/root/.config/borg/security/dd06".into(),
+    }
+}
+
+impl<M: Mock> Backend for M {
+    type Update = M::Update;
+
+    fn init_repository(
+        borg: &Borg,
+        repository: &mut Repo,
+        encryption: Encryption,
+        append_only: bool,
+        storage_quota: Option<usize>,
+        make_parent_dirs: bool,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        M::init_repository(
+            borg,
+            repository,
+            encryption,
+            append_only,
+            storage_quota,
+            make_parent_dirs,
+            on_update,
+        )
+    }
+
+    fn create_archive(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &Archive,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<Option<CreateStats>> {
+        M::create_archive(borg, repository, archive, on_update)
+    }
+
+    fn check(
+        borg: &Borg,
+        repository: &Repo,
+        options: &CheckOptions,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        M::check(borg, repository, options, on_update)
+    }
+
+    fn prune(
+        borg: &Borg,
+        repository: &Repo,
+        policy: &PrunePolicy,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        M::prune(borg, repository, policy, on_update)
+    }
+
+    fn compact(
+        borg: &Borg,
+        repository: &Repo,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        M::compact(borg, repository, on_update)
+    }
+
+    fn delete_repository(
+        borg: &Borg,
+        repository: &Repo,
+        cache_only: bool,
+        keep_security_info: bool,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        M::delete_repository(borg, repository, cache_only, keep_security_info, on_update)
+    }
+
+    fn version(borg: &Borg) -> Result<Version> {
+        M::version(borg)
+    }
+
+    fn repo_info(borg: &Borg, repository: &Repo) -> Result<RepoInfo> {
+        M::repo_info(borg, repository)
+    }
+
+    fn repo_config(borg: &Borg, repository: &Repo, key: &str) -> Result<String> {
+        M::repo_config(borg, repository, key)
+    }
+
+    fn list_archives(borg: &Borg, repository: &Repo) -> Result<Vec<ArchiveInfo>> {
+        M::list_archives(borg, repository)
+    }
+
+    fn list_archive(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &str,
+        on_entry: impl FnMut(ArchiveListEntry),
+    ) -> Result<()> {
+        M::list_archive(borg, repository, archive, on_entry)
+    }
+}
@@ -1 +1,3 @@
-pub mod borg;
\ No newline at end of file
+pub mod borg;
+#[cfg(test)]
+pub(crate) mod mock;
@@ -1,8 +1,25 @@
 use std::fmt::Display;
 use std::num::NonZeroU8;
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+mod cache;
+mod cancel;
+mod file_status;
+mod info_cache;
+mod interaction;
+mod placeholders;
+mod progress;
 mod repo;
+pub use cache::Cache;
+pub use cancel::CancellationToken;
+pub use file_status::{FileChangeKind, FileChanges, FileFilter};
+pub use info_cache::RepoInfoCache;
+pub use interaction::{Interaction, BORG_QUESTIONS};
+pub use placeholders::{
+    NameContext, NamePlaceholders, PlaceholderError, BORG_PLACEHOLDERS, BORRG_PLACEHOLDERS,
+};
+pub use progress::{Phase, ProgressDelta, ProgressTracker, Stage, Throughput};
 pub use repo::Repo;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -10,13 +27,29 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[non_exhaustive]
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum Passphrase {
     Passphrase(String),
+    /// Shell command line for borg's `BORG_PASSCOMMAND`
     Command(String),
+    /// Program and arguments, run by borrg without a shell
+    ///
+    /// The first line it prints is passed to borg as `BORG_PASSPHRASE`. It runs right before
+    /// each borg command, not when the command is built.
+    CommandArgs(Vec<String>),
     FileDescriptor(i32),
+    /// The value of a variable borrg was started with
+    ///
+    /// `BORG_PASSCOMMAND` and `BORG_PASSPHRASE_FD` are passed on to borg as they are, any other
+    /// variable is passed as `BORG_PASSPHRASE`.
+    Environment(String),
 }
 
-#[derive(Clone, Debug, clap::ValueEnum)]
+#[derive(Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
 #[non_exhaustive]
 pub enum Encryption {
     None,
@@ -28,6 +61,73 @@ pub enum Encryption {
     AuthenticatedBlake2,
 }
 
+impl serde::Serialize for Encryption {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Encryption {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let s = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parses the encryption modes as borg names them
+impl std::str::FromStr for Encryption {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Encryption::None),
+            "repokey" => Ok(Encryption::RepoKey),
+            "repokey-blake2" => Ok(Encryption::RepoKeyBlake2),
+            "keyfile" => Ok(Encryption::KeyFile),
+            "keyfile-blake2" => Ok(Encryption::KeyFileBlake2),
+            "authenticated" => Ok(Encryption::Authenticated),
+            "authenticated-blake2" => Ok(Encryption::AuthenticatedBlake2),
+            _ => Err(format!("unsupported encryption mode \"{s}\"")),
+        }
+    }
+}
+
+impl Encryption {
+    /// Whether the data is encrypted, the authenticated modes only protect it from tampering
+    ///
+    /// ```rust
+    /// use borrg::Encryption;
+    ///
+    /// assert!(Encryption::RepoKeyBlake2.is_encrypted());
+    /// assert!(!Encryption::Authenticated.is_encrypted());
+    /// assert!(!Encryption::None.is_encrypted());
+    /// ```
+    pub fn is_encrypted(&self) -> bool {
+        !matches!(
+            self,
+            Encryption::None | Encryption::Authenticated | Encryption::AuthenticatedBlake2
+        )
+    }
+
+    /// Whether the key is only stored on the client, the repository can't be read without it
+    ///
+    /// ```rust
+    /// use borrg::Encryption;
+    ///
+    /// assert!(Encryption::KeyFileBlake2.is_keyfile());
+    /// assert!(!Encryption::RepoKey.is_keyfile());
+    /// ```
+    pub fn is_keyfile(&self) -> bool {
+        matches!(self, Encryption::KeyFile | Encryption::KeyFileBlake2)
+    }
+}
+
 impl Display for Encryption {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -93,7 +193,7 @@ impl Display for Compression {
                 write!(f, "{}none", fmt_obfuscation(obfuscation))
             }
             Lz4 { auto, obfuscation } => {
-                write!(f, "{}lz4{}", fmt_obfuscation(obfuscation), fmt_auto(auto))
+                write!(f, "{}{}lz4", fmt_obfuscation(obfuscation), fmt_auto(auto))
             }
             Zstd {
                 level,
@@ -138,25 +238,129 @@ impl Display for Compression {
     }
 }
 
-#[derive(Debug)]
+/// Parses a compression spec as passed to `borg create --compression`,
+/// e.g. "auto,zstd,19" or "obfuscate,3,lz4"
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid compression \"{s}\"");
+        let mut parts: Vec<&str> = s.split(',').collect();
+
+        let mut obfuscation = None;
+        if parts.first() == Some(&"obfuscate") {
+            let spec = parts.get(1).ok_or_else(invalid)?;
+            obfuscation = Some(spec.parse().map_err(|_| invalid())?);
+            parts.drain(..2);
+        }
+        let auto = parts.first() == Some(&"auto");
+        if auto {
+            parts.remove(0);
+        }
+
+        let (algorithm, level) = match parts[..] {
+            [algorithm] => (algorithm, None),
+            [algorithm, level] => (algorithm, Some(level.parse().map_err(|_| invalid())?)),
+            _ => return Err(invalid()),
+        };
+
+        match (algorithm, level, auto) {
+            ("none", None, false) => Ok(Compression::None { obfuscation }),
+            ("lz4", None, _) => Ok(Compression::Lz4 { auto, obfuscation }),
+            ("zstd", level, _) => Ok(Compression::Zstd {
+                level,
+                auto,
+                obfuscation,
+            }),
+            ("zlib", level, _) => Ok(Compression::Zlib {
+                level,
+                auto,
+                obfuscation,
+            }),
+            ("lzma", level, _) => Ok(Compression::Lzma {
+                level,
+                auto,
+                obfuscation,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Represented as the spec borg takes
+#[cfg(feature = "serde")]
+impl serde::Serialize for Compression {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Compression {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let s = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Archive {
     pub(crate) name: String,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub(crate) paths: Vec<PathBuf>,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub(crate) compression: Option<Compression>,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub(crate) pattern_file: Option<PathBuf>,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub(crate) exclude_file: Option<PathBuf>,
+    /// What to do if the exclude file doesn't exist
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) missing_exclude_file: MissingFile,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub(crate) comment: Option<String>,
+    /// Names of files which exclude the directory containing them
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) exclude_if_present: Vec<String>,
+    /// Back up the marker files of excluded directories, but nothing else in them
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) keep_exclude_tags: bool,
+    /// Directory borg runs in, the paths are passed relative to it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) relative_to: Option<PathBuf>,
+    /// Creation time recorded instead of the current time
+    #[cfg_attr(feature = "serde", serde(default, with = "crate::util::optional_time"))]
+    pub(crate) timestamp: Option<SystemTime>,
 }
 
+#[allow(unexpected_cfgs)]
 impl Archive {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: impl Into<String>) -> Self {
         Archive {
-            name,
+            name: name.into(),
             paths: Vec::new(),
             compression: None,
             pattern_file: None,
             exclude_file: None,
+            missing_exclude_file: MissingFile::Error,
             comment: None,
+            exclude_if_present: Vec::new(),
+            keep_exclude_tags: false,
+            relative_to: None,
+            timestamp: None,
+        }
+    }
+
+    /// Start building an archive, see [`ArchiveBuilder`]
+    pub fn builder(name: impl Into<String>) -> ArchiveBuilder {
+        ArchiveBuilder {
+            archive: Archive::new(name),
         }
     }
 
@@ -167,30 +371,297 @@ impl Archive {
         Archive::new(name)
     }
 
-    pub fn path(&mut self, path: PathBuf) -> &mut Self {
-        self.paths.push(path);
+    pub fn path(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.paths.push(path.into());
+        self
+    }
+
+    /// Add several paths at once, like [`Archive::path`]
+    pub fn extend_paths<P: Into<PathBuf>>(
+        &mut self,
+        paths: impl IntoIterator<Item = P>,
+    ) -> &mut Self {
+        self.paths.extend(paths.into_iter().map(Into::into));
         self
     }
 
+    #[deprecated(note = "use `set_compression`")]
     pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.set_compression(compression)
+    }
+
+    #[deprecated(note = "use `set_pattern_file`")]
+    pub fn pattern_file(&mut self, pattern_file: PathBuf) -> &mut Self {
+        self.set_pattern_file(pattern_file)
+    }
+
+    #[deprecated(note = "use `set_exclude_file`")]
+    pub fn exclude_file(&mut self, exclude_file: PathBuf) -> &mut Self {
+        self.set_exclude_file(exclude_file)
+    }
+
+    #[deprecated(note = "use `set_comment`")]
+    pub fn comment(&mut self, comment: String) -> &mut Self {
+        self.set_comment(comment)
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) -> &mut Self {
         self.compression.replace(compression);
         self
     }
 
-    pub fn pattern_file(&mut self, pattern_file: PathBuf) -> &mut Self {
-        self.pattern_file.replace(pattern_file);
+    pub fn set_pattern_file(&mut self, pattern_file: impl Into<PathBuf>) -> &mut Self {
+        self.pattern_file.replace(pattern_file.into());
         self
     }
 
-    pub fn exclude_file(&mut self, exclude_file: PathBuf) -> &mut Self {
-        self.exclude_file.replace(exclude_file);
+    pub fn set_exclude_file(&mut self, exclude_file: impl Into<PathBuf>) -> &mut Self {
+        self.exclude_file.replace(exclude_file.into());
         self
     }
 
-    pub fn comment(&mut self, comment: String) -> &mut Self {
-        self.comment.replace(comment);
+    /// What creating the archive does if the exclude file doesn't exist, fail by default
+    pub fn set_missing_exclude_file(&mut self, missing: MissingFile) -> &mut Self {
+        self.missing_exclude_file = missing;
+        self
+    }
+
+    pub fn set_comment(&mut self, comment: impl Into<String>) -> &mut Self {
+        self.comment.replace(comment.into());
+        self
+    }
+
+    /// Exclude directories containing a file with this name, like `.nobackup`
+    pub fn exclude_if_present(&mut self, name: impl Into<String>) -> &mut Self {
+        self.exclude_if_present.push(name.into());
+        self
+    }
+
+    /// Keep the marker files of directories excluded by [`Archive::exclude_if_present`]
+    pub fn set_keep_exclude_tags(&mut self, keep: bool) -> &mut Self {
+        self.keep_exclude_tags = keep;
+        self
+    }
+
+    /// Run borg in `dir` and store the paths relative to it, see [`Archive::relative_to`]
+    pub fn set_relative_to(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.relative_to.replace(dir.into());
         self
     }
+
+    /// Record `time` as the creation time of the archive, see [`Archive::timestamp`]
+    pub fn set_timestamp(&mut self, time: SystemTime) -> &mut Self {
+        self.timestamp.replace(time);
+        self
+    }
+
+    /// Name of the archive, may contain placeholders like `{now}`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Paths to back up
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Directory borg runs in, relative paths are relative to it
+    ///
+    /// Borg stores paths as it gets them. By default they are made absolute, so archives
+    /// created from different working directories contain the same paths. With a directory
+    /// to run in, they are stored relative to it instead, and absolute paths have to be
+    /// inside of it.
+    ///
+    /// ```
+    /// use borrg::Archive;
+    ///
+    /// let archive = Archive::builder("www")
+    ///     .relative_to("/srv")
+    ///     .paths(["www", "/srv/cgi-bin"])
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(archive.relative_to(), Some(std::path::Path::new("/srv")));
+    /// ```
+    pub fn relative_to(&self) -> Option<&Path> {
+        self.relative_to.as_deref()
+    }
+
+    /// Creation time borg records instead of the current one, e.g. to backfill archives from
+    /// old snapshots
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        self.timestamp
+    }
+
+    /// Where `path` is, with `~` expanded and relative to [`Archive::relative_to`] if set
+    pub(crate) fn locate(&self, path: &Path) -> PathBuf {
+        let path = crate::util::resolve_path(path);
+        match &self.relative_to {
+            Some(dir) if path.is_relative() => crate::util::resolve_path(dir).join(path),
+            _ => path,
+        }
+    }
+
+    /// The paths as passed to borg, see [`Archive::relative_to`]
+    pub(crate) fn borg_paths(&self) -> Result<Vec<PathBuf>> {
+        let Some(dir) = &self.relative_to else {
+            return Ok(self.paths.iter().map(crate::util::absolute_path).collect());
+        };
+        let dir = crate::util::resolve_path(dir);
+        self.paths
+            .iter()
+            .map(|path| {
+                let path = crate::util::resolve_path(path);
+                if path.is_relative() {
+                    return Ok(path);
+                }
+                match path.strip_prefix(&dir) {
+                    Ok(rest) if rest.as_os_str().is_empty() => Ok(PathBuf::from(".")),
+                    Ok(rest) => Ok(rest.to_owned()),
+                    Err(_) => Err(format!(
+                        "{} is outside of {}, the directory borg runs in",
+                        path.display(),
+                        dir.display()
+                    )
+                    .into()),
+                }
+            })
+            .collect()
+    }
+
+    /// Pairs of paths where the second one is inside the first one
+    ///
+    /// Borg would read the nested path twice. A path listed twice is nested in itself.
+    ///
+    /// ```
+    /// use borrg::Archive;
+    /// use std::path::Path;
+    ///
+    /// let archive = Archive::builder("docs")
+    ///     .paths(["/home/me/projects", "/home/me", "/home/meow"])
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     archive.nested_paths(),
+    ///     [(Path::new("/home/me"), Path::new("/home/me/projects"))]
+    /// );
+    /// ```
+    pub fn nested_paths(&self) -> Vec<(&Path, &Path)> {
+        let resolved: Vec<_> = self.paths.iter().map(|p| self.locate(p)).collect();
+        let mut nested = vec![];
+        for (i, outer) in resolved.iter().enumerate() {
+            for (j, inner) in resolved.iter().enumerate() {
+                // Of two equal paths only the second one is nested
+                if inner.starts_with(outer) && (inner != outer || i < j) {
+                    nested.push((self.paths[i].as_path(), self.paths[j].as_path()));
+                }
+            }
+        }
+        nested
+    }
+
+    pub fn get_compression(&self) -> Option<&Compression> {
+        self.compression.as_ref()
+    }
+
+    pub fn get_pattern_file(&self) -> Option<&Path> {
+        self.pattern_file.as_deref()
+    }
+
+    /// The exclude file as configured, see [`Archive::exclude_file_path`] for its location
+    pub fn get_exclude_file(&self) -> Option<&Path> {
+        self.exclude_file.as_deref()
+    }
+
+    pub fn get_comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Marker files excluding their directory, see [`Archive::exclude_if_present`]
+    pub fn exclusion_markers(&self) -> &[String] {
+        &self.exclude_if_present
+    }
+
+    /// Location of the exclude file, relative paths are relative to the first path
+    pub fn exclude_file_path(&self) -> Option<PathBuf> {
+        let exclude_file = self.exclude_file.as_ref()?;
+        if exclude_file.is_absolute() {
+            Some(exclude_file.to_owned())
+        } else {
+            let path = self.paths.first()?;
+            Some(self.locate(&path.join(exclude_file)))
+        }
+    }
+
+    /// Whether creating the archive would use the exclude file, `None` if there is none
+    ///
+    /// ```rust
+    /// use borrg::{Archive, ExcludeFile, MissingFile};
+    ///
+    /// let mut archive = Archive::new("docs");
+    /// archive.path("/nonexistent").set_exclude_file(".borgignore");
+    /// let path = std::path::PathBuf::from("/nonexistent/.borgignore");
+    /// assert_eq!(archive.exclude_file_status(), Some(ExcludeFile::Missing(path.clone())));
+    ///
+    /// archive.set_missing_exclude_file(MissingFile::Warn);
+    /// assert_eq!(
+    ///     archive.exclude_file_status(),
+    ///     Some(ExcludeFile::Skipped(path, MissingFile::Warn))
+    /// );
+    /// ```
+    pub fn exclude_file_status(&self) -> Option<ExcludeFile> {
+        self.exclude_file.as_ref()?;
+        let Some(path) = self.exclude_file_path() else {
+            // Relative to no path at all, there is nothing to back up either
+            return Some(ExcludeFile::Missing(self.exclude_file.clone()?));
+        };
+        Some(match self.missing_exclude_file {
+            _ if path.is_file() => ExcludeFile::Applied(path),
+            MissingFile::Error => ExcludeFile::Missing(path),
+            missing => ExcludeFile::Skipped(path, missing),
+        })
+    }
+}
+
+/// What to do when a configured file doesn't exist
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum MissingFile {
+    /// Fail
+    #[default]
+    Error,
+    /// Go on without it and log a warning
+    Warn,
+    /// Go on without it, it was only a default
+    Ignore,
+}
+
+/// The exclude file of an archive, see [`Archive::exclude_file_status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExcludeFile {
+    /// Passed to borg
+    Applied(PathBuf),
+    /// Doesn't exist and the archive is created without it
+    Skipped(PathBuf, MissingFile),
+    /// Doesn't exist, creating the archive fails
+    Missing(PathBuf),
+}
+
+impl Display for ExcludeFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExcludeFile::Applied(path) => write!(f, "excludes from {}", path.display()),
+            ExcludeFile::Skipped(path, _) => {
+                write!(f, "no excludes ({} doesn't exist)", path.display())
+            }
+            ExcludeFile::Missing(path) => {
+                write!(f, "exclude file does not exist: {}", path.display())
+            }
+        }
+    }
 }
 
 impl Display for Archive {
@@ -199,6 +670,88 @@ impl Display for Archive {
     }
 }
 
+/// Builds an [`Archive`] in a single expression
+///
+/// ```rust
+/// use borrg::{Archive, Compression};
+///
+/// let archive = Archive::builder("{hostname}-{now}")
+///     .paths(["/home", "/etc"])
+///     .compression("auto,zstd,9".parse().unwrap())
+///     .comment("nightly")
+///     .build()
+///     .unwrap();
+/// assert_eq!(archive.paths().len(), 2);
+///
+/// // An archive without paths would back up nothing
+/// assert!(Archive::builder("empty").build().is_err());
+/// ```
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ArchiveBuilder {
+    archive: Archive,
+}
+
+impl ArchiveBuilder {
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.archive.paths.push(path.into());
+        self
+    }
+
+    pub fn paths<P: Into<PathBuf>>(mut self, paths: impl IntoIterator<Item = P>) -> Self {
+        self.archive.paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.archive.compression = Some(compression);
+        self
+    }
+
+    pub fn pattern_file(mut self, pattern_file: impl Into<PathBuf>) -> Self {
+        self.archive.pattern_file = Some(pattern_file.into());
+        self
+    }
+
+    pub fn exclude_file(mut self, exclude_file: impl Into<PathBuf>) -> Self {
+        self.archive.exclude_file = Some(exclude_file.into());
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.archive.comment = Some(comment.into());
+        self
+    }
+
+    pub fn exclude_if_present(mut self, name: impl Into<String>) -> Self {
+        self.archive.exclude_if_present.push(name.into());
+        self
+    }
+
+    pub fn keep_exclude_tags(mut self, keep: bool) -> Self {
+        self.archive.keep_exclude_tags = keep;
+        self
+    }
+
+    pub fn relative_to(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.archive.relative_to = Some(dir.into());
+        self
+    }
+
+    pub fn timestamp(mut self, time: SystemTime) -> Self {
+        self.archive.timestamp = Some(time);
+        self
+    }
+
+    /// Fails if there is no path to back up
+    pub fn build(self) -> std::result::Result<Archive, &'static str> {
+        if self.archive.paths.is_empty() {
+            return Err("Archive has no paths");
+        }
+        Ok(self.archive)
+    }
+}
+
 #[derive(Debug)]
 pub enum Event {
     ArchiveProgress {
@@ -236,6 +789,21 @@ pub enum Event {
         status: String,
         path: PathBuf,
     },
+    /// An archive listed by `borg prune --list`
+    PruneDecision {
+        archive: String,
+        pruned: bool,
+        /// Retention rule which kept the archive, e.g. "daily #1"
+        rule: Option<String>,
+    },
+    /// The last message of borg, e.g. "terminating with warning status, rc 1"
+    ///
+    /// The exit status is checked against `rc`, which survives wrappers that lose the exit
+    /// code of borg.
+    Termination {
+        rc: i32,
+        level: Option<log::Level>,
+    },
     Prompt {
         prompt: String,
         msgid: String,
@@ -286,6 +854,25 @@ impl Display for Event {
             }
             ProgressPercent { message, .. } => write!(f, "{message}"),
             FileStatus { path, status } => write!(f, "{} {}", status, path.display()),
+            PruneDecision {
+                archive,
+                pruned: true,
+                ..
+            } => write!(f, "Pruning archive {archive}"),
+            PruneDecision {
+                archive,
+                rule: Some(rule),
+                ..
+            } => write!(f, "Keeping archive {archive} (rule: {rule})"),
+            PruneDecision { archive, .. } => write!(f, "Keeping archive {archive}"),
+            Termination { rc, .. } => {
+                let status = match rc {
+                    0 => "success",
+                    1 | 100..=127 => "warning",
+                    _ => "error",
+                };
+                write!(f, "borg terminated with {status} status (rc {rc})")
+            }
             Prompt { prompt, .. } => write!(f, "{}", prompt),
             Answer { answer, .. } => write!(f, "{}", answer),
             Other(s) => write!(f, "{}", s),
@@ -294,6 +881,58 @@ impl Display for Event {
     }
 }
 
+impl Event {
+    /// What borg is busy with, for progress events with a msgid
+    pub fn stage(&self) -> Option<Stage> {
+        match self {
+            Event::ProgressMessage {
+                msgid: Some(msgid), ..
+            } => Some(msgid.as_str().into()),
+            Event::ProgressPercent { msgid, .. } if !msgid.is_empty() => {
+                Some(msgid.as_str().into())
+            }
+            _ => None,
+        }
+    }
+
+    /// What borg did with a file it listed
+    pub fn file_change(&self) -> Option<FileChangeKind> {
+        match self {
+            Event::FileStatus { status, .. } => Some(status.as_str().into()),
+            _ => None,
+        }
+    }
+}
+
+/// Number of archives kept and pruned by a prune run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PruneStats {
+    pub kept: u64,
+    pub pruned: u64,
+}
+
+impl PruneStats {
+    pub fn update(&mut self, event: &Event) {
+        match event {
+            Event::PruneDecision { pruned: true, .. } => self.pruned += 1,
+            Event::PruneDecision { pruned: false, .. } => self.kept += 1,
+            _ => {}
+        }
+    }
+}
+
+impl Display for PruneStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use crate::format::count;
+        write!(
+            f,
+            "removed {} archive(s), kept {}",
+            count(self.pruned),
+            count(self.kept)
+        )
+    }
+}
+
 impl Repo {
     pub fn passphrase(&mut self, passphrase: Passphrase) -> &mut Self {
         self.passphrase = Some(passphrase);
@@ -304,23 +943,387 @@ impl Repo {
         &self,
         borg: &Borg,
         archive: &Archive,
-        on_update: impl Fn(B::Update),
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<Option<CreateStats>> {
+        borg.create_archive::<B>(self, archive, on_update)
+    }
+
+    pub fn info<B: Backend>(&self, borg: &Borg) -> Result<RepoInfo> {
+        borg.repo_info::<B>(self)
+    }
+
+    /// Archives in the repository
+    ///
+    /// ```no_run
+    /// # use borrg::{Borg, Repo, backend::borg::BorgWrapper};
+    /// let repo: Repo = "/srv/backup".parse()?;
+    /// for archive in repo.list_archives::<BorgWrapper>(&Borg::default())? {
+    ///     println!("{} {}", archive.name, archive.id);
+    /// }
+    /// # Ok::<(), borrg::Error>(())
+    /// ```
+    pub fn list_archives<B: Backend>(&self, borg: &Borg) -> Result<Vec<ArchiveInfo>> {
+        B::list_archives(borg, self)
+    }
+
+    /// Contents of the archive `archive`, passed to `on_entry` as they are read
+    ///
+    /// ```no_run
+    /// # use borrg::{Borg, Repo, backend::borg::BorgWrapper};
+    /// let repo: Repo = "/srv/backup".parse()?;
+    /// repo.list_archive::<BorgWrapper>(&Borg::default(), "2024-05-01", |entry| {
+    ///     println!("{}", entry.path.display());
+    /// })?;
+    /// # Ok::<(), borrg::Error>(())
+    /// ```
+    pub fn list_archive<B: Backend>(
+        &self,
+        borg: &Borg,
+        archive: &str,
+        on_entry: impl FnMut(ArchiveListEntry),
     ) -> Result<()> {
-        B::create_archive(borg, self, archive, on_update)
+        B::list_archive(borg, self, archive, on_entry)
     }
 
-    pub fn info<B: Backend>(&self) -> Result<RepoInfo> {
-        B::repo_info(self)
+    /// Verify the repository and its archives
+    ///
+    /// ```no_run
+    /// # use borrg::{Borg, CheckOptions, Repo, backend::borg::BorgWrapper};
+    /// # use std::ops::ControlFlow;
+    /// let repo: Repo = "/srv/backup".parse()?;
+    /// let options = CheckOptions {
+    ///     last: Some(3),
+    ///     ..Default::default()
+    /// };
+    /// repo.check::<BorgWrapper>(&Borg::default(), &options, |event| {
+    ///     println!("{event}");
+    ///     ControlFlow::Continue(())
+    /// })?;
+    /// # Ok::<(), borrg::Error>(())
+    /// ```
+    pub fn check<B: Backend>(
+        &self,
+        borg: &Borg,
+        options: &CheckOptions,
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        B::check(borg, self, options, on_update)
+    }
+
+    /// Delete the archives not matching `policy`
+    ///
+    /// ```no_run
+    /// # use borrg::{Borg, PrunePolicy, Repo, backend::borg::BorgWrapper};
+    /// # use std::ops::ControlFlow;
+    /// let repo: Repo = "/srv/backup".parse()?;
+    /// let policy = PrunePolicy {
+    ///     keep_daily: Some(7),
+    ///     keep_weekly: Some(4),
+    ///     ..Default::default()
+    /// };
+    /// repo.prune::<BorgWrapper>(&Borg::default(), &policy, |_| ControlFlow::Continue(()))?;
+    /// # Ok::<(), borrg::Error>(())
+    /// ```
+    pub fn prune<B: Backend>(
+        &self,
+        borg: &Borg,
+        policy: &PrunePolicy,
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        borg.prune::<B>(self, policy, on_update)
+    }
+
+    /// Free the space of deleted archives, usually after [`Repo::prune`]
+    ///
+    /// ```no_run
+    /// # use borrg::{Borg, Repo, backend::borg::BorgWrapper};
+    /// # use std::ops::ControlFlow;
+    /// let repo: Repo = "/srv/backup".parse()?;
+    /// repo.compact::<BorgWrapper>(&Borg::default(), |_| ControlFlow::Continue(()))?;
+    /// # Ok::<(), borrg::Error>(())
+    /// ```
+    pub fn compact<B: Backend>(
+        &self,
+        borg: &Borg,
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        borg.compact::<B>(self, on_update)
+    }
+
+    /// Delete the whole repository, or only its local cache if `cache_only` is set
+    ///
+    /// ```no_run
+    /// # use borrg::{Borg, Repo, backend::borg::BorgWrapper};
+    /// # use std::ops::ControlFlow;
+    /// let repo: Repo = "/srv/backup".parse()?;
+    /// repo.delete::<BorgWrapper>(&Borg::default(), true, false, |_| ControlFlow::Continue(()))?;
+    /// # Ok::<(), borrg::Error>(())
+    /// ```
+    pub fn delete<B: Backend>(
+        &self,
+        borg: &Borg,
+        cache_only: bool,
+        keep_security_info: bool,
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        borg.delete_repository::<B>(self, cache_only, keep_security_info, on_update)
+    }
+}
+
+/// What to verify after an archive has been created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verify {
+    /// Don't verify anything
+    #[default]
+    None,
+    /// Check the metadata of the newest archive
+    Archive,
+    /// Check the whole repository including all archives
+    Repository,
+}
+
+impl Verify {
+    /// Options for `borg check` implementing this verification, if any
+    pub fn check_options(&self) -> Option<CheckOptions> {
+        match self {
+            Verify::None => None,
+            Verify::Archive => Some(CheckOptions {
+                archives_only: true,
+                last: Some(1),
+                ..Default::default()
+            }),
+            Verify::Repository => Some(CheckOptions::default()),
+        }
+    }
+}
+
+impl Display for Verify {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verify::None => write!(f, "none"),
+            Verify::Archive => write!(f, "archive"),
+            Verify::Repository => write!(f, "repository"),
+        }
+    }
+}
+
+/// Options for `borg check`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckOptions {
+    /// Only check the repository
+    pub repository_only: bool,
+    /// Only check the archives
+    pub archives_only: bool,
+    /// Only check the last N archives
+    pub last: Option<u32>,
+    /// Read all data of the archives and verify it cryptographically
+    pub verify_data: bool,
+}
+
+/// Which archives to keep when pruning a repository
+///
+/// Mirrors the `--keep-*` options of `borg prune`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrunePolicy {
+    /// Keep all archives within this time interval (e.g. "2d")
+    pub keep_within: Option<String>,
+    pub keep_last: Option<u32>,
+    pub keep_hourly: Option<u32>,
+    pub keep_daily: Option<u32>,
+    pub keep_weekly: Option<u32>,
+    pub keep_monthly: Option<u32>,
+    pub keep_yearly: Option<u32>,
+    /// Only consider archives matching this shell pattern (e.g. "monthly-*"), the others are
+    /// neither kept nor pruned
+    pub glob_archives: Option<String>,
+}
+
+impl PrunePolicy {
+    /// Whether the policy doesn't keep anything explicitly
+    ///
+    /// Borg refuses to prune with an empty policy.
+    pub fn is_empty(&self) -> bool {
+        let rules = PrunePolicy {
+            glob_archives: None,
+            ..self.clone()
+        };
+        rules == Self::default()
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct RateLimit {
     pub up: Option<u64>,
     pub down: Option<u64>,
 }
 
-#[derive(Debug)]
+/// An archive in a repository
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ArchiveInfo {
+    pub name: String,
+    pub id: String,
+    #[serde(serialize_with = "crate::util::serialize_time")]
+    pub time: SystemTime,
+}
+
+impl ArchiveInfo {
+    /// Whether borg left this archive behind while creating another one, e.g.
+    /// "docs.checkpoint" or "docs.checkpoint.1"
+    ///
+    /// ```rust
+    /// # use borrg::ArchiveInfo;
+    /// let archive = |name: &str| ArchiveInfo {
+    ///     name: name.to_owned(),
+    ///     id: "f00d".to_owned(),
+    ///     time: std::time::SystemTime::UNIX_EPOCH,
+    /// };
+    /// assert!(archive("docs.checkpoint").is_checkpoint());
+    /// assert!(archive("docs.checkpoint.2").is_checkpoint());
+    /// assert!(!archive("docs").is_checkpoint());
+    /// assert!(!archive("checkpoints").is_checkpoint());
+    /// ```
+    pub fn is_checkpoint(&self) -> bool {
+        let name = match self.name.rsplit_once('.') {
+            Some((name, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => &self.name,
+        };
+        name.ends_with(".checkpoint")
+    }
+}
+
+/// Kind of an item in an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Other(char),
+}
+
+impl From<char> for EntryKind {
+    fn from(c: char) -> Self {
+        match c {
+            '-' => EntryKind::File,
+            'd' => EntryKind::Directory,
+            'l' => EntryKind::Symlink,
+            'p' => EntryKind::Fifo,
+            'c' => EntryKind::CharDevice,
+            'b' => EntryKind::BlockDevice,
+            c => EntryKind::Other(c),
+        }
+    }
+}
+
+impl serde::Serialize for EntryKind {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_char((*self).into())
+    }
+}
+
+impl From<EntryKind> for char {
+    fn from(kind: EntryKind) -> Self {
+        match kind {
+            EntryKind::File => '-',
+            EntryKind::Directory => 'd',
+            EntryKind::Symlink => 'l',
+            EntryKind::Fifo => 'p',
+            EntryKind::CharDevice => 'c',
+            EntryKind::BlockDevice => 'b',
+            EntryKind::Other(c) => c,
+        }
+    }
+}
+
+/// An item in an archive as listed by `borg list REPO::ARCHIVE`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ArchiveListEntry {
+    pub path: PathBuf,
+    #[serde(rename = "type")]
+    pub kind: EntryKind,
+    pub size: u64,
+    #[serde(serialize_with = "crate::util::serialize_time")]
+    pub mtime: SystemTime,
+    /// Mode as string like "drwxr-xr-x"
+    pub mode: String,
+    pub user: String,
+    pub group: String,
+    /// Target of symlinks and hardlinks
+    pub link_target: Option<PathBuf>,
+}
+
+/// Stats of a newly created archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateStats {
+    pub name: String,
+    pub id: String,
+    pub duration: Duration,
+    pub original_size: u64,
+    pub compressed_size: u64,
+    pub deduplicated_size: u64,
+    pub nfiles: u64,
+}
+
+impl CreateStats {
+    /// Original size of the archive per byte after compression, `None` if nothing was
+    /// compressed
+    pub fn compression_ratio(&self) -> Option<Ratio> {
+        Ratio::new(self.original_size, self.compressed_size)
+    }
+
+    /// Share of the original size which didn't have to be stored thanks to compression and
+    /// deduplication, `None` for an empty archive
+    pub fn space_saving(&self) -> Option<Percentage> {
+        let stored = self.deduplicated_size.min(self.original_size);
+        Percentage::new(self.original_size - stored, self.original_size)
+    }
+}
+
+/// How many times larger one size is than another, e.g. "1.42×"
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, serde::Serialize)]
+#[serde(transparent)]
+pub struct Ratio(pub f64);
+
+impl Ratio {
+    /// `numerator / denominator`, `None` if `denominator` is 0
+    pub fn new(numerator: u64, denominator: u64) -> Option<Ratio> {
+        (denominator != 0).then(|| Ratio(numerator as f64 / denominator as f64))
+    }
+}
+
+impl Display for Ratio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            r if r < 10.0 => write!(f, "{r:.2}×"),
+            r => write!(f, "{r:.1}×"),
+        }
+    }
+}
+
+/// A share in percent, e.g. "61.3%"
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, serde::Serialize)]
+#[serde(transparent)]
+pub struct Percentage(pub f64);
+
+impl Percentage {
+    /// `part` of `total` in percent, `None` if `total` is 0
+    pub fn new(part: u64, total: u64) -> Option<Percentage> {
+        Ratio::new(part, total).map(|r| Percentage(r.0 * 100.0))
+    }
+}
+
+impl Display for Percentage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.1}%", self.0)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct RepoInfo {
     pub cache_path: PathBuf,
     pub total_chunks: u64,
@@ -332,7 +1335,10 @@ pub struct RepoInfo {
     pub encryption: Encryption,
     pub id: String,
     pub location: String,
-    // pub(crate) last_modified: SystemTime,
+    #[serde(serialize_with = "crate::util::serialize_time")]
+    pub last_modified: SystemTime,
+    /// Where the key is stored on the client, only for keyfile modes
+    pub keyfile: Option<PathBuf>,
     pub security_dir: PathBuf,
     // "cache": {
     //     "path": "/home/seb/.cache/borg/dd06d1d72e5925b63f9c929b088b1cfa2e6bd548f5037c05352a61d71e4d2819",
@@ -356,10 +1362,106 @@ pub struct RepoInfo {
     // "security_dir": "/home/seb/.config/borg/security/dd06d1d72e5925b63f9c929b088b1cfa2e6bd548f5037c05352a61d71e4d2819"
 }
 
-#[derive(Debug, Default)]
+impl RepoInfo {
+    /// Compressed size of all archives per byte actually stored, `None` for an empty
+    /// repository
+    pub fn dedup_ratio(&self) -> Option<Ratio> {
+        Ratio::new(self.total_csize, self.unique_csize)
+    }
+
+    /// Original size of all archives per byte after compression, `None` for an empty
+    /// repository
+    pub fn compression_ratio(&self) -> Option<Ratio> {
+        Ratio::new(self.total_size, self.total_csize)
+    }
+}
+
+/// Version of the installed borg
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    /// The first version supporting `BORG_EXIT_CODES=modern`
+    pub const MODERN_EXIT_CODES: Version = Version {
+        major: 1,
+        minor: 4,
+        patch: 0,
+    };
+
+    /// Whether borg of `other` version works together with this one, the same major and
+    /// minor version
+    ///
+    /// ```rust
+    /// use borrg::Version;
+    ///
+    /// let version = |s: &str| s.parse::<Version>().unwrap();
+    /// assert!(version("borg 1.2.8").is_compatible(&version("borg 1.2.4")));
+    /// assert!(!version("borg 1.2.8").is_compatible(&version("borg 1.4.0")));
+    /// ```
+    pub fn is_compatible(&self, other: &Version) -> bool {
+        (self.major, self.minor) == (other.major, other.minor)
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = &'static str;
+
+    /// Parse the output of `borg --version`, e.g. "borg 1.2.8" or "borg 2.0.0b14"
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let version = s.split_whitespace().last().ok_or("Empty version")?;
+        let mut parts = version.split('.').map(|part| {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(part.len());
+            part[..digits].parse::<u32>()
+        });
+        let mut next = || parts.next().unwrap_or(Ok(0)).map_err(|_| "Invalid version");
+
+        Ok(Version {
+            major: next()?,
+            minor: next()?,
+            patch: next()?,
+        })
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct Borg {
     pub(crate) dry_run: bool,
     pub(crate) rate_limit: RateLimit,
+    pub(crate) cache: Cache,
+    /// Seconds to wait for a repository lock, borg's default is 1
+    pub(crate) lock_wait: Option<u32>,
+    /// Run borg with `BORG_EXIT_CODES=modern` and interpret its exit codes accordingly
+    pub(crate) modern_exit_codes: bool,
+    /// How long querying a repository may take before borg is killed, no limit if unset
+    pub(crate) info_timeout: Option<Duration>,
+    /// Level of the messages borg logs, borg's default (warning) if unset
+    pub(crate) log_level: Option<log::Level>,
+    /// Let borg log its exit code as its last message
+    pub(crate) show_rc: bool,
+    /// Let `create` list the paths it excludes
+    pub(crate) list_excluded: bool,
+    /// Let `create` list the files it processes
+    pub(crate) list_files: Option<FileFilter>,
+    /// Interrupts borg once cancelled
+    pub(crate) cancellation: Option<CancellationToken>,
+    /// Shared by the clones, see [`Borg::info_cache`]
+    pub(crate) info_cache: Option<std::sync::Arc<RepoInfoCache>>,
+    /// Whether borg may wait for input, see [`Borg::interaction`]
+    pub(crate) interaction: Interaction,
+    /// Output of passcommands borrg runs itself, shared by the clones
+    pub(crate) passphrases: std::sync::Arc<crate::backend::borg::Passphrases>,
 }
 
 impl Borg {
@@ -368,6 +1470,137 @@ impl Borg {
         self
     }
 
+    pub fn cache(&mut self, cache: Cache) -> &mut Self {
+        self.cache = cache;
+        self
+    }
+
+    /// How long read-only operations wait for a locked repository
+    pub fn lock_wait(&mut self, seconds: u32) -> &mut Self {
+        self.lock_wait = Some(seconds);
+        self
+    }
+
+    /// Kill `repo_info` and `list_archives` queries which take longer than `timeout`
+    pub fn info_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.info_timeout = Some(timeout);
+        self
+    }
+
+    /// Let borg log messages up to `level`, independent of the log level of borrg
+    pub fn log_level(&mut self, level: log::Level) -> &mut Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Let borg log its exit code at the end, "terminating with success status, rc 0", see
+    /// [`Event::Termination`]
+    pub fn show_rc(&mut self) -> &mut Self {
+        self.show_rc = true;
+        self
+    }
+
+    /// Report every path `create` excludes as [`Event::FileStatus`] with status `x`
+    pub fn list_excluded(&mut self) -> &mut Self {
+        self.list_excluded = true;
+        self
+    }
+
+    /// Report the files `create` processes as [`Event::FileStatus`], only the ones `filter`
+    /// lets through
+    pub fn list_files(&mut self, filter: FileFilter) -> &mut Self {
+        self.list_files = Some(filter);
+        self
+    }
+
+    /// Interrupt borg commands once `token` is cancelled, see [`CancellationToken`]
+    pub fn cancellation(&mut self, token: CancellationToken) -> &mut Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Never let borg wait for input with [`Interaction::Batch`]
+    pub fn interaction(&mut self, interaction: Interaction) -> &mut Self {
+        self.interaction = interaction;
+        self
+    }
+
+    /// Answer [`Borg::repo_info`] from `cache`, dropping the entry of a repository whenever
+    /// it is changed through this `Borg` or one of its clones
+    pub fn info_cache(&mut self, cache: std::sync::Arc<RepoInfoCache>) -> &mut Self {
+        self.info_cache = Some(cache);
+        self
+    }
+
+    /// Run `change` on a repository, dropping its cached info before and after
+    ///
+    /// Info asked for while borg is still changing the repository may be outdated once it is
+    /// done, so it isn't kept either.
+    fn changing<T>(&self, repository: &Repo, change: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.invalidate(repository);
+        let result = change();
+        self.invalidate(repository);
+        result
+    }
+
+    fn invalidate(&self, repository: &Repo) {
+        if let Some(cache) = &self.info_cache {
+            cache.invalidate(repository);
+        }
+    }
+
+    /// Use modern exit codes if the installed borg supports them, returns whether it does
+    pub fn modern_exit_codes<B: Backend>(&mut self) -> Result<bool> {
+        let supported = self.version::<B>()? >= Version::MODERN_EXIT_CODES;
+        self.modern_exit_codes = supported;
+        Ok(supported)
+    }
+
+    pub fn version<B: Backend>(&self) -> Result<Version> {
+        B::version(self)
+    }
+
+    /// `borg info` of the repository, from the [`RepoInfoCache`] if there is one
+    pub fn repo_info<B: Backend>(&self, repository: &Repo) -> Result<RepoInfo> {
+        match &self.info_cache {
+            Some(cache) => cache.get::<B>(self, repository),
+            None => B::repo_info(self, repository),
+        }
+    }
+
+    pub fn repo_config<B: Backend>(&self, repository: &Repo, key: &str) -> Result<String> {
+        B::repo_config(self, repository, key)
+    }
+
+    /// Whether the repository only appends to its log, pruned archives are then only removed
+    /// once the repository is compacted without append-only mode
+    ///
+    /// `None` for remote repositories, `borg config` only reads local ones.
+    pub fn is_append_only<B: Backend>(&self, repository: &Repo) -> Result<Option<bool>> {
+        if repository.is_remote() {
+            return Ok(None);
+        }
+        let value = self.repo_config::<B>(repository, "append_only")?;
+        match value.trim() {
+            "1" | "True" | "true" | "yes" => Ok(Some(true)),
+            "0" | "False" | "false" | "no" | "" => Ok(Some(false)),
+            other => Err(format!("invalid append_only value: {other:?}").into()),
+        }
+    }
+
+    pub fn list_archives<B: Backend>(&self, repository: &Repo) -> Result<Vec<ArchiveInfo>> {
+        B::list_archives(self, repository)
+    }
+
+    pub fn list_archive<B: Backend>(
+        &self,
+        repository: &Repo,
+        archive: &str,
+        on_entry: impl FnMut(ArchiveListEntry),
+    ) -> Result<()> {
+        B::list_archive(self, repository, archive, on_entry)
+    }
+
     pub fn init_repository<B: Backend>(
         &self,
         repository: &mut Repo,
@@ -375,9 +1608,11 @@ impl Borg {
         append_only: bool,
         storage_quota: Option<usize>,
         make_parent_dirs: bool,
-        on_update: impl Fn(B::Update),
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
     ) -> Result<()> {
-        B::init_repository(
+        // Not through `changing`, the backend borrows `repository` mutably
+        self.invalidate(repository);
+        let result = B::init_repository(
             self,
             repository,
             encryption,
@@ -385,19 +1620,66 @@ impl Borg {
             storage_quota,
             make_parent_dirs,
             on_update,
-        )
+        );
+        self.invalidate(repository);
+        result
     }
 
     pub fn create_archive<B: Backend>(
         &self,
         repository: &Repo,
         archive: &Archive,
-        on_update: impl Fn(B::Update),
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<Option<CreateStats>> {
+        self.changing(repository, || {
+            B::create_archive(self, repository, archive, on_update)
+        })
+    }
+
+    pub fn check<B: Backend>(
+        &self,
+        repository: &Repo,
+        options: &CheckOptions,
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        B::check(self, repository, options, on_update)
+    }
+
+    pub fn prune<B: Backend>(
+        &self,
+        repository: &Repo,
+        policy: &PrunePolicy,
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        self.changing(repository, || B::prune(self, repository, policy, on_update))
+    }
+
+    pub fn compact<B: Backend>(
+        &self,
+        repository: &Repo,
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
+    ) -> Result<()> {
+        self.changing(repository, || B::compact(self, repository, on_update))
+    }
+
+    pub fn delete_repository<B: Backend>(
+        &self,
+        repository: &Repo,
+        cache_only: bool,
+        keep_security_info: bool,
+        on_update: impl FnMut(B::Update) -> ControlFlow<()>,
     ) -> Result<()> {
-        B::create_archive(self, repository, archive, on_update)
+        self.changing(repository, || {
+            B::delete_repository(self, repository, cache_only, keep_security_info, on_update)
+        })
     }
 }
 
+/// Operations on borg repositories
+///
+/// Long-running operations pass their progress to `on_update`. Returning
+/// [`ControlFlow::Break`] from it cancels the operation, which then fails with a cancelled
+/// error (e.g. [`BorgError::Cancelled`](crate::backend::borg::BorgError::Cancelled)).
 pub trait Backend {
     type Update: Display;
 
@@ -409,20 +1691,131 @@ pub trait Backend {
         append_only: bool,
         storage_quota: Option<usize>,
         make_parent_dirs: bool,
-        on_update: impl Fn(Self::Update),
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
     ) -> Result<()>;
 
-    /// Create new archive
+    /// Create new archive, returns its stats unless it was a dry run
     fn create_archive(
         borg: &Borg,
         repository: &Repo,
         archive: &Archive,
-        on_update: impl Fn(Self::Update),
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<Option<CreateStats>>;
+
+    /// Check the consistency of a repository and its archives
+    fn check(
+        borg: &Borg,
+        repository: &Repo,
+        options: &CheckOptions,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()>;
+
+    /// Delete archives not matching the policy
+    fn prune(
+        borg: &Borg,
+        repository: &Repo,
+        policy: &PrunePolicy,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()>;
+
+    /// Free space by compacting the segment files of a repository
+    fn compact(
+        borg: &Borg,
+        repository: &Repo,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()>;
+
+    /// Delete a repository including all archives, or only its local cache
+    fn delete_repository(
+        borg: &Borg,
+        repository: &Repo,
+        cache_only: bool,
+        keep_security_info: bool,
+        on_update: impl FnMut(Self::Update) -> ControlFlow<()>,
+    ) -> Result<()>;
+
+    /// Version of the installed borg
+    fn version(borg: &Borg) -> Result<Version>;
+
+    fn repo_info(borg: &Borg, repository: &Repo) -> Result<RepoInfo>;
+
+    /// Value of a key in the config of a repository, e.g. `append_only`
+    ///
+    /// Not supported by default, backends without a way to read it fail.
+    fn repo_config(_borg: &Borg, _repository: &Repo, key: &str) -> Result<String> {
+        Err(format!("reading {key} from the repository config is not supported").into())
+    }
+
+    /// List the archives in a repository
+    fn list_archives(borg: &Borg, repository: &Repo) -> Result<Vec<ArchiveInfo>>;
+
+    /// List the contents of an archive
+    ///
+    /// Entries are passed to `on_entry` as they are read, so archives with
+    /// millions of files don't need to fit into memory.
+    fn list_archive(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &str,
+        on_entry: impl FnMut(ArchiveListEntry),
     ) -> Result<()>;
+}
 
-    fn repo_info(repository: &Repo) -> Result<RepoInfo>;
+/// Asynchronous counterpart of [`Backend`]
+///
+/// Updates are sent to `updates` instead of being passed to a callback. Dropping a returned
+/// future kills borg.
+#[cfg(feature = "async")]
+pub trait AsyncBackend {
+    type Update: Display + Send;
+
+    /// Create new archive, returns its stats unless it was a dry run
+    fn create_archive(
+        borg: &Borg,
+        repository: &Repo,
+        archive: &Archive,
+        updates: &tokio::sync::mpsc::UnboundedSender<Self::Update>,
+    ) -> impl std::future::Future<Output = Result<Option<CreateStats>>> + Send;
+
+    /// Check the consistency of a repository and its archives
+    fn check(
+        borg: &Borg,
+        repository: &Repo,
+        options: &CheckOptions,
+        updates: &tokio::sync::mpsc::UnboundedSender<Self::Update>,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+
+    /// Delete archives not matching the policy
+    fn prune(
+        borg: &Borg,
+        repository: &Repo,
+        policy: &PrunePolicy,
+        updates: &tokio::sync::mpsc::UnboundedSender<Self::Update>,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+
+    /// Free space by compacting the segment files of a repository
+    fn compact(
+        borg: &Borg,
+        repository: &Repo,
+        updates: &tokio::sync::mpsc::UnboundedSender<Self::Update>,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+
+    /// Version of the installed borg
+    fn version(borg: &Borg) -> impl std::future::Future<Output = Result<Version>> + Send;
+
+    fn repo_info(
+        borg: &Borg,
+        repository: &Repo,
+    ) -> impl std::future::Future<Output = Result<RepoInfo>> + Send;
+
+    /// List the archives in a repository
+    fn list_archives(
+        borg: &Borg,
+        repository: &Repo,
+    ) -> impl std::future::Future<Output = Result<Vec<ArchiveInfo>>> + Send;
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ByteSize(pub u64);
 
 impl ByteSize {
@@ -474,6 +1867,323 @@ impl std::fmt::Display for ByteSize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::mock::{self, Mock};
+
+    #[test]
+    fn test_version() {
+        let version = |s: &str| s.parse::<Version>().unwrap();
+        assert_eq!(version("borg 1.2.8").to_string(), "1.2.8");
+        assert_eq!(version("borg 2.0.0b14").to_string(), "2.0.0");
+        assert_eq!(version("borg.exe 1.4").to_string(), "1.4.0");
+        assert!(version("borg 1.2.8") < Version::MODERN_EXIT_CODES);
+        assert!(version("borg 1.4.1") >= Version::MODERN_EXIT_CODES);
+        assert!("borg".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn test_compression_spec() {
+        for spec in [
+            "none",
+            "lz4",
+            "auto,lz4",
+            "zstd",
+            "zstd,19",
+            "auto,zlib,6",
+            "obfuscate,3,auto,lzma,9",
+            "obfuscate,110,none",
+        ] {
+            let compression: Compression = spec.parse().unwrap();
+            assert_eq!(compression.to_string(), spec);
+        }
+        assert_eq!(
+            "auto,zstd,3".parse::<Compression>(),
+            Ok(Compression::Zstd {
+                level: Some(3),
+                auto: true,
+                obfuscation: None
+            })
+        );
+
+        for invalid in [
+            "",
+            "brotli",
+            "lz4,3",
+            "auto,none",
+            "zstd,high",
+            "obfuscate,lz4",
+        ] {
+            assert!(invalid.parse::<Compression>().is_err(), "{invalid}");
+        }
+    }
+
+    /// Backend reporting which operation was called with which arguments
+    struct MockBackend;
+
+    impl MockBackend {
+        fn report(
+            mut on_update: impl FnMut(String) -> ControlFlow<()>,
+            update: String,
+        ) -> Result<()> {
+            match on_update(update) {
+                ControlFlow::Continue(()) => Ok(()),
+                ControlFlow::Break(()) => Err(crate::backend::borg::BorgError::Cancelled.into()),
+            }
+        }
+    }
+
+    impl Mock for MockBackend {
+        type Update = String;
+
+        fn check(
+            _: &Borg,
+            repository: &Repo,
+            options: &CheckOptions,
+            on_update: impl FnMut(String) -> ControlFlow<()>,
+        ) -> Result<()> {
+            let last = options.last.unwrap_or_default();
+            Self::report(on_update, format!("check {repository} last {last}"))
+        }
+
+        fn prune(
+            _: &Borg,
+            repository: &Repo,
+            policy: &PrunePolicy,
+            on_update: impl FnMut(String) -> ControlFlow<()>,
+        ) -> Result<()> {
+            let daily = policy.keep_daily.unwrap_or_default();
+            Self::report(on_update, format!("prune {repository} daily {daily}"))
+        }
+
+        fn compact(
+            _: &Borg,
+            repository: &Repo,
+            on_update: impl FnMut(String) -> ControlFlow<()>,
+        ) -> Result<()> {
+            Self::report(on_update, format!("compact {repository}"))
+        }
+
+        fn delete_repository(
+            _: &Borg,
+            repository: &Repo,
+            cache_only: bool,
+            _: bool,
+            on_update: impl FnMut(String) -> ControlFlow<()>,
+        ) -> Result<()> {
+            Self::report(on_update, format!("delete {repository} cache {cache_only}"))
+        }
+
+        fn repo_config(_: &Borg, repository: &Repo, key: &str) -> Result<String> {
+            match (repository.path().to_str(), key) {
+                (Some("/append-only"), "append_only") => Ok("1".to_owned()),
+                (Some("/invalid"), _) => Ok("maybe".to_owned()),
+                _ => Ok("0".to_owned()),
+            }
+        }
+
+        fn list_archives(_: &Borg, repository: &Repo) -> Result<Vec<ArchiveInfo>> {
+            Ok(vec![ArchiveInfo {
+                name: repository.to_string(),
+                id: "0123abcd".to_owned(),
+                time: SystemTime::UNIX_EPOCH,
+            }])
+        }
+
+        fn list_archive(
+            _: &Borg,
+            _: &Repo,
+            archive: &str,
+            mut on_entry: impl FnMut(ArchiveListEntry),
+        ) -> Result<()> {
+            on_entry(ArchiveListEntry {
+                path: archive.into(),
+                kind: EntryKind::File,
+                size: 0,
+                mtime: SystemTime::UNIX_EPOCH,
+                mode: "-rw-r--r--".to_owned(),
+                user: "root".to_owned(),
+                group: "root".to_owned(),
+                link_target: None,
+            });
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_repo_operations() {
+        let borg = Borg::default();
+        let repo: Repo = "/srv/backup".parse().unwrap();
+
+        let mut updates = vec![];
+        let mut collect = |update| {
+            updates.push(update);
+            ControlFlow::Continue(())
+        };
+        let options = CheckOptions {
+            last: Some(3),
+            ..Default::default()
+        };
+        repo.check::<MockBackend>(&borg, &options, &mut collect)
+            .unwrap();
+        let policy = PrunePolicy {
+            keep_daily: Some(7),
+            ..Default::default()
+        };
+        repo.prune::<MockBackend>(&borg, &policy, &mut collect)
+            .unwrap();
+        repo.compact::<MockBackend>(&borg, &mut collect).unwrap();
+        repo.delete::<MockBackend>(&borg, true, false, &mut collect)
+            .unwrap();
+        assert_eq!(
+            updates,
+            [
+                "check /srv/backup last 3",
+                "prune /srv/backup daily 7",
+                "compact /srv/backup",
+                "delete /srv/backup cache true",
+            ]
+        );
+
+        let err = repo
+            .compact::<MockBackend>(&borg, |_| ControlFlow::Break(()))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(crate::backend::borg::BorgError::Cancelled)
+        ));
+
+        let archives = repo.list_archives::<MockBackend>(&borg).unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].name, "/srv/backup");
+
+        let mut paths = vec![];
+        repo.list_archive::<MockBackend>(&borg, "docs", |entry| paths.push(entry.path))
+            .unwrap();
+        assert_eq!(paths, [PathBuf::from("docs")]);
+    }
+
+    #[test]
+    fn test_append_only() {
+        let borg = Borg::default();
+        let append_only =
+            |location: &str| borg.is_append_only::<MockBackend>(&location.parse().unwrap());
+
+        assert_eq!(append_only("/append-only").unwrap(), Some(true));
+        assert_eq!(append_only("/srv/backup").unwrap(), Some(false));
+        assert!(append_only("/invalid").is_err());
+        // Borg can't tell
+        assert_eq!(append_only("ssh://nas/./append-only").unwrap(), None);
+    }
+
+    #[test]
+    fn test_archive_builder() {
+        let archive = Archive::builder("docs")
+            .path("/home/docs")
+            .paths(["/etc", "/srv"])
+            .exclude_file(".borgignore")
+            .build()
+            .unwrap();
+        assert_eq!(archive.name(), "docs");
+        assert_eq!(
+            archive.paths(),
+            [
+                PathBuf::from("/home/docs"),
+                PathBuf::from("/etc"),
+                PathBuf::from("/srv")
+            ]
+        );
+        assert_eq!(archive.get_exclude_file(), Some(Path::new(".borgignore")));
+        assert_eq!(archive.get_pattern_file(), None);
+        assert_eq!(archive.get_compression(), None);
+        assert_eq!(archive.get_comment(), None);
+
+        let mut archive = Archive::new("docs");
+        archive
+            .path("/home/docs")
+            .extend_paths([Path::new("/etc"), Path::new("/srv")])
+            .set_comment("manual");
+        assert_eq!(archive.paths().len(), 3);
+        assert_eq!(archive.get_comment(), Some("manual"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        use serde_json::json;
+
+        fn round_trip<T>(value: &T, expected: serde_json::Value) -> T
+        where
+            T: serde::Serialize + serde::de::DeserializeOwned,
+        {
+            let json = serde_json::to_value(value).unwrap();
+            assert_eq!(json, expected);
+            serde_json::from_value(json).unwrap()
+        }
+
+        let mut repo: Repo = "ssh://user@host:22/./backup".parse().unwrap();
+        assert_eq!(
+            round_trip(&repo, json!("ssh://user@host:22/./backup")),
+            repo
+        );
+        repo.passphrase(Passphrase::Command("pass backup".to_owned()));
+        repo.set_env("BORG_RSH", "ssh -i ~/.ssh/backup");
+        // Secrets stay out of the output
+        assert_eq!(
+            serde_json::to_value(&repo).unwrap(),
+            json!("ssh://user@host:22/./backup")
+        );
+        let parsed: Repo = serde_json::from_value(json!({
+            "location": "ssh://user@host:22/./backup",
+            "passphrase": { "command": "pass backup" },
+        }))
+        .unwrap();
+        assert_eq!(parsed, repo);
+        assert_eq!(parsed.passphrase, repo.passphrase);
+
+        let archive = Archive::builder("{now}")
+            .path("/home")
+            .compression(Compression::Zstd {
+                level: Some(19),
+                auto: true,
+                obfuscation: None,
+            })
+            .comment("nightly")
+            .timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_532_400))
+            .build()
+            .unwrap();
+        let parsed = round_trip(
+            &archive,
+            json!({
+                "name": "{now}",
+                "paths": ["/home"],
+                "compression": "auto,zstd,19",
+                "pattern_file": null,
+                "exclude_file": null,
+                "missing_exclude_file": "error",
+                "comment": "nightly",
+                "exclude_if_present": [],
+                "keep_exclude_tags": false,
+                "relative_to": null,
+                "timestamp": "2024-05-01T03:00:00+00:00",
+            }),
+        );
+        assert_eq!(parsed.paths, archive.paths);
+        assert_eq!(parsed.compression, archive.compression);
+        assert_eq!(parsed.timestamp, archive.timestamp);
+
+        // Everything but the name is optional
+        let archive: Archive = serde_json::from_value(json!({ "name": "docs" })).unwrap();
+        assert!(archive.paths.is_empty());
+
+        let encryption = round_trip(&Encryption::KeyFileBlake2, json!("keyfile-blake2"));
+        assert!(matches!(encryption, Encryption::KeyFileBlake2));
+        assert!(serde_json::from_value::<Encryption>(json!("rot13")).is_err());
+
+        let passphrase = Passphrase::FileDescriptor(3);
+        assert_eq!(
+            round_trip(&passphrase, json!({ "file_descriptor": 3 })),
+            passphrase
+        );
+    }
 
     #[test]
     fn test_byte_size() {
@@ -502,4 +2212,46 @@ mod tests {
         assert_eq!(ByteSize(1025).iec(Some(3)), "1.001Ki");
         assert_eq!(ByteSize(1025).si(Some(3)), "1.025K");
     }
+
+    #[test]
+    fn test_ratios() {
+        let mut info = RepoInfo {
+            total_csize: 26289835627221,
+            total_size: 38449962381221,
+            unique_csize: 300958014008,
+            unique_size: 477242905022,
+            ..mock::repo_info(&"/srv/backup".parse().unwrap())
+        };
+        assert_eq!(info.dedup_ratio().unwrap().to_string(), "87.4×");
+        assert_eq!(info.compression_ratio().unwrap().to_string(), "1.46×");
+
+        info.total_csize = 0;
+        info.unique_csize = 0;
+        assert_eq!(info.dedup_ratio(), None);
+        assert_eq!(info.compression_ratio(), None);
+
+        let mut stats = CreateStats {
+            name: String::new(),
+            id: String::new(),
+            duration: Duration::ZERO,
+            original_size: 2048,
+            compressed_size: 1024,
+            deduplicated_size: 512,
+            nfiles: 3,
+        };
+        assert_eq!(stats.compression_ratio(), Some(Ratio(2.0)));
+        assert_eq!(stats.space_saving(), Some(Percentage(75.0)));
+        assert_eq!(stats.space_saving().unwrap().to_string(), "75.0%");
+
+        // Nothing new, or more stored than read (e.g. incompressible chunks with overhead)
+        stats.deduplicated_size = 0;
+        assert_eq!(stats.space_saving(), Some(Percentage(100.0)));
+        stats.deduplicated_size = 4096;
+        assert_eq!(stats.space_saving(), Some(Percentage(0.0)));
+
+        stats.original_size = 0;
+        stats.compressed_size = 0;
+        assert_eq!(stats.compression_ratio(), None);
+        assert_eq!(stats.space_saving(), None);
+    }
 }
@@ -0,0 +1,93 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Settings for borg's client-side cache
+///
+/// The cache of every repository lives in its own directory below `dir`
+/// (`~/.cache/borg` by default). Its location can be queried with
+/// [`Repo::info`](crate::Repo::info).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cache {
+    /// Base directory for all caches (`BORG_CACHE_DIR`)
+    pub dir: Option<PathBuf>,
+    /// Number of backups a file may be missing before it is evicted from the files cache
+    /// (`BORG_FILES_CACHE_TTL`)
+    pub files_ttl: Option<u32>,
+}
+
+impl Cache {
+    /// Environment variables to pass to borg
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![];
+        if let Some(dir) = &self.dir {
+            env.push((
+                "BORG_CACHE_DIR",
+                crate::util::resolve_path(dir).display().to_string(),
+            ));
+        }
+        if let Some(ttl) = self.files_ttl {
+            env.push(("BORG_FILES_CACHE_TTL", ttl.to_string()));
+        }
+        env
+    }
+
+    /// Whether `path` looks like the cache directory of a single repository
+    pub fn is_cache_dir(path: &Path) -> bool {
+        std::fs::read_to_string(path.join("README"))
+            .map(|readme| readme.starts_with("This is a Borg Backup cache directory"))
+            .unwrap_or(false)
+    }
+
+    /// Total size of all files below `path`
+    pub fn disk_usage(path: &Path) -> io::Result<u64> {
+        let meta = std::fs::symlink_metadata(path)?;
+        if !meta.is_dir() {
+            return Ok(meta.len());
+        }
+
+        let mut size = 0;
+        for entry in std::fs::read_dir(path)? {
+            size += Self::disk_usage(&entry?.path())?;
+        }
+        Ok(size)
+    }
+
+    /// Remove the cache directory of a single repository
+    ///
+    /// Refuses to remove directories which don't look like a borg cache.
+    pub fn clear(path: &Path) -> io::Result<()> {
+        if !Self::is_cache_dir(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not a borg cache directory: {}", path.display()),
+            ));
+        }
+        std::fs::remove_dir_all(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clear() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-cache-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/data"), [0u8; 100]).unwrap();
+
+        assert_eq!(Cache::disk_usage(&dir).unwrap(), 100);
+        assert!(Cache::clear(&dir).is_err());
+        assert!(dir.exists());
+
+        std::fs::write(
+            dir.join("README"),
+            "This is a Borg Backup cache directory.\n",
+        )
+        .unwrap();
+        assert!(Cache::clear(&dir).is_ok());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Cancels borg commands from another thread, e.g. on a signal or after a timeout
+///
+/// Clones share their state, cancelling one cancels all of them. Commands of a [`Borg`] with
+/// the token interrupt borg like Ctrl+C once it is cancelled and fail with
+/// [`BorgError::Cancelled`], commands started afterwards fail right away. The
+/// [`Runner`](crate::runner::Runner) doesn't start any more backups then. After
+/// [`cancel_interrupted`](Self::cancel_interrupted) borg isn't interrupted a second time.
+///
+/// [`Borg`]: crate::Borg
+/// [`BorgError::Cancelled`]: crate::backend::borg::BorgError::Cancelled
+///
+/// # Examples
+/// ```rust
+/// use borrg::CancellationToken;
+/// use std::time::Duration;
+///
+/// let token = CancellationToken::new();
+/// assert!(!token.wait_timeout(Duration::from_millis(1)));
+///
+/// let handle = token.clone();
+/// std::thread::spawn(move || handle.cancel());
+/// assert!(token.wait_timeout(Duration::from_secs(5)));
+/// assert!(token.is_cancelled());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<(Mutex<State>, Condvar)>);
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: bool,
+    /// Borg already got SIGINT, e.g. from Ctrl+C in the terminal
+    interrupted: bool,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel everything using this token or one of its clones, waking up every waiter
+    pub fn cancel(&self) {
+        self.set(false);
+    }
+
+    /// Like [`cancel`](Self::cancel), but borg already got SIGINT and isn't interrupted again
+    ///
+    /// For Ctrl+C in a terminal, which reaches borg as well. A second SIGINT makes borg quit
+    /// without cleaning up.
+    pub fn cancel_interrupted(&self) {
+        self.set(true);
+    }
+
+    fn set(&self, interrupted: bool) {
+        let (state, changed) = &*self.0;
+        let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.cancelled {
+            state.interrupted = interrupted;
+        }
+        state.cancelled = true;
+        changed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state().cancelled
+    }
+
+    /// Whether running borg commands still have to be interrupted, see
+    /// [`cancel_interrupted`](Self::cancel_interrupted)
+    pub(crate) fn interrupts(&self) -> bool {
+        !self.state().interrupted
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0 .0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until the token is cancelled, at most `timeout`, returns whether it was cancelled
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (state, changed) = &*self.0;
+        let guard = state.lock().unwrap_or_else(|e| e.into_inner());
+        let (guard, _) = changed
+            .wait_timeout_while(guard, timeout, |state| !state.cancelled)
+            .unwrap_or_else(|e| e.into_inner());
+        guard.cancelled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+
+        let waiter = std::thread::spawn(move || clone.wait_timeout(Duration::from_secs(10)));
+        std::thread::sleep(Duration::from_millis(20));
+        token.cancel();
+        assert!(waiter.join().unwrap());
+
+        // Stays cancelled
+        assert!(token.wait_timeout(Duration::ZERO));
+        assert!(!CancellationToken::new().wait_timeout(Duration::ZERO));
+        assert!(token.interrupts());
+    }
+
+    #[test]
+    fn test_cancel_interrupted() {
+        let token = CancellationToken::new();
+        token.cancel_interrupted();
+        assert!(token.is_cancelled());
+        assert!(!token.interrupts());
+
+        // The first cancellation decides
+        let token = CancellationToken::new();
+        token.cancel();
+        token.cancel_interrupted();
+        assert!(token.interrupts());
+    }
+}
@@ -0,0 +1,309 @@
+use super::Event;
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+/// What `borg create --list` did with a file, from the letter it lists the file with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileChangeKind {
+    /// `A`, a regular file which wasn't in the files cache
+    Added,
+    /// `M`, a regular file which changed since it was last backed up
+    Modified,
+    /// `U`, a regular file which didn't change
+    Unchanged,
+    /// `C`, a regular file which changed while borg read it
+    ChangedWhileReading,
+    /// `E`, borg failed to read the file
+    Error,
+    /// `d`
+    Directory,
+    /// `b`
+    BlockDevice,
+    /// `c`
+    CharDevice,
+    /// `h`, a hard link to a file already in the archive
+    HardLink,
+    /// `s`
+    Symlink,
+    /// `f`
+    Fifo,
+    /// `i`, data read from stdin
+    Stdin,
+    /// `-`, a file which would be backed up, only in dry runs
+    DryRun,
+    /// `x`, left out by an exclusion rule
+    Excluded,
+    /// `?`, borg doesn't know the status itself
+    Unknown,
+    /// Any other letter, as borg reported it
+    Other(char),
+}
+
+impl FileChangeKind {
+    /// Every kind borg knows, in the order borg documents them
+    pub const ALL: [FileChangeKind; 15] = [
+        FileChangeKind::Added,
+        FileChangeKind::Modified,
+        FileChangeKind::Unchanged,
+        FileChangeKind::ChangedWhileReading,
+        FileChangeKind::Error,
+        FileChangeKind::Directory,
+        FileChangeKind::BlockDevice,
+        FileChangeKind::CharDevice,
+        FileChangeKind::HardLink,
+        FileChangeKind::Symlink,
+        FileChangeKind::Fifo,
+        FileChangeKind::Stdin,
+        FileChangeKind::DryRun,
+        FileChangeKind::Excluded,
+        FileChangeKind::Unknown,
+    ];
+
+    /// The letter borg lists the file with
+    pub fn letter(&self) -> char {
+        match self {
+            FileChangeKind::Added => 'A',
+            FileChangeKind::Modified => 'M',
+            FileChangeKind::Unchanged => 'U',
+            FileChangeKind::ChangedWhileReading => 'C',
+            FileChangeKind::Error => 'E',
+            FileChangeKind::Directory => 'd',
+            FileChangeKind::BlockDevice => 'b',
+            FileChangeKind::CharDevice => 'c',
+            FileChangeKind::HardLink => 'h',
+            FileChangeKind::Symlink => 's',
+            FileChangeKind::Fifo => 'f',
+            FileChangeKind::Stdin => 'i',
+            FileChangeKind::DryRun => '-',
+            FileChangeKind::Excluded => 'x',
+            FileChangeKind::Unknown => '?',
+            FileChangeKind::Other(letter) => *letter,
+        }
+    }
+}
+
+impl From<char> for FileChangeKind {
+    fn from(letter: char) -> Self {
+        FileChangeKind::ALL
+            .into_iter()
+            .find(|kind| kind.letter() == letter)
+            .unwrap_or(FileChangeKind::Other(letter))
+    }
+}
+
+/// The status of [`Event::FileStatus`], [`FileChangeKind::Unknown`] if it is empty
+impl From<&str> for FileChangeKind {
+    fn from(status: &str) -> Self {
+        status
+            .chars()
+            .next()
+            .map_or(FileChangeKind::Unknown, Into::into)
+    }
+}
+
+impl Display for FileChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            FileChangeKind::Added => "added",
+            FileChangeKind::Modified => "modified",
+            FileChangeKind::Unchanged => "unchanged",
+            FileChangeKind::ChangedWhileReading => "changed while reading",
+            FileChangeKind::Error => "failed",
+            FileChangeKind::Directory => "directories",
+            FileChangeKind::BlockDevice => "block devices",
+            FileChangeKind::CharDevice => "character devices",
+            FileChangeKind::HardLink => "hard links",
+            FileChangeKind::Symlink => "symlinks",
+            FileChangeKind::Fifo => "fifos",
+            FileChangeKind::Stdin => "from stdin",
+            FileChangeKind::DryRun => "to back up",
+            FileChangeKind::Excluded => "excluded",
+            FileChangeKind::Unknown => "unknown",
+            FileChangeKind::Other(letter) => return write!(f, "status {letter}"),
+        };
+        f.write_str(description)
+    }
+}
+
+/// Which files `borg create --list` reports, the letters of borg's `--filter`
+///
+/// The default lets every file through. Parsing fails for letters borg doesn't know, borg
+/// would only reject them after starting.
+///
+/// # Examples
+/// ```rust
+/// use borrg::{FileChangeKind, FileFilter};
+///
+/// let filter: FileFilter = "AME".parse().unwrap();
+/// assert!(filter.contains(FileChangeKind::Modified));
+/// assert!(!filter.contains(FileChangeKind::Unchanged));
+/// assert_eq!(filter.to_string(), "AME");
+///
+/// assert!("AZ".parse::<FileFilter>().is_err());
+/// assert!(FileFilter::default().contains(FileChangeKind::Unchanged));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileFilter(Vec<FileChangeKind>);
+
+impl FileFilter {
+    /// Whether files of this kind are reported
+    pub fn contains(&self, kind: FileChangeKind) -> bool {
+        self.0.is_empty() || self.0.contains(&kind)
+    }
+
+    /// Whether every file is reported
+    pub fn is_all(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Also report files of this kind
+    pub fn with(mut self, kind: FileChangeKind) -> Self {
+        if !self.contains(kind) {
+            self.0.push(kind);
+        }
+        self
+    }
+}
+
+/// Only files of this kind
+impl From<FileChangeKind> for FileFilter {
+    fn from(kind: FileChangeKind) -> Self {
+        FileFilter(vec![kind])
+    }
+}
+
+impl FromStr for FileFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("No status letters".to_owned());
+        }
+        let mut kinds = vec![];
+        for letter in s.chars() {
+            let kind = match FileChangeKind::from(letter) {
+                FileChangeKind::Other(_) => {
+                    let known: String = FileChangeKind::ALL.iter().map(|k| k.letter()).collect();
+                    return Err(format!(
+                        "Unknown status letter {letter:?}, borg knows {known}"
+                    ));
+                }
+                kind => kind,
+            };
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+        Ok(FileFilter(kinds))
+    }
+}
+
+/// The letters as passed to `--filter`, empty for every file
+impl Display for FileFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|kind| write!(f, "{}", kind.letter()))
+    }
+}
+
+/// Number of files listed by `borg create --list` per kind
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileChanges(BTreeMap<FileChangeKind, u64>);
+
+impl FileChanges {
+    pub fn update(&mut self, event: &Event) {
+        if let Some(kind) = event.file_change() {
+            *self.0.entry(kind).or_default() += 1;
+        }
+    }
+
+    pub fn get(&self, kind: FileChangeKind) -> u64 {
+        self.0.get(&kind).copied().unwrap_or_default()
+    }
+
+    /// Whether no file was listed, borg only lists them with `--list`
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The kinds with at least one file, with their number
+    pub fn iter(&self) -> impl Iterator<Item = (FileChangeKind, u64)> + '_ {
+        self.0.iter().map(|(kind, count)| (*kind, *count))
+    }
+}
+
+/// E.g. "12 added, 3 modified, 5320 unchanged"
+impl Display for FileChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use crate::format::count;
+        for (i, (kind, n)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {kind}", count(n))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(status: &str) -> Event {
+        Event::FileStatus {
+            status: status.to_owned(),
+            path: "home/seb/notes.txt".into(),
+        }
+    }
+
+    #[test]
+    fn test_file_change_kind() {
+        for kind in FileChangeKind::ALL {
+            assert_eq!(FileChangeKind::from(kind.letter()), kind);
+        }
+        assert_eq!(FileChangeKind::from("A"), FileChangeKind::Added);
+        assert_eq!(FileChangeKind::from("x"), FileChangeKind::Excluded);
+        assert_eq!(FileChangeKind::from("Z"), FileChangeKind::Other('Z'));
+        assert_eq!(FileChangeKind::from(""), FileChangeKind::Unknown);
+        assert_eq!(FileChangeKind::Other('Z').to_string(), "status Z");
+    }
+
+    #[test]
+    fn test_file_filter() {
+        let filter: FileFilter = "AMEx".parse().unwrap();
+        assert!(filter.contains(FileChangeKind::Excluded));
+        assert!(!filter.contains(FileChangeKind::Directory));
+        assert!(!filter.is_all());
+        assert_eq!(filter.clone().with(FileChangeKind::Added), filter);
+
+        assert_eq!("AA".parse::<FileFilter>().unwrap().to_string(), "A");
+        assert!("".parse::<FileFilter>().is_err());
+        let unknown = "AZ".parse::<FileFilter>().unwrap_err();
+        assert!(
+            unknown.starts_with("Unknown status letter 'Z'"),
+            "{unknown}"
+        );
+
+        // Letting every file through already includes excluded ones
+        let all = FileFilter::default().with(FileChangeKind::Excluded);
+        assert!(all.is_all());
+    }
+
+    #[test]
+    fn test_file_changes() {
+        let mut changes = FileChanges::default();
+        assert!(changes.is_empty());
+        for s in ["A", "U", "U", "M", "U", "Z"] {
+            changes.update(&status(s));
+        }
+        changes.update(&Event::Other("not a file".to_owned()));
+
+        assert_eq!(changes.get(FileChangeKind::Unchanged), 3);
+        assert_eq!(changes.get(FileChangeKind::Error), 0);
+        assert_eq!(
+            changes.to_string(),
+            "1 added, 1 modified, 3 unchanged, 1 status Z"
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use super::{Backend, Borg, Repo, RepoInfo, Result};
+use crate::backend::borg::BorgError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Answers of `borg info` per repository, so a command asking several times runs borg once
+///
+/// Requests for the same repository, however it is written, share one borg call: concurrent
+/// ones wait for the first. Failures are kept as well, an unreachable host isn't asked again.
+/// A [`Borg`] with the cache drops the entry of a repository when it changes it, e.g. by
+/// creating an archive or pruning, see [`Borg::info_cache`].
+///
+/// # Examples
+/// ```rust,no_run
+/// use borrg::{backend::borg::BorgWrapper, Borg, Repo, RepoInfoCache};
+/// use std::sync::Arc;
+///
+/// let repo: Repo = "ssh://nas/./backup".parse()?;
+/// let mut borg = Borg::default();
+/// borg.info_cache(Arc::new(RepoInfoCache::new()));
+/// let info = borg.repo_info::<BorgWrapper>(&repo)?;
+/// // Answered from the cache
+/// assert_eq!(borg.repo_info::<BorgWrapper>(&repo)?.id, info.id);
+/// # Ok::<(), borrg::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct RepoInfoCache {
+    /// How long an answer is used, for the lifetime of the cache if unset
+    ttl: Option<Duration>,
+    /// A slot per canonical location, locked while borg is asked
+    entries: Mutex<HashMap<String, Arc<Mutex<Option<Entry>>>>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    time: Instant,
+    result: std::result::Result<RepoInfo, CachedError>,
+}
+
+/// A failure kept in the cache, borg's errors can still be told apart
+#[derive(Debug, Clone)]
+enum CachedError {
+    Borg(BorgError),
+    Other(String),
+}
+
+impl RepoInfoCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask borg again once an answer is older than `ttl`, for long-running processes
+    pub fn with_ttl(ttl: Duration) -> Self {
+        RepoInfoCache {
+            ttl: Some(ttl),
+            ..Self::default()
+        }
+    }
+
+    /// The info of `repository`, from the cache if it is there and fresh
+    pub fn get<B: Backend>(&self, borg: &Borg, repository: &Repo) -> Result<RepoInfo> {
+        let slot = Arc::clone(
+            self.lock()
+                .entry(repository.canonical_location())
+                .or_default(),
+        );
+        let mut entry = slot.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = entry.as_ref().filter(|e| self.is_fresh(e)) {
+            return entry.result.clone().map_err(Into::into);
+        }
+
+        let result = B::repo_info(borg, repository);
+        let cached = match &result {
+            Ok(info) => Ok(info.clone()),
+            // Another try may not be cancelled
+            Err(e) if matches!(e.downcast_ref(), Some(BorgError::Cancelled)) => return result,
+            Err(e) => Err(match e.downcast_ref::<BorgError>() {
+                Some(e) => CachedError::Borg(e.clone()),
+                None => CachedError::Other(e.to_string()),
+            }),
+        };
+        *entry = Some(Entry {
+            time: Instant::now(),
+            result: cached,
+        });
+        result
+    }
+
+    /// Forget the info of `repository`, e.g. after changing it
+    ///
+    /// A request which is waiting for borg right now still gets the old answer.
+    pub fn invalidate(&self, repository: &Repo) {
+        self.lock().remove(&repository.canonical_location());
+    }
+
+    /// Forget every repository
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn is_fresh(&self, entry: &Entry) -> bool {
+        self.ttl.is_none_or(|ttl| entry.time.elapsed() < ttl)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<Mutex<Option<Entry>>>>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl From<CachedError> for super::Error {
+    fn from(e: CachedError) -> Self {
+        match e {
+            CachedError::Borg(e) => e.into(),
+            CachedError::Other(message) => message.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::{self, Mock};
+    use crate::{Archive, CreateStats, Event, PrunePolicy};
+    use std::ops::ControlFlow;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Backend answering `borg info` and creating archives slowly, failing for repositories
+    /// below /missing
+    struct SlowInfo;
+
+    static QUERIES: AtomicU32 = AtomicU32::new(0);
+
+    impl Mock for SlowInfo {
+        type Update = Event;
+
+        fn create_archive(
+            _: &Borg,
+            _: &Repo,
+            _: &Archive,
+            _: impl FnMut(Event) -> ControlFlow<()>,
+        ) -> Result<Option<CreateStats>> {
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(None)
+        }
+
+        fn repo_info(_: &Borg, repository: &Repo) -> Result<RepoInfo> {
+            QUERIES.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(100));
+            if repository.path().starts_with("/missing") {
+                return Err(BorgError::Repository(13).into());
+            }
+            Ok(mock::repo_info(repository))
+        }
+    }
+
+    #[test]
+    fn test_repo_info_cache() {
+        let mut borg = Borg::default();
+        borg.info_cache(Arc::new(RepoInfoCache::new()));
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let same: Repo = "/srv/backup/".parse().unwrap();
+        let queries = || QUERIES.load(Ordering::SeqCst);
+
+        // Concurrent requests share one call
+        std::thread::scope(|s| {
+            let threads: Vec<_> = [&repo, &same, &repo]
+                .into_iter()
+                .map(|repo| s.spawn(|| borg.repo_info::<SlowInfo>(repo).unwrap()))
+                .collect();
+            for thread in threads {
+                assert_eq!(thread.join().unwrap().id, "dd06");
+            }
+        });
+        assert_eq!(queries(), 1);
+
+        // Changing the repository drops its entry
+        borg.prune::<SlowInfo>(
+            &repo,
+            &PrunePolicy::default(),
+            |_| ControlFlow::Continue(()),
+        )
+        .unwrap();
+        borg.repo_info::<SlowInfo>(&same).unwrap();
+        assert_eq!(queries(), 2);
+
+        // Info asked for while the repository changes isn't kept
+        std::thread::scope(|s| {
+            let change = s.spawn(|| {
+                let archive = Archive::new("slow");
+                borg.create_archive::<SlowInfo>(&repo, &archive, |_| ControlFlow::Continue(()))
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            borg.repo_info::<SlowInfo>(&repo).unwrap();
+            change.join().unwrap().unwrap();
+        });
+        borg.repo_info::<SlowInfo>(&repo).unwrap();
+        assert_eq!(queries(), 4);
+
+        // Failures are kept, and can still be told apart
+        let missing: Repo = "/missing/backup".parse().unwrap();
+        for _ in 0..2 {
+            let err = borg.repo_info::<SlowInfo>(&missing).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(BorgError::Repository(13))
+            ));
+        }
+        assert_eq!(queries(), 5);
+
+        // Expired answers are asked for again
+        borg.info_cache(Arc::new(RepoInfoCache::with_ttl(Duration::ZERO)));
+        borg.repo_info::<SlowInfo>(&repo).unwrap();
+        borg.repo_info::<SlowInfo>(&repo).unwrap();
+        assert_eq!(queries(), 7);
+    }
+}
@@ -0,0 +1,50 @@
+/// Whether borrg and borg may wait for the user to answer, see [`Borg::interaction`]
+///
+/// [`Borg::interaction`]: crate::Borg::interaction
+///
+/// # Examples
+/// ```rust
+/// use borrg::Interaction;
+///
+/// assert!(Interaction::Interactive.can_prompt(true));
+/// assert!(!Interaction::Interactive.can_prompt(false));
+/// assert!(!Interaction::Batch.can_prompt(true));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Interaction {
+    /// Ask on a terminal, e.g. for missing passphrases and before deleting something
+    #[default]
+    Interactive,
+    /// Never wait for input, for unattended runs
+    ///
+    /// Borg gets no stdin, an empty passphrase if it has none, and "no" as the answer to its
+    /// questions (see [`BORG_QUESTIONS`]). It fails where it would have asked for a
+    /// passphrase, and logs the answer as [`Event::Answer`](crate::Event::Answer).
+    Batch,
+}
+
+/// Variables borg reads the answer to a yes/no question from instead of asking, batch mode
+/// answers all of them with "no" unless they are set
+pub const BORG_QUESTIONS: &[&str] = &[
+    "BORG_UNKNOWN_UNENCRYPTED_REPO_ACCESS_IS_OK",
+    "BORG_RELOCATED_REPO_ACCESS_IS_OK",
+    "BORG_CHECK_I_KNOW_WHAT_I_AM_DOING",
+    "BORG_DELETE_I_KNOW_WHAT_I_AM_DOING",
+    "BORG_RECREATE_I_KNOW_WHAT_I_AM_DOING",
+];
+
+impl Interaction {
+    pub fn is_batch(self) -> bool {
+        self == Interaction::Batch
+    }
+
+    /// Whether to ask the user on a `terminal`, never in batch mode
+    pub fn can_prompt(self, terminal: bool) -> bool {
+        !self.is_batch() && terminal
+    }
+
+    /// Whether to draw progress bars on a `terminal`, never in batch mode
+    pub fn shows_progress(self, terminal: bool) -> bool {
+        self.can_prompt(terminal)
+    }
+}
@@ -0,0 +1,339 @@
+use std::time::SystemTime;
+
+/// Placeholders borg expands in archive names
+pub const BORG_PLACEHOLDERS: &[&str] = &[
+    "hostname",
+    "fqdn",
+    "reverse-fqdn",
+    "now",
+    "utcnow",
+    "user",
+    "pid",
+    "uuid4",
+    "borgversion",
+    "borgmajor",
+    "borgminor",
+    "borgpatch",
+];
+
+/// The placeholders of [`BORG_PLACEHOLDERS`] borrg can expand itself
+pub const BORRG_PLACEHOLDERS: &[&str] = &["hostname", "now", "utcnow", "user"];
+
+/// Format of `{now}` and `{utcnow}` without one, the same as borg's
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Who expands the placeholders of an archive name, e.g. `{hostname}-{now:%Y-%m-%d}`
+///
+/// Borg's syntax is used either way, `{{` and `}}` are literal braces. When borrg expands
+/// them, borg gets a name without any placeholders left.
+///
+/// # Examples
+/// ```rust
+/// use borrg::{NameContext, NamePlaceholders};
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let context = NameContext {
+///     time: UNIX_EPOCH + Duration::from_secs(1_714_532_400),
+///     hostname: Some("laptop".to_owned()),
+///     user: Some("seb".to_owned()),
+/// };
+///
+/// let name = "{hostname}-{utcnow:%Y-%m-%d}";
+/// let borrg = NamePlaceholders::Borrg;
+/// assert_eq!(borrg.name_for_borg(name, &context).unwrap(), "laptop-2024-05-01");
+///
+/// let borg = NamePlaceholders::Borg;
+/// assert_eq!(borg.name_for_borg(name, &context).unwrap(), name);
+/// assert_eq!(
+///     borg.expected_name(name, &context).unwrap().as_deref(),
+///     Some("laptop-2024-05-01")
+/// );
+///
+/// // Only borg knows its version and random ids
+/// assert_eq!(borg.expected_name("{uuid4}", &context).unwrap(), None);
+/// assert!(borrg.validate("{uuid4}").is_err());
+/// assert!(borg.validate("{date}").is_err());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NamePlaceholders {
+    /// borrg expands [`BORRG_PLACEHOLDERS`] before passing the name to borg
+    #[default]
+    Borrg,
+    /// The name is passed untouched, borg expands all of [`BORG_PLACEHOLDERS`]
+    Borg,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PlaceholderError {
+    #[error("Unmatched \"{0}\", write \"{0}{0}\" for a literal one")]
+    Unmatched(char),
+    #[error("Unknown placeholder \"{{{0}}}\"")]
+    Unknown(String),
+    #[error("Placeholder \"{{{0}}}\" is only expanded by borg itself")]
+    BorgOnly(String),
+    #[error("Invalid format of placeholder \"{{{0}}}\"")]
+    InvalidFormat(String),
+}
+
+/// What the placeholders stand for when an archive is created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameContext {
+    /// `{now}` and `{utcnow}`
+    pub time: SystemTime,
+    /// `{hostname}`, without the domain
+    pub hostname: Option<String>,
+    /// `{user}`
+    pub user: Option<String>,
+}
+
+impl NameContext {
+    /// This machine and user at `time`
+    pub fn at(time: SystemTime) -> Self {
+        NameContext {
+            time,
+            hostname: crate::util::hostname(),
+            user: crate::util::username(),
+        }
+    }
+
+    /// Value of a placeholder, `None` if only borg knows it
+    fn value(&self, key: &str, format: Option<&str>) -> Option<String> {
+        let format = match (key, format) {
+            ("now" | "utcnow", format) => format.unwrap_or(DEFAULT_TIME_FORMAT),
+            (_, Some(_)) => return None,
+            (_, None) => "",
+        };
+        match key {
+            "hostname" => self.hostname.clone(),
+            "user" => self.user.clone(),
+            "now" => Some(
+                chrono::DateTime::<chrono::Local>::from(self.time)
+                    .format(format)
+                    .to_string(),
+            ),
+            "utcnow" => Some(
+                chrono::DateTime::<chrono::Utc>::from(self.time)
+                    .format(format)
+                    .to_string(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl NamePlaceholders {
+    /// The placeholders expanded by this engine
+    pub fn known(self) -> &'static [&'static str] {
+        match self {
+            NamePlaceholders::Borrg => BORRG_PLACEHOLDERS,
+            NamePlaceholders::Borg => BORG_PLACEHOLDERS,
+        }
+    }
+
+    /// Check that every placeholder of `name` gets expanded
+    pub fn validate(self, name: &str) -> Result<(), PlaceholderError> {
+        for part in parse(name)? {
+            let Part::Placeholder { key, format } = part else {
+                continue;
+            };
+            if !BORG_PLACEHOLDERS.contains(&key) {
+                return Err(PlaceholderError::Unknown(key.to_owned()));
+            }
+            if !self.known().contains(&key) {
+                return Err(PlaceholderError::BorgOnly(key.to_owned()));
+            }
+            let Some(format) = format else {
+                continue;
+            };
+            let valid = match key {
+                "now" | "utcnow" => !chrono::format::StrftimeItems::new(format)
+                    .any(|item| item == chrono::format::Item::Error),
+                // Borg formats the other values like Python strings, borrg can't
+                _ => self == NamePlaceholders::Borg,
+            };
+            if !valid {
+                return Err(PlaceholderError::InvalidFormat(key.to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The name passed to borg, with the placeholders borrg expands replaced
+    ///
+    /// Placeholders without a value, e.g. `{hostname}` if it can't be determined, are left for
+    /// borg.
+    pub fn name_for_borg(
+        self,
+        name: &str,
+        context: &NameContext,
+    ) -> Result<String, PlaceholderError> {
+        self.validate(name)?;
+        match self {
+            NamePlaceholders::Borg => Ok(name.to_owned()),
+            NamePlaceholders::Borrg => Ok(render(&parse(name)?, context, true)
+                .expect("unknown placeholders are kept for borg")),
+        }
+    }
+
+    /// The name of the archive borg creates, `None` if only borg knows it, e.g. with `{pid}`
+    pub fn expected_name(
+        self,
+        name: &str,
+        context: &NameContext,
+    ) -> Result<Option<String>, PlaceholderError> {
+        self.validate(name)?;
+        Ok(render(&parse(name)?, context, false))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Part<'a> {
+    Text(&'a str),
+    Placeholder {
+        key: &'a str,
+        format: Option<&'a str>,
+    },
+}
+
+/// Split a name into text and placeholders, like Python's `str.format` does
+fn parse(name: &str) -> Result<Vec<Part<'_>>, PlaceholderError> {
+    let mut parts = vec![];
+    let mut rest = name;
+    while let Some(start) = rest.find(['{', '}']) {
+        parts.push(Part::Text(&rest[..start]));
+        let brace = &rest[start..start + 1];
+        let after = &rest[start + 1..];
+        if let Some(after) = after.strip_prefix(brace) {
+            parts.push(Part::Text(brace));
+            rest = after;
+            continue;
+        }
+        if brace == "}" {
+            return Err(PlaceholderError::Unmatched('}'));
+        }
+        let end = after.find('}').ok_or(PlaceholderError::Unmatched('{'))?;
+        let (key, format) = match after[..end].split_once(':') {
+            Some((key, format)) => (key, Some(format)),
+            None => (&after[..end], None),
+        };
+        parts.push(Part::Placeholder { key, format });
+        rest = &after[end + 1..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+/// Join the parts with the placeholders replaced
+///
+/// `for_borg` escapes the braces borg would expand again and keeps placeholders without a
+/// value, otherwise there is no name if one of them has none.
+fn render(parts: &[Part], context: &NameContext, for_borg: bool) -> Option<String> {
+    let escape = |s: &str| match for_borg {
+        true => s.replace('{', "{{").replace('}', "}}"),
+        false => s.to_owned(),
+    };
+
+    let mut name = String::new();
+    for part in parts {
+        match part {
+            Part::Text(text) => name.push_str(&escape(text)),
+            Part::Placeholder { key, format } => {
+                let value = BORRG_PLACEHOLDERS
+                    .contains(key)
+                    .then(|| context.value(key, *format))
+                    .flatten();
+                match (value, format) {
+                    (Some(value), _) => name.push_str(&escape(&value)),
+                    (None, _) if !for_borg => return None,
+                    (None, Some(format)) => name.push_str(&format!("{{{key}:{format}}}")),
+                    (None, None) => name.push_str(&format!("{{{key}}}")),
+                }
+            }
+        }
+    }
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn context() -> NameContext {
+        NameContext {
+            time: UNIX_EPOCH + Duration::from_secs(1_714_532_400),
+            hostname: Some("laptop".to_owned()),
+            user: None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("a{{b}}-{now:%Y}").unwrap(),
+            [
+                Part::Text("a"),
+                Part::Text("{"),
+                Part::Text("b"),
+                Part::Text("}"),
+                Part::Text("-"),
+                Part::Placeholder {
+                    key: "now",
+                    format: Some("%Y")
+                },
+                Part::Text(""),
+            ]
+        );
+        assert_eq!(parse("{now"), Err(PlaceholderError::Unmatched('{')));
+        assert_eq!(parse("now}"), Err(PlaceholderError::Unmatched('}')));
+    }
+
+    #[test]
+    fn test_validate() {
+        use NamePlaceholders::*;
+
+        assert_eq!(Borrg.validate("{hostname}-{now:%Y-%m-%d}"), Ok(()));
+        assert_eq!(Borg.validate("{fqdn}-{pid}-{user:>8}"), Ok(()));
+        assert_eq!(
+            Borrg.validate("{fqdn}"),
+            Err(PlaceholderError::BorgOnly("fqdn".to_owned()))
+        );
+        assert_eq!(
+            Borg.validate("{date}"),
+            Err(PlaceholderError::Unknown("date".to_owned()))
+        );
+        assert_eq!(
+            Borrg.validate("{user:>8}"),
+            Err(PlaceholderError::InvalidFormat("user".to_owned()))
+        );
+        assert_eq!(
+            Borg.validate("{now:%Q}"),
+            Err(PlaceholderError::InvalidFormat("now".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        use NamePlaceholders::*;
+        let context = context();
+
+        let name = "{{{hostname}}}-{utcnow}";
+        assert_eq!(
+            Borrg.name_for_borg(name, &context).unwrap(),
+            "{{laptop}}-2024-05-01T03:00:00"
+        );
+        assert_eq!(
+            Borrg.expected_name(name, &context).unwrap().as_deref(),
+            Some("{laptop}-2024-05-01T03:00:00")
+        );
+
+        // Without a user name borg has to expand it
+        assert_eq!(
+            Borrg.name_for_borg("{user}-{utcnow:%Y}", &context).unwrap(),
+            "{user}-2024"
+        );
+        assert_eq!(Borrg.expected_name("{user}", &context).unwrap(), None);
+        assert_eq!(Borg.expected_name("{pid}", &context).unwrap(), None);
+        assert_eq!(Borg.name_for_borg("{pid}", &context).unwrap(), "{pid}");
+    }
+}
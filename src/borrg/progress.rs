@@ -0,0 +1,519 @@
+use super::{Event, FileChanges, PruneStats};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::SystemTime,
+};
+
+/// Number of progress samples the current throughput is averaged over
+const THROUGHPUT_WINDOW: usize = 10;
+
+/// Operation a backup is currently busy with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Nothing reported yet
+    #[default]
+    Waiting,
+    Creating,
+    Checking,
+    Pruning,
+    Compacting,
+    Done,
+}
+
+/// Step of a borg operation, from the msgid of its progress events
+///
+/// Phases are what borrg runs, stages are what borg does within them.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stage {
+    /// `cache.begin_transaction`
+    BeginCacheTransaction,
+    /// `cache.download_chunks`
+    DownloadChunks,
+    /// `cache.sync`, merging the chunk indices of the archives
+    SyncChunksCache,
+    /// `cache.commit`, saving the files and chunks cache
+    SaveCache,
+    /// `archive.calc_stats`
+    CalcStats,
+    /// `archive.delete`
+    DeleteArchive,
+    /// `prune`
+    Prune,
+    /// `repository.compact_segments`
+    CompactSegments,
+    /// `repository.replay_segments`
+    ReplaySegments,
+    /// `repository.check`
+    CheckSegments,
+    /// `check.verify_data`
+    VerifyData,
+    /// `check.rebuild_manifest`
+    RebuildManifest,
+    /// `extract`
+    Extract,
+    /// `extract.permissions`
+    RestorePermissions,
+    /// Any other msgid, as borg reported it
+    Other(String),
+}
+
+impl From<&str> for Stage {
+    fn from(msgid: &str) -> Self {
+        match msgid {
+            "cache.begin_transaction" => Stage::BeginCacheTransaction,
+            "cache.download_chunks" => Stage::DownloadChunks,
+            "cache.sync" => Stage::SyncChunksCache,
+            "cache.commit" => Stage::SaveCache,
+            "archive.calc_stats" => Stage::CalcStats,
+            "archive.delete" => Stage::DeleteArchive,
+            "prune" => Stage::Prune,
+            "repository.compact_segments" => Stage::CompactSegments,
+            "repository.replay_segments" => Stage::ReplaySegments,
+            "repository.check" => Stage::CheckSegments,
+            "check.verify_data" => Stage::VerifyData,
+            "check.rebuild_manifest" => Stage::RebuildManifest,
+            "extract" => Stage::Extract,
+            "extract.permissions" => Stage::RestorePermissions,
+            other => Stage::Other(other.to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            Stage::BeginCacheTransaction => "initializing cache",
+            Stage::DownloadChunks => "downloading chunk index",
+            Stage::SyncChunksCache => "syncing chunks cache",
+            Stage::SaveCache => "saving cache",
+            Stage::CalcStats => "calculating statistics",
+            Stage::DeleteArchive => "deleting archive",
+            Stage::Prune => "pruning archives",
+            Stage::CompactSegments => "compacting segments",
+            Stage::ReplaySegments => "replaying segments",
+            Stage::CheckSegments => "checking segments",
+            Stage::VerifyData => "verifying data",
+            Stage::RebuildManifest => "rebuilding manifest",
+            Stage::Extract => "extracting",
+            Stage::RestorePermissions => "restoring permissions",
+            Stage::Other(msgid) => msgid,
+        };
+        f.write_str(description)
+    }
+}
+
+/// Original data read by borg per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughput {
+    /// Over the last few progress updates
+    pub current: f64,
+    /// Since the first progress update
+    pub average: f64,
+    /// Highest current throughput so far
+    pub peak: f64,
+}
+
+/// A change reported to the subscribers of a [`ProgressTracker`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressDelta {
+    Phase(Phase),
+    /// Increase of the totals since the previous progress
+    Progress {
+        nfiles: u64,
+        original_size: u64,
+        compressed_size: u64,
+        deduplicated_size: u64,
+        path: PathBuf,
+    },
+    Warning(String),
+    Error(String),
+}
+
+/// Aggregates the events of an operation into its current state
+///
+/// Feed every event of the update callback into [`ProgressTracker::update`]:
+///
+/// ```no_run
+/// # use borrg::{Archive, Borg, ProgressTracker, Repo, backend::borg::BorgWrapper};
+/// # use std::ops::ControlFlow;
+/// # let (repo, archive): (Repo, Archive) = todo!();
+/// let mut tracker = ProgressTracker::new();
+/// let stats = repo.create_archive::<BorgWrapper>(&Borg::default(), &archive, |event| {
+///     tracker.update(&event);
+///     ControlFlow::Continue(())
+/// });
+/// println!("{} files", tracker.nfiles());
+/// ```
+#[derive(Debug, Default)]
+pub struct ProgressTracker {
+    phase: Phase,
+    stage: Option<Stage>,
+    nfiles: u64,
+    original_size: u64,
+    compressed_size: u64,
+    deduplicated_size: u64,
+    path: Option<PathBuf>,
+    warnings: u64,
+    errors: u64,
+    pruned: PruneStats,
+    file_changes: FileChanges,
+    /// Time and original size of the first and the most recent archive progress updates
+    first_sample: Option<(SystemTime, u64)>,
+    samples: VecDeque<(SystemTime, u64)>,
+    peak_throughput: f64,
+    subscribers: Vec<mpsc::Sender<ProgressDelta>>,
+}
+
+impl ProgressTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive every change from now on
+    ///
+    /// Dropping the receiver unsubscribes.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<ProgressDelta> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Mark the start of the next operation, borg's events don't tell them apart reliably
+    pub fn begin(&mut self, phase: Phase) {
+        if self.phase != phase {
+            self.phase = phase;
+            self.publish(ProgressDelta::Phase(phase));
+        }
+    }
+
+    pub fn update(&mut self, event: &Event) {
+        self.pruned.update(event);
+        self.file_changes.update(event);
+
+        match event {
+            Event::ArchiveProgress {
+                nfiles,
+                original_size,
+                compressed_size,
+                deduplicated_size,
+                path,
+                time,
+            } => {
+                self.sample(time.unwrap_or_else(SystemTime::now), *original_size);
+                if self.phase == Phase::Waiting {
+                    self.begin(Phase::Creating);
+                }
+                // borg reports running totals
+                let delta = ProgressDelta::Progress {
+                    nfiles: nfiles.saturating_sub(self.nfiles),
+                    original_size: original_size.saturating_sub(self.original_size),
+                    compressed_size: compressed_size.saturating_sub(self.compressed_size),
+                    deduplicated_size: deduplicated_size.saturating_sub(self.deduplicated_size),
+                    path: path.to_owned(),
+                };
+                self.nfiles = *nfiles;
+                self.original_size = *original_size;
+                self.compressed_size = *compressed_size;
+                self.deduplicated_size = *deduplicated_size;
+                self.path = Some(path.to_owned());
+                self.publish(delta);
+            }
+            Event::LogMessage {
+                level: Some(log::Level::Warn),
+                message,
+                ..
+            } => {
+                self.warnings += 1;
+                self.publish(ProgressDelta::Warning(message.to_owned()));
+            }
+            Event::LogMessage {
+                level: Some(log::Level::Error),
+                message,
+                ..
+            } => {
+                self.errors += 1;
+                self.publish(ProgressDelta::Error(message.to_owned()));
+            }
+            Event::Error(e) => {
+                self.errors += 1;
+                self.publish(ProgressDelta::Error(e.to_string()));
+            }
+            Event::ProgressMessage {
+                finished: Some(true),
+                ..
+            }
+            | Event::ProgressPercent { finished: true, .. } => self.stage = None,
+            event => {
+                if let Some(stage) = event.stage() {
+                    self.stage = Some(stage);
+                }
+            }
+        }
+    }
+
+    fn sample(&mut self, time: SystemTime, original_size: u64) {
+        self.first_sample.get_or_insert((time, original_size));
+        if self.samples.len() == THROUGHPUT_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((time, original_size));
+        if let Some(current) = self.current_throughput() {
+            self.peak_throughput = self.peak_throughput.max(current);
+        }
+    }
+
+    fn current_throughput(&self) -> Option<f64> {
+        rate(*self.samples.front()?, *self.samples.back()?)
+    }
+
+    fn publish(&mut self, delta: ProgressDelta) {
+        self.subscribers.retain(|tx| tx.send(delta.clone()).is_ok());
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// What borg is busy with within the phase, if it reports it
+    pub fn stage(&self) -> Option<&Stage> {
+        self.stage.as_ref()
+    }
+
+    /// Files processed so far
+    pub fn nfiles(&self) -> u64 {
+        self.nfiles
+    }
+
+    pub fn original_size(&self) -> u64 {
+        self.original_size
+    }
+
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    /// Size of the data not already in the repository
+    pub fn deduplicated_size(&self) -> u64 {
+        self.deduplicated_size
+    }
+
+    /// File borg is currently working on, `None` until it reports progress
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn warnings(&self) -> u64 {
+        self.warnings
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Archives kept and pruned, as listed by `borg prune --list`
+    pub fn pruned(&self) -> PruneStats {
+        self.pruned
+    }
+
+    /// Files per kind, as listed by `borg create --list`
+    pub fn file_changes(&self) -> &FileChanges {
+        &self.file_changes
+    }
+
+    /// How fast borg reads the files, `None` until it reported progress twice
+    pub fn throughput(&self) -> Option<Throughput> {
+        Some(Throughput {
+            current: self.current_throughput()?,
+            average: rate(self.first_sample?, *self.samples.back()?)?,
+            peak: self.peak_throughput,
+        })
+    }
+}
+
+/// Bytes per second between two samples, `None` if no time passed
+fn rate((start, start_size): (SystemTime, u64), (end, end_size): (SystemTime, u64)) -> Option<f64> {
+    let secs = end.duration_since(start).ok()?.as_secs_f64();
+    (secs > 0.0).then(|| end_size.saturating_sub(start_size) as f64 / secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(nfiles: u64, original_size: u64, path: &str) -> Event {
+        Event::ArchiveProgress {
+            nfiles,
+            compressed_size: original_size / 2,
+            deduplicated_size: original_size / 4,
+            original_size,
+            path: path.into(),
+            time: None,
+        }
+    }
+
+    fn log(level: log::Level, message: &str) -> Event {
+        Event::LogMessage {
+            name: None,
+            level: Some(level),
+            message: message.to_owned(),
+            msgid: None,
+            time: None,
+        }
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut tracker = ProgressTracker::new();
+        assert_eq!(tracker.phase(), Phase::Waiting);
+        assert_eq!(tracker.path(), None);
+
+        tracker.update(&progress(1, 400, "/home/a"));
+        tracker.update(&log(log::Level::Warn, "file changed while we backed it up"));
+        tracker.update(&progress(3, 1000, "/home/b"));
+        tracker.update(&log(log::Level::Info, "ignored"));
+
+        assert_eq!(tracker.phase(), Phase::Creating);
+        assert_eq!(tracker.nfiles(), 3);
+        assert_eq!(tracker.original_size(), 1000);
+        assert_eq!(tracker.compressed_size(), 500);
+        assert_eq!(tracker.deduplicated_size(), 250);
+        assert_eq!(tracker.path(), Some(Path::new("/home/b")));
+        assert_eq!(tracker.warnings(), 1);
+        assert_eq!(tracker.errors(), 0);
+
+        tracker.update(&Event::FileStatus {
+            status: "M".to_owned(),
+            path: "/home/b".into(),
+        });
+        assert_eq!(
+            tracker.file_changes().get(crate::FileChangeKind::Modified),
+            1
+        );
+
+        tracker.begin(Phase::Pruning);
+        tracker.update(&Event::PruneDecision {
+            archive: "2024-05-01".to_owned(),
+            pruned: true,
+            rule: None,
+        });
+        tracker.update(&Event::Error("connection closed".into()));
+        assert_eq!(tracker.phase(), Phase::Pruning);
+        assert_eq!(tracker.pruned(), PruneStats { kept: 0, pruned: 1 });
+        assert_eq!(tracker.errors(), 1);
+    }
+
+    #[test]
+    fn test_throughput() {
+        let at = |secs, original_size| Event::ArchiveProgress {
+            nfiles: 1,
+            compressed_size: 0,
+            deduplicated_size: 0,
+            original_size,
+            path: "/home/a".into(),
+            time: Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)),
+        };
+        const MIB: u64 = 1024 * 1024;
+
+        let mut tracker = ProgressTracker::new();
+        tracker.update(&at(100, 0));
+        assert_eq!(tracker.throughput(), None);
+
+        // 10 MiB/s for 10s, then 40 MiB/s for 10s
+        for i in 1..=10 {
+            tracker.update(&at(100 + i, i * 10 * MIB));
+        }
+        for i in 1..=10 {
+            tracker.update(&at(110 + i, 100 * MIB + i * 40 * MIB));
+        }
+        let throughput = tracker.throughput().unwrap();
+        // The window only covers the last samples
+        assert_eq!(throughput.current, (40 * MIB) as f64);
+        assert_eq!(throughput.average, (25 * MIB) as f64);
+        assert_eq!(throughput.peak, (40 * MIB) as f64);
+
+        // A stall lowers the current throughput but not the peak
+        tracker.update(&at(140, 500 * MIB));
+        let throughput = tracker.throughput().unwrap();
+        assert!(throughput.current < throughput.peak);
+    }
+
+    #[test]
+    fn test_stage() {
+        let percent = |msgid: &str, finished| Event::ProgressPercent {
+            current: 34,
+            finished,
+            message: String::new(),
+            msgid: msgid.to_owned(),
+            operation: 1,
+            time: std::time::SystemTime::UNIX_EPOCH,
+            total: 100,
+        };
+        assert_eq!(
+            percent("cache.sync", false).stage(),
+            Some(Stage::SyncChunksCache)
+        );
+        assert_eq!(
+            percent("cache.new_thing", false).stage(),
+            Some(Stage::Other("cache.new_thing".to_owned()))
+        );
+        assert_eq!(Stage::SyncChunksCache.to_string(), "syncing chunks cache");
+        assert_eq!(
+            Stage::from("cache.new_thing").to_string(),
+            "cache.new_thing"
+        );
+        assert_eq!(progress(1, 400, "/home/a").stage(), None);
+
+        let mut tracker = ProgressTracker::new();
+        tracker.update(&percent("cache.sync", false));
+        assert_eq!(tracker.stage(), Some(&Stage::SyncChunksCache));
+        tracker.update(&progress(1, 400, "/home/a"));
+        assert_eq!(tracker.stage(), Some(&Stage::SyncChunksCache));
+        tracker.update(&percent("cache.sync", true));
+        assert_eq!(tracker.stage(), None);
+
+        tracker.update(&Event::ProgressMessage {
+            message: Some("Saving files cache".to_owned()),
+            finished: Some(false),
+            msgid: Some("cache.commit".to_owned()),
+            operation: Some(2),
+            time: None,
+        });
+        assert_eq!(tracker.stage(), Some(&Stage::SaveCache));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut tracker = ProgressTracker::new();
+        tracker.update(&progress(1, 400, "/home/a"));
+
+        let rx = tracker.subscribe();
+        tracker.update(&progress(3, 1000, "/home/b"));
+        tracker.begin(Phase::Checking);
+        tracker.begin(Phase::Checking);
+        tracker.update(&log(log::Level::Warn, "slow"));
+
+        let deltas: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            deltas,
+            [
+                ProgressDelta::Progress {
+                    nfiles: 2,
+                    original_size: 600,
+                    compressed_size: 300,
+                    deduplicated_size: 150,
+                    path: "/home/b".into(),
+                },
+                ProgressDelta::Phase(Phase::Checking),
+                ProgressDelta::Warning("slow".to_owned()),
+            ]
+        );
+
+        // Dropped receivers are forgotten
+        drop(rx);
+        tracker.begin(Phase::Done);
+        assert!(tracker.subscribers.is_empty());
+    }
+}
@@ -1,5 +1,10 @@
 use super::Passphrase;
-use std::{fmt::Display, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 /// A repository specifier
 ///
@@ -36,6 +41,16 @@ use std::{fmt::Display, path::PathBuf, str::FromStr};
 /// let using_file: Repo = "file:///path/to/repo".parse().unwrap();
 /// assert_eq!(using_file.to_string(), "/path/to/repo");
 ///
+/// let file_in_home: Repo = "file://~/path/to/repo".parse().unwrap();
+/// assert_eq!(file_in_home.to_string(), "~/path/to/repo");
+///
+/// let encoded: Repo = "file:///path/to/my%20repo".parse().unwrap();
+/// assert_eq!(encoded.to_string(), "/path/to/my repo");
+/// assert!("file:///path/to/my%+1repo".parse::<Repo>().is_err());
+///
+/// // Only local file URLs are supported
+/// assert!("file://host/path/to/repo".parse::<Repo>().is_err());
+///
 /// let remote_absolute: Repo = "ssh://user@host:22/path/to/repo".parse().unwrap();
 /// assert_eq!(remote_absolute.to_string(), "ssh://user@host:22/path/to/repo");
 ///
@@ -47,22 +62,32 @@ use std::{fmt::Display, path::PathBuf, str::FromStr};
 ///
 /// let old: Repo = "user@host:/path/to/repo".parse().unwrap();
 /// assert_eq!(old.to_string(), "ssh://user@host/path/to/repo");
+/// assert_eq!(old.location(), old.to_string());
+/// assert_eq!(old.host(), Some("host"));
+/// assert_eq!(old.path(), std::path::Path::new("/path/to/repo"));
 /// ```
 #[derive(Debug, Clone, Eq)]
 pub struct Repo {
     remote: Option<Remote>,
     pub(crate) path: PathBuf,
     pub(crate) passphrase: Option<Passphrase>,
+    pub(crate) env: BTreeMap<String, String>,
 }
 
 impl FromStr for Repo {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some(path) = s.strip_prefix("file://") {
+        if let Some(url) = s.strip_prefix("file://") {
+            let path = match url.split_once('/') {
+                Some(("" | "~", _)) => url,
+                None if url == "~" => url,
+                _ => return Err("Invalid repository specifier (file URL with a host)"),
+            };
             return Ok(Repo {
                 remote: None,
-                path: path.into(),
+                path: percent_decode(path)?.into(),
                 passphrase: None,
+                env: BTreeMap::new(),
             });
         }
 
@@ -76,12 +101,14 @@ impl FromStr for Repo {
                     remote: Some(remote),
                     path: PathBuf::from("/").join(path),
                     passphrase: None,
+                    env: BTreeMap::new(),
                 });
             }
             return Ok(Repo {
                 remote: Some(remote),
                 path: path.into(),
                 passphrase: None,
+                env: BTreeMap::new(),
             });
         }
 
@@ -96,6 +123,7 @@ impl FromStr for Repo {
                 remote: Some(remote),
                 path: path.into(),
                 passphrase: None,
+                env: BTreeMap::new(),
             });
         }
 
@@ -103,10 +131,35 @@ impl FromStr for Repo {
             remote: None,
             path: s.into(),
             passphrase: None,
+            env: BTreeMap::new(),
         })
     }
 }
 
+/// Decode `%XX` escapes of a URL path
+fn percent_decode(s: &str) -> Result<String, &'static str> {
+    const INVALID: &str = "Invalid repository specifier (Invalid percent-encoding)";
+
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = tail;
+            continue;
+        }
+        let hex = tail.get(..2).ok_or(INVALID)?;
+        // `from_str_radix` alone would also take a sign, e.g. "%+1"
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return Err(INVALID);
+        }
+        let hex = std::str::from_utf8(hex).map_err(|_| INVALID)?;
+        bytes.push(u8::from_str_radix(hex, 16).map_err(|_| INVALID)?);
+        rest = &tail[2..];
+    }
+    String::from_utf8(bytes).map_err(|_| INVALID)
+}
+
 // impl From<String> for Repo {
 //     fn from(s: String) -> Self {
 //         todo!()
@@ -126,6 +179,112 @@ impl Display for Repo {
     }
 }
 
+/// Serialized as its location only
+///
+/// The passphrase and the environment, e.g. `BORG_PASSPHRASE`, are secrets and mustn't end up
+/// in JSON output or a history. They are only read when deserializing.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Repo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Repo {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Location(String),
+            Full {
+                location: String,
+                #[serde(default)]
+                passphrase: Option<Passphrase>,
+                #[serde(default)]
+                env: BTreeMap<String, String>,
+            },
+        }
+
+        let (location, passphrase, env) = match Repr::deserialize(deserializer)? {
+            Repr::Location(location) => (location, None, BTreeMap::new()),
+            Repr::Full {
+                location,
+                passphrase,
+                env,
+            } => (location, passphrase, env),
+        };
+        let mut repo: Repo = location.parse().map_err(serde::de::Error::custom)?;
+        repo.passphrase = passphrase;
+        repo.env = env;
+        Ok(repo)
+    }
+}
+
+impl Repo {
+    /// Whether the repository is accessed over ssh
+    pub fn is_remote(&self) -> bool {
+        self.remote.is_some()
+    }
+
+    /// Host of a remote repository
+    pub fn host(&self) -> Option<&str> {
+        self.remote.as_ref().map(|r| r.host.as_str())
+    }
+
+    /// Path of the repository, on the remote host for remote repositories
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Set an environment variable for every borg command on this repository
+    ///
+    /// The passphrase takes precedence over variables like `BORG_PASSPHRASE`.
+    pub fn set_env(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.env.insert(key.into(), value.into());
+        self
+    }
+
+    /// Environment variables for borg, see [`Repo::set_env`]
+    pub fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    /// `user@host` or `host` and the port of a remote repository, as passed to ssh
+    pub(crate) fn ssh_destination(&self) -> Option<(String, Option<u16>)> {
+        let remote = self.remote.as_ref()?;
+        let destination = match &remote.user {
+            Some(user) => format!("{user}@{}", remote.host),
+            None => remote.host.to_owned(),
+        };
+        Some((destination, remote.port))
+    }
+
+    /// Location as written, the same as [`Display`]
+    pub fn location(&self) -> String {
+        self.to_string()
+    }
+
+    /// Location which is the same however the repository is written, e.g. `~/backup` and
+    /// `/home/seb/backup/`
+    pub(crate) fn canonical_location(&self) -> String {
+        match self.remote {
+            Some(_) => self.to_string().trim_end_matches('/').to_owned(),
+            None => crate::util::absolute_path(&self.path).display().to_string(),
+        }
+    }
+
+    /// Location as passed to borg, with `~` and `~user` expanded for local repositories
+    ///
+    /// Borg leaves a `~` in a local path alone, it would create a directory called `~`.
+    pub(crate) fn borg_location(&self) -> String {
+        if self.remote.is_some() {
+            return self.to_string();
+        }
+        crate::util::resolve_path(&self.path).display().to_string()
+    }
+}
+
 impl PartialEq for Repo {
     fn eq(&self, other: &Self) -> bool {
         self.path == other.path && self.remote == other.remote
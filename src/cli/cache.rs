@@ -0,0 +1,115 @@
+use super::*;
+use crate::{backend, Borg, Cache};
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show location and size of the cache
+    Info {
+        /// Id or repository of the backup, all backups if omitted
+        backup: Option<String>,
+    },
+    /// Delete the cache, it will be rebuilt by the next borg operation
+    Clear {
+        /// Id or repository of the backup, all backups if omitted
+        backup: Option<String>,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+pub fn cache(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let query = match &args.command {
+        Command::Info { backup } | Command::Clear { backup, .. } => backup.as_deref(),
+    };
+
+    let backups = match config.select(query) {
+        Ok(backups) => backups,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Several backups can share a repository and with it the cache
+    let mut repos: Vec<&crate::Repo> = vec![];
+    for backup in backups {
+        if !repos.contains(&&backup.repo) {
+            repos.push(&backup.repo);
+        }
+    }
+
+    let mut success = true;
+    for repo in repos {
+        let info = match borg.repo_info::<backend::borg::BorgWrapper>(repo) {
+            Ok(info) => info,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    style::error_stderr(format!("[{repo}] Failed to get repository info: {e}"))
+                );
+                success = false;
+                continue;
+            }
+        };
+        let path = &info.cache_path;
+
+        match &args.command {
+            Command::Info { .. } => {
+                let size = match Cache::disk_usage(path) {
+                    Ok(size) => fmt_bytes(size),
+                    Err(e) => format!("unknown ({e})"),
+                };
+                println!("[{repo}] {} ({size})", path.display());
+            }
+            Command::Clear { yes, .. } => {
+                if !path.exists() {
+                    println!("[{repo}] No cache at {}", path.display());
+                    continue;
+                }
+                if !yes {
+                    match confirm(
+                        borg.interaction,
+                        &format!("Delete cache {}?", path.display()),
+                    ) {
+                        Some(true) => {}
+                        Some(false) => continue,
+                        None => {
+                            let message = format!(
+                                "[{repo}] Not deleting {} without --yes in batch mode",
+                                path.display()
+                            );
+                            eprintln!("{}", style::error_stderr(message));
+                            success = false;
+                            continue;
+                        }
+                    }
+                }
+                match Cache::clear(path) {
+                    Ok(()) => println!("[{repo}] Deleted {}", path.display()),
+                    Err(e) => {
+                        eprintln!(
+                            "{}",
+                            style::error_stderr(format!("[{repo}] Failed to delete cache: {e}"))
+                        );
+                        success = false;
+                    }
+                }
+            }
+        }
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -1,8 +1,22 @@
-use std::{fmt::Display, num::NonZeroU8, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    num::{NonZeroU8, NonZeroUsize},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 
 use log::{debug, warn};
 
-use crate::{Archive, Compression, Passphrase, Repo};
+use super::util::{
+    fmt_humantime, fnv1a, parse_byte_size, parse_duration, slugify, InvalidDuration,
+};
+use super::Limits;
+use crate::backend::borg::shell_quote;
+use crate::{
+    Archive, ArchiveInfo, ByteSize, Cache, Compression, Encryption, MissingFile, NameContext,
+    NamePlaceholders, Passphrase, PlaceholderError, PrunePolicy, Repo, Verify,
+};
 
 #[derive(Debug)]
 pub enum ConfigError {
@@ -11,13 +25,26 @@ pub enum ConfigError {
         found: Option<&'static str>,
     },
     ValueError,
+    /// The value, which is neither seconds nor a humantime duration
+    InvalidDuration(String),
     MissingKey(&'static str),
     ExclusiveKeys(&'static str, &'static str),
     MissingTemplate(String),
+    UnknownBackup(String),
+    UndefinedVariable(String),
+    DuplicateId(String),
+    /// Two backups would create the same archive, with their ids and the archive
+    DuplicateArchive(String, String, String),
+    DependencyCycle(Vec<String>),
+    Placeholder(PlaceholderError),
     Keyed {
         key: String,
         err: Box<ConfigError>,
     },
+    /// The config file doesn't exist
+    NotFound,
+    /// The config has no backups, with its path
+    NoBackups(PathBuf),
     IOError(std::io::Error),
     ParseError(toml::de::Error),
     Other(&'static str),
@@ -59,11 +86,23 @@ impl Display for ConfigError {
                 found: Some(received),
             } => write!(f, "Invalid type: found {}", received),
             Self::ValueError => write!(f, "Invalid value"),
+            Self::InvalidDuration(value) => InvalidDuration(value.to_owned()).fmt(f),
             Self::MissingKey(key) => write!(f, "Missing key \"{}\"", key),
             Self::ExclusiveKeys(key, other_key) => {
                 write!(f, "{} and {} are exclusive", key, other_key)
             }
             Self::MissingTemplate(name) => write!(f, "Missing template \"{}\"", name),
+            Self::UnknownBackup(name) => write!(f, "No backup matches \"{}\"", name),
+            Self::UndefinedVariable(name) => write!(f, "Undefined variable \"{}\"", name),
+            Self::DuplicateId(id) => write!(f, "Several backups have the id \"{}\"", id),
+            Self::DuplicateArchive(a, b, archive) => write!(
+                f,
+                "Backups \"{a}\" and \"{b}\" would both create the archive {archive}, \
+                set archive_name or auto_disambiguate_names = true"
+            ),
+            Self::DependencyCycle(ids) => {
+                write!(f, "Backups depend on each other: {}", ids.join(" -> "))
+            }
             Self::Keyed { err, key } => {
                 let mut cur = err.to_owned();
                 let mut path = vec![key.to_owned()];
@@ -73,6 +112,18 @@ impl Display for ConfigError {
                 }
                 write!(f, "{cur} at {}", path.join("."))
             }
+            Self::Placeholder(err) => err.fmt(f),
+            Self::NotFound => write!(
+                f,
+                "The file doesn't exist, add a [[backup]] with a repository to it or import a \
+                borgmatic config with `borrg config import-borgmatic`"
+            ),
+            Self::NoBackups(path) => write!(
+                f,
+                "No backups in {}, add one with `borrg init LOCATION` or import a borgmatic \
+                config with `borrg config import-borgmatic`",
+                path.display()
+            ),
             Self::IOError(err) => err.fmt(f),
             Self::ParseError(err) => err.fmt(f),
             Self::Other(msg) => write!(f, "{}", msg),
@@ -161,6 +212,20 @@ impl Display for RepoConfig {
     }
 }
 
+/// The location a `repository` value of the config stands for, `None` if parts of it are
+/// left to a template
+pub(super) fn repository_location(value: &toml::Value) -> Option<String> {
+    match RepoConfig::parse(value).ok()? {
+        RepoConfig::Split { path: None, .. }
+        | RepoConfig::Split {
+            user: Some(_),
+            host: None,
+            ..
+        } => None,
+        repo => Some(repo.to_string()),
+    }
+}
+
 /// Configuration for a backup
 ///
 /// All fields are optional, because they can be inherited.
@@ -175,19 +240,103 @@ struct BackupConfig {
     /// Passphrase
     pub passphrase: Option<Passphrase>,
 
+    /// Remote shell borg reaches the repository through, as `BORG_RSH`
+    pub rsh: Option<String>,
+
     /// Paths to backup
     ///
     /// To inherit from a template, use `...` as path.
     pub paths: Vec<PathBuf>,
 
+    /// File listing more paths to back up, one per line
+    pub paths_from: Option<PathBuf>,
+
+    /// Directory borg runs in, the paths are stored relative to it
+    pub relative_to: Option<PathBuf>,
+
+    /// Creation time recorded instead of the current one
+    pub timestamp: Option<SystemTime>,
+
+    /// Name of the archives, with placeholders like `{hostname}`
+    pub archive_name: Option<String>,
+
+    /// Who expands the placeholders of `archive_name`
+    pub name_placeholders: Option<NamePlaceholders>,
+
     /// Compression level
     pub compression: Option<Compression>,
 
     /// Pattern file
     pub pattern_file: Option<PathBuf>,
 
-    /// Exclude file
+    /// Exclude file, [`DEFAULT_EXCLUDE_FILE`] if none is configured
     pub exclude_file: Option<PathBuf>,
+
+    /// Back up without a configured exclude file which doesn't exist
+    pub allow_missing_exclude_file: Option<bool>,
+
+    /// Names of files which exclude the directory containing them
+    pub exclude_if_present: Option<Vec<String>>,
+
+    /// Exclude directories containing a `.nobackup` file, but keep the file
+    pub respect_nobackup: Option<bool>,
+
+    /// Leave out paths which don't exist instead of failing
+    pub allow_missing_paths: Option<bool>,
+
+    /// Don't warn about paths inside other paths
+    pub allow_nested_paths: Option<bool>,
+
+    /// Verification after a successful backup
+    pub verify: Option<Verify>,
+
+    /// Encryption the repository has to use
+    pub require_encryption: Option<RequiredEncryption>,
+
+    /// Warn before running if borg on the host of a remote repository is another version
+    pub check_remote_version: Option<bool>,
+
+    /// Level borg logs at for this backup, instead of the one of the command
+    pub borg_log_level: Option<log::Level>,
+
+    /// Let borg log its exit code for this backup
+    pub show_rc: Option<bool>,
+
+    /// Space which has to be free on the filesystem of a local repository before running
+    pub min_free_space: Option<ByteSize>,
+
+    /// Encryption `borrg init` creates the repository with
+    pub encryption: Option<Encryption>,
+
+    /// `borrg init` creates an append-only repository
+    pub append_only: Option<bool>,
+
+    /// Storage quota `borrg init` sets on the repository
+    pub storage_quota: Option<ByteSize>,
+
+    /// `borrg init` creates the missing parent directories of the repository
+    pub make_parent_dirs: Option<bool>,
+
+    /// Which archives to keep when pruning
+    pub retention: Option<PrunePolicy>,
+
+    /// When to prune
+    pub prune: Option<Prune>,
+
+    /// Compact the repository after pruning
+    pub compact: Option<bool>,
+
+    /// When `borrg daemon` runs the backup
+    pub schedule: Option<Schedule>,
+
+    /// Archive series the backup creates instead of a single archive
+    pub series: Option<Vec<Series>>,
+
+    /// Extra environment variables for borg
+    pub env: BTreeMap<String, String>,
+
+    /// Backups which have to succeed first, not inherited
+    pub after: Vec<String>,
 }
 
 impl BackupConfig {
@@ -238,9 +387,17 @@ impl BackupConfig {
             self.passphrase = template.passphrase.to_owned();
         }
 
-        // Inherit path if empty otherwise replace "..." with paths from template
+        // Inherit remote shell
+        if self.rsh.is_none() {
+            self.rsh = template.rsh.to_owned();
+        }
+
+        // Inherit path if empty otherwise replace "..." with paths from template. Paths from a
+        // file are enough, the default "~" isn't added to them.
         if self.paths.is_empty() {
-            self.paths = template.paths.clone();
+            if self.paths_from.is_none() {
+                self.paths = template.paths.clone();
+            }
         } else {
             self.paths = self
                 .paths
@@ -255,6 +412,22 @@ impl BackupConfig {
                 .collect();
         }
 
+        if self.paths_from.is_none() {
+            self.paths_from = template.paths_from.to_owned();
+        }
+        if self.relative_to.is_none() {
+            self.relative_to = template.relative_to.to_owned();
+        }
+        if self.timestamp.is_none() {
+            self.timestamp = template.timestamp;
+        }
+        if self.archive_name.is_none() {
+            self.archive_name = template.archive_name.to_owned();
+        }
+        if self.name_placeholders.is_none() {
+            self.name_placeholders = template.name_placeholders;
+        }
+
         // Inherit compression
         if self.compression.is_none() {
             self.compression = template.compression.to_owned();
@@ -269,6 +442,92 @@ impl BackupConfig {
         if self.exclude_file.is_none() {
             self.exclude_file = template.exclude_file.to_owned();
         }
+
+        // Inherit exclusion markers
+        if self.exclude_if_present.is_none() {
+            self.exclude_if_present = template.exclude_if_present.to_owned();
+        }
+        if self.allow_missing_exclude_file.is_none() {
+            self.allow_missing_exclude_file = template.allow_missing_exclude_file;
+        }
+        if self.respect_nobackup.is_none() {
+            self.respect_nobackup = template.respect_nobackup;
+        }
+        if self.allow_missing_paths.is_none() {
+            self.allow_missing_paths = template.allow_missing_paths;
+        }
+        if self.allow_nested_paths.is_none() {
+            self.allow_nested_paths = template.allow_nested_paths;
+        }
+
+        // Inherit verification
+        if self.verify.is_none() {
+            self.verify = template.verify;
+        }
+        if self.require_encryption.is_none() {
+            self.require_encryption = template.require_encryption.to_owned();
+        }
+        if self.check_remote_version.is_none() {
+            self.check_remote_version = template.check_remote_version;
+        }
+        if self.borg_log_level.is_none() {
+            self.borg_log_level = template.borg_log_level;
+        }
+        if self.show_rc.is_none() {
+            self.show_rc = template.show_rc;
+        }
+        if self.min_free_space.is_none() {
+            self.min_free_space = template.min_free_space;
+        }
+        if self.encryption.is_none() {
+            self.encryption = template.encryption.to_owned();
+        }
+        if self.append_only.is_none() {
+            self.append_only = template.append_only;
+        }
+        if self.storage_quota.is_none() {
+            self.storage_quota = template.storage_quota;
+        }
+        if self.make_parent_dirs.is_none() {
+            self.make_parent_dirs = template.make_parent_dirs;
+        }
+
+        // Inherit retention policy
+        if self.retention.is_none() {
+            self.retention = template.retention.to_owned();
+        }
+
+        // Inherit prune mode
+        if self.prune.is_none() {
+            self.prune = template.prune;
+        }
+
+        // Inherit compaction
+        if self.compact.is_none() {
+            self.compact = template.compact;
+        }
+
+        // Inherit schedule
+        if self.schedule.is_none() {
+            self.schedule = template.schedule.to_owned();
+        }
+
+        // Inherit archive series
+        if self.series.is_none() {
+            self.series = template.series.to_owned();
+        }
+
+        // Inherit prerequisites
+        if self.after.is_empty() {
+            self.after = template.after.to_owned();
+        }
+
+        // Merge environment, variables of the backup win
+        for (key, value) in &template.env {
+            self.env
+                .entry(key.to_owned())
+                .or_insert_with(|| value.to_owned());
+        }
     }
 }
 
@@ -278,14 +537,209 @@ impl Default for BackupConfig {
             template: None,
             repo: None,
             passphrase: None,
+            rsh: None,
             paths: vec![PathBuf::from("~")],
+            paths_from: None,
+            relative_to: None,
+            timestamp: None,
+            archive_name: None,
+            name_placeholders: None,
             compression: None,
             pattern_file: None,
-            exclude_file: Some(PathBuf::from(".borgignore")),
+            exclude_file: None,
+            allow_missing_exclude_file: None,
+            exclude_if_present: None,
+            respect_nobackup: None,
+            allow_missing_paths: None,
+            allow_nested_paths: None,
+            verify: Some(Verify::None),
+            require_encryption: None,
+            check_remote_version: None,
+            borg_log_level: None,
+            show_rc: None,
+            min_free_space: None,
+            encryption: None,
+            append_only: None,
+            storage_quota: None,
+            make_parent_dirs: None,
+            retention: None,
+            prune: Some(Prune::Manual),
+            compact: Some(false),
+            schedule: None,
+            series: None,
+            env: BTreeMap::new(),
+            after: vec![],
+        }
+    }
+}
+
+/// Encryption the repository of a backup has to use
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RequiredEncryption {
+    #[default]
+    Any,
+    /// Any mode which encrypts the data, see [`Encryption::is_encrypted`]
+    Encrypted,
+    /// Exactly this mode, which also notices a repository swapped for another one
+    Mode(Encryption),
+}
+
+impl RequiredEncryption {
+    /// Why a repository using `actual` doesn't satisfy the requirement
+    pub fn check(&self, actual: &Encryption) -> Result<(), String> {
+        match self {
+            RequiredEncryption::Encrypted if !actual.is_encrypted() => Err(format!(
+                "The repository is not encrypted ({actual}), but require_encryption is set"
+            )),
+            RequiredEncryption::Mode(mode) if mode != actual => Err(format!(
+                "The repository uses encryption {actual}, but require_encryption is {mode}"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Settings of `borrg init` for the repository of a backup, `None` where the config has none
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InitOptions {
+    pub encryption: Option<Encryption>,
+    pub append_only: Option<bool>,
+    pub storage_quota: Option<ByteSize>,
+    pub make_parent_dirs: Option<bool>,
+}
+
+/// The name of a mode, e.g. "repokey-blake2"
+impl ConfigProperty for Encryption {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::String(s) => s.parse().map_err(|_| ConfigError::ValueError),
+            _ => Err(ConfigError::TypeError {
+                expected: Some("string"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+/// `true` for any encrypting mode, `false` for none, or the name of a mode
+impl ConfigProperty for RequiredEncryption {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::Boolean(true) => Ok(RequiredEncryption::Encrypted),
+            toml::Value::Boolean(false) => Ok(RequiredEncryption::Any),
+            toml::Value::String(s) => s
+                .parse()
+                .map(RequiredEncryption::Mode)
+                .map_err(|_| ConfigError::ValueError),
+            _ => Err(ConfigError::TypeError {
+                expected: Some("boolean or string"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+/// When to prune a repository
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Prune {
+    /// Only when explicitly requested
+    #[default]
+    Manual,
+    /// After every successful backup
+    Auto,
+}
+
+impl ConfigProperty for Prune {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::String(s) => match s.to_lowercase().as_str() {
+                "manual" => Ok(Prune::Manual),
+                "auto" => Ok(Prune::Auto),
+                _ => Err(ConfigError::ValueError),
+            },
+            _ => Err(ConfigError::TypeError {
+                expected: Some("string"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+impl ConfigProperty for PrunePolicy {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        let map = value.as_table().ok_or(ConfigError::TypeError {
+            expected: Some("table"),
+            found: Some(value.type_str()),
+        })?;
+
+        if let Some(key) = map.keys().find(|k| {
+            ![
+                "keep_within",
+                "keep_last",
+                "keep_hourly",
+                "keep_daily",
+                "keep_weekly",
+                "keep_monthly",
+                "keep_yearly",
+            ]
+            .contains(&k.as_str())
+        }) {
+            return Err(ConfigError::Other("Unknown retention rule").at_key(key));
+        }
+
+        let policy = PrunePolicy {
+            keep_within: ConfigProperty::from_map(map, "keep_within")?,
+            keep_last: ConfigProperty::from_map(map, "keep_last")?,
+            keep_hourly: ConfigProperty::from_map(map, "keep_hourly")?,
+            keep_daily: ConfigProperty::from_map(map, "keep_daily")?,
+            keep_weekly: ConfigProperty::from_map(map, "keep_weekly")?,
+            keep_monthly: ConfigProperty::from_map(map, "keep_monthly")?,
+            keep_yearly: ConfigProperty::from_map(map, "keep_yearly")?,
+            glob_archives: None,
+        };
+
+        if policy.is_empty() {
+            return Err(ConfigError::Other("Retention policy keeps nothing"));
+        }
+
+        Ok(policy)
+    }
+}
+
+impl ConfigProperty for Verify {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::String(s) => match s.to_lowercase().as_str() {
+                "none" => Ok(Verify::None),
+                "archive" => Ok(Verify::Archive),
+                "repository" => Ok(Verify::Repository),
+                _ => Err(ConfigError::ValueError),
+            },
+            _ => Err(ConfigError::TypeError {
+                expected: Some("string"),
+                found: Some(value.type_str()),
+            }),
         }
     }
 }
 
+impl ConfigProperty for Limits {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        let hosts: Vec<(String, u32)> = ConfigProperty::parse(value)?;
+        let hosts = hosts
+            .into_iter()
+            .map(|(host, max)| match NonZeroUsize::new(max as usize) {
+                Some(max) => Ok((host, max)),
+                None => Err(ConfigError::ValueError.at_key(host)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Limits {
+            hosts,
+            ..Limits::default()
+        })
+    }
+}
+
 impl ConfigProperty for Compression {
     fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
         use toml::Value::*;
@@ -392,6 +846,152 @@ impl ConfigProperty for Compression {
     }
 }
 
+/// When `borrg daemon` runs a backup
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Cron expression, the seconds field is optional
+    #[cfg(feature = "daemon")]
+    Cron(Box<cron::Schedule>),
+    /// Fixed interval between runs
+    Every(std::time::Duration),
+}
+
+/// The cron expression, or the interval like "every 6h"
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "daemon")]
+            Schedule::Cron(schedule) => schedule.fmt(f),
+            Schedule::Every(interval) => write!(f, "every {}", fmt_humantime(*interval)),
+        }
+    }
+}
+
+impl Schedule {
+    /// The first time the backup is due after `last`
+    pub fn next_after(
+        &self,
+        last: chrono::DateTime<chrono::Local>,
+    ) -> Option<chrono::DateTime<chrono::Local>> {
+        match self {
+            #[cfg(feature = "daemon")]
+            Schedule::Cron(schedule) => schedule.after(&last).next(),
+            Schedule::Every(interval) => Some(last + chrono::Duration::from_std(*interval).ok()?),
+        }
+    }
+
+    fn from_map(map: &toml::map::Map<String, toml::Value>) -> Result<Option<Self>, ConfigError> {
+        let cron: Option<String> = ConfigProperty::from_map(map, "schedule")?;
+        let every: Option<Duration> = ConfigProperty::from_map(map, "every")?;
+
+        match (cron, every) {
+            (Some(_), Some(_)) => Err(ConfigError::ExclusiveKeys("schedule", "every")),
+            #[cfg(feature = "daemon")]
+            (Some(cron), None) => {
+                // Standard cron expressions don't have a seconds field
+                let cron = match cron.split_whitespace().count() {
+                    5 => format!("0 {cron}"),
+                    _ => cron,
+                };
+                cron.parse()
+                    .map(|s| Some(Schedule::Cron(Box::new(s))))
+                    .map_err(|_| ConfigError::ValueError.at_key("schedule"))
+            }
+            #[cfg(not(feature = "daemon"))]
+            (Some(_), None) => Err(ConfigError::Other(
+                "borrg was built without the daemon feature",
+            )
+            .at_key("schedule")),
+            (None, Some(every)) if every.is_zero() => Err(ConfigError::ValueError.at_key("every")),
+            (None, Some(every)) => Ok(Some(Schedule::Every(every))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// A series of archives a backup creates, e.g. daily and monthly ones, from a
+/// `[[backup.series]]` table
+///
+/// The archives of a series are told apart by the fixed start of their names, see
+/// [`Series::prefix`].
+#[derive(Debug, Clone)]
+pub struct Series {
+    /// Template of the archive names, e.g. "monthly-{now:%Y-%m}"
+    pub name: String,
+    /// How often the series gets an archive, otherwise whenever the name is a new one
+    pub schedule: Option<Schedule>,
+    /// Which archives of the series to keep, the retention of the backup if unset
+    pub retention: Option<PrunePolicy>,
+}
+
+impl Series {
+    /// The start of the archive names up to the first placeholder, e.g. "monthly-"
+    pub fn prefix(&self) -> &str {
+        match self.name.find('{') {
+            Some(end) => &self.name[..end],
+            None => &self.name,
+        }
+    }
+}
+
+impl ConfigProperty for Series {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        let map = value.as_table().ok_or(ConfigError::TypeError {
+            expected: Some("table"),
+            found: Some(value.type_str()),
+        })?;
+
+        if let Some(key) = map
+            .keys()
+            .find(|k| !["name", "schedule", "every", "retention"].contains(&k.as_str()))
+        {
+            return Err(ConfigError::Other("Unknown series key").at_key(key));
+        }
+
+        Ok(Series {
+            name: ConfigProperty::from_map(map, "name")?.ok_or(ConfigError::MissingKey("name"))?,
+            schedule: Schedule::from_map(map)?,
+            retention: ConfigProperty::from_map(map, "retention")?,
+        })
+    }
+}
+
+/// Make sure the archives of every series can be told apart from the others
+fn check_series(series: &[Series], placeholders: NamePlaceholders) -> Result<(), ConfigError> {
+    for (idx, s) in series.iter().enumerate() {
+        placeholders
+            .validate(&s.name)
+            .map_err(|e| ConfigError::Placeholder(e).at_key("name"))?;
+        let prefix = s.prefix();
+        if prefix.is_empty() {
+            let err = ConfigError::Other("The name has to start with text, not a placeholder");
+            return Err(err.at_key("name"));
+        }
+        // Pruning one series would consider the archives of the other
+        if series[..idx]
+            .iter()
+            .any(|other| other.prefix().starts_with(prefix) || prefix.starts_with(other.prefix()))
+        {
+            let err = ConfigError::Other("The name starts like the one of another series");
+            return Err(err.at_key("name"));
+        }
+    }
+    Ok(())
+}
+
+/// `s` as a pattern of `--glob-archives` which only matches itself
+fn escape_glob(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '*' | '?' | '[' | ']' => format!("[{c}]"),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Timers started a little early still find the series of an interval due
+const SERIES_SLACK: Duration = Duration::from_secs(5 * 60);
+
 impl TryFrom<&BackupConfig> for Repo {
     type Error = ConfigError;
     fn try_from(config: &BackupConfig) -> Result<Self, Self::Error> {
@@ -404,45 +1004,166 @@ impl TryFrom<&BackupConfig> for Repo {
         let mut repo = repository.parse::<Repo>().map_err(ConfigError::Other)?;
 
         repo.passphrase = config.passphrase.to_owned();
+        repo.env = config.env.to_owned();
+        if let Some(rsh) = &config.rsh {
+            if config.env.contains_key("BORG_RSH") {
+                return Err(ConfigError::ExclusiveKeys("rsh", "env.BORG_RSH"));
+            }
+            repo.set_env("BORG_RSH", rsh);
+        }
 
         Ok(repo)
     }
 }
 
+/// Exclude file used if it exists, relative to the first path
+const DEFAULT_EXCLUDE_FILE: &str = ".borgignore";
+
+/// Marker file of `respect_nobackup`
+const NOBACKUP: &str = ".nobackup";
+
 impl TryFrom<&BackupConfig> for Archive {
     type Error = ConfigError;
     fn try_from(config: &BackupConfig) -> Result<Self, Self::Error> {
-        let name = chrono::Local::now().format("%Y-%m-%d").to_string();
+        let placeholders = config.name_placeholders.unwrap_or_default();
+        if let Some(template) = &config.archive_name {
+            placeholders
+                .validate(template)
+                .map_err(|e| ConfigError::Placeholder(e).at_key("archive_name"))?;
+        }
+        let time = config.timestamp.unwrap_or_else(SystemTime::now);
+        let name = name_at(config.archive_name.as_deref(), placeholders, time);
 
-        let paths = if config.paths.is_empty() {
+        // The paths from `paths_from` are added when the backup runs
+        let paths = if config.paths.is_empty() && config.paths_from.is_none() {
             return Err(ConfigError::MissingKey("path"));
         } else {
             config.paths.clone()
         };
 
+        // Borg gets the paths relative to `relative_to`, absolute ones have to be inside of it
+        let relative_to = config.relative_to.as_deref().map(crate::util::resolve_path);
+        if let Some(dir) = &relative_to {
+            if dir.is_relative() {
+                let err = ConfigError::Other("Not an absolute path");
+                return Err(err.at_key("relative_to"));
+            }
+            if paths
+                .iter()
+                .map(crate::util::resolve_path)
+                .any(|path| path.is_absolute() && !path.starts_with(dir))
+            {
+                let err = ConfigError::Other("Absolute path outside of relative_to");
+                return Err(err.at_key("path"));
+            }
+        }
+
         let compression = config.compression.to_owned();
         let pattern_file = config.pattern_file.to_owned();
-        let exclude_file = config.exclude_file.to_owned();
+        let (exclude_file, missing_exclude_file) = match &config.exclude_file {
+            Some(file) if config.allow_missing_exclude_file == Some(true) => {
+                (file.to_owned(), MissingFile::Warn)
+            }
+            Some(file) => (file.to_owned(), MissingFile::Error),
+            None => (PathBuf::from(DEFAULT_EXCLUDE_FILE), MissingFile::Ignore),
+        };
+
+        let mut exclude_if_present = config.exclude_if_present.clone().unwrap_or_default();
+        let keep_exclude_tags = config.respect_nobackup.unwrap_or_default();
+        if keep_exclude_tags && !exclude_if_present.iter().any(|n| n == NOBACKUP) {
+            exclude_if_present.push(NOBACKUP.to_owned());
+        }
 
         Ok(Self {
             name,
             paths,
             compression,
             pattern_file,
-            exclude_file,
+            exclude_file: Some(exclude_file),
+            missing_exclude_file,
             comment: None,
+            exclude_if_present,
+            keep_exclude_tags,
+            relative_to: config.relative_to.to_owned(),
+            timestamp: config.timestamp,
         })
     }
 }
 
-impl TryFrom<BackupConfig> for (Repo, Archive) {
+/// Name of an archive created at `time`, its local date
+pub(super) fn archive_name(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Name passed to borg for an archive created at `time`, [`archive_name`] without a template
+fn name_at(template: Option<&str>, placeholders: NamePlaceholders, time: SystemTime) -> String {
+    match template {
+        // Validated when the config was loaded
+        Some(template) => placeholders
+            .name_for_borg(template, &NameContext::at(time))
+            .unwrap_or_else(|_| template.to_owned()),
+        None => archive_name(time),
+    }
+}
+
+impl ConfigProperty for NamePlaceholders {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::String(s) => match s.to_lowercase().as_str() {
+                "borrg" => Ok(NamePlaceholders::Borrg),
+                "borg" => Ok(NamePlaceholders::Borg),
+                _ => Err(ConfigError::ValueError),
+            },
+            _ => Err(ConfigError::TypeError {
+                expected: Some("string"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+impl TryFrom<BackupConfig> for Backup {
     type Error = ConfigError;
     fn try_from(config: BackupConfig) -> Result<Self, ConfigError> {
-        Ok((Repo::try_from(&config)?, Archive::try_from(&config)?))
+        let series = config.series.clone().unwrap_or_default();
+        check_series(&series, config.name_placeholders.unwrap_or_default())
+            .map_err(at_key("series"))?;
+        Ok(Backup {
+            name: None,
+            id: String::new(),
+            repo: Repo::try_from(&config)?,
+            archive: Archive::try_from(&config)?,
+            verify: config.verify.unwrap_or_default(),
+            require_encryption: config.require_encryption.unwrap_or_default(),
+            check_remote_version: config.check_remote_version.unwrap_or_default(),
+            borg_log_level: config.borg_log_level,
+            show_rc: config.show_rc.unwrap_or_default(),
+            min_free_space: config.min_free_space,
+            init: InitOptions {
+                encryption: config.encryption,
+                append_only: config.append_only,
+                storage_quota: config.storage_quota,
+                make_parent_dirs: config.make_parent_dirs,
+            },
+            retention: config.retention,
+            prune: config.prune.unwrap_or_default(),
+            compact: config.compact.unwrap_or_default(),
+            schedule: config.schedule,
+            series,
+            after: config.after,
+            allow_missing_paths: config.allow_missing_paths.unwrap_or_default(),
+            allow_nested_paths: config.allow_nested_paths.unwrap_or_default(),
+            paths_from: config.paths_from,
+            name_template: config.archive_name,
+            name_placeholders: config.name_placeholders.unwrap_or_default(),
+            name_suffix: None,
+        })
     }
 }
 
-trait ConfigProperty
+pub(super) trait ConfigProperty
 where
     Self: Sized,
 {
@@ -471,65 +1192,158 @@ impl ConfigProperty for String {
     }
 }
 
-impl ConfigProperty for PathBuf {
+impl ConfigProperty for bool {
     fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
         match value {
-            toml::Value::String(s) => Ok(PathBuf::from(s)),
+            toml::Value::Boolean(b) => Ok(*b),
             _ => Err(ConfigError::TypeError {
-                expected: Some("string"),
+                expected: Some("boolean"),
                 found: Some(value.type_str()),
             }),
         }
     }
 }
 
-impl ConfigProperty for RepoConfig {
+impl ConfigProperty for u32 {
     fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
         match value {
-            toml::Value::String(s) => Ok(RepoConfig::Combined(s.to_owned())),
-            toml::Value::Table(t) => {
-                let user: Option<String> = ConfigProperty::from_map(t, "user")?;
-                let host: Option<String> = ConfigProperty::from_map(t, "host")?;
-                let path: Option<PathBuf> = ConfigProperty::from_map(t, "path")?;
-
-                Ok(RepoConfig::Split { user, host, path })
-            }
+            toml::Value::Integer(i) => u32::try_from(*i).map_err(|_| ConfigError::ValueError),
             _ => Err(ConfigError::TypeError {
-                expected: Some("string or table"),
+                expected: Some("integer"),
                 found: Some(value.type_str()),
             }),
         }
     }
 }
 
-impl<T> ConfigProperty for Vec<T>
-where
-    T: ConfigProperty,
-{
+/// "error", "warning", "info" or "debug", like the options of borg
+impl ConfigProperty for log::Level {
     fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
-        if let Ok(val) = T::parse(value) {
-            return Ok(vec![val]);
-        }
         match value {
-            toml::Value::Array(a) => a.iter().map(T::parse).collect(),
+            toml::Value::String(s) if s == "warning" => Ok(log::Level::Warn),
+            toml::Value::String(s) => s.parse().map_err(|_| ConfigError::ValueError),
             _ => Err(ConfigError::TypeError {
-                expected: Some("array"),
+                expected: Some("string"),
                 found: Some(value.type_str()),
             }),
         }
     }
 }
 
-impl<T> ConfigProperty for Vec<(String, T)>
-where
-    T: ConfigProperty,
-{
+/// Either seconds or a human-readable duration like "90s", "15m", "1h30m" or "2d"
+impl ConfigProperty for Duration {
     fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
         match value {
-            toml::Value::Table(t) => t
-                .iter()
-                .map(|(k, v)| Ok((k.to_owned(), T::parse(v)?)))
-                .collect(),
+            toml::Value::Integer(i) => u64::try_from(*i)
+                .map(Duration::from_secs)
+                .map_err(|_| ConfigError::InvalidDuration(i.to_string())),
+            toml::Value::String(s) => {
+                parse_duration(s).map_err(|e| ConfigError::InvalidDuration(e.0))
+            }
+            _ => Err(ConfigError::TypeError {
+                expected: Some("integer or string"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+/// Either bytes or a size with a suffix like "5G", see [`parse_byte_size`]
+impl ConfigProperty for ByteSize {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::Integer(i) => u64::try_from(*i)
+                .map(ByteSize)
+                .map_err(|_| ConfigError::ValueError),
+            toml::Value::String(s) => parse_byte_size(s)
+                .map(ByteSize)
+                .map_err(|_| ConfigError::ValueError),
+            _ => Err(ConfigError::TypeError {
+                expected: Some("integer or string"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+/// An RFC 3339 string or a TOML date-time with an offset, e.g. `2024-05-01T03:00:00+02:00`
+impl ConfigProperty for SystemTime {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        let s = match value {
+            toml::Value::String(s) => s.to_owned(),
+            toml::Value::Datetime(d) => d.to_string(),
+            _ => {
+                return Err(ConfigError::TypeError {
+                    expected: Some("date-time"),
+                    found: Some(value.type_str()),
+                })
+            }
+        };
+        chrono::DateTime::parse_from_rfc3339(&s)
+            .map(Into::into)
+            .map_err(|_| ConfigError::ValueError)
+    }
+}
+
+impl ConfigProperty for PathBuf {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::String(s) => Ok(PathBuf::from(s)),
+            _ => Err(ConfigError::TypeError {
+                expected: Some("string"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+impl ConfigProperty for RepoConfig {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::String(s) => Ok(RepoConfig::Combined(s.to_owned())),
+            toml::Value::Table(t) => {
+                let user: Option<String> = ConfigProperty::from_map(t, "user")?;
+                let host: Option<String> = ConfigProperty::from_map(t, "host")?;
+                let path: Option<PathBuf> = ConfigProperty::from_map(t, "path")?;
+
+                Ok(RepoConfig::Split { user, host, path })
+            }
+            _ => Err(ConfigError::TypeError {
+                expected: Some("string or table"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+impl<T> ConfigProperty for Vec<T>
+where
+    T: ConfigProperty,
+{
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        if let Ok(val) = T::parse(value) {
+            return Ok(vec![val]);
+        }
+        match value {
+            toml::Value::Array(a) => a.iter().map(T::parse).collect(),
+            _ => Err(ConfigError::TypeError {
+                expected: Some("array"),
+                found: Some(value.type_str()),
+            }),
+        }
+    }
+}
+
+impl<T> ConfigProperty for Vec<(String, T)>
+where
+    T: ConfigProperty,
+{
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        match value {
+            toml::Value::Table(t) => t
+                .iter()
+                .map(|(k, v)| Ok((k.to_owned(), T::parse(v)?)))
+                .collect(),
             _ => Err(ConfigError::TypeError {
                 expected: Some("table"),
                 found: Some(value.type_str()),
@@ -553,36 +1367,230 @@ impl ConfigProperty for BackupConfig {
         let repo: Option<RepoConfig> = ConfigProperty::from_map(map, "repository")?;
 
         let passphrase = match (map.get("passphrase"), map.get("passcommand")) {
+            (Some(T::Table(t)), None) => match (t.get("env"), t.len()) {
+                (Some(T::String(var)), 1) => Some(Passphrase::Environment(var.to_owned())),
+                _ => return Err(ConfigError::ValueError.at_key("passphrase")),
+            },
             (Some(T::String(p)), None) => Some(Passphrase::Passphrase(p.to_owned())),
+            #[cfg(unix)]
             (Some(T::Integer(fd)), None) => Some(Passphrase::FileDescriptor(fd.to_owned() as i32)),
+            #[cfg(not(unix))]
+            (Some(T::Integer(_)), None) => {
+                return Err(ConfigError::Other(
+                    "Passing the passphrase by file descriptor is only supported on Unix",
+                )
+                .at_key("passphrase"))
+            }
             (None, Some(T::String(cmd))) => Some(Passphrase::Command(cmd.to_owned())),
+            (None, Some(cmd @ T::Array(_))) => {
+                let cmd: Vec<String> = ConfigProperty::parse(cmd).map_err(at_key("passcommand"))?;
+                if cmd.is_empty() {
+                    return Err(ConfigError::ValueError.at_key("passcommand"));
+                }
+                Some(Passphrase::CommandArgs(cmd))
+            }
             (Some(_), Some(_)) => {
                 return Err(ConfigError::ExclusiveKeys("passphrase", "passcommand"))
             }
             _ => None,
         };
 
+        let rsh = parse_rsh(map).map_err(at_key("rsh"))?;
+
         let paths: Vec<PathBuf> = ConfigProperty::from_map(map, "path")?.unwrap_or_default();
 
+        let paths_from: Option<PathBuf> = ConfigProperty::from_map(map, "paths_from")?;
+
+        let relative_to: Option<PathBuf> = ConfigProperty::from_map(map, "relative_to")?;
+
+        let timestamp: Option<SystemTime> = ConfigProperty::from_map(map, "timestamp")?;
+
+        let archive_name: Option<String> = ConfigProperty::from_map(map, "archive_name")?;
+
+        let name_placeholders: Option<NamePlaceholders> =
+            ConfigProperty::from_map(map, "name_placeholders")?;
+
         let compression: Option<Compression> = ConfigProperty::from_map(map, "compression")?;
 
         let pattern_file: Option<PathBuf> = ConfigProperty::from_map(map, "pattern_file")?;
 
         let exclude_file: Option<PathBuf> = ConfigProperty::from_map(map, "exclude_file")?;
 
+        let allow_missing_exclude_file: Option<bool> =
+            ConfigProperty::from_map(map, "allow_missing_exclude_file")?;
+
+        let exclude_if_present: Option<Vec<String>> =
+            ConfigProperty::from_map(map, "exclude_if_present")?;
+
+        let respect_nobackup: Option<bool> = ConfigProperty::from_map(map, "respect_nobackup")?;
+
+        let allow_missing_paths: Option<bool> =
+            ConfigProperty::from_map(map, "allow_missing_paths")?;
+
+        let allow_nested_paths: Option<bool> = ConfigProperty::from_map(map, "allow_nested_paths")?;
+
+        let verify: Option<Verify> = ConfigProperty::from_map(map, "verify")?;
+
+        let require_encryption: Option<RequiredEncryption> =
+            ConfigProperty::from_map(map, "require_encryption")?;
+
+        let check_remote_version: Option<bool> =
+            ConfigProperty::from_map(map, "check_remote_version")?;
+
+        let borg_log_level: Option<log::Level> = ConfigProperty::from_map(map, "borg_log_level")?;
+
+        let show_rc: Option<bool> = ConfigProperty::from_map(map, "show_rc")?;
+
+        let min_free_space: Option<ByteSize> = ConfigProperty::from_map(map, "min_free_space")?;
+
+        let encryption: Option<Encryption> = ConfigProperty::from_map(map, "encryption")?;
+
+        let append_only: Option<bool> = ConfigProperty::from_map(map, "append_only")?;
+
+        let storage_quota: Option<ByteSize> = ConfigProperty::from_map(map, "storage_quota")?;
+
+        let make_parent_dirs: Option<bool> = ConfigProperty::from_map(map, "make_parent_dirs")?;
+
+        let retention: Option<PrunePolicy> = ConfigProperty::from_map(map, "retention")?;
+
+        let prune: Option<Prune> = ConfigProperty::from_map(map, "prune")?;
+
+        let compact: Option<bool> = ConfigProperty::from_map(map, "compact")?;
+
+        let schedule = Schedule::from_map(map)?;
+
+        let series: Option<Vec<Series>> = ConfigProperty::from_map(map, "series")?;
+
+        let env = parse_env(map).map_err(at_key("env"))?;
+
+        let after: Vec<String> = ConfigProperty::from_map(map, "after")?.unwrap_or_default();
+
         Ok(Self {
             template: Some(template),
             repo,
             passphrase,
+            rsh,
             paths,
+            paths_from,
+            relative_to,
+            timestamp,
+            archive_name,
+            name_placeholders,
             compression,
             pattern_file,
             exclude_file,
+            allow_missing_exclude_file,
+            exclude_if_present,
+            respect_nobackup,
+            allow_missing_paths,
+            allow_nested_paths,
+            verify,
+            require_encryption,
+            check_remote_version,
+            borg_log_level,
+            show_rc,
+            min_free_space,
+            encryption,
+            append_only,
+            storage_quota,
+            make_parent_dirs,
+            retention,
+            prune,
+            compact,
+            schedule,
+            series,
+            env,
+            after,
+        })
+    }
+}
+
+/// Variables borrg sets itself, from the passphrase and repository
+const RESERVED_ENV: &[&str] = &[
+    "BORG_PASSPHRASE",
+    "BORG_PASSCOMMAND",
+    "BORG_PASSPHRASE_FD",
+    "BORG_NEW_PASSPHRASE",
+    "BORG_REPO",
+    "BORG_EXIT_CODES",
+];
+
+/// The `env` table of a backup, with `${VAR}` in values replaced
+fn parse_env(
+    map: &toml::map::Map<String, toml::Value>,
+) -> Result<BTreeMap<String, String>, ConfigError> {
+    let Some(value) = map.get("env") else {
+        return Ok(BTreeMap::new());
+    };
+    let vars: Vec<(String, String)> = ConfigProperty::parse(value)?;
+
+    vars.into_iter()
+        .map(|(key, value)| {
+            if RESERVED_ENV.contains(&key.as_str()) {
+                let err = ConfigError::Other("Reserved, use passphrase or passcommand instead");
+                return Err(err.at_key(key));
+            }
+            let value = interpolate(&value).map_err(at_key(&key))?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// `rsh` of a backup as `BORG_RSH`, either a command line or its words
+///
+/// Words are quoted so borg splits them the same way, e.g. a path with spaces stays one word.
+/// `${VAR}` is replaced like in `env`.
+fn parse_rsh(map: &toml::map::Map<String, toml::Value>) -> Result<Option<String>, ConfigError> {
+    let Some(value) = map.get("rsh") else {
+        return Ok(None);
+    };
+    if let toml::Value::String(command) = value {
+        return interpolate(command).map(Some);
+    }
+    let words: Vec<String> = ConfigProperty::parse(value)?;
+    if words.is_empty() {
+        return Err(ConfigError::ValueError);
+    }
+    let words = words
+        .iter()
+        .map(|word| Ok(shell_quote(&interpolate(word)?).into_owned()))
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    Ok(Some(words.join(" ")))
+}
+
+/// Replace `${VAR}` with the value of the environment variable `VAR`
+fn interpolate(value: &str) -> Result<String, ConfigError> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let (name, after) = rest[start + 2..]
+            .split_once('}')
+            .ok_or(ConfigError::Other("Unterminated \"${\""))?;
+        let var =
+            std::env::var(name).map_err(|_| ConfigError::UndefinedVariable(name.to_owned()))?;
+        result.push_str(&var);
+        rest = after;
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+impl ConfigProperty for Cache {
+    fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
+        let map = value.as_table().ok_or(ConfigError::TypeError {
+            expected: Some("table"),
+            found: Some(value.type_str()),
+        })?;
+
+        Ok(Cache {
+            dir: ConfigProperty::from_map(map, "dir")?,
+            files_ttl: ConfigProperty::from_map(map, "files_ttl")?,
         })
     }
 }
 
-impl ConfigProperty for Vec<(Repo, Archive)> {
+impl ConfigProperty for Vec<Backup> {
     fn parse(value: &toml::Value) -> Result<Self, ConfigError> {
         let map = value.as_table().ok_or(ConfigError::TypeError {
             expected: Some("table"),
@@ -609,40 +1617,478 @@ impl ConfigProperty for Vec<(Repo, Archive)> {
             templates.push(("default".to_string(), BackupConfig::default()));
         }
 
-        let backups: Vec<BackupConfig> =
-            ConfigProperty::from_map(map, "backup")?.unwrap_or_default();
+        let backups = parse_backups(map)?;
 
         debug!("Parsed templates: {:#?}", templates);
         debug!("Parsed backups: {:#?}", backups);
 
-        backups
+        let mut backups = backups
+            .into_iter()
+            .map(|(name, c)| {
+                let mut backup: Backup = c.resolve(&templates)?.try_into()?;
+                backup.name = name;
+                Ok(backup)
+            })
+            .collect::<Result<Vec<_>, ConfigError>>()?;
+        assign_ids(&mut backups)?;
+        let disambiguate: Option<bool> = ConfigProperty::from_map(map, "auto_disambiguate_names")?;
+        check_archive_names(&mut backups, disambiguate.unwrap_or_default())?;
+        resolve_dependencies(&mut backups).map_err(at_key("backup"))?;
+        Ok(backups)
+    }
+}
+
+/// Keys of a backup which may be tables themselves
+const TABLE_KEYS: &[&str] = &[
+    "repository",
+    "passphrase",
+    "compression",
+    "retention",
+    "env",
+];
+
+/// The `[[backup]]` array, or the `[backup.NAME]` tables with their names
+fn parse_backups(
+    map: &toml::map::Map<String, toml::Value>,
+) -> Result<Vec<(Option<String>, BackupConfig)>, ConfigError> {
+    match map.get("backup") {
+        Some(toml::Value::Table(t))
+            if t.iter()
+                .all(|(k, v)| v.is_table() && !TABLE_KEYS.contains(&k.as_str())) =>
+        {
+            t.iter()
+                .map(|(name, value)| {
+                    let config = BackupConfig::parse(value).map_err(at_key(name))?;
+                    Ok((Some(name.to_owned()), config))
+                })
+                .collect::<Result<_, _>>()
+                .map_err(at_key("backup"))
+        }
+        _ => {
+            let backups: Vec<BackupConfig> =
+                ConfigProperty::from_map(map, "backup")?.unwrap_or_default();
+            Ok(backups.into_iter().map(|c| (None, c)).collect())
+        }
+    }
+}
+
+/// Give every backup its id, see [`Backup::id`]
+fn assign_ids(backups: &mut [Backup]) -> Result<(), ConfigError> {
+    let mut ids: Vec<String> = vec![];
+    for backup in backups.iter_mut() {
+        let id = match &backup.name {
+            Some(name) => match slugify(name, 64) {
+                slug if slug.is_empty() => format!("{:08x}", fnv1a(name) as u32),
+                slug => slug,
+            },
+            None => {
+                let base = anonymous_id(backup);
+                // Identical backups only differ in their position
+                let mut id = base.clone();
+                for n in 2.. {
+                    if !ids.contains(&id) {
+                        break;
+                    }
+                    id = format!("{base}-{n}");
+                }
+                id
+            }
+        };
+        if ids.contains(&id) {
+            return Err(ConfigError::DuplicateId(id));
+        }
+        ids.push(id.clone());
+        backup.id = id;
+    }
+    Ok(())
+}
+
+/// Make sure no two backups create the same archive in the same repository
+///
+/// Borg would fail the second one. With `disambiguate` the ids of the backups are appended to
+/// the names instead. Names only borg knows, e.g. with `{uuid4}`, can't collide.
+fn check_archive_names(backups: &mut [Backup], disambiguate: bool) -> Result<(), ConfigError> {
+    let now = SystemTime::now();
+    let keys: Vec<_> = backups
+        .iter()
+        .map(|b| {
+            let time = b.archive.timestamp().unwrap_or(now);
+            let name = b.expected_archive_name(time)?;
+            Some((b.repo.canonical_location(), name))
+        })
+        .collect();
+
+    for (i, key) in keys.iter().enumerate() {
+        let Some(key) = key else {
+            continue;
+        };
+        let Some(other) = keys[..i].iter().position(|k| k.as_ref() == Some(key)) else {
+            continue;
+        };
+        if !disambiguate {
+            let (a, b) = (backups[other].id.clone(), backups[i].id.clone());
+            return Err(ConfigError::DuplicateArchive(a, b, key.1.to_owned()));
+        }
+        for idx in [other, i] {
+            let backup = &mut backups[idx];
+            if backup.name_suffix.is_none() {
+                backup.name_suffix = Some(backup.id.clone());
+                backup.archive.name = format!("{}-{}", backup.archive.name, backup.id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the names in `after` by ids and make sure there are no cycles
+fn resolve_dependencies(backups: &mut [Backup]) -> Result<(), ConfigError> {
+    let ids: Vec<_> = backups.iter().map(|b| b.id.clone()).collect();
+    let names: Vec<_> = backups.iter().map(|b| b.name.clone()).collect();
+    for backup in backups.iter_mut() {
+        for after in &mut backup.after {
+            let idx = (0..ids.len())
+                .find(|&i| ids[i] == *after || names[i].as_ref() == Some(after))
+                .ok_or_else(|| ConfigError::UnknownBackup(after.clone()))
+                .map_err(at_key("after"))
+                .map_err(at_key(backup.name.as_ref().unwrap_or(&backup.id)))?;
+            after.clone_from(&ids[idx]);
+        }
+    }
+
+    // Depth-first search, `path` is the chain of backups leading to the current one
+    fn visit<'a>(
+        backups: &'a [Backup],
+        idx: usize,
+        path: &mut Vec<&'a str>,
+        done: &mut [bool],
+    ) -> Result<(), ConfigError> {
+        let id = backups[idx].id();
+        if let Some(start) = path.iter().position(|p| *p == id) {
+            let mut cycle: Vec<_> = path[start..].iter().map(|p| p.to_string()).collect();
+            cycle.push(id.to_owned());
+            return Err(ConfigError::DependencyCycle(cycle));
+        }
+        if done[idx] {
+            return Ok(());
+        }
+        path.push(id);
+        for after in &backups[idx].after {
+            let next = backups.iter().position(|b| b.id() == after).unwrap();
+            visit(backups, next, path, done)?;
+        }
+        path.pop();
+        done[idx] = true;
+        Ok(())
+    }
+
+    let mut done = vec![false; backups.len()];
+    for idx in 0..backups.len() {
+        visit(backups, idx, &mut vec![], &mut done)?;
+    }
+    Ok(())
+}
+
+/// Host and path of the repository and a hash of the repository and paths, e.g.
+/// "srv-backup-0123abcd"
+///
+/// It doesn't depend on the position of the backup in the config.
+fn anonymous_id(backup: &Backup) -> String {
+    let location = format!(
+        "{} {}",
+        backup.repo.host().unwrap_or_default(),
+        backup.repo.path().display()
+    );
+    let paths: Vec<_> = backup
+        .archive
+        .paths()
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect();
+    let hash = fnv1a(&format!("{}::{}", backup.repo, paths.join(","))) as u32;
+    match slugify(&location, 32) {
+        slug if slug.is_empty() => format!("{hash:08x}"),
+        slug => format!("{slug}-{hash:08x}"),
+    }
+}
+
+/// A fully resolved backup
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// Name of a `[backup.NAME]` table, `None` for `[[backup]]` entries
+    pub name: Option<String>,
+    pub(crate) id: String,
+    pub repo: Repo,
+    pub archive: Archive,
+    pub verify: Verify,
+    /// Checked before the backup runs and by `borrg status`
+    pub require_encryption: RequiredEncryption,
+    /// Compare the version of borg on the host of a remote repository before the backup runs
+    pub check_remote_version: bool,
+    /// See [`Borg::log_level`](crate::Borg::log_level), the one of the command if unset
+    pub borg_log_level: Option<log::Level>,
+    /// Pass `--show-rc` to borg, see [`Borg::show_rc`](crate::Borg::show_rc)
+    pub show_rc: bool,
+    /// Checked before the backup runs if the repository is local
+    pub min_free_space: Option<ByteSize>,
+    /// How `borrg init` creates the repository, unless overridden on the command line
+    pub init: InitOptions,
+    pub retention: Option<PrunePolicy>,
+    pub prune: Prune,
+    pub compact: bool,
+    pub schedule: Option<Schedule>,
+    /// Archive series created instead of `archive`, see [`Backup::for_series`]
+    pub series: Vec<Series>,
+    /// Ids of the backups which have to succeed before this one starts in the same run
+    pub after: Vec<String>,
+    /// Leave out paths which don't exist when running the backup instead of failing
+    pub allow_missing_paths: bool,
+    /// Don't warn about paths inside other paths, see [`Archive::nested_paths`]
+    pub allow_nested_paths: bool,
+    /// File listing more paths, read when the backup runs, see [`PathsFrom`]
+    pub paths_from: Option<PathBuf>,
+    /// Configured `archive_name`, the date of the backup if there is none
+    pub name_template: Option<String>,
+    /// Who expands the placeholders of `name_template`
+    pub name_placeholders: NamePlaceholders,
+    /// Appended to the archive name to tell it apart from another backup's, see
+    /// `auto_disambiguate_names`
+    pub(crate) name_suffix: Option<String>,
+}
+
+impl Backup {
+    /// Stable identifier, e.g. for state files and to select the backup on the command line
+    ///
+    /// It is the name of a `[backup.NAME]` table, made file name safe. `[[backup]]` entries
+    /// get the repository's host and path and a hash of the repository and paths, reordering
+    /// them doesn't change their ids. Only identical entries are numbered.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Name passed to borg for an archive created at `time`
+    pub fn archive_name_at(&self, time: SystemTime) -> String {
+        let name = name_at(self.name_template.as_deref(), self.name_placeholders, time);
+        self.disambiguate(name)
+    }
+
+    /// Name of the archive borg creates at `time`, `None` if only borg knows it
+    pub fn expected_archive_name(&self, time: SystemTime) -> Option<String> {
+        let name = match &self.name_template {
+            Some(template) => self
+                .name_placeholders
+                .expected_name(template, &NameContext::at(time))
+                .ok()
+                .flatten()?,
+            None => archive_name(time),
+        };
+        Some(self.disambiguate(name))
+    }
+
+    /// Id of the backup creating the archives of `series`, e.g. "home-monthly"
+    pub fn series_id(&self, series: &Series) -> String {
+        match slugify(series.prefix(), 32) {
+            slug if slug.is_empty() => format!("{}-{:08x}", self.id, fnv1a(series.prefix()) as u32),
+            slug => format!("{}-{slug}", self.id),
+        }
+    }
+
+    /// The backup creating the archive of `series`, at `time` unless the archive has a timestamp
+    ///
+    /// It only prunes the archives of the series, by the retention of the series or else the
+    /// one of the backup.
+    pub fn for_series(&self, series: &Series, time: SystemTime) -> Backup {
+        let time = self.archive.timestamp().unwrap_or(time);
+        let mut backup = self.clone();
+        backup.id = self.series_id(series);
+        backup.series = vec![];
+        backup.name_template = Some(series.name.clone());
+        backup.archive.name = backup.archive_name_at(time);
+        backup.retention = series
+            .retention
+            .as_ref()
+            .or(self.retention.as_ref())
+            .map(|policy| PrunePolicy {
+                glob_archives: Some(format!("{}*", escape_glob(series.prefix()))),
+                ..policy.clone()
+            });
+        backup
+    }
+
+    /// A backup per series, see [`Backup::for_series`], or just this one without series
+    ///
+    /// Only the last one compacts the repository, once all series are pruned.
+    pub fn per_series(&self, time: SystemTime) -> Vec<Backup> {
+        if self.series.is_empty() {
+            return vec![self.clone()];
+        }
+        self.for_each_series(&self.series, time)
+    }
+
+    /// A backup per series of `series`, e.g. the due ones, see [`Backup::for_series`]
+    ///
+    /// Only the last one compacts the repository, once all of them are pruned.
+    pub fn for_each_series<'a>(
+        &self,
+        series: impl IntoIterator<Item = &'a Series>,
+        time: SystemTime,
+    ) -> Vec<Backup> {
+        let mut backups: Vec<_> = series
             .into_iter()
-            .map(|c| c.resolve(&templates)?.try_into())
-            .collect()
+            .map(|series| Backup {
+                compact: false,
+                ..self.for_series(series, time)
+            })
+            .collect();
+        if let Some(last) = backups.last_mut() {
+            last.compact = self.compact;
+        }
+        backups
+    }
+
+    /// Whether `series` needs a new archive at `time`, given the archives in the repository
+    ///
+    /// It does unless the archive it would create exists already, or the newest one of the
+    /// series isn't due for a successor by the schedule of the series.
+    pub fn series_due(&self, series: &Series, archives: &[ArchiveInfo], time: SystemTime) -> bool {
+        let time = self.archive.timestamp().unwrap_or(time);
+        let expected = self.for_series(series, time).expected_archive_name(time);
+        if expected.is_some_and(|name| archives.iter().any(|a| a.name == name)) {
+            return false;
+        }
+        let Some(schedule) = &series.schedule else {
+            return true;
+        };
+        let last = archives
+            .iter()
+            .filter(|a| a.name.starts_with(series.prefix()) && !a.is_checkpoint())
+            .map(|a| a.time)
+            .max();
+        match last {
+            Some(last) => schedule
+                .next_after(last.into())
+                .is_some_and(|next| SystemTime::from(next) <= time + SERIES_SLACK),
+            None => true,
+        }
+    }
+
+    /// `name` with the suffix of `auto_disambiguate_names`, if the backup needs one
+    pub fn disambiguate(&self, name: String) -> String {
+        match &self.name_suffix {
+            Some(suffix) => format!("{name}-{suffix}"),
+            None => name,
+        }
+    }
+}
+
+impl Display for Backup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}::{}", self.repo, self.archive)
     }
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub(crate) source: PathBuf,
-    pub backups: Vec<(Repo, Archive)>,
+    pub backups: Vec<Backup>,
+    pub cache: Cache,
+    /// How long `status` waits for a single repository
+    pub info_timeout: Duration,
+    pub limits: Limits,
+    /// Where `borrg key backup-all` exports the keys of keyfile-mode repositories to
+    pub key_backup_dir: Option<PathBuf>,
 }
 
 impl Config {
+    pub const DEFAULT_INFO_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// `~/.config/borg/borrg.toml`, or `borrg\borrg.toml` in the roaming AppData on Windows
+    pub fn default_path() -> PathBuf {
+        #[cfg(windows)]
+        if let Some(dir) = dirs::config_dir() {
+            return dir.join("borrg").join("borrg.toml");
+        }
+
+        crate::util::resolve_path("~/.config/borg/borrg.toml")
+    }
+
     pub fn load<P>(path: &P) -> Result<Self, ConfigError>
     where
         P: AsRef<std::path::Path>,
     {
-        let value = toml::from_str(&std::fs::read_to_string(path).map_err(ConfigError::IOError)?)
-            .map_err(ConfigError::ParseError)?;
+        let content = std::fs::read_to_string(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => ConfigError::NotFound,
+            _ => ConfigError::IOError(e),
+        })?;
+        let value = toml::from_str(&content).map_err(ConfigError::ParseError)?;
 
         let backups = ConfigProperty::parse(&value)?;
 
+        let (cache, info_timeout, limits): (Option<Cache>, Option<Duration>, Option<Limits>) =
+            match &value {
+                toml::Value::Table(t) => (
+                    ConfigProperty::from_map(t, "cache")?,
+                    ConfigProperty::from_map(t, "info_timeout")?,
+                    ConfigProperty::from_map(t, "limits")?,
+                ),
+                _ => (None, None, None),
+            };
+        let (stagger, jitter): (Option<Duration>, Option<Duration>) = match &value {
+            toml::Value::Table(t) => (
+                ConfigProperty::from_map(t, "stagger")?,
+                ConfigProperty::from_map(t, "jitter")?,
+            ),
+            _ => (None, None),
+        };
+        let key_backup_dir: Option<PathBuf> = match &value {
+            toml::Value::Table(t) => ConfigProperty::from_map(t, "key_backup_dir")?,
+            _ => None,
+        };
+        let limits = Limits {
+            stagger: stagger.unwrap_or_default(),
+            jitter: jitter.unwrap_or_default(),
+            ..limits.unwrap_or_default()
+        };
+
         Ok(Self {
             source: path.as_ref().into(),
             backups,
+            cache: cache.unwrap_or_default(),
+            info_timeout: info_timeout.unwrap_or(Self::DEFAULT_INFO_TIMEOUT),
+            limits,
+            key_backup_dir: key_backup_dir.map(crate::util::resolve_path),
         })
     }
+
+    /// Fail with [`ConfigError::NoBackups`] if the config has no backups
+    ///
+    /// An empty config loads fine, e.g. for `borrg init` to add the first backup to it.
+    pub fn require_backups(&self) -> Result<(), ConfigError> {
+        match self.backups.is_empty() {
+            true => Err(ConfigError::NoBackups(self.source.clone())),
+            false => Ok(()),
+        }
+    }
+
+    /// Select the backups matching `query` by id or repository, or all backups if there is no
+    /// query
+    pub fn select(&self, query: Option<&str>) -> Result<Vec<&Backup>, ConfigError> {
+        let Some(query) = query else {
+            return Ok(self.backups.iter().collect());
+        };
+
+        let repo = query.parse::<Repo>().ok();
+        let selected: Vec<_> = self
+            .backups
+            .iter()
+            .filter(|b| b.id() == query || b.to_string() == query || Some(&b.repo) == repo.as_ref())
+            .collect();
+
+        if selected.is_empty() {
+            return Err(ConfigError::UnknownBackup(query.to_owned()));
+        }
+
+        Ok(selected)
+    }
 }
 
 #[cfg(test)]
@@ -654,10 +2100,35 @@ mod tests {
     fn test_empty() {
         let config = "";
         let value = config.parse().unwrap();
-        let result: Result<Vec<(Repo, Archive)>, ConfigError> = ConfigProperty::parse(&value);
+        let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
 
         assert!(result.is_ok());
         assert!(result.unwrap().is_empty());
+
+        let dir = std::env::temp_dir().join(format!("borrg-test-empty-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("borrg.toml");
+        assert!(matches!(Config::load(&path), Err(ConfigError::NotFound)));
+
+        for content in ["", "# nothing yet\n", "[template.nas]\nhost = \"nas\"\n"] {
+            std::fs::write(&path, content).unwrap();
+            let config = Config::load(&path).unwrap();
+            assert!(config.backups.is_empty());
+            let err = config.require_backups().unwrap_err();
+            assert!(matches!(&err, ConfigError::NoBackups(p) if p == &path));
+            assert!(err.to_string().contains("borrg init"));
+        }
+
+        std::fs::write(&path, "[[backup]]\nrepository = \"/tmp/repo\"\n").unwrap();
+        assert!(Config::load(&path).unwrap().require_backups().is_ok());
+
+        std::fs::write(&path, "key_backup_dir = \"~/borg-keys\"\n").unwrap();
+        assert_eq!(
+            Config::load(&path).unwrap().key_backup_dir,
+            Some(crate::util::resolve_path("~/borg-keys"))
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
     }
 
     #[test]
@@ -668,19 +2139,260 @@ mod tests {
         "#;
 
         let value = config.parse().unwrap();
-        let result: Result<Vec<(Repo, Archive)>, ConfigError> = ConfigProperty::parse(&value);
+        let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
 
         dbg!(&result);
         assert!(result.is_ok());
         let results = result.unwrap();
         assert_eq!(results.len(), 1);
-        let (repo, archive) = results.first().unwrap();
+        let Backup {
+            repo,
+            archive,
+            verify,
+            ..
+        } = results.first().unwrap();
         assert_eq!(repo.to_string(), ".");
         assert_eq!(repo.passphrase, None);
         assert_eq!(archive.paths, vec![PathBuf::from("~")]);
         assert_eq!(archive.compression, None);
         assert_eq!(archive.pattern_file, None);
         assert_eq!(archive.exclude_file, Some(PathBuf::from(".borgignore")));
+        assert_eq!(verify, &Verify::None);
+    }
+
+    #[test]
+    fn test_paths_from() {
+        let backups: Vec<Backup> = ConfigProperty::parse(
+            &r#"
+            [[backup]]
+            repository = "/srv/a"
+            paths_from = "/etc/borrg/paths"
+            [[backup]]
+            repository = "/srv/b"
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+        // No default "~" next to the listed paths
+        assert!(backups[0].archive.paths.is_empty());
+        assert_eq!(
+            backups[0].paths_from.as_deref(),
+            Some(std::path::Path::new("/etc/borrg/paths"))
+        );
+        assert_eq!(backups[1].archive.paths, [PathBuf::from("~")]);
+        assert_eq!(backups[1].paths_from, None);
+    }
+
+    #[test]
+    fn test_relative_to() {
+        let parse = |backup: &str| -> Result<Vec<Backup>, ConfigError> {
+            ConfigProperty::parse(
+                &format!("[[backup]]\nrepository = \"/srv/a\"\n{backup}")
+                    .parse()
+                    .unwrap(),
+            )
+        };
+
+        let backups = parse("relative_to = \"/srv\"\npath = [\"www\", \"/srv/cgi-bin\"]").unwrap();
+        assert_eq!(
+            backups[0].archive.relative_to(),
+            Some(std::path::Path::new("/srv"))
+        );
+        assert!(parse("relative_to = \"srv\"\npath = \"www\"").is_err());
+        assert!(parse("relative_to = \"/srv\"\npath = \"/etc\"").is_err());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let parse = |backup: &str| -> Result<Vec<Backup>, ConfigError> {
+            ConfigProperty::parse(
+                &format!("[[backup]]\nrepository = \"/srv/a\"\n{backup}")
+                    .parse()
+                    .unwrap(),
+            )
+        };
+        let may_first = SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_532_400);
+
+        let backups = parse("timestamp = \"2024-05-01T05:00:00+02:00\"").unwrap();
+        assert_eq!(backups[0].archive.timestamp(), Some(may_first));
+        assert_eq!(backups[0].archive.name(), archive_name(may_first));
+
+        let backups = parse("timestamp = 2024-05-01T03:00:00Z").unwrap();
+        assert_eq!(backups[0].archive.timestamp(), Some(may_first));
+
+        assert!(parse("timestamp = \"yesterday\"").is_err());
+        // Without an offset it would depend on the time zone
+        assert!(parse("timestamp = 2024-05-01T03:00:00").is_err());
+    }
+
+    #[test]
+    fn test_duplicate_archives() {
+        let parse = |config: &str| -> Result<Vec<Backup>, ConfigError> {
+            ConfigProperty::parse(&config.parse().unwrap())
+        };
+        // The same repository, written differently
+        let home = dirs::home_dir().unwrap();
+        let config = format!(
+            "[backup.home]\nrepository = \"~/backup\"\npath = \"/home\"\n\
+            [backup.etc]\nrepository = \"{}/backup/\"\npath = \"/etc\"\n",
+            home.display()
+        );
+        assert_eq!(
+            parse(&config).unwrap_err().to_string(),
+            format!(
+                "Backups \"etc\" and \"home\" would both create the archive {}, set archive_name \
+                or auto_disambiguate_names = true",
+                archive_name(SystemTime::now())
+            )
+        );
+
+        let backups = parse(&format!("auto_disambiguate_names = true\n{config}")).unwrap();
+        let today = archive_name(SystemTime::now());
+        assert_eq!(backups[0].archive.name(), format!("{today}-etc"));
+        assert_eq!(backups[1].archive.name(), format!("{today}-home"));
+        assert_eq!(
+            backups[0].archive_name_at(SystemTime::UNIX_EPOCH),
+            format!("{}-etc", archive_name(SystemTime::UNIX_EPOCH))
+        );
+
+        // Different names or repositories don't collide
+        let named = config.replace(
+            "path = \"/etc\"",
+            "path = \"/etc\"\narchive_name = \"etc-{now}\"",
+        );
+        assert!(parse(&named).is_ok());
+        assert!(parse(&config.replace("~/backup", "~/other")).is_ok());
+    }
+
+    #[test]
+    fn test_min_free_space() {
+        let parse = |backup: &str| -> Result<Vec<Backup>, ConfigError> {
+            ConfigProperty::parse(
+                &format!("[[backup]]\nrepository = \"/srv/a\"\n{backup}")
+                    .parse()
+                    .unwrap(),
+            )
+        };
+        let min = |backup: &str| parse(backup).unwrap()[0].min_free_space.map(|size| size.0);
+        assert_eq!(min(""), None);
+        assert_eq!(min("min_free_space = 4096"), Some(4096));
+        assert_eq!(min("min_free_space = \"5G\""), Some(5 << 30));
+        assert!(parse("min_free_space = \"5 parsecs\"").is_err());
+        assert!(parse("min_free_space = -1").is_err());
+    }
+
+    #[test]
+    fn test_borg_log_level() {
+        let config = r#"
+        [template.default]
+        show_rc = true
+
+        [backup.flaky]
+        repository = "/srv/flaky"
+        borg_log_level = "debug"
+
+        [backup.quiet]
+        repository = "/srv/quiet"
+        borg_log_level = "warning"
+        show_rc = false
+
+        [backup.other]
+        repository = "/srv/other"
+        "#;
+        let backups: Vec<Backup> = ConfigProperty::parse(&config.parse().unwrap()).unwrap();
+        let logging: Vec<_> = backups
+            .iter()
+            .map(|b| (b.id(), b.borg_log_level, b.show_rc))
+            .collect();
+        assert_eq!(
+            logging,
+            [
+                ("flaky", Some(log::Level::Debug), true),
+                ("other", None, true),
+                ("quiet", Some(log::Level::Warn), false),
+            ]
+        );
+
+        let invalid = "[[backup]]\nrepository = \".\"\nborg_log_level = \"chatty\"";
+        let result: Result<Vec<Backup>, ConfigError> =
+            ConfigProperty::parse(&invalid.parse().unwrap());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_require_encryption() {
+        let parse = |backup: &str| -> Result<Vec<Backup>, ConfigError> {
+            ConfigProperty::parse(
+                &format!("[[backup]]\nrepository = \"/srv/a\"\n{backup}")
+                    .parse()
+                    .unwrap(),
+            )
+        };
+
+        let required = |backup: &str| parse(backup).unwrap()[0].require_encryption.clone();
+        assert_eq!(required(""), RequiredEncryption::Any);
+        assert_eq!(
+            required("require_encryption = false"),
+            RequiredEncryption::Any
+        );
+        assert_eq!(
+            required("require_encryption = true"),
+            RequiredEncryption::Encrypted
+        );
+        assert_eq!(
+            required("require_encryption = \"keyfile-blake2\""),
+            RequiredEncryption::Mode(Encryption::KeyFileBlake2)
+        );
+        assert!(parse("require_encryption = \"rot13\"").is_err());
+
+        assert!(RequiredEncryption::Any.check(&Encryption::None).is_ok());
+        assert!(RequiredEncryption::Encrypted
+            .check(&Encryption::RepoKey)
+            .is_ok());
+        assert!(RequiredEncryption::Encrypted
+            .check(&Encryption::Authenticated)
+            .is_err());
+        // A repository swapped for another one usually has a different mode
+        let keyfile = RequiredEncryption::Mode(Encryption::KeyFile);
+        assert!(keyfile.check(&Encryption::KeyFile).is_ok());
+        assert!(keyfile.check(&Encryption::RepoKey).is_err());
+    }
+
+    #[test]
+    fn test_archive_name() {
+        let parse = |backup: &str| -> Result<Vec<Backup>, ConfigError> {
+            ConfigProperty::parse(
+                &format!("[[backup]]\nrepository = \"/srv/a\"\ntimestamp = 2024-05-01T03:00:00Z\n{backup}")
+                    .parse()
+                    .unwrap(),
+            )
+        };
+        let may_first = SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_532_400);
+
+        let backups = parse("archive_name = \"data-{utcnow:%Y-%m}\"").unwrap();
+        assert_eq!(backups[0].archive.name(), "data-2024-05");
+        assert_eq!(
+            backups[0].expected_archive_name(may_first).as_deref(),
+            Some("data-2024-05")
+        );
+
+        let backups =
+            parse("archive_name = \"{fqdn}-{utcnow:%Y}\"\nname_placeholders = \"borg\"").unwrap();
+        assert_eq!(backups[0].archive.name(), "{fqdn}-{utcnow:%Y}");
+        assert_eq!(backups[0].expected_archive_name(may_first), None);
+
+        // Borrg doesn't know the domain, and nobody knows {date}
+        assert!(parse("archive_name = \"{fqdn}\"").is_err());
+        assert!(parse("archive_name = \"{date}\"\nname_placeholders = \"borg\"").is_err());
+        assert!(parse("name_placeholders = \"python\"").is_err());
+
+        let backups = parse("").unwrap();
+        assert_eq!(backups[0].archive.name(), archive_name(may_first));
+        assert_eq!(
+            backups[0].expected_archive_name(may_first),
+            Some(archive_name(may_first))
+        );
     }
 
     #[test]
@@ -694,16 +2406,54 @@ mod tests {
         "#;
 
         let value = config.parse().unwrap();
-        let result: Result<Vec<(Repo, Archive)>, ConfigError> = ConfigProperty::parse(&value);
+        let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
 
         dbg!(&result);
         assert!(result.is_ok());
         let results = result.unwrap();
         assert_eq!(results.len(), 1);
-        let (_, archive) = results.first().unwrap();
+        let archive = &results.first().unwrap().archive;
         assert!(matches!(archive.compression, Some(Compression::Lz4 { .. })));
     }
 
+    #[test]
+    fn test_exclusion_markers() {
+        let config = r#"
+        [template.default]
+        respect_nobackup = true
+
+        [[backup]]
+        repository = "."
+
+        [[backup]]
+        repository = "./other"
+        exclude_if_present = ".nobackup"
+        respect_nobackup = false
+        "#;
+
+        let backups: Vec<Backup> = ConfigProperty::parse(&config.parse().unwrap()).unwrap();
+        assert_eq!(backups[0].archive.exclude_if_present, [".nobackup"]);
+        assert!(backups[0].archive.keep_exclude_tags);
+        assert_eq!(backups[1].archive.exclude_if_present, [".nobackup"]);
+        assert!(!backups[1].archive.keep_exclude_tags);
+
+        // Only a configured exclude file has to exist
+        assert_eq!(backups[0].archive.missing_exclude_file, MissingFile::Ignore);
+        let config = r#"
+        [[backup]]
+        repository = "."
+        exclude_file = "excludes"
+
+        [[backup]]
+        repository = "./other"
+        exclude_file = "/etc/excludes"
+        allow_missing_exclude_file = true
+        "#;
+        let backups: Vec<Backup> = ConfigProperty::parse(&config.parse().unwrap()).unwrap();
+        assert_eq!(backups[0].archive.missing_exclude_file, MissingFile::Error);
+        assert_eq!(backups[1].archive.missing_exclude_file, MissingFile::Warn);
+    }
+
     #[test]
     fn test_custom_template() {
         let config = r#"
@@ -716,13 +2466,436 @@ mod tests {
         "#;
 
         let value = config.parse().unwrap();
-        let result: Result<Vec<(Repo, Archive)>, ConfigError> = ConfigProperty::parse(&value);
+        let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
 
         dbg!(&result);
         assert!(result.is_ok());
         let results = result.unwrap();
         assert_eq!(results.len(), 1);
-        let (_, archive) = results.first().unwrap();
+        let archive = &results.first().unwrap().archive;
         assert!(matches!(archive.compression, Some(Compression::Lz4 { .. })));
     }
+
+    #[test]
+    fn test_verify() {
+        let config = r#"
+        [template.default]
+        verify = "archive"
+
+        [[backup]]
+        repository = "."
+
+        [[backup]]
+        repository = "./other"
+        verify = "repository"
+        "#;
+
+        let value = config.parse().unwrap();
+        let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
+
+        let results = result.unwrap();
+        assert_eq!(results[0].verify, Verify::Archive);
+        assert_eq!(results[1].verify, Verify::Repository);
+
+        let config = r#"
+        [[backup]]
+        repository = "."
+        verify = "everything"
+        "#;
+
+        let value = config.parse().unwrap();
+        let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_retention() {
+        let config = r#"
+        [template.default]
+        retention = { keep_daily = 7, keep_weekly = 4 }
+        prune = "auto"
+
+        [[backup]]
+        repository = "."
+        compact = true
+
+        [[backup]]
+        repository = "./other"
+        retention = { keep_within = "2d" }
+        prune = "manual"
+        "#;
+
+        let value = config.parse().unwrap();
+        let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
+
+        let results = result.unwrap();
+        let policy = results[0].retention.as_ref().unwrap();
+        assert_eq!(policy.keep_daily, Some(7));
+        assert_eq!(policy.keep_weekly, Some(4));
+        assert_eq!(results[0].prune, Prune::Auto);
+        assert!(results[0].compact);
+        let policy = results[1].retention.as_ref().unwrap();
+        assert_eq!(policy.keep_within.as_deref(), Some("2d"));
+        assert_eq!(policy.keep_daily, None);
+        assert_eq!(results[1].prune, Prune::Manual);
+        assert!(!results[1].compact);
+
+        for invalid in [
+            "retention = {}",
+            "retention = { keep_dayly = 7 }",
+            "retention = { keep_daily = -1 }",
+        ] {
+            let config = format!("[[backup]]\nrepository = \".\"\n{invalid}");
+            let value = config.parse().unwrap();
+            let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
+            assert!(result.is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_series() {
+        let config = r#"
+        [backup.home]
+        repository = "/srv/backup"
+        path = "/home"
+        retention = { keep_daily = 7 }
+        compact = true
+
+        [[backup.home.series]]
+        name = "daily-{now:%Y-%m-%d}"
+        every = "1d"
+
+        [[backup.home.series]]
+        name = "monthly-{now:%Y-%m}"
+        retention = { keep_monthly = 12 }
+        "#;
+
+        let value = config.parse().unwrap();
+        let backups: Vec<Backup> = ConfigProperty::parse(&value).unwrap();
+        let backup = &backups[0];
+        let [daily, monthly] = &backup.series[..] else {
+            panic!("{:?}", backup.series);
+        };
+        assert_eq!(daily.prefix(), "daily-");
+        assert_eq!(monthly.prefix(), "monthly-");
+
+        let now = SystemTime::now();
+        let backups = backup.per_series(now);
+        assert_eq!(backups[0].id(), "home-daily");
+        assert!(backups[0].archive.name.starts_with("daily-"));
+        let policy = backups[0].retention.as_ref().unwrap();
+        assert_eq!(policy.keep_daily, Some(7));
+        assert_eq!(policy.glob_archives.as_deref(), Some("daily-*"));
+        assert!(!backups[0].compact);
+        assert_eq!(backups[1].id(), "home-monthly");
+        let policy = backups[1].retention.as_ref().unwrap();
+        assert_eq!(policy.keep_monthly, Some(12));
+        assert_eq!(policy.keep_daily, None);
+        assert_eq!(policy.glob_archives.as_deref(), Some("monthly-*"));
+        assert!(backups[1].compact);
+        let due = backup.for_each_series([daily], now);
+        assert!(due[0].compact);
+
+        // Due without archives, until the name exists or the interval passed
+        let archive = |name: &str, age: u64| ArchiveInfo {
+            name: name.to_owned(),
+            id: "f00d".to_owned(),
+            time: now - Duration::from_secs(age),
+        };
+        assert!(backup.series_due(daily, &[], now));
+        assert!(backup.series_due(monthly, &[], now));
+        let archives = [archive(&backups[1].archive.name, 3600)];
+        assert!(!backup.series_due(monthly, &archives, now));
+        assert!(backup.series_due(daily, &archives, now));
+        let archives = [archive("daily-yesterday", 3600)];
+        assert!(!backup.series_due(daily, &archives, now));
+        let archives = [archive("daily-yesterday", 86400 - 60)];
+        assert!(backup.series_due(daily, &archives, now));
+        let archives = [archive("daily-yesterday.checkpoint", 3600)];
+        assert!(backup.series_due(daily, &archives, now));
+
+        for invalid in [
+            "name = \"{now}\"",
+            "name = \"monthly-{nope}\"",
+            "every = \"1d\"",
+            "name = \"daily-{now}\"\nkeep_daily = 7",
+            "name = \"daily{now}\"\n[[backup.series]]\nname = \"daily-{now}\"",
+        ] {
+            let config = format!("[[backup]]\nrepository = \".\"\n[[backup.series]]\n{invalid}\n");
+            let value = config.parse().unwrap();
+            let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
+            assert!(result.is_err(), "{invalid}");
+        }
+
+        assert_eq!(escape_glob("a*b[1]?"), "a[*]b[[]1[]][?]");
+    }
+
+    #[test]
+    fn test_env() {
+        let config = r#"
+        [template.default]
+        env = { SSH_AUTH_SOCK = "/run/agent.sock", BORG_RSH = "ssh -i key" }
+
+        [[backup]]
+        repository = "."
+        env = { BORG_RSH = "ssh -i ${PATH}/key", SECRETS = "${PATH}" }
+        "#;
+
+        let value = config.parse().unwrap();
+        let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
+
+        let path = std::env::var("PATH").unwrap();
+        let results = result.unwrap();
+        let env: Vec<_> = results[0]
+            .repo
+            .env()
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.to_owned()))
+            .collect();
+        assert_eq!(
+            env,
+            [
+                ("BORG_RSH", format!("ssh -i {path}/key")),
+                ("SECRETS", path.to_owned()),
+                ("SSH_AUTH_SOCK", "/run/agent.sock".to_owned()),
+            ]
+        );
+
+        for (invalid, message) in [
+            (
+                "env = { BORG_PASSPHRASE = \"hunter2\" }",
+                "Reserved, use passphrase or passcommand instead at backup.env.BORG_PASSPHRASE",
+            ),
+            (
+                "env = { X = \"${BORRG_TEST_UNDEFINED}\" }",
+                "Undefined variable \"BORRG_TEST_UNDEFINED\" at backup.env.X",
+            ),
+            (
+                "env = { X = \"${PATH\" }",
+                "Unterminated \"${\" at backup.env.X",
+            ),
+            (
+                "env = { X = 1 }",
+                "Invalid type: expected string, found integer at backup.env",
+            ),
+        ] {
+            let config = format!("[[backup]]\nrepository = \".\"\n{invalid}");
+            let value = config.parse().unwrap();
+            let result: Result<Vec<Backup>, ConfigError> = ConfigProperty::parse(&value);
+            assert_eq!(result.unwrap_err().to_string(), message, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_rsh() {
+        let rsh = |line: &str| -> Result<Option<String>, ConfigError> {
+            let config = format!("[[backup]]\nrepository = \"ssh://nas/./backup\"\n{line}");
+            let backups: Vec<Backup> = ConfigProperty::parse(&config.parse().unwrap())?;
+            Ok(backups[0].repo.env().get("BORG_RSH").cloned())
+        };
+
+        assert_eq!(rsh("").unwrap(), None);
+        assert_eq!(
+            rsh("rsh = \"ssh -J bastion\"").unwrap().as_deref(),
+            Some("ssh -J bastion")
+        );
+        // Words stay words for borg
+        assert_eq!(
+            rsh("rsh = [\"/opt/my tools/rsh\", \"-o\", \"SendEnv=LANG LC_*\", \"nas's\"]")
+                .unwrap()
+                .as_deref(),
+            Some("'/opt/my tools/rsh' -o 'SendEnv=LANG LC_*' 'nas'\\''s'")
+        );
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            rsh("rsh = [\"ssh\", \"-i\", \"${HOME}/key\"]").unwrap(),
+            Some(format!("ssh -i {}", shell_quote(&format!("{home}/key"))))
+        );
+
+        assert!(rsh("rsh = []").is_err());
+        assert!(rsh("rsh = 1").is_err());
+        assert!(rsh("rsh = \"ssh\"\nenv = { BORG_RSH = \"ssh\" }").is_err());
+    }
+
+    #[test]
+    fn test_backup_ids() {
+        let parse = |config: &str| -> Result<Vec<Backup>, ConfigError> {
+            ConfigProperty::parse(&config.parse().unwrap())
+        };
+        let ids = |config: &str| -> Vec<String> {
+            parse(config)
+                .unwrap()
+                .iter()
+                .map(|b| b.id().to_owned())
+                .collect()
+        };
+
+        let home = "[[backup]]\nrepository = \"ssh://seb@nas:22/srv/Backup\"\npath = \"/home\"\n";
+        let etc = "[[backup]]\nrepository = \"/mnt/usb\"\npath = \"/etc\"\n";
+        let first = ids(&format!("{home}{etc}"));
+        assert!(first[0].starts_with("nas-srv-backup-"), "{}", first[0]);
+        assert!(first[1].starts_with("mnt-usb-"), "{}", first[1]);
+        // Reordering entries keeps their ids
+        assert_eq!(ids(&format!("{etc}{home}")), [&*first[1], &*first[0]]);
+        // Only identical entries are numbered
+        let twice = ids(&format!("auto_disambiguate_names = true\n{home}{home}"));
+        assert_eq!(twice, [first[0].to_owned(), format!("{}-2", first[0])]);
+
+        let named = r#"
+        [backup."Home Dir"]
+        repository = "/srv/backup"
+        env = { X = "1" }
+        retention = { keep_daily = 7 }
+
+        [backup.etc]
+        repository = "/srv/backup"
+        path = "/etc"
+        archive_name = "etc-{now}"
+        "#;
+        let backups = parse(named).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].name.as_deref(), Some("Home Dir"));
+        assert_eq!(backups[0].id(), "home-dir");
+        assert_eq!(backups[1].id(), "etc");
+
+        // A single `[backup]` table is still an anonymous backup
+        let single = parse("[backup]\nrepository = \"/srv/backup\"\nenv = { X = \"1\" }").unwrap();
+        assert_eq!(single[0].name, None);
+
+        let clash = "[backup.Home]\nrepository = \".\"\n[backup.home]\nrepository = \".\"";
+        assert_eq!(
+            parse(clash).unwrap_err().to_string(),
+            "Several backups have the id \"home\""
+        );
+
+        let deps = r#"
+        [backup.files]
+        repository = "/srv/backup"
+        after = ["db-dump"]
+
+        [backup.db-dump]
+        repository = "/srv/backup"
+        archive_name = "db-{now}"
+        "#;
+        assert_eq!(parse(deps).unwrap()[1].after, ["db-dump"]);
+        let inherited = deps.replace(
+            "after = [\"db-dump\"]",
+            "template = \"late\"\n[template.late]\nafter = [\"db-dump\"]",
+        );
+        assert_eq!(parse(&inherited).unwrap()[1].after, ["db-dump"]);
+        let unknown = deps.replace("after = [\"db-dump\"]", "after = [\"db\"]");
+        assert_eq!(
+            parse(&unknown).unwrap_err().to_string(),
+            "No backup matches \"db\" at backup.files.after"
+        );
+        let cycle = format!("{deps}after = [\"files\"]");
+        assert_eq!(
+            parse(&cycle).unwrap_err().to_string(),
+            "Backups depend on each other: db-dump -> files -> db-dump at backup"
+        );
+
+        let error = "[backup.home]\nrepository = 1";
+        assert_eq!(
+            parse(error).unwrap_err().to_string(),
+            "Invalid type: expected string or table, found integer at backup.home.repository"
+        );
+
+        let config = Config {
+            source: PathBuf::new(),
+            backups: parse(named).unwrap(),
+            cache: Cache::default(),
+            info_timeout: Config::DEFAULT_INFO_TIMEOUT,
+            limits: Limits::default(),
+            key_backup_dir: None,
+        };
+        assert_eq!(config.select(Some("etc")).unwrap().len(), 1);
+        assert_eq!(config.select(Some("/srv/backup")).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_passphrase() {
+        let passphrase = |line: &str| -> Option<Passphrase> {
+            let config = format!("[[backup]]\nrepository = \".\"\n{line}");
+            let backups: Vec<Backup> = ConfigProperty::parse(&config.parse().unwrap()).unwrap();
+            backups[0].repo.passphrase.clone()
+        };
+        assert_eq!(passphrase(""), None);
+        assert_eq!(
+            passphrase("passphrase = \"hunter2\""),
+            Some(Passphrase::Passphrase("hunter2".to_owned()))
+        );
+        assert_eq!(
+            passphrase("passphrase = \"env\""),
+            Some(Passphrase::Passphrase("env".to_owned()))
+        );
+        assert_eq!(
+            passphrase("passphrase = { env = \"BORG_PASSPHRASE\" }"),
+            Some(Passphrase::Environment("BORG_PASSPHRASE".to_owned()))
+        );
+        assert_eq!(
+            passphrase("passcommand = \"pass borg\""),
+            Some(Passphrase::Command("pass borg".to_owned()))
+        );
+        assert_eq!(
+            passphrase("passcommand = [\"pass\", \"show\", \"my key\"]"),
+            Some(Passphrase::CommandArgs(
+                ["pass", "show", "my key"].map(String::from).into()
+            ))
+        );
+
+        for (invalid, message) in [
+            (
+                "passphrase = \"hunter2\"\npasscommand = [\"pass\"]",
+                "passphrase and passcommand are exclusive at backup",
+            ),
+            (
+                "passphrase = \"env\"\npasscommand = \"pass borg\"",
+                "passphrase and passcommand are exclusive at backup",
+            ),
+            ("passcommand = []", "Invalid value at backup.passcommand"),
+            (
+                "passcommand = [\"pass\", 1]",
+                "Invalid type: expected string, found integer at backup.passcommand",
+            ),
+        ] {
+            let config = format!("[[backup]]\nrepository = \".\"\n{invalid}");
+            let result: Result<Vec<Backup>, ConfigError> =
+                ConfigProperty::parse(&config.parse().unwrap());
+            assert_eq!(result.unwrap_err().to_string(), message, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_duration() {
+        use toml::Value;
+        assert_eq!(
+            Duration::parse(&Value::Integer(45)).unwrap(),
+            Duration::from_secs(45)
+        );
+        assert_eq!(
+            Duration::parse(&Value::String("1m 30s".to_owned())).unwrap(),
+            Duration::from_secs(90)
+        );
+        assert!(Duration::parse(&Value::Integer(-1)).is_err());
+        assert!(Duration::parse(&Value::String("soon".to_owned())).is_err());
+        assert!(Duration::parse(&Value::Boolean(true)).is_err());
+
+        let backups: Vec<Backup> = ConfigProperty::parse(
+            &"[[backup]]\nrepository = \".\"\nevery = 5400"
+                .parse()
+                .unwrap(),
+        )
+        .unwrap();
+        let schedule = backups[0].schedule.as_ref().unwrap();
+        assert_eq!(schedule.to_string(), "every 1h 30m");
+
+        let config = "[[backup]]\nrepository = \".\"\nevery = \"soon\"";
+        let result: Result<Vec<Backup>, ConfigError> =
+            ConfigProperty::parse(&config.parse().unwrap());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Invalid duration \"soon\", expected seconds or e.g. \"90s\", \"15m\", \"1h30m\", \
+            \"2d\" at backup.every"
+        );
+    }
 }
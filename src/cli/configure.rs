@@ -0,0 +1,469 @@
+use super::*;
+use crate::backend::borg::{passphrase_source, shell_quote, Invocation};
+use crate::Borg;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::ExitCode;
+
+#[cfg(feature = "borgmatic")]
+mod borgmatic;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Translate a borgmatic config into a borrg config
+    #[cfg(feature = "borgmatic")]
+    ImportBorgmatic {
+        /// borgmatic config file (YAML)
+        file: std::path::PathBuf,
+
+        /// Where to write the borrg config, defaults to the config path
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+
+        /// Where to write borgmatic's exclude patterns, defaults to borrg.exclude next to the config
+        #[arg(long)]
+        exclude_file: Option<std::path::PathBuf>,
+    },
+    /// Print the borg commands of every backup, to run them without borrg
+    Export {
+        #[arg(short, long, value_enum, default_value_t)]
+        format: ExportFormat,
+    },
+    /// Load the config and show where borg gets the passphrase of each backup from
+    Check {
+        /// Don't look for directories excluded by marker files like `.nobackup`
+        #[arg(long)]
+        no_fs_checks: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+enum ExportFormat {
+    /// A shell script exporting the environment and running borg
+    #[default]
+    Shell,
+    /// The argument vectors and environment as JSON
+    Json,
+}
+
+/// Unlike the other commands this loads the config itself, importing works without one
+pub fn configure(config_path: &Path, args: Args) -> ExitCode {
+    match args.command {
+        #[cfg(feature = "borgmatic")]
+        Command::ImportBorgmatic {
+            file,
+            output,
+            exclude_file,
+        } => {
+            let output =
+                output.map_or_else(|| config_path.to_owned(), |o| crate::util::resolve_path(&o));
+            let exclude_file = exclude_file.map_or_else(
+                || output.with_file_name("borrg.exclude"),
+                |e| crate::util::resolve_path(&e),
+            );
+            match import_borgmatic(&crate::util::resolve_path(&file), &output, &exclude_file) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        style::error_stderr(format!("Failed to import {}: {e}", file.display()))
+                    );
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Command::Export { format } => match load(config_path) {
+            Some(config) => export(&config, format),
+            None => ExitCode::FAILURE,
+        },
+        Command::Check { no_fs_checks } => match load(config_path) {
+            Some(config) => check(&config, !no_fs_checks),
+            None => ExitCode::FAILURE,
+        },
+    }
+}
+
+fn load(config_path: &Path) -> Option<Config> {
+    match Config::load(&config_path) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            let message = format!("Failed to load config ({}): {}", config_path.display(), e);
+            eprintln!("{}", style::error_stderr(message));
+            None
+        }
+    }
+}
+
+/// Loading the config already validated it, what's left is the environment and, with
+/// `fs_checks`, the directories excluded by marker files
+fn check(config: &Config, fs_checks: bool) -> ExitCode {
+    println!(
+        "{}: {} backup(s)",
+        config.source.display(),
+        config.backups.len()
+    );
+    let rows: Vec<_> = config
+        .backups
+        .iter()
+        .map(|backup| {
+            let source = passphrase_source(backup.repo.passphrase.as_ref(), |var| {
+                std::env::var_os(var).is_some()
+            });
+            let mut row = vec![
+                backup.id().to_owned(),
+                backup.repo.to_string(),
+                source,
+                borg_logging(backup),
+            ];
+            if fs_checks {
+                let markers = backup.archive.exclusion_markers();
+                row.push(
+                    match count_marked(backup.archive.paths(), markers, WALK_LIMIT) {
+                        _ if markers.is_empty() => "-".to_owned(),
+                        (count, true) => count.to_string(),
+                        (count, false) => format!("{count}+"),
+                    },
+                );
+            }
+            row
+        })
+        .collect();
+    let header: &[&str] = if fs_checks {
+        &[
+            "Id",
+            "Repository",
+            "Passphrase",
+            "Borg log",
+            "Excluded dirs",
+        ]
+    } else {
+        &["Id", "Repository", "Passphrase", "Borg log"]
+    };
+    print_table(header, &rows);
+
+    for backup in config.backups.iter().filter(|b| !b.allow_nested_paths) {
+        for (outer, inner) in backup.archive.nested_paths() {
+            let warning = format!(
+                "[{}] {} is inside {}, remove it or set allow_nested_paths = true",
+                backup.id(),
+                inner.display(),
+                outer.display()
+            );
+            eprintln!("{}", style::warning_stderr(warning));
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// The level borg logs at for the backup, e.g. "debug, rc", "default" if the command decides
+fn borg_logging(backup: &Backup) -> String {
+    let level = match backup.borg_log_level {
+        None => "default",
+        Some(log::Level::Error) => "error",
+        Some(log::Level::Warn) => "warning",
+        Some(log::Level::Info) => "info",
+        Some(log::Level::Debug | log::Level::Trace) => "debug",
+    };
+    match backup.show_rc {
+        true => format!("{level}, rc"),
+        false => level.to_owned(),
+    }
+}
+
+/// Most directory entries [`count_marked`] looks at per backup
+const WALK_LIMIT: usize = 100_000;
+
+/// Number of directories under `paths` which contain one of `markers`, and whether all
+/// entries were looked at within `limit`
+///
+/// Like borg it doesn't follow symlinks or descend into excluded directories.
+fn count_marked(paths: &[std::path::PathBuf], markers: &[String], limit: usize) -> (usize, bool) {
+    if markers.is_empty() {
+        return (0, true);
+    }
+    let mut stack: Vec<_> = paths.iter().map(crate::util::resolve_path).collect();
+    let (mut count, mut seen) = (0, 0);
+    while let Some(dir) = stack.pop() {
+        if markers.iter().any(|m| dir.join(m).exists()) {
+            count += 1;
+            continue;
+        }
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            seen += 1;
+            if seen > limit {
+                return (count, false);
+            }
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                stack.push(entry.path());
+            }
+        }
+    }
+    (count, true)
+}
+
+/// Placeholder for secrets which borrg doesn't export
+const SECRET_PLACEHOLDER: &str = "<secret>";
+
+#[derive(Serialize)]
+struct ExportedBackup {
+    backup: String,
+    commands: Vec<ExportedCommand>,
+}
+
+#[derive(Serialize)]
+struct ExportedCommand {
+    program: String,
+    args: Vec<String>,
+    /// Secret values are null
+    env: BTreeMap<String, Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_dir: Option<String>,
+}
+
+impl From<&Invocation> for ExportedCommand {
+    fn from(invocation: &Invocation) -> Self {
+        let lossy = |s: &std::ffi::OsString| s.to_string_lossy().into_owned();
+        ExportedCommand {
+            program: lossy(&invocation.program),
+            args: invocation.args.iter().map(lossy).collect(),
+            env: invocation
+                .env
+                .iter()
+                .map(|(name, value)| (lossy(name), value.as_ref().map(lossy)))
+                .collect(),
+            current_dir: invocation
+                .current_dir
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+        }
+    }
+}
+
+fn export(config: &Config, format: ExportFormat) -> ExitCode {
+    let mut borg = Borg::default();
+    borg.cache(config.cache.clone());
+
+    let mut success = true;
+    let mut backups = vec![];
+    for backup in &config.backups {
+        let mut commands = vec![];
+        for invocation in run::invocations(&borg, backup, false) {
+            match invocation {
+                Ok(invocation) => commands.push(invocation),
+                Err(e) => {
+                    eprintln!("{}", style::error_stderr(format!("[{backup}] {e}")));
+                    success = false;
+                }
+            }
+        }
+        backups.push((backup.to_string(), commands));
+    }
+
+    match format {
+        ExportFormat::Shell => print!("{}", shell_script(&config.source, &backups)),
+        ExportFormat::Json => {
+            #[derive(Serialize)]
+            struct Output {
+                backups: Vec<ExportedBackup>,
+            }
+            let backups = backups
+                .iter()
+                .map(|(backup, commands)| ExportedBackup {
+                    backup: backup.clone(),
+                    commands: commands.iter().map(ExportedCommand::from).collect(),
+                })
+                .collect();
+            print_json(Output { backups });
+        }
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Every backup runs in a subshell, so its environment doesn't leak into the next one
+fn shell_script(source: &Path, backups: &[(String, Vec<Invocation>)]) -> String {
+    let mut script = format!(
+        "#!/bin/sh\n# borg commands of the backups in {}\nset -e\n",
+        source.display()
+    );
+
+    for (backup, commands) in backups {
+        script.push_str(&format!("\n# {backup}\n(\n"));
+
+        let mut exported: Vec<&std::ffi::OsString> = vec![];
+        for (name, value) in commands.iter().flat_map(|c| &c.env) {
+            if exported.contains(&name) {
+                continue;
+            }
+            exported.push(name);
+
+            let name = name.to_string_lossy();
+            match value {
+                Some(value) => script.push_str(&format!(
+                    "    export {name}={}\n",
+                    shell_quote(&value.to_string_lossy())
+                )),
+                None => script.push_str(&format!(
+                    "    # Not exported by borrg, fill in before running\n    export {name}='{SECRET_PLACEHOLDER}'\n"
+                )),
+            }
+        }
+
+        for command in commands {
+            match &command.current_dir {
+                Some(dir) => script.push_str(&format!(
+                    "    (cd {} && {})\n",
+                    shell_quote(&dir.to_string_lossy()),
+                    command.command_line()
+                )),
+                None => script.push_str(&format!("    {}\n", command.command_line())),
+            }
+        }
+        script.push_str(")\n");
+    }
+
+    script
+}
+
+#[cfg(feature = "borgmatic")]
+fn import_borgmatic(file: &Path, output: &Path, exclude_file: &Path) -> Result<(), crate::Error> {
+    let yaml = std::fs::read_to_string(file)?;
+    let mut import = borgmatic::convert(&yaml, exclude_file)?;
+
+    import.document.decor_mut().set_prefix(format!(
+        "# Imported from borgmatic config {}\n\n",
+        file.display()
+    ));
+
+    // Never overwrite an existing config or exclude file
+    write_new(output, &import.document.to_string())?;
+    let mut patterns = String::from("# Exclude patterns imported from borgmatic\n");
+    for pattern in &import.excludes {
+        patterns.push_str(pattern);
+        patterns.push('\n');
+    }
+    write_new(exclude_file, &patterns)?;
+
+    println!("Wrote {}", output.display());
+    println!("Wrote {}", exclude_file.display());
+
+    if !import.skipped.is_empty() {
+        eprintln!(
+            "{}",
+            style::warning(format!(
+                "Could not translate {} setting(s):",
+                import.skipped.len()
+            ))
+            .for_stderr()
+        );
+        for (key, reason) in &import.skipped {
+            eprintln!("  {key}: {reason}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "borgmatic")]
+fn write_new(path: &Path, contents: &str) -> Result<(), crate::Error> {
+    use std::io::Write;
+
+    // Not atomic_write, its rename would replace a file created in the meantime
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_marked() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-marked-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        for sub in ["a/cache", "a/cache/deep", "b/tmp", "c"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for marked in ["a/cache", "a/cache/deep", "b/tmp"] {
+            std::fs::write(dir.join(marked).join(".nobackup"), "").unwrap();
+        }
+        let markers = [".nobackup".to_owned()];
+
+        let paths = [dir.clone()];
+
+        // Excluded directories aren't descended into
+        assert_eq!(count_marked(&paths, &markers, 100), (2, true));
+        assert_eq!(count_marked(&[dir.join("c")], &markers, 100), (0, true));
+        assert!(!count_marked(&paths, &markers, 2).1);
+        assert_eq!(count_marked(&paths, &[], 100), (0, true));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_shell_script() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-export-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(".borgignore"), "").unwrap();
+        let config_path = dir.join("borrg.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "[cache]\ndir = \"/var/cache/borg\"\n\
+                [[backup]]\nrepository = \"/srv/backup\"\npassphrase = \"hunter2\"\npath = \"{0}\"\n\
+                [[backup]]\nrepository = \"/srv/other\"\npath = \"{0}\"\n\
+                passcommand = [\"pass\", \"show\", \"borg key\"]\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        let config = Config::load(&config_path).unwrap();
+
+        let mut borg = Borg::default();
+        borg.cache(config.cache.clone());
+        let commands: Vec<_> = run::invocations(&borg, &config.backups[0], false)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        let exported = ExportedCommand::from(&commands[0]);
+        assert_eq!(exported.env.get("BORG_PASSPHRASE"), Some(&None));
+
+        let script = shell_script(&config_path, &[("backup".to_owned(), commands)]);
+
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("export BORG_CACHE_DIR=/var/cache/borg\n"));
+        assert!(script.contains("export BORG_PASSPHRASE='<secret>'\n"));
+        assert!(script.contains("    borg create "));
+        assert!(!script.contains("hunter2"));
+
+        // The passcommand is no secret, borg runs it instead of borrg
+        let commands: Vec<_> = run::invocations(&borg, &config.backups[1], false)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        let script = shell_script(&config_path, &[("other".to_owned(), commands)]);
+        assert!(script.contains(r"    export BORG_PASSCOMMAND='pass show '\''borg key'\'''"));
+        assert!(!script.contains("BORG_PASSPHRASE"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
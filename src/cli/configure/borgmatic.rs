@@ -0,0 +1,290 @@
+use serde_yaml::{Mapping, Value};
+use std::path::Path;
+use toml_edit::{value, Array, DocumentMut, InlineTable, Item, Table};
+
+/// Sections of the old borgmatic config format, newer versions use a flat mapping
+const SECTIONS: &[&str] = &[
+    "location",
+    "storage",
+    "retention",
+    "consistency",
+    "hooks",
+    "output",
+];
+
+const RETENTION: &[&str] = &[
+    "keep_within",
+    "keep_hourly",
+    "keep_daily",
+    "keep_weekly",
+    "keep_monthly",
+    "keep_yearly",
+];
+
+/// A borgmatic config translated to borrg
+pub(in crate::cli) struct Import {
+    pub document: DocumentMut,
+    /// Patterns for the exclude file referenced by the document
+    pub excludes: Vec<String>,
+    /// Settings which couldn't be translated, with the reason
+    pub skipped: Vec<(String, &'static str)>,
+}
+
+/// Translate a borgmatic config, exclude patterns are expected to be written to `exclude_file`
+pub(in crate::cli) fn convert(yaml: &str, exclude_file: &Path) -> Result<Import, crate::Error> {
+    let root: Mapping = serde_yaml::from_str(yaml)?;
+
+    // Merge the sections of the old format into one mapping
+    let mut settings = Vec::new();
+    for (key, val) in root {
+        let key = key
+            .as_str()
+            .ok_or("borgmatic config keys must be strings")?;
+        match val {
+            Value::Mapping(section) if SECTIONS.contains(&key) => {
+                for (k, v) in section {
+                    let k = k.as_str().ok_or("borgmatic config keys must be strings")?;
+                    settings.push((k.to_owned(), v));
+                }
+            }
+            val => settings.push((key.to_owned(), val)),
+        }
+    }
+
+    let mut skipped = vec![];
+    let mut repositories = vec![];
+    let mut excludes = vec![];
+    let mut template = Table::new();
+    let mut retention = InlineTable::new();
+
+    for (key, val) in settings {
+        match (key.as_str(), val) {
+            ("repositories", Value::Sequence(repos)) => {
+                for repo in repos {
+                    match repo {
+                        Value::String(path) => repositories.push(path),
+                        Value::Mapping(repo) => {
+                            match repo.get("path") {
+                                Some(Value::String(path)) => repositories.push(path.to_owned()),
+                                _ => skipped.push((
+                                    "repositories[]".to_owned(),
+                                    "repository without a path",
+                                )),
+                            }
+                            if repo.contains_key("label") {
+                                skipped.push((
+                                    "repositories[].label".to_owned(),
+                                    "repositories have no names",
+                                ));
+                            }
+                        }
+                        _ => return Err("Invalid repository".into()),
+                    }
+                }
+            }
+            ("source_directories", Value::Sequence(paths)) => {
+                template["path"] = value(strings(&paths).collect::<Array>());
+            }
+            ("exclude_patterns", Value::Sequence(patterns)) => {
+                excludes.extend(strings(&patterns).map(str::to_owned));
+            }
+            ("patterns_from", Value::Sequence(files)) if files.len() == 1 => {
+                if let Some(file) = strings(&files).next() {
+                    template["pattern_file"] = value(file);
+                }
+            }
+            ("encryption_passphrase", Value::String(passphrase)) => {
+                template["passphrase"] = value(passphrase);
+            }
+            ("encryption_passcommand", Value::String(command)) => {
+                template["passcommand"] = value(command);
+            }
+            ("compression", Value::String(compression)) => {
+                match convert_compression(&compression) {
+                    Some(compression) => template["compression"] = compression,
+                    None => skipped.push((key, "unsupported compression")),
+                }
+            }
+            ("keep_within", Value::String(within)) => {
+                retention.insert("keep_within", within.into());
+            }
+            (rule, Value::Number(n)) if RETENTION.contains(&rule) => match n.as_i64() {
+                Some(n) => {
+                    retention.insert(rule, n.into());
+                }
+                None => skipped.push((key, "not an integer")),
+            },
+            ("before_backup" | "after_backup" | "on_error", _) => {
+                skipped.push((key, "hooks aren't supported"))
+            }
+            ("exclude_from", _) => skipped.push((key, "only a single exclude file is supported")),
+            _ => skipped.push((key, "no equivalent")),
+        }
+    }
+
+    if repositories.is_empty() {
+        return Err("No repositories in borgmatic config".into());
+    }
+
+    // Always reference the exclude file, otherwise borrg expects a .borgignore
+    template["exclude_file"] = value(exclude_file.display().to_string());
+
+    if !retention.is_empty() {
+        template["retention"] = value(retention);
+    }
+
+    let mut templates = Table::new();
+    templates.set_implicit(true);
+    templates.insert("default", Item::Table(template));
+
+    let mut document = DocumentMut::new();
+    document.insert("template", Item::Table(templates));
+
+    let mut backups = toml_edit::ArrayOfTables::new();
+    for repository in repositories {
+        let mut backup = Table::new();
+        backup["repository"] = value(repository);
+        backups.push(backup);
+    }
+    document.insert("backup", Item::ArrayOfTables(backups));
+
+    Ok(Import {
+        document,
+        excludes,
+        skipped,
+    })
+}
+
+fn strings(values: &[Value]) -> impl Iterator<Item = &str> {
+    values.iter().filter_map(Value::as_str)
+}
+
+/// borgmatic passes compression through to borg, e.g. "auto,zstd,10"
+fn convert_compression(compression: &str) -> Option<Item> {
+    let mut parts: Vec<_> = compression.split(',').collect();
+    let auto = parts.first() == Some(&"auto");
+    if auto {
+        parts.remove(0);
+    }
+
+    let (algorithm, level) = match parts[..] {
+        [algorithm] => (algorithm, None),
+        [algorithm, level] => (algorithm, Some(level.parse::<i64>().ok()?)),
+        _ => return None,
+    };
+    if !["none", "lz4", "zstd", "zlib", "lzma"].contains(&algorithm) {
+        return None;
+    }
+
+    if !auto && level.is_none() {
+        return Some(value(algorithm));
+    }
+
+    let mut table = InlineTable::new();
+    table.insert("algorithm", algorithm.into());
+    if let Some(level) = level {
+        table.insert("level", level.into());
+    }
+    if auto {
+        table.insert("auto", true.into());
+    }
+    Some(value(table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Config;
+
+    const BORGMATIC: &str = r#"
+location:
+    source_directories:
+        - /home
+        - /etc
+    repositories:
+        - ssh://user@backupserver/./sourcehostname.borg
+        - path: /mnt/backup
+          label: local
+        - label: nowhere
+    exclude_patterns:
+        - '*.pyc'
+        - /home/*/.cache
+storage:
+    encryption_passcommand: secret-tool lookup borg-repository repo-name
+    compression: auto,zstd,10
+    checkpoint_interval: 1800
+retention:
+    keep_daily: 7
+    keep_weekly: 4
+    keep_secondly: 60
+hooks:
+    before_backup:
+        - echo "Starting a backup."
+"#;
+
+    #[test]
+    fn test_convert() {
+        let import = convert(BORGMATIC, Path::new("/etc/borrg.exclude")).unwrap();
+
+        assert_eq!(import.excludes, ["*.pyc", "/home/*/.cache"]);
+        let skipped: Vec<_> = import.skipped.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(
+            skipped,
+            [
+                "repositories[].label",
+                "repositories[]",
+                "repositories[].label",
+                "checkpoint_interval",
+                "keep_secondly",
+                "before_backup"
+            ]
+        );
+
+        // The result has to be a valid borrg config
+        let path = std::env::temp_dir().join(format!(
+            "borrg-test-import-borgmatic-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, import.document.to_string()).unwrap();
+        let config = Config::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(config.backups.len(), 2);
+        let backup = &config.backups[1];
+        assert_eq!(backup.repo.to_string(), "/mnt/backup");
+        assert_eq!(
+            backup.archive.paths,
+            [Path::new("/home"), Path::new("/etc")]
+        );
+        assert_eq!(
+            backup.archive.exclude_file.as_deref(),
+            Some(Path::new("/etc/borrg.exclude"))
+        );
+        assert_eq!(
+            backup.archive.compression.as_ref().unwrap().to_string(),
+            "auto,zstd,10"
+        );
+        let retention = backup.retention.as_ref().unwrap();
+        assert_eq!(retention.keep_daily, Some(7));
+        assert_eq!(retention.keep_weekly, Some(4));
+        assert_eq!(
+            backup.repo.passphrase,
+            Some(crate::Passphrase::Command(
+                "secret-tool lookup borg-repository repo-name".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_convert_flat() {
+        let yaml = "repositories: ['/srv/repo']\nsource_directories: ['/home']\ncompression: lz4\n";
+        let import = convert(yaml, Path::new("/tmp/excludes")).unwrap();
+        assert!(import.skipped.is_empty());
+        assert!(import
+            .document
+            .to_string()
+            .contains("compression = \"lz4\""));
+
+        assert!(convert("source_directories: ['/home']", Path::new("x")).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use super::run::{
+    expand_series, prefix, print_summary, record_history, record_state, run_backups, RunContext,
+};
+use super::*;
+use crate::{
+    backend,
+    runner::{RunEvent, Runner},
+    util::random_delay,
+    Borg, Event, RepoInfoCache,
+};
+use chrono::{DateTime, Local};
+use log::Level;
+use std::{
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How long the daemon uses what `borg info` said about a repository, others may change it
+const INFO_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Delay every run by a random duration up to this (e.g. 10m) to spread the load
+    #[arg(long, value_parser = parse_duration)]
+    jitter: Option<Duration>,
+
+    /// Prune (and compact) repositories after successful backups according to their retention policy
+    #[arg(long)]
+    prune: bool,
+}
+
+/// Run backups according to their `schedule` or `every` setting until killed
+///
+/// The config is reloaded on SIGHUP.
+pub fn daemon(mut borg: Borg, mut config: Config, args: Args) -> ExitCode {
+    let reload = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload)) {
+        log::warn!("Failed to register SIGHUP handler: {e}");
+    }
+    borg.info_cache(Arc::new(RepoInfoCache::with_ttl(INFO_CACHE_TTL)));
+
+    let mut next = plan(
+        &config.backups,
+        Local::now(),
+        args.jitter,
+        &last_runs(&config.backups),
+    );
+    if next.iter().all(Option::is_none) {
+        eprintln!("{}", style::error_stderr("No backup has a schedule"));
+        return ExitCode::FAILURE;
+    }
+    print_upcoming(&config.backups, &next);
+
+    loop {
+        if reload.swap(false, Ordering::Relaxed) {
+            match Config::load(&config.source) {
+                Ok(new) => {
+                    println!("Reloaded config from {}", new.source.display());
+                    config = new;
+                    borg.cache(config.cache.clone());
+                    next = plan(
+                        &config.backups,
+                        Local::now(),
+                        args.jitter,
+                        &last_runs(&config.backups),
+                    );
+                    print_upcoming(&config.backups, &next);
+                }
+                Err(e) => eprintln!(
+                    "{}",
+                    style::error_stderr(format!(
+                        "Failed to reload config, keeping the old one: {e}"
+                    ))
+                ),
+            }
+        }
+
+        let now = Local::now();
+        let due: Vec<_> = (0..config.backups.len())
+            .filter(|&idx| next[idx].is_some_and(|t| t <= now))
+            .collect();
+
+        if due.is_empty() {
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        // Archive names have to be unique, even for multiple runs per day
+        let name = now.format("%Y-%m-%dT%H:%M:%S").to_string();
+        let mut backups: Vec<_> = due
+            .iter()
+            .map(|&idx| {
+                let mut backup = config.backups[idx].clone();
+                backup.archive.name = match backup.name_template {
+                    Some(_) => backup.archive_name_at(now.into()),
+                    None => backup.disambiguate(name.clone()),
+                };
+                backup
+            })
+            .collect();
+
+        // Of backups with archive series, only the due series run
+        let mut rejected = vec![None; backups.len()];
+        for (backup, reason) in expand_series(&borg, &mut backups, &mut rejected, now.into()) {
+            println!("[{backup}] {reason}");
+        }
+        if !backups.is_empty() {
+            run_round(&borg, backups, config.limits.clone(), args.prune);
+        }
+
+        let now = Local::now();
+        for idx in due {
+            next[idx] = plan_one(&config.backups[idx], now, args.jitter);
+        }
+        print_upcoming(&config.backups, &next);
+    }
+}
+
+/// Run backups in parallel and print their summary
+fn run_round(borg: &Borg, mut backups: Vec<Backup>, limits: Limits, prune: bool) {
+    let ctx = RunContext::new();
+    log::info!("Starting run {}", ctx.id);
+    ctx.apply(&mut backups);
+
+    let prefixes: Vec<_> = backups
+        .iter()
+        .map(|b| prefix(b, true, borg.dry_run))
+        .collect();
+    let repos: Vec<_> = backups.iter().map(|b| b.repo.clone()).collect();
+    let ids: Vec<_> = backups.iter().map(|b| b.id().to_owned()).collect();
+    // The daemon never asks for passphrases, but reads `paths_from` every round
+    let rejected = PathsFrom::default().apply(&mut backups);
+
+    let mut runner = Runner::new(borg.clone());
+    runner.limits(limits);
+    let results =
+        run_backups::<backend::borg::BorgWrapper>(&runner, backups, rejected, prune, None, {
+            let prefixes = prefixes.clone();
+            let mut repeats: Vec<_> = prefixes
+                .iter()
+                .map(|_| render::Repeats::default())
+                .collect();
+            move |idx, event| match event {
+                RunEvent::Event(Event::Error(e)) => {
+                    eprintln!("{}{}", prefixes[idx], style::error_stderr(e))
+                }
+                RunEvent::Event(Event::LogMessage {
+                    level: Some(Level::Warn),
+                    msgid,
+                    message,
+                    ..
+                }) if !repeats[idx].first(msgid.as_deref(), &message) => {}
+                RunEvent::Event(Event::LogMessage {
+                    level: Some(level),
+                    message,
+                    ..
+                }) if level <= Level::Warn => eprintln!("{}{message}", prefixes[idx]),
+                RunEvent::Finished(_) => {
+                    for line in repeats[idx].summary() {
+                        eprintln!("{}{line}", prefixes[idx]);
+                    }
+                }
+                _ => {}
+            }
+        });
+
+    record_history(&ctx, &repos, &ids, &results);
+    record_state(&ctx, &ids, &results);
+    if let Err(e) = print_summary(&ctx, &results, &prefixes) {
+        log::warn!("Failed to print the summary: {e}");
+    }
+}
+
+/// When each backup is due next, `None` if it has no schedule
+///
+/// Backups with an `every` interval continue from their last run, also one of `borrg run`,
+/// instead of starting over whenever the daemon starts.
+fn plan(
+    backups: &[Backup],
+    now: DateTime<Local>,
+    jitter: Option<Duration>,
+    last_runs: &[Option<DateTime<Local>>],
+) -> Vec<Option<DateTime<Local>>> {
+    backups
+        .iter()
+        .zip(last_runs)
+        .map(|(backup, last)| match (&backup.schedule, last) {
+            (Some(Schedule::Every(_)), Some(last)) => {
+                plan_one(backup, *last, jitter).map(|next| next.max(now))
+            }
+            _ => plan_one(backup, now, jitter),
+        })
+        .collect()
+}
+
+/// When each backup last ran according to its state file, the newest of its series
+fn last_runs(backups: &[Backup]) -> Vec<Option<DateTime<Local>>> {
+    let store = match state::StateStore::default_dir().map(state::StateStore::open) {
+        Some(Ok(store)) => store,
+        Some(Err(e)) => {
+            log::warn!("Failed to open state directory: {e}");
+            return vec![None; backups.len()];
+        }
+        None => return vec![None; backups.len()],
+    };
+    backups
+        .iter()
+        .map(|backup| {
+            let series = backup.series.iter().map(|s| backup.series_id(s));
+            std::iter::once(backup.id().to_owned())
+                .chain(series)
+                .filter_map(|id| store.load(&id).last_run)
+                .map(|run| run.time.into())
+                .max()
+        })
+        .collect()
+}
+
+fn plan_one(
+    backup: &Backup,
+    after: DateTime<Local>,
+    jitter: Option<Duration>,
+) -> Option<DateTime<Local>> {
+    let next = backup.schedule.as_ref()?.next_after(after)?;
+    match jitter {
+        Some(max) => Some(next + chrono::Duration::from_std(random_delay(max)).ok()?),
+        None => Some(next),
+    }
+}
+
+fn print_upcoming(backups: &[Backup], next: &[Option<DateTime<Local>>]) {
+    for (backup, next) in backups.iter().zip(next) {
+        if let (Some(next), Some(schedule)) = (next, &backup.schedule) {
+            println!(
+                "[{backup}] next run at {} ({schedule})",
+                next.format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Vec<Backup> {
+        ConfigProperty::parse(&toml.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_plan() {
+        let backups = config(
+            r#"
+            [[backup]]
+            repository = "/srv/a"
+            schedule = "30 3 * * *"
+            [[backup]]
+            repository = "/srv/b"
+            every = "6h"
+            [[backup]]
+            repository = "/srv/c"
+            "#,
+        );
+
+        let now = "2024-05-02T12:00:00+02:00"
+            .parse::<DateTime<chrono::FixedOffset>>()
+            .unwrap()
+            .with_timezone(&Local);
+        let next = plan(&backups, now, None, &[None; 3]);
+
+        let cron = next[0].unwrap();
+        assert!(cron > now && cron - now <= chrono::Duration::days(1));
+        assert_eq!(cron.format("%M").to_string(), "30");
+        assert_eq!(next[1], Some(now + chrono::Duration::hours(6)));
+        assert_eq!(next[2], None);
+
+        let jittered = plan(&backups, now, Some(Duration::from_secs(600)), &[None; 3]);
+        let delay = jittered[1].unwrap() - next[1].unwrap();
+        assert!(delay >= chrono::Duration::zero() && delay < chrono::Duration::minutes(10));
+
+        // Intervals continue from the last run, overdue backups run right away
+        let last = Some(now - chrono::Duration::hours(2));
+        let next = plan(&backups, now, None, &[last; 3]);
+        assert!(next[0].unwrap() > now);
+        assert_eq!(next[1], Some(now + chrono::Duration::hours(4)));
+        assert_eq!(next[2], None);
+
+        let last = Some(now - chrono::Duration::days(1));
+        let next = plan(&backups, now, None, &[last; 3]);
+        assert_eq!(next[1], Some(now));
+    }
+}
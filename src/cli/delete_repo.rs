@@ -0,0 +1,247 @@
+use super::*;
+use crate::{backend, Backend, Borg, Event, Repo};
+use std::io::BufRead;
+use std::ops::ControlFlow;
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Repository of a configured backup, or any repository location
+    #[arg(value_name = "BACKUP-OR-LOCATION")]
+    target: String,
+
+    /// Only delete the local cache of the repository
+    #[arg(long)]
+    cache_only: bool,
+
+    /// Keep the local security info, e.g. to detect a replaced repository later
+    #[arg(long)]
+    keep_security_info: bool,
+
+    /// Don't ask to retype the repository location, needed in batch mode
+    #[arg(long, visible_alias = "yes")]
+    yes_i_know: bool,
+}
+
+pub fn delete_repo(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let stdin = std::io::stdin();
+    delete_repo_with::<backend::borg::BorgWrapper>(borg, config, args, &mut stdin.lock())
+}
+
+fn delete_repo_with<B: Backend<Update = Event>>(
+    borg: Borg,
+    config: Config,
+    args: Args,
+    input: &mut impl BufRead,
+) -> ExitCode {
+    let repo = match resolve(&config, &args.target) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if !args.yes_i_know && !borg.dry_run {
+        let what = match args.cache_only {
+            true => "the cache of",
+            false => "the repository and all archives in",
+        };
+        if borg.interaction.is_batch() {
+            let message = format!("Not deleting {what} {repo} without --yes-i-know in batch mode");
+            eprintln!("{}", style::error_stderr(message));
+            return ExitCode::FAILURE;
+        }
+        eprintln!(
+            "{}",
+            style::warning_stderr(format!("This will delete {what} {repo}"))
+        );
+        if !confirm_location(&repo, input) {
+            eprintln!(
+                "{}",
+                style::error_stderr("Location doesn't match, nothing deleted")
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let res =
+        borg.delete_repository::<B>(&repo, args.cache_only, args.keep_security_info, |event| {
+            match event {
+                Event::Error(e) => eprintln!("{}", style::error_stderr(e)),
+                event => eprintln!("{event}"),
+            }
+            ControlFlow::Continue(())
+        });
+
+    match res {
+        Ok(()) if args.cache_only => println!("Deleted the cache of {repo}"),
+        Ok(()) => println!("Deleted repository {repo}"),
+        Err(e) => {
+            eprintln!(
+                "{}",
+                style::error_stderr(format!("Failed to delete {repo}: {e}"))
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Find the repository meant by `target`, a configured backup or any location
+///
+/// Nothing is guessed, a location which isn't configured is taken as it is.
+fn resolve(config: &Config, target: &str) -> Result<Repo, String> {
+    let backups = config.select(Some(target)).unwrap_or_default();
+
+    let mut repos: Vec<&Repo> = vec![];
+    for backup in backups {
+        if !repos.contains(&&backup.repo) {
+            repos.push(&backup.repo);
+        }
+    }
+
+    match repos[..] {
+        [repo] => Ok(repo.to_owned()),
+        [] => target
+            .parse()
+            .map_err(|e| format!("Invalid repository \"{target}\": {e}")),
+        _ => {
+            let repos: Vec<_> = repos.iter().map(ToString::to_string).collect();
+            Err(format!(
+                "\"{target}\" matches several repositories ({}), use the full location",
+                repos.join(", ")
+            ))
+        }
+    }
+}
+
+/// Make the user retype the location of the repository
+fn confirm_location(repo: &Repo, input: &mut impl BufRead) -> bool {
+    use std::io::Write;
+
+    eprint!("Type \"{repo}\" to confirm: ");
+    std::io::stderr().flush().ok();
+
+    let mut answer = String::new();
+    if input.read_line(&mut answer).is_err() {
+        return false;
+    }
+    answer.trim() == repo.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::Mock;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static DELETED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    }
+
+    /// Backend which only records deleted repositories
+    struct MockBackend;
+
+    impl Mock for MockBackend {
+        type Update = Event;
+
+        fn delete_repository(
+            _: &Borg,
+            repository: &Repo,
+            _: bool,
+            _: bool,
+            _: impl FnMut(Event) -> ControlFlow<()>,
+        ) -> crate::Result<()> {
+            DELETED.with(|d| d.borrow_mut().push(repository.to_string()));
+            Ok(())
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            source: "borrg.toml".into(),
+            backups: ConfigProperty::parse(
+                &r#"
+                [[backup]]
+                repository = "ssh://backup.example.com/srv/home"
+                path = "/home"
+                [[backup]]
+                repository = "/mnt/usb/home"
+                path = "/home"
+                [[backup]]
+                repository = "/mnt/usb/etc"
+                path = "/etc"
+                "#
+                .parse()
+                .unwrap(),
+            )
+            .unwrap(),
+            cache: Default::default(),
+            info_timeout: Config::DEFAULT_INFO_TIMEOUT,
+            limits: Default::default(),
+            key_backup_dir: None,
+        }
+    }
+
+    fn delete(target: &str, yes_i_know: bool, input: &str) -> (ExitCode, Vec<String>) {
+        delete_with(Borg::default(), target, yes_i_know, input)
+    }
+
+    fn delete_with(
+        borg: Borg,
+        target: &str,
+        yes_i_know: bool,
+        input: &str,
+    ) -> (ExitCode, Vec<String>) {
+        DELETED.with(|d| d.borrow_mut().clear());
+        let args = Args {
+            target: target.to_owned(),
+            cache_only: false,
+            keep_security_info: false,
+            yes_i_know,
+        };
+        let code = delete_repo_with::<MockBackend>(borg, config(), args, &mut input.as_bytes());
+        (code, DELETED.with(|d| d.borrow().clone()))
+    }
+
+    #[test]
+    fn test_confirmation() {
+        let (code, deleted) = delete("/mnt/usb/etc", false, "/mnt/usb/etc\n");
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert_eq!(deleted, ["/mnt/usb/etc"]);
+
+        let (code, deleted) = delete("/mnt/usb/etc", false, "");
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(deleted.is_empty());
+
+        // Batch mode doesn't ask, not even with the right answer waiting
+        let mut batch = Borg::default();
+        batch.interaction(crate::Interaction::Batch);
+        let (code, deleted) = delete_with(batch.clone(), "/mnt/usb/etc", false, "/mnt/usb/etc\n");
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(deleted.is_empty());
+
+        let (code, deleted) = delete_with(batch, "/mnt/usb/etc", true, "");
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert_eq!(deleted, ["/mnt/usb/etc"]);
+    }
+
+    #[test]
+    fn test_unknown_location() {
+        // Unknown locations are taken as they are
+        let (code, deleted) = delete("/tmp/test-repo", true, "");
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert_eq!(deleted, ["/tmp/test-repo"]);
+
+        // The end of a configured location is no match, neither of a remote nor a local one
+        let (code, deleted) = delete("/srv/home", true, "");
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert_eq!(deleted, ["/srv/home"]);
+
+        let (code, deleted) = delete("usb/etc", false, "/mnt/usb/etc\n");
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(deleted.is_empty());
+    }
+}
@@ -0,0 +1,359 @@
+use super::*;
+use crate::{backend::borg::BorgWrapper, Backend, Borg, Repo, Version};
+use key::KeyBackup;
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup, all backups if omitted
+    backup: Option<String>,
+
+    /// Compare the version of borg on the hosts of remote repositories with the local one
+    ///
+    /// Like every check it also runs without any option.
+    #[arg(long)]
+    remote_versions: bool,
+
+    /// Compare the configured append_only and storage_quota of each backup with the config of
+    /// its repository
+    #[arg(long)]
+    repo_config: bool,
+
+    /// Check that the keys of keyfile-mode repositories are exported to key_backup_dir, and not
+    /// older than the key files
+    #[arg(long)]
+    keys: bool,
+}
+
+pub fn doctor(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let backups = match config.select(args.backup.as_deref()) {
+        Ok(backups) => backups,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Without any option every check runs
+    let all = !args.remote_versions && !args.repo_config && !args.keys;
+    let mut success = true;
+
+    if args.remote_versions || all {
+        let local = match BorgWrapper::version(&borg) {
+            Ok(version) => version,
+            Err(e) => {
+                let message = format!("Failed to get the version of borg: {e}");
+                println!("{}", style::error(message));
+                return ExitCode::FAILURE;
+            }
+        };
+        println!("borg {local} here");
+
+        let repos = remote_repos(backups.iter().map(|b| &b.repo));
+        if repos.is_empty() {
+            println!("No remote repositories");
+        }
+        let versions = parallel_map(&repos, |repo| RemoteVersion::check(&borg, repo, &local));
+        for (repo, version) in repos.iter().zip(versions) {
+            match &version {
+                RemoteVersion::Compatible(remote) => {
+                    println!("[{repo}] {}", style::success(format!("borg {remote}")))
+                }
+                RemoteVersion::Mismatch(_) => {
+                    success = false;
+                    let warning = version.warning(repo, &local).unwrap_or_default();
+                    println!("[{repo}] {}", style::error(warning));
+                }
+                RemoteVersion::Unknown(_) | RemoteVersion::Unreachable(_) => {
+                    let warning = version.warning(repo, &local).unwrap_or_default();
+                    println!("[{repo}] {}", style::warning(warning));
+                }
+            }
+        }
+    }
+
+    if args.repo_config || all {
+        success &= check_repo_configs(&borg, &backups);
+    }
+
+    if args.keys || all {
+        success &= check_key_backups(&borg, &config, &backups);
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Print where repositories differ from the config of their backups, returns whether none does
+///
+/// Backups sharing a repository are compared with it each, they may disagree.
+fn check_repo_configs(borg: &Borg, backups: &[&Backup]) -> bool {
+    let declared: Vec<_> = backups
+        .iter()
+        .filter(|b| b.init.append_only.is_some() || b.init.storage_quota.is_some())
+        .collect();
+    if declared.is_empty() {
+        println!("No backup sets append_only or storage_quota");
+        return true;
+    }
+
+    let drifts = parallel_map(&declared, |backup| read_drift(borg, backup));
+    let mut success = true;
+    for (backup, drift) in declared.iter().zip(drifts) {
+        match drift {
+            Ok(drift) if drift.is_empty() => {
+                println!(
+                    "[{backup}] {}",
+                    style::success("repository matches the config")
+                )
+            }
+            Ok(drift) => {
+                success = false;
+                for difference in drift {
+                    println!("[{backup}] {}", style::error(difference));
+                }
+            }
+            Err(e) => {
+                let warning = format!("Failed to read the config of {}: {e}", backup.repo);
+                println!("[{backup}] {}", style::warning(warning));
+            }
+        }
+    }
+    success
+}
+
+/// Print whether the keys of keyfile-mode repositories are exported, returns whether all are
+///
+/// Without `key_backup_dir` a keyfile-mode repository is only warned about.
+fn check_key_backups(borg: &Borg, config: &Config, backups: &[&Backup]) -> bool {
+    let mut repos: Vec<&Repo> = vec![];
+    for backup in backups {
+        if !repos.contains(&&backup.repo) {
+            repos.push(&backup.repo);
+        }
+    }
+    let infos = parallel_map(&repos, |repo| borg.repo_info::<BorgWrapper>(repo));
+
+    let mut success = true;
+    let mut keyfile_repos = 0;
+    for (repo, info) in repos.iter().zip(infos) {
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => {
+                let warning = format!("Failed to get the info of {repo}: {e}");
+                println!("[{repo}] {}", style::warning(warning));
+                continue;
+            }
+        };
+        let key_backup = KeyBackup::check(&info, config.key_backup_dir.as_deref());
+        match (&key_backup, key_backup.warning(&info)) {
+            (KeyBackup::NotNeeded, _) => continue,
+            (KeyBackup::Current(path), _) => {
+                let message = format!("key exported to {}", path.display());
+                println!("[{repo}] {}", style::success(message));
+            }
+            (KeyBackup::Unconfigured, Some(warning)) => {
+                println!("[{repo}] {}", style::warning(warning));
+            }
+            (_, warning) => {
+                success = false;
+                println!("[{repo}] {}", style::error(warning.unwrap_or_default()));
+            }
+        }
+        keyfile_repos += 1;
+    }
+    if keyfile_repos == 0 {
+        println!("No keyfile-mode repositories");
+    }
+    success
+}
+
+/// How the repository of `backup` differs from its config
+fn read_drift(borg: &Borg, backup: &Backup) -> crate::Result<Vec<String>> {
+    if backup.repo.is_remote() {
+        return Err("borg only reads the config of local repositories".into());
+    }
+    let append_only = match backup.init.append_only {
+        Some(_) => borg.is_append_only::<BorgWrapper>(&backup.repo)?,
+        None => None,
+    };
+    let storage_quota = match backup.init.storage_quota {
+        Some(_) => {
+            let value = borg.repo_config::<BorgWrapper>(&backup.repo, "storage_quota")?;
+            // Borg writes 0 for no quota, older repositories may lack the key
+            let value = value.trim();
+            let quota = match value {
+                "" => 0,
+                value => value
+                    .parse()
+                    .map_err(|_| format!("invalid storage_quota value: {value:?}"))?,
+            };
+            Some(quota)
+        }
+        None => None,
+    };
+    Ok(drift(&backup.init, append_only, storage_quota))
+}
+
+/// Differences between the settings `borrg init` would use and the ones of the repository,
+/// e.g. "config says append_only=true, repository has append_only=0"
+fn drift(
+    declared: &InitOptions,
+    append_only: Option<bool>,
+    storage_quota: Option<u64>,
+) -> Vec<String> {
+    let mut drift = vec![];
+    if let Some((declared, actual)) = declared.append_only.zip(append_only) {
+        if declared != actual {
+            drift.push(format!(
+                "config says append_only={declared}, repository has append_only={}",
+                u8::from(actual)
+            ));
+        }
+    }
+    if let Some((declared, actual)) = declared.storage_quota.zip(storage_quota) {
+        if declared.0 != actual {
+            drift.push(format!(
+                "config says storage_quota={}, repository has storage_quota={actual}",
+                declared.0
+            ));
+        }
+    }
+    drift
+}
+
+/// The remote ones of `repos`, each once however it is written
+pub(super) fn remote_repos<'a>(repos: impl IntoIterator<Item = &'a Repo>) -> Vec<&'a Repo> {
+    let mut remote: Vec<&Repo> = vec![];
+    for repo in repos.into_iter().filter(|r| r.is_remote()) {
+        let location = repo.canonical_location();
+        if !remote.iter().any(|r| r.canonical_location() == location) {
+            remote.push(repo);
+        }
+    }
+    remote
+}
+
+/// Borg on the host of a remote repository, compared with the local one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum RemoteVersion {
+    /// The same major and minor version, see [`Version::is_compatible`]
+    Compatible(Version),
+    Mismatch(Version),
+    /// Why borg couldn't be run on the host
+    Unknown(String),
+    /// Why the remote shell couldn't reach the host
+    Unreachable(String),
+}
+
+impl RemoteVersion {
+    pub(super) fn check(borg: &Borg, repo: &Repo, local: &Version) -> Self {
+        match BorgWrapper::remote_version(borg, repo) {
+            Ok(remote) => Self::compare(remote, local),
+            // Tell a missing borg apart from a host which can't be reached
+            Err(e) => match BorgWrapper::probe_remote(borg, repo) {
+                Ok(()) => RemoteVersion::Unknown(e.to_string()),
+                Err(e) => RemoteVersion::Unreachable(e.to_string()),
+            },
+        }
+    }
+
+    fn compare(remote: Version, local: &Version) -> Self {
+        match remote.is_compatible(local) {
+            true => RemoteVersion::Compatible(remote),
+            false => RemoteVersion::Mismatch(remote),
+        }
+    }
+
+    /// What to warn about, `None` if the versions are compatible
+    pub(super) fn warning(&self, repo: &Repo, local: &Version) -> Option<String> {
+        let host = repo.host().unwrap_or_default();
+        match self {
+            RemoteVersion::Compatible(_) => None,
+            RemoteVersion::Mismatch(remote) => Some(format!(
+                "borg {remote} on {host} doesn't match borg {local} here, use the same major and \
+                minor version on both"
+            )),
+            RemoteVersion::Unknown(e) => Some(format!(
+                "Failed to check the version of borg on {host}: {}",
+                e.trim()
+            )),
+            RemoteVersion::Unreachable(e) => Some(format!(
+                "Can't reach {host} through the remote shell: {}",
+                e.trim()
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_version() {
+        let version = |s: &str| s.parse::<Version>().unwrap();
+        let repo: Repo = "ssh://seb@nas/./backup".parse().unwrap();
+        let local = version("borg 1.2.8");
+
+        let same = RemoteVersion::compare(version("borg 1.2.4"), &local);
+        assert_eq!(same, RemoteVersion::Compatible(version("1.2.4")));
+        assert_eq!(same.warning(&repo, &local), None);
+
+        let newer = RemoteVersion::compare(version("borg 1.4.0"), &local);
+        assert_eq!(
+            newer.warning(&repo, &local).unwrap(),
+            "borg 1.4.0 on nas doesn't match borg 1.2.8 here, use the same major and minor \
+            version on both"
+        );
+
+        let unknown = RemoteVersion::Unknown("ssh: connect to host nas: timed out\n".to_owned());
+        assert_eq!(
+            unknown.warning(&repo, &local).unwrap(),
+            "Failed to check the version of borg on nas: ssh: connect to host nas: timed out"
+        );
+
+        let unreachable = RemoteVersion::Unreachable("ssh: connect to host nas: timed out".into());
+        assert_eq!(
+            unreachable.warning(&repo, &local).unwrap(),
+            "Can't reach nas through the remote shell: ssh: connect to host nas: timed out"
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        let declared = InitOptions {
+            append_only: Some(true),
+            storage_quota: Some(crate::ByteSize(500 << 30)),
+            ..Default::default()
+        };
+        assert!(drift(&declared, Some(true), Some(500 << 30)).is_empty());
+        assert_eq!(
+            drift(&declared, Some(false), Some(0)),
+            [
+                "config says append_only=true, repository has append_only=0",
+                "config says storage_quota=536870912000, repository has storage_quota=0",
+            ]
+        );
+        // Only what the config sets is compared
+        assert!(drift(&InitOptions::default(), Some(true), Some(0)).is_empty());
+    }
+
+    #[test]
+    fn test_remote_repos() {
+        let repos: Vec<Repo> = [
+            "ssh://nas/./a",
+            "/srv/local",
+            "ssh://nas/./a/",
+            "ssh://nas/./b",
+        ]
+        .iter()
+        .map(|r| r.parse().unwrap())
+        .collect();
+        let remote: Vec<_> = remote_repos(&repos).iter().map(|r| r.to_string()).collect();
+        assert_eq!(remote, ["ssh://nas/./a", "ssh://nas/./b"]);
+    }
+}
@@ -0,0 +1,394 @@
+use super::*;
+use crate::{Archive, ExcludeFile};
+use glob::{MatchOptions, Pattern};
+use std::path::{Path, PathBuf};
+
+/// Most directory entries looked at per rule to add up what it excluded
+const WALK_LIMIT: usize = 100_000;
+
+/// How a pattern is interpreted, borg's `fm:`, `sh:`, `re:`, `pp:` and `pf:`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Fnmatch,
+    Shell,
+    Regex,
+    PathPrefix,
+    PathFull,
+}
+
+impl Style {
+    /// The style of `pattern` from its prefix, `default` without one
+    fn split(pattern: &str, default: Style) -> (Style, &str) {
+        let style = match pattern.split_once(':') {
+            Some(("fm", _)) => Style::Fnmatch,
+            Some(("sh", _)) => Style::Shell,
+            Some(("re", _)) => Style::Regex,
+            Some(("pp", _)) => Style::PathPrefix,
+            Some(("pf", _)) => Style::PathFull,
+            _ => return (default, pattern),
+        };
+        (style, &pattern[3..])
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Glob(Pattern, MatchOptions),
+    Prefix(PathBuf),
+    Full(PathBuf),
+    /// Regular expressions aren't evaluated
+    Unknown,
+    /// Directories containing this file, see [`Archive::exclude_if_present`]
+    Marker(String),
+}
+
+/// A configured way to exclude paths
+#[derive(Debug)]
+pub(super) struct Rule {
+    /// As written, e.g. "*.tmp"
+    pattern: String,
+    /// Where it is configured, e.g. ".borgignore"
+    source: String,
+    matcher: Matcher,
+}
+
+impl Rule {
+    fn pattern(pattern: &str, default: Style, source: &str) -> Self {
+        let (style, text) = Style::split(pattern, default);
+        // Borg matches paths without the leading separator
+        let text = text.trim_start_matches('/');
+        // A trailing separator only matches the contents of a directory
+        let text = match text.strip_suffix('/') {
+            Some(dir) if matches!(style, Style::Fnmatch | Style::Shell) => format!("{dir}/*"),
+            _ => text.to_owned(),
+        };
+        let glob = |literal_separator| match Pattern::new(&text) {
+            Ok(glob) => Matcher::Glob(
+                glob,
+                MatchOptions {
+                    require_literal_separator: literal_separator,
+                    ..MatchOptions::new()
+                },
+            ),
+            Err(_) => Matcher::Unknown,
+        };
+        let matcher = match style {
+            Style::Fnmatch => glob(false),
+            Style::Shell => glob(true),
+            Style::Regex => Matcher::Unknown,
+            Style::PathPrefix => Matcher::Prefix(text.into()),
+            Style::PathFull => Matcher::Full(text.into()),
+        };
+        Rule {
+            pattern: pattern.to_owned(),
+            source: source.to_owned(),
+            matcher,
+        }
+    }
+
+    /// Whether the rule excludes `path` as listed by borg, `None` if borrg can't tell
+    ///
+    /// Like borg, a rule matching a directory also matches everything inside of it. `live` is
+    /// where the path is on this machine.
+    fn matches(&self, path: &Path, live: &Path) -> Option<bool> {
+        let mut ancestors = path.ancestors().filter(|a| !a.as_os_str().is_empty());
+        let matches = match &self.matcher {
+            Matcher::Glob(glob, options) => ancestors.any(|a| glob.matches_path_with(a, *options)),
+            Matcher::Prefix(prefix) => ancestors.any(|a| a == prefix),
+            Matcher::Full(full) => path == full,
+            Matcher::Unknown => return None,
+            Matcher::Marker(name) => live.is_dir() && live.join(name).exists(),
+        };
+        Some(matches)
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.pattern, self.source)
+    }
+}
+
+/// The exclusion rules of `archive`, in the order borg applies them
+pub(super) fn rules(archive: &Archive) -> Vec<Rule> {
+    let mut rules = vec![];
+    let name = |path: &Path| {
+        path.file_name().map_or_else(
+            || path.display().to_string(),
+            |n| n.to_string_lossy().into(),
+        )
+    };
+
+    let pattern_file = archive.get_pattern_file().and_then(|file| {
+        if file.is_absolute() {
+            return Some(file.to_owned());
+        }
+        Some(archive.locate(&archive.paths().first()?.join(file)))
+    });
+    if let Some(file) = pattern_file {
+        let mut style = Style::Shell;
+        for line in read_lines(&file) {
+            let Some(command) = line.chars().next() else {
+                continue;
+            };
+            let value = line[command.len_utf8()..].trim_start();
+            match command {
+                '-' | '!' => rules.push(Rule::pattern(value, style, &name(&file))),
+                'P' | 'p' => style = Style::split(&format!("{value}:"), style).0,
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(ExcludeFile::Applied(file)) = archive.exclude_file_status() {
+        for line in read_lines(&file) {
+            rules.push(Rule::pattern(&line, Style::Fnmatch, &name(&file)));
+        }
+    }
+
+    for marker in archive.exclusion_markers() {
+        rules.push(Rule {
+            pattern: marker.to_owned(),
+            source: "exclude_if_present".to_owned(),
+            matcher: Matcher::Marker(marker.to_owned()),
+        });
+    }
+    rules
+}
+
+/// Lines of a pattern or exclude file without comments and blank lines
+fn read_lines(file: &Path) -> Vec<String> {
+    let contents = match std::fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(e) => {
+            log::warn!("Failed to read {}: {e}", file.display());
+            return vec![];
+        }
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Paths and bytes excluded by a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Matched {
+    paths: u64,
+    bytes: u64,
+    /// Whether every path was looked at within [`WALK_LIMIT`]
+    complete: bool,
+}
+
+impl Default for Matched {
+    fn default() -> Self {
+        Matched {
+            paths: 0,
+            bytes: 0,
+            complete: true,
+        }
+    }
+}
+
+impl Matched {
+    /// Add `path` and everything inside of it, without following symlinks
+    fn add(&mut self, path: &Path, budget: &mut usize) {
+        let mut stack = vec![path.to_owned()];
+        while let Some(path) = stack.pop() {
+            if *budget == 0 {
+                self.complete = false;
+                return;
+            }
+            *budget -= 1;
+            self.paths += 1;
+            let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+                continue;
+            };
+            if metadata.is_file() {
+                self.bytes += metadata.len();
+            } else if metadata.is_dir() {
+                if let Ok(entries) = std::fs::read_dir(&path) {
+                    stack.extend(entries.flatten().map(|e| e.path()));
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Matched {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.paths, self.complete) {
+            (0, _) => write!(f, "nothing"),
+            (paths, true) => write!(f, "{} paths, {}", fmt_count(paths), fmt_bytes(self.bytes)),
+            (paths, false) => write!(
+                f,
+                "{}+ paths, at least {}",
+                fmt_count(paths),
+                fmt_bytes(self.bytes)
+            ),
+        }
+    }
+}
+
+/// What the rules of a backup excluded, from the paths borg reported as excluded
+#[derive(Debug)]
+pub(super) struct ExclusionReport {
+    rules: Vec<(Rule, Matched)>,
+    /// Excluded paths no rule borrg can evaluate matches, e.g. by a regular expression
+    other: Matched,
+}
+
+impl ExclusionReport {
+    /// Attribute every excluded path to the first rule matching it, and add up its size
+    pub(super) fn new(archive: &Archive, excluded: &[PathBuf]) -> Self {
+        let mut rules: Vec<_> = rules(archive)
+            .into_iter()
+            .map(|rule| (rule, Matched::default(), WALK_LIMIT))
+            .collect();
+        let mut other = (Matched::default(), WALK_LIMIT);
+
+        let root = archive
+            .relative_to()
+            .map(crate::util::resolve_path)
+            .unwrap_or_else(|| PathBuf::from("/"));
+        for path in excluded {
+            let path = path.strip_prefix("/").unwrap_or(path);
+            let live = root.join(path);
+            let rule = rules
+                .iter_mut()
+                .find(|(rule, ..)| rule.matches(path, &live) == Some(true));
+            match rule {
+                Some((_, matched, budget)) => matched.add(&live, budget),
+                None => other.0.add(&live, &mut other.1),
+            }
+        }
+
+        ExclusionReport {
+            rules: rules
+                .into_iter()
+                .map(|(rule, matched, _)| (rule, matched))
+                .collect(),
+            other: other.0,
+        }
+    }
+
+    /// A line per rule, e.g. "- .cache (.borgignore) matched 8,231 paths, 12.40 GiB"
+    pub(super) fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<_> = self
+            .rules
+            .iter()
+            .map(|(rule, matched)| format!("- {rule} matched {matched}"))
+            .collect();
+        if self.other.paths > 0 {
+            lines.push(format!("- other rules matched {}", self.other));
+        }
+        if lines.is_empty() {
+            lines.push("No exclusion rules".to_owned());
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_matches() {
+        let matches = |pattern: &str, style: Style, path: &str| {
+            Rule::pattern(pattern, style, "test").matches(Path::new(path), Path::new("/nowhere"))
+        };
+        // fm: * crosses separators, and a directory excludes its contents
+        assert_eq!(
+            matches("/home/*/.cache", Style::Fnmatch, "home/seb/.cache"),
+            Some(true)
+        );
+        assert_eq!(
+            matches("*.tmp", Style::Fnmatch, "home/seb/a/b.tmp"),
+            Some(true)
+        );
+        assert_eq!(
+            matches("home/seb", Style::Fnmatch, "home/seb/x"),
+            Some(true)
+        );
+        assert_eq!(
+            matches("home/seb", Style::Fnmatch, "home/sebastian"),
+            Some(false)
+        );
+        // Only the contents with a trailing separator
+        assert_eq!(
+            matches("home/seb/", Style::Fnmatch, "home/seb"),
+            Some(false)
+        );
+        assert_eq!(
+            matches("home/seb/", Style::Fnmatch, "home/seb/x"),
+            Some(true)
+        );
+
+        // sh: * stays within a directory, ** doesn't
+        assert_eq!(
+            matches("sh:home/*.tmp", Style::Fnmatch, "home/a/b.tmp"),
+            Some(false)
+        );
+        assert_eq!(
+            matches("sh:home/**/*.tmp", Style::Fnmatch, "home/a/b.tmp"),
+            Some(true)
+        );
+
+        assert_eq!(
+            matches("pp:/home/seb", Style::Fnmatch, "home/seb/x"),
+            Some(true)
+        );
+        assert_eq!(
+            matches("pf:/home/seb", Style::Fnmatch, "home/seb/x"),
+            Some(false)
+        );
+        assert_eq!(matches("re:\\.tmp$", Style::Fnmatch, "a.tmp"), None);
+    }
+
+    #[test]
+    fn test_exclusion_report() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-excludes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src/.cache/a")).unwrap();
+        std::fs::create_dir_all(dir.join("src/private")).unwrap();
+        std::fs::write(dir.join("src/.cache/a/blob"), [0; 100]).unwrap();
+        std::fs::write(dir.join("src/.cache/index"), [0; 20]).unwrap();
+        std::fs::write(dir.join("src/private/.nobackup"), "").unwrap();
+        std::fs::write(dir.join("src/debug.log"), [0; 5]).unwrap();
+        std::fs::write(
+            dir.join("src/.borgignore"),
+            "# caches\n*/.cache\n*.tmp\nre:\\.log$\n",
+        )
+        .unwrap();
+
+        let mut archive = Archive::new("test");
+        archive
+            .path("src")
+            .set_relative_to(&dir)
+            .set_exclude_file(".borgignore")
+            .exclude_if_present(".nobackup");
+        let excluded = ["src/.cache", "src/private", "src/debug.log"].map(PathBuf::from);
+        let report = ExclusionReport::new(&archive, &excluded);
+
+        assert_eq!(
+            report.lines(),
+            [
+                "- */.cache (.borgignore) matched 4 paths, 120 B",
+                "- *.tmp (.borgignore) matched nothing",
+                "- re:\\.log$ (.borgignore) matched nothing",
+                "- .nobackup (exclude_if_present) matched 2 paths, 0 B",
+                "- other rules matched 1 paths, 5 B",
+            ]
+        );
+
+        let mut budget = 2;
+        let mut matched = Matched::default();
+        matched.add(&dir.join("src/.cache"), &mut budget);
+        assert_eq!(matched.to_string(), "2+ paths, at least 0 B");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use super::*;
+use crate::util::resolve_path;
+use std::path::PathBuf;
+
+/// Restrict which backups are used
+///
+/// Repeating a flag matches any of its values, different flags all have to match.
+#[derive(Args, Debug, Default, Clone)]
+pub struct Filter {
+    /// Only backups to repositories on this host
+    #[arg(long, value_name = "HOST")]
+    repo_host: Vec<String>,
+
+    /// Only backups of paths inside (or containing) this path
+    #[arg(long, value_name = "PATH")]
+    path_under: Vec<PathBuf>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.repo_host.is_empty() && self.path_under.is_empty()
+    }
+
+    pub fn matches(&self, backup: &Backup) -> bool {
+        self.exclusion(backup).is_none()
+    }
+
+    /// Why the backup doesn't match, `None` if it does
+    pub fn exclusion(&self, backup: &Backup) -> Option<String> {
+        let host = self.repo_host.is_empty()
+            || backup
+                .repo
+                .host()
+                .is_some_and(|host| self.repo_host.iter().any(|h| h == host));
+        if !host {
+            let hosts = self.repo_host.join(" or ");
+            return Some(match backup.repo.host() {
+                Some(host) => format!("repository is on {host}, not {hosts} (--repo-host)"),
+                None => format!("repository is local, not on {hosts} (--repo-host)"),
+            });
+        }
+
+        let path = self.path_under.is_empty()
+            || backup.archive.paths.iter().map(resolve_path).any(|path| {
+                self.path_under
+                    .iter()
+                    .map(resolve_path)
+                    .any(|under| path.starts_with(&under) || under.starts_with(&path))
+            });
+        if !path {
+            let paths = self
+                .path_under
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" or ");
+            return Some(format!("no path inside {paths} (--path-under)"));
+        }
+
+        None
+    }
+
+    /// Keep the matching backups, returns the removed ones with why they don't match
+    pub fn apply(&self, backups: &mut Vec<Backup>) -> Vec<(String, String)> {
+        let mut excluded = vec![];
+        backups.retain(|backup| match self.exclusion(backup) {
+            Some(reason) => {
+                excluded.push((backup.to_string(), reason));
+                false
+            }
+            None => true,
+        });
+        excluded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backups() -> Vec<Backup> {
+        ConfigProperty::parse(
+            &r#"
+            [[backup]]
+            repository = "ssh://backup.example.com/srv/home"
+            path = "/home/seb"
+            [[backup]]
+            repository = "ssh://backup.example.com/srv/etc"
+            path = "/etc"
+            [[backup]]
+            repository = "/mnt/usb/root"
+            path = "/"
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn filtered(filter: &Filter) -> Vec<String> {
+        let mut backups = backups();
+        filter.apply(&mut backups);
+        backups.iter().map(|b| b.repo.to_string()).collect()
+    }
+
+    #[test]
+    fn test_filter() {
+        let all = filtered(&Filter::default());
+        assert_eq!(all.len(), 3);
+
+        let filter = Filter {
+            repo_host: vec!["backup.example.com".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            filtered(&filter),
+            [
+                "ssh://backup.example.com/srv/home",
+                "ssh://backup.example.com/srv/etc"
+            ]
+        );
+
+        let filter = Filter {
+            path_under: vec!["/home".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            filtered(&filter),
+            ["ssh://backup.example.com/srv/home", "/mnt/usb/root"]
+        );
+
+        let filter = Filter {
+            repo_host: vec!["backup.example.com".to_owned()],
+            path_under: vec!["/home".into()],
+        };
+        assert_eq!(filtered(&filter), ["ssh://backup.example.com/srv/home"]);
+
+        let filter = Filter {
+            repo_host: vec!["nas".to_owned()],
+            ..Default::default()
+        };
+        let mut none = backups();
+        let excluded = filter.apply(&mut none);
+        assert!(none.is_empty());
+        let reasons: Vec<_> = excluded.iter().map(|(_, reason)| reason.as_str()).collect();
+        assert_eq!(
+            reasons,
+            [
+                "repository is on backup.example.com, not nas (--repo-host)",
+                "repository is on backup.example.com, not nas (--repo-host)",
+                "repository is local, not on nas (--repo-host)",
+            ]
+        );
+
+        let filter = Filter {
+            path_under: vec!["/srv".into()],
+            ..Default::default()
+        };
+        let excluded = filter.apply(&mut backups());
+        assert_eq!(excluded.len(), 2);
+        assert_eq!(excluded[0].1, "no path inside /srv (--path-under)");
+    }
+}
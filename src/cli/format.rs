@@ -0,0 +1,185 @@
+use crate::{ArchiveInfo, ArchiveListEntry, ByteSize};
+use thiserror::Error;
+
+/// Something that can be rendered by a [`Template`]
+pub(super) trait Placeholders {
+    /// Valid placeholder keys
+    const KEYS: &'static [&'static str];
+
+    /// Value of a placeholder, `key` is always one of [`Self::KEYS`]
+    fn value(&self, key: &str) -> String;
+}
+
+/// Only what `borg list --json` reports, counting files would mean reading every archive
+impl Placeholders for ArchiveInfo {
+    const KEYS: &'static [&'static str] = &["name", "id", "time"];
+
+    fn value(&self, key: &str) -> String {
+        match key {
+            "name" => self.name.to_owned(),
+            "id" => self.id.to_owned(),
+            "time" => super::util::fmt_time(self.time),
+            _ => unreachable!("unknown key {key}"),
+        }
+    }
+}
+
+impl Placeholders for ArchiveListEntry {
+    const KEYS: &'static [&'static str] = &[
+        "path",
+        "type",
+        "mode",
+        "user",
+        "group",
+        "size",
+        "bytes",
+        "mtime",
+        "linktarget",
+    ];
+
+    fn value(&self, key: &str) -> String {
+        match key {
+            "path" => self.path.display().to_string(),
+            "type" => char::from(self.kind).to_string(),
+            "mode" => self.mode.to_owned(),
+            "user" => self.user.to_owned(),
+            "group" => self.group.to_owned(),
+            "size" => ByteSize(self.size).to_string(),
+            "bytes" => self.size.to_string(),
+            "mtime" => super::util::fmt_time(self.mtime),
+            "linktarget" => self
+                .link_target
+                .as_ref()
+                .map(|t| t.display().to_string())
+                .unwrap_or_default(),
+            _ => unreachable!("unknown key {key}"),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub(super) enum FormatError {
+    #[error("Unknown placeholder \"{{{key}}}\", valid placeholders are: {valid}")]
+    UnknownKey { key: String, valid: String },
+    #[error("Unclosed placeholder at position {0}")]
+    Unclosed(usize),
+    #[error("Unmatched \"}}\" at position {0}")]
+    Unmatched(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Key(String),
+}
+
+/// A format string like `"{name}\t{time}"`
+///
+/// `{{` and `}}` produce literal braces, `\t`, `\n` and `\\` are unescaped.
+#[derive(Debug)]
+pub(super) struct Template(Vec<Part>);
+
+impl Template {
+    /// Parse a format string, checking that all placeholders are valid for `T`
+    pub fn parse<T: Placeholders>(format: &str) -> Result<Self, FormatError> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = format.char_indices().peekable();
+
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.next_if(|(_, c)| *c == '{').is_some() => literal.push('{'),
+                '}' if chars.next_if(|(_, c)| *c == '}').is_some() => literal.push('}'),
+                '}' => return Err(FormatError::Unmatched(pos)),
+                '\\' => match chars.next_if(|(_, c)| matches!(c, 't' | 'n' | '\\')) {
+                    Some((_, 't')) => literal.push('\t'),
+                    Some((_, 'n')) => literal.push('\n'),
+                    Some(_) => literal.push('\\'),
+                    None => literal.push('\\'),
+                },
+                '{' => {
+                    let mut key = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => key.push(c),
+                            None => return Err(FormatError::Unclosed(pos)),
+                        }
+                    }
+                    if !T::KEYS.contains(&key.as_str()) {
+                        return Err(FormatError::UnknownKey {
+                            key,
+                            valid: T::KEYS.join(", "),
+                        });
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Key(key));
+                }
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Template(parts))
+    }
+
+    pub fn render<T: Placeholders>(&self, item: &T) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Literal(l) => l.to_owned(),
+                Part::Key(k) => item.value(k),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn archive() -> ArchiveInfo {
+        ArchiveInfo {
+            name: "2024-05-01".to_string(),
+            id: "f00d".to_string(),
+            time: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = Template::parse::<ArchiveInfo>("{name}\\t{id}").unwrap();
+        assert_eq!(template.render(&archive()), "2024-05-01\tf00d");
+
+        let template = Template::parse::<ArchiveInfo>("{{{name}}} \\\\ {id}\\n").unwrap();
+        assert_eq!(template.render(&archive()), "{2024-05-01} \\ f00d\n");
+
+        let template = Template::parse::<ArchiveInfo>("plain").unwrap();
+        assert_eq!(template.render(&archive()), "plain");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            Template::parse::<ArchiveInfo>("{name} {nfiles}").unwrap_err(),
+            FormatError::UnknownKey {
+                key: "nfiles".to_string(),
+                valid: "name, id, time".to_string()
+            }
+        );
+        assert_eq!(
+            Template::parse::<ArchiveInfo>("{name").unwrap_err(),
+            FormatError::Unclosed(0)
+        );
+        assert_eq!(
+            Template::parse::<ArchiveInfo>("name}").unwrap_err(),
+            FormatError::Unmatched(4)
+        );
+    }
+}
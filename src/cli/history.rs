@@ -0,0 +1,325 @@
+use super::*;
+use crate::{CreateStats, Repo};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup
+    backup: String,
+
+    /// Only show the newest entries
+    #[arg(long, value_name = "N")]
+    last: Option<usize>,
+
+    /// Print JSON to stdout
+    #[arg(long, conflicts_with = "format")]
+    json: bool,
+
+    /// Format of each line, e.g. "{id}"
+    ///
+    /// Placeholders: {repository}, {name}, {id}, {time}, {duration}, {original_size},
+    /// {compressed_size}, {deduplicated_size}, {nfiles}, {average_throughput},
+    /// {peak_throughput}, {run_id}.
+    #[arg(long)]
+    format: Option<String>,
+}
+
+/// An archive created by borrg, stored as one JSON object per line in the history file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct HistoryEntry {
+    pub repository: String,
+    pub name: String,
+    pub id: String,
+    /// When the backup finished
+    #[serde(
+        serialize_with = "crate::util::serialize_time",
+        deserialize_with = "crate::util::deserialize_time"
+    )]
+    pub time: SystemTime,
+    /// Seconds borg took to create the archive
+    pub duration: f64,
+    pub original_size: u64,
+    pub compressed_size: u64,
+    pub deduplicated_size: u64,
+    pub nfiles: u64,
+    /// Bytes of original data read per second, if borg reported progress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average_throughput: Option<u64>,
+    /// Highest throughput over a few seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peak_throughput: Option<u64>,
+    /// The `borrg run` which created the archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// Id of the backup which created the archive, see [`Backup::id`](super::config::Backup::id)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn new(repo: &Repo, stats: &CreateStats) -> Self {
+        HistoryEntry {
+            repository: repo.to_string(),
+            name: stats.name.to_owned(),
+            id: stats.id.to_owned(),
+            time: SystemTime::now(),
+            duration: stats.duration.as_secs_f64(),
+            original_size: stats.original_size,
+            compressed_size: stats.compressed_size,
+            deduplicated_size: stats.deduplicated_size,
+            nfiles: stats.nfiles,
+            average_throughput: None,
+            peak_throughput: None,
+            run_id: None,
+            backup: None,
+        }
+    }
+}
+
+impl Placeholders for HistoryEntry {
+    const KEYS: &'static [&'static str] = &[
+        "repository",
+        "name",
+        "id",
+        "time",
+        "duration",
+        "original_size",
+        "compressed_size",
+        "deduplicated_size",
+        "nfiles",
+        "average_throughput",
+        "peak_throughput",
+        "run_id",
+    ];
+
+    fn value(&self, key: &str) -> String {
+        match key {
+            "repository" => self.repository.to_owned(),
+            "name" => self.name.to_owned(),
+            "id" => self.id.to_owned(),
+            "time" => fmt_time(self.time),
+            "duration" => fmt_duration(Duration::from_secs_f64(self.duration)),
+            "original_size" => self.original_size.to_string(),
+            "compressed_size" => self.compressed_size.to_string(),
+            "deduplicated_size" => self.deduplicated_size.to_string(),
+            "nfiles" => self.nfiles.to_string(),
+            "average_throughput" => self
+                .average_throughput
+                .map(fmt_throughput)
+                .unwrap_or_default(),
+            "peak_throughput" => self.peak_throughput.map(fmt_throughput).unwrap_or_default(),
+            "run_id" => self.run_id.clone().unwrap_or_default(),
+            _ => unreachable!("unknown key {key}"),
+        }
+    }
+}
+
+/// Location of the history file, in the state directory of the user
+pub(super) fn history_path() -> Option<PathBuf> {
+    let dir = dirs::state_dir().or_else(dirs::data_local_dir)?;
+    Some(dir.join("borrg").join("history.jsonl"))
+}
+
+/// Location of the prune history, a [`PruneReport`](super::prune::PruneReport) per line next
+/// to the history file
+pub(super) fn prune_history_path() -> Option<PathBuf> {
+    Some(history_path()?.with_file_name("prunes.jsonl"))
+}
+
+/// Append entries to the history file, creating it if needed
+pub(super) fn append<T: Serialize>(path: &Path, entries: &[T]) -> Result<(), crate::Error> {
+    use std::io::Write;
+
+    if entries.is_empty() {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(lines.as_bytes())?;
+    Ok(())
+}
+
+/// Newest entry of a backup, the baseline of `borrg run --report`
+///
+/// Entries recorded before the backup id was stored have none.
+pub(super) fn latest<'a>(entries: &'a [HistoryEntry], backup: &str) -> Option<&'a HistoryEntry> {
+    entries
+        .iter()
+        .rev()
+        .find(|e| e.backup.as_deref() == Some(backup))
+}
+
+/// Read all entries of the history file, oldest first
+///
+/// A missing file is an empty history, invalid lines are skipped.
+pub(super) fn read(path: &Path) -> Result<Vec<HistoryEntry>, crate::Error> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+    };
+
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("Invalid history entry: {line:?} ({e})");
+                None
+            }
+        })
+        .collect())
+}
+
+pub fn history(config: Config, args: Args) -> ExitCode {
+    let repo = match util::select_repo(&config, &args.backup) {
+        Ok(repo) => repo.to_string(),
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let template = match args.format.as_deref().map(Template::parse::<HistoryEntry>) {
+        Some(Ok(template)) => Some(template),
+        Some(Err(e)) => {
+            eprintln!("{}", style::error_stderr(format!("Invalid format: {e}")));
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+
+    let Some(path) = history_path() else {
+        eprintln!("{}", style::error_stderr("No state directory"));
+        return ExitCode::FAILURE;
+    };
+    let mut entries = match read(&path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                style::error_stderr(format!("Failed to read history: {e}"))
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    entries.retain(|e| e.repository == repo);
+    if let Some(last) = args.last {
+        entries.drain(..entries.len().saturating_sub(last));
+    }
+
+    if args.json {
+        #[derive(Serialize)]
+        struct Output {
+            history: Vec<HistoryEntry>,
+        }
+        print_json(Output { history: entries });
+        return ExitCode::SUCCESS;
+    }
+
+    for entry in &entries {
+        match &template {
+            Some(template) => println!("{}", template.render(entry)),
+            None => println!(
+                "{}  {:<30} {:>10} new  {:>13}  [{}]",
+                fmt_time(entry.time),
+                entry.name,
+                fmt_bytes(entry.deduplicated_size),
+                entry
+                    .average_throughput
+                    .map(fmt_throughput)
+                    .unwrap_or_default(),
+                entry.id
+            ),
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(repository: &str, id: &str) -> HistoryEntry {
+        HistoryEntry {
+            repository: repository.to_owned(),
+            name: "2024-05-10".to_owned(),
+            id: id.to_owned(),
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1715310183),
+            duration: 12.5,
+            original_size: 2048,
+            compressed_size: 1024,
+            deduplicated_size: 512,
+            nfiles: 3,
+            average_throughput: None,
+            peak_throughput: None,
+            run_id: None,
+            backup: None,
+        }
+    }
+
+    #[test]
+    fn test_append_read() {
+        let path = std::env::temp_dir()
+            .join(format!("borrg-test-history-{}", std::process::id()))
+            .join("history.jsonl");
+        std::fs::remove_file(&path).ok();
+
+        assert!(read(&path).unwrap().is_empty());
+
+        let entries = [entry("/srv/a", "aaaa"), entry("/srv/b", "bbbb")];
+        append(&path, &entries[..1]).unwrap();
+        append(&path, &entries[1..]).unwrap();
+
+        // Garbage doesn't hide the other entries
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str("{not json\n");
+        std::fs::write(&path, content).unwrap();
+
+        assert_eq!(read(&path).unwrap(), entries);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn test_format() {
+        let template = Template::parse::<HistoryEntry>("{id} {name} {duration}").unwrap();
+        assert_eq!(
+            template.render(&entry("/srv/a", "aaaa")),
+            "aaaa 2024-05-10 12s"
+        );
+
+        let template = Template::parse::<HistoryEntry>("{average_throughput}").unwrap();
+        assert_eq!(template.render(&entry("/srv/a", "aaaa")), "");
+        let entry = HistoryEntry {
+            average_throughput: Some(42 * 1024 * 1024),
+            ..entry("/srv/a", "aaaa")
+        };
+        assert_eq!(template.render(&entry), "42.00 MiB/s");
+
+        // Entries written before throughput was recorded
+        let json = serde_json::to_string(&entry)
+            .unwrap()
+            .replace(",\"average_throughput\":44040192", "");
+        let old: HistoryEntry = serde_json::from_str(&json).unwrap();
+        assert_eq!(old.average_throughput, None);
+    }
+}
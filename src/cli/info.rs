@@ -0,0 +1,202 @@
+use super::*;
+use crate::{backend, Borg, Ratio, Repo, RepoInfo};
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup
+    #[arg(required_unless_present = "all")]
+    backup: Option<String>,
+
+    /// Compare all configured repositories
+    #[arg(long, conflicts_with = "backup")]
+    all: bool,
+
+    /// Print JSON to stdout
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn info(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let backups = match config.select(args.backup.as_deref()) {
+        Ok(backups) => backups,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Several backups can share a repository
+    let mut repos: Vec<&Repo> = vec![];
+    for backup in backups {
+        if !repos.contains(&&backup.repo) {
+            repos.push(&backup.repo);
+        }
+    }
+
+    let infos = parallel_map(&repos, |repo| {
+        borg.repo_info::<backend::borg::BorgWrapper>(repo)
+    });
+    // `borg info` doesn't count archives, the overview table leaves them out anyway
+    let archive_counts = if args.all {
+        vec![None; repos.len()]
+    } else {
+        parallel_map(&repos, |repo| {
+            borg.list_archives::<backend::borg::BorgWrapper>(repo)
+                .ok()
+                .map(|archives| archives.len())
+        })
+    };
+    let infos: Vec<_> = repos.into_iter().zip(infos).collect();
+
+    if args.json {
+        #[derive(serde::Serialize)]
+        struct Output<'a> {
+            repositories: Vec<RepoResult<'a, InfoOutput<'a>>>,
+        }
+        let outputs: Vec<_> = infos
+            .iter()
+            .zip(&archive_counts)
+            .map(|((_, info), count)| info.as_ref().ok().map(|info| InfoOutput::new(info, *count)))
+            .collect();
+        print_json(Output {
+            repositories: infos
+                .iter()
+                .zip(&outputs)
+                .map(|((repo, info), output)| RepoResult {
+                    repository: repo.to_string(),
+                    info: output.as_ref(),
+                    error: info.as_ref().err().map(ToString::to_string),
+                })
+                .collect(),
+        });
+    } else if args.all {
+        print_table(&infos);
+    } else {
+        for ((repo, info), count) in infos.iter().zip(archive_counts) {
+            match info {
+                Ok(info) => print_info(info, count),
+                Err(e) => eprintln!("[{repo}] Failed to get repository info: {e}"),
+            }
+        }
+    }
+
+    if infos.iter().all(|(_, info)| info.is_ok()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// The info of a repository with the ratios derived from its sizes
+#[derive(serde::Serialize)]
+struct InfoOutput<'a> {
+    #[serde(flatten)]
+    info: &'a RepoInfo,
+    dedup_ratio: Option<Ratio>,
+    compression_ratio: Option<Ratio>,
+    /// `None` if listing the archives failed
+    archive_count: Option<usize>,
+}
+
+impl<'a> InfoOutput<'a> {
+    fn new(info: &'a RepoInfo, archive_count: Option<usize>) -> Self {
+        InfoOutput {
+            info,
+            dedup_ratio: info.dedup_ratio(),
+            compression_ratio: info.compression_ratio(),
+            archive_count,
+        }
+    }
+}
+
+/// E.g. "dedup 61.3×, compression 1.42×", `None` for an empty repository
+fn fmt_ratios(info: &RepoInfo) -> Option<String> {
+    Some(format!(
+        "dedup {}, compression {}",
+        info.dedup_ratio()?,
+        info.compression_ratio()?
+    ))
+}
+
+fn print_info(info: &RepoInfo, archive_count: Option<usize>) {
+    println!("Location:      {}", info.location);
+    println!("ID:            {}", info.id);
+    println!("Encryption:    {}", info.encryption);
+    println!("Last modified: {}", fmt_time(info.last_modified));
+    if let Some(count) = archive_count {
+        println!("Archives:      {count}");
+    }
+    println!("Original size: {}", fmt_bytes(info.total_size));
+    println!("Compressed:    {}", fmt_bytes(info.total_csize));
+    println!("Deduplicated:  {}", fmt_bytes(info.unique_csize));
+    if let Some(ratios) = fmt_ratios(info) {
+        println!("Ratios:        {ratios}");
+    }
+    println!("Cache:         {}", info.cache_path.display());
+    println!("Security dir:  {}", info.security_dir.display());
+}
+
+fn print_table(infos: &[(&Repo, crate::Result<RepoInfo>)]) {
+    let header = [
+        "Location",
+        "Encryption",
+        "Size",
+        "Unique",
+        "Dedup",
+        "Last modified",
+    ];
+    let rows = infos
+        .iter()
+        .map(|(repo, info)| match info {
+            Ok(info) => vec![
+                info.location.to_owned(),
+                info.encryption.to_string(),
+                fmt_bytes(info.total_size),
+                fmt_bytes(info.unique_csize),
+                match info.dedup_ratio() {
+                    Some(ratio) => ratio.to_string(),
+                    None => "-".to_string(),
+                },
+                fmt_time(info.last_modified),
+            ],
+            Err(e) => vec![repo.to_string(), format!("error: {e}")],
+        })
+        .collect::<Vec<_>>();
+
+    super::util::print_table(&header, &rows);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ratios() {
+        let mut info = RepoInfo {
+            total_chunks: 2,
+            total_csize: 30,
+            total_size: 45,
+            total_unique_chunks: 1,
+            unique_csize: 10,
+            unique_size: 20,
+            ..backend::mock::repo_info(&"/backup/repo".parse().unwrap())
+        };
+        assert_eq!(
+            fmt_ratios(&info).as_deref(),
+            Some("dedup 3.00×, compression 1.50×")
+        );
+
+        let json = serde_json::to_value(InfoOutput::new(&info, Some(2))).unwrap();
+        assert_eq!(json["dedup_ratio"], 3.0);
+        assert_eq!(json["archive_count"], 2);
+        assert_eq!(json["compression_ratio"], 1.5);
+        assert_eq!(json["unique_csize"], 10);
+
+        info.unique_csize = 0;
+        assert_eq!(fmt_ratios(&info), None);
+        let json = serde_json::to_value(InfoOutput::new(&info, None)).unwrap();
+        assert!(json["dedup_ratio"].is_null());
+        assert!(json["archive_count"].is_null());
+    }
+}
@@ -1,76 +1,541 @@
 use super::*;
-use crate::{backend, Borg, Encryption};
+use crate::{backend, backend::borg::BorgError, Borg, Encryption};
+use std::ops::ControlFlow;
+use std::process::ExitCode;
 
 #[derive(Args, Debug)]
 pub struct Args {
-    /// Select encryption key mode
+    /// Select encryption key mode, the backup's `encryption` if omitted
     #[arg(short, long, value_enum)]
-    encryption: Encryption,
+    encryption: Option<Encryption>,
 
     /// Create an append-only mode repository. Note that this only affects the low level structure of the repository, and running `delete` or `prune` will still be allowed.
-    #[arg(long)]
-    append_only: bool,
+    ///
+    /// The backup's `append_only` if omitted, --append-only=false overrides it.
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    append_only: Option<bool>,
 
-    /// Set storage quota of the new repository (e.g. 5G, 1.5T). Default: no quota.
+    /// Set storage quota of the new repository (e.g. 5G, 1.5T). Default: the backup's `storage_quota`, or no quota.
     #[arg(long,value_parser = parse_byte_size)]
     storage_quota: Option<usize>,
 
     /// Create the parent directories of the repository directory, if they are missing.
+    ///
+    /// Also if the backup sets `make_parent_dirs`.
     #[arg(long, default_value = "false")]
     make_parent_dirs: bool,
 
-    /// Path to the new repository
-    #[arg(value_name = "REPOSITORY")]
-    repository: crate::Repo,
+    /// Print the borg command that would be executed instead of running it
+    #[arg(long)]
+    explain: bool,
+
+    /// Run the backup right after initializing the repository
+    #[arg(long)]
+    and_run: bool,
+
+    /// Create the configured exclude file if it doesn't exist yet
+    #[arg(long)]
+    create_exclude_file: bool,
+
+    /// Initialize the repositories of all backups which don't exist yet, with their configured
+    /// encryption
+    #[arg(
+        long,
+        conflicts_with_all = ["backup", "encryption", "explain", "and_run", "create_exclude_file"]
+    )]
+    all_missing: bool,
+
+    /// Id or repository of a configured backup, or the location of the new repository
+    #[arg(value_name = "BACKUP", required_unless_present = "all_missing")]
+    backup: Option<String>,
+}
+
+/// How to initialize a repository, from the flags or else the config of its backup
+#[derive(Debug, PartialEq, Eq)]
+struct Settings {
+    encryption: Encryption,
+    append_only: bool,
+    storage_quota: Option<usize>,
+    make_parent_dirs: bool,
+}
+
+impl Settings {
+    fn resolve(args: &Args, config: &InitOptions) -> Result<Self, String> {
+        let encryption = args
+            .encryption
+            .clone()
+            .or_else(|| config.encryption.clone())
+            .ok_or("No encryption, pass --encryption or set encryption for the backup")?;
+        Ok(Settings {
+            encryption,
+            append_only: args.append_only.or(config.append_only).unwrap_or_default(),
+            storage_quota: args
+                .storage_quota
+                .or(config.storage_quota.map(|quota| quota.0 as usize)),
+            make_parent_dirs: args.make_parent_dirs || config.make_parent_dirs.unwrap_or_default(),
+        })
+    }
+}
+
+/// The repository to initialize with its backup, `query` is a location if it selects none
+///
+/// Backups sharing the repository are initialized with the settings of the first one.
+fn target<'a>(
+    config: &'a Config,
+    query: &str,
+) -> Result<(crate::Repo, Option<&'a Backup>), String> {
+    let selected = match config.select(Some(query)) {
+        Ok(selected) => selected,
+        Err(ConfigError::UnknownBackup(_)) => vec![],
+        Err(e) => return Err(e.to_string()),
+    };
+    if let Some(&backup) = selected.first() {
+        if selected.iter().any(|b| b.repo != backup.repo) {
+            return Err(format!("\"{query}\" matches several repositories"));
+        }
+        return Ok((backup.repo.clone(), Some(backup)));
+    }
+    let repo = query
+        .parse()
+        .map_err(|e| format!("Invalid repository {query}: {e}"))?;
+    Ok((repo, None))
 }
 
-pub fn init(borg: Borg, config: Config, args: Args) {
-    let mut repo = args.repository;
+pub fn init(borg: Borg, config: Config, args: Args) -> ExitCode {
+    if args.all_missing {
+        return init_all_missing(&borg, &config, &args);
+    }
+    // Required by clap without --all-missing
+    let query = args.backup.as_deref().unwrap_or_default();
+    let (mut repo, backup) = match target(&config, query) {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+    let exists_already = backup.is_some();
+    let settings =
+        match Settings::resolve(&args, &backup.map(|b| b.init.clone()).unwrap_or_default()) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("{}", style::error_stderr(e));
+                return ExitCode::FAILURE;
+            }
+        };
+
+    if args.explain {
+        match backend::borg::BorgWrapper::explain_init(
+            &borg,
+            &repo,
+            &settings.encryption,
+            settings.append_only,
+            settings.storage_quota,
+            settings.make_parent_dirs,
+        ) {
+            Ok(invocation) => {
+                println!("{invocation}");
+                return ExitCode::SUCCESS;
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    style::error_stderr(format!("Failed to initialize repository: {}", e))
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    }
 
-    // Search matching backup in config
-    let backup = config.backups.iter().map(|(r, _)| r).find(|r| r == &&repo);
+    if borg.dry_run {
+        print_dry_run(&config, &repo, &settings, exists_already);
+        return ExitCode::SUCCESS;
+    }
 
-    let mut exists_already = false;
-    if let Some(backup) = backup {
-        repo.passphrase = backup.passphrase.to_owned();
-        exists_already = true;
+    if let Err(e) = batch_passphrase(&borg, &repo, &settings.encryption)
+        .and_then(|()| precheck(&borg, &repo, settings.make_parent_dirs))
+    {
+        eprintln!("{}", style::error_stderr(e));
+        return ExitCode::FAILURE;
     }
 
     if let Err(e) = borg.init_repository::<backend::borg::BorgWrapper>(
         &mut repo,
-        args.encryption,
-        args.append_only,
-        args.storage_quota,
-        args.make_parent_dirs,
+        settings.encryption,
+        settings.append_only,
+        settings.storage_quota,
+        settings.make_parent_dirs,
         |u| {
             println!("{}", u);
+            ControlFlow::Continue(())
         },
     ) {
-        eprintln!("Failed to initialize repository: {}", e);
-        std::process::exit(1);
+        eprintln!(
+            "{}",
+            style::error_stderr(format!("Failed to initialize repository: {}", e))
+        );
+        return ExitCode::FAILURE;
     }
 
     if !exists_already {
-        if let Err(e) = append_backup_config(&config.source, &repo) {
-            eprintln!("Failed to append backup to config: {}", e);
+        match append_backup_config(&config.source, &repo) {
+            Ok(true) => println!("Added backup of {repo} to {}", config.source.display()),
+            Ok(false) => {}
+            Err(e) => eprintln!(
+                "{}",
+                style::error_stderr(format!("Failed to append backup to config: {}", e))
+            ),
+        }
+    }
+
+    println!("Initialized repository {repo}");
+
+    if !args.and_run && !args.create_exclude_file {
+        return ExitCode::SUCCESS;
+    }
+
+    // Reload to pick up the backup appended above
+    let backup = Config::load(&config.source)
+        .map_err(|e| e.to_string())
+        .and_then(|c| {
+            c.backups
+                .into_iter()
+                .find(|b| b.repo == repo)
+                .map(|backup| (c.source, c.cache, backup))
+                .ok_or_else(|| format!("No backup for {repo} in the config"))
+        });
+    let (source, cache, backup) = match backup {
+        Ok(backup) => backup,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                style::error_stderr(format!("Skipping follow-up steps: {e}"))
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if args.create_exclude_file {
+        if let Err(e) = create_exclude_file(&backup.archive) {
+            eprintln!(
+                "{}",
+                style::error_stderr(format!("Failed to create exclude file: {e}"))
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if args.and_run {
+        let config = Config {
+            source,
+            backups: vec![backup],
+            cache,
+            info_timeout: Config::DEFAULT_INFO_TIMEOUT,
+            limits: Limits::default(),
+            key_backup_dir: None,
+        };
+        return run::run(borg, config, Default::default());
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// What `--all-missing` did with a repository
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Initialized(Encryption),
+    /// Missing, but it was a dry run
+    WouldInitialize(Encryption),
+    Existed,
+    Failed(String),
+}
+
+impl Outcome {
+    fn is_failure(&self) -> bool {
+        matches!(self, Outcome::Failed(_))
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Initialized(mode) => {
+                write!(f, "{}", style::success(format!("initialized ({mode})")))
+            }
+            Outcome::WouldInitialize(mode) => write!(f, "would initialize ({mode})"),
+            Outcome::Existed => f.write_str("already initialized"),
+            Outcome::Failed(e) => write!(f, "{}", style::error(format!("failed: {e}"))),
+        }
+    }
+}
+
+/// Initialize the repositories of all backups which don't exist yet and print a line per
+/// repository
+fn init_all_missing(borg: &Borg, config: &Config, args: &Args) -> ExitCode {
+    // Backups sharing a repository are initialized with the settings of the first one
+    let mut backups: Vec<&Backup> = vec![];
+    for backup in &config.backups {
+        let location = backup.repo.canonical_location();
+        if !backups
+            .iter()
+            .any(|b| b.repo.canonical_location() == location)
+        {
+            backups.push(backup);
+        }
+    }
+    if backups.is_empty() {
+        println!("No backups configured");
+        return ExitCode::SUCCESS;
+    }
+
+    let outcomes: Vec<_> = backups
+        .iter()
+        .map(|backup| {
+            let outcome = init_if_missing(borg, backup, args);
+            println!("[{}] {outcome}", backup.repo);
+            outcome
+        })
+        .collect();
+
+    let count = |f: fn(&Outcome) -> bool| outcomes.iter().filter(|o| f(o)).count();
+    let initialized = count(|o| matches!(o, Outcome::Initialized(_) | Outcome::WouldInitialize(_)));
+    let existed = count(|o| *o == Outcome::Existed);
+    let failed = count(Outcome::is_failure);
+    let verb = match borg.dry_run {
+        true => "would initialize",
+        false => "initialized",
+    };
+    println!("{initialized} {verb}, {existed} already initialized, {failed} failed");
+
+    match failed {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+/// Initialize the repository of `backup` with its configured settings if it doesn't exist
+fn init_if_missing(borg: &Borg, backup: &Backup, args: &Args) -> Outcome {
+    let mut repo = backup.repo.clone();
+    match repo_exists(borg, &repo) {
+        Ok(true) => return Outcome::Existed,
+        Ok(false) => {}
+        Err(e) => return Outcome::Failed(format!("Failed to check the repository: {e}")),
+    }
+
+    if backup.init.encryption.is_none() {
+        return Outcome::Failed("No encryption configured, set encryption for the backup".into());
+    }
+    let settings = match Settings::resolve(args, &backup.init) {
+        Ok(settings) => settings,
+        Err(e) => return Outcome::Failed(e),
+    };
+    if let Err(e) = batch_passphrase(borg, &repo, &settings.encryption)
+        .and_then(|()| precheck(borg, &repo, settings.make_parent_dirs))
+    {
+        return Outcome::Failed(e);
+    }
+    if borg.dry_run {
+        return Outcome::WouldInitialize(settings.encryption);
+    }
+
+    let mode = settings.encryption.clone();
+    match borg.init_repository::<backend::borg::BorgWrapper>(
+        &mut repo,
+        settings.encryption,
+        settings.append_only,
+        settings.storage_quota,
+        settings.make_parent_dirs,
+        |u| {
+            log::info!("[{}] {u}", backup.repo);
+            ControlFlow::Continue(())
+        },
+    ) {
+        Ok(()) => Outcome::Initialized(mode),
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+/// Whether there is a repository at the location, an error if borg can't tell
+fn repo_exists(borg: &Borg, repo: &crate::Repo) -> Result<bool, crate::Error> {
+    // Nothing to ask borg about
+    if !repo.is_remote() && !crate::util::resolve_path(&repo.path).exists() {
+        return Ok(false);
+    }
+    match probing(borg).repo_info::<backend::borg::BorgWrapper>(repo) {
+        Ok(_) => Ok(true),
+        Err(e) if is_missing_repo(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// `borg` for asking whether a repository exists, which never waits for input
+///
+/// Neither borg nor ssh prompt for a passphrase, and borg doesn't wait long for a lock.
+fn probing(borg: &Borg) -> Borg {
+    let mut borg = borg.clone();
+    borg.interaction(crate::Interaction::Batch);
+    borg.lock_wait(5);
+    borg
+}
+
+/// Whether borg failed because there is no repository, e.g. "Repository /srv/docs does not
+/// exist." or an empty directory, which "is not a valid repository"
+fn is_missing_repo(err: &crate::Error) -> bool {
+    // RepositoryDoesNotExist and InvalidRepository with modern exit codes
+    if matches!(err.downcast_ref(), Some(BorgError::Repository(13 | 15))) {
+        return true;
+    }
+    let message = err.to_string();
+    message.contains("does not exist") || message.contains("is not a valid repository")
+}
+
+const EXCLUDE_FILE_TEMPLATE: &str = "\
+# Patterns of files to exclude from the backup, one per line
+# See `borg help patterns` for the syntax, e.g.
+# *.tmp
+# */.cache
+";
+
+/// Create an exclude file with some explanatory comments, unless it exists already
+fn create_exclude_file(archive: &crate::Archive) -> Result<(), crate::Error> {
+    let Some(path) = archive.exclude_file_path() else {
+        return Err("No exclude file configured".into());
+    };
+
+    // Not atomic_write, its rename would replace a file created in the meantime
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+    {
+        Ok(mut file) => {
+            use std::io::Write;
+            file.write_all(EXCLUDE_FILE_TEMPLATE.as_bytes())?;
+            println!("Created exclude file {}", path.display());
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(format!("{}: {e}", path.display()).into()),
+    }
+}
+
+/// Catch the common mistakes before borg does, because its errors are hard to spot
+fn precheck(borg: &Borg, repo: &crate::Repo, make_parent_dirs: bool) -> Result<(), String> {
+    if !repo.is_remote() {
+        let parent = repo.path.parent().filter(|p| !p.as_os_str().is_empty());
+        if let Some(parent) = parent.map(crate::util::resolve_path) {
+            if !parent.exists() && !make_parent_dirs {
+                return Err(format!(
+                    "{} doesn't exist, pass --make-parent-dirs or set make_parent_dirs to create it",
+                    parent.display()
+                ));
+            }
         }
+
+        // Nothing to ask borg about
+        if !crate::util::resolve_path(&repo.path).exists() {
+            return Ok(());
+        }
+    }
+
+    match probing(borg).repo_info::<backend::borg::BorgWrapper>(repo) {
+        Ok(info) => Err(format!(
+            "Repository already initialized (encryption {}, id {}), did you mean `borrg info`?",
+            info.encryption, info.id
+        )),
+        Err(e) if is_missing_repo(&e) => Ok(()),
+        // E.g. a repository whose passphrase borg didn't get, or an unreachable host
+        Err(e) => Err(format!(
+            "Failed to check whether {repo} exists already: {e}"
+        )),
+    }
+}
+
+/// Refuse to encrypt a repository in batch mode without a passphrase
+///
+/// Borg would get an empty one instead of asking, see
+/// [`Interaction::Batch`](crate::Interaction::Batch).
+fn batch_passphrase(
+    borg: &Borg,
+    repo: &crate::Repo,
+    encryption: &Encryption,
+) -> Result<(), String> {
+    let is_set = |var: &str| std::env::var_os(var).is_some() || repo.env.contains_key(var);
+    if !borg.interaction.is_batch()
+        || *encryption == Encryption::None
+        || backend::borg::has_passphrase_source(repo.passphrase.as_ref(), is_set)
+    {
+        return Ok(());
     }
+    Err(format!(
+        "No passphrase for the {encryption} encryption of {repo}, borrg doesn't ask in batch mode"
+    ))
 }
 
+/// Describe what init would do without touching the repository or the config
+fn print_dry_run(config: &Config, repo: &crate::Repo, settings: &Settings, exists_already: bool) {
+    println!(
+        "Would initialize {repo} with encryption {}",
+        settings.encryption
+    );
+    if settings.append_only {
+        println!("  in append-only mode");
+    }
+    if let Some(quota) = settings.storage_quota {
+        println!("  with a storage quota of {}", fmt_bytes(quota as u64));
+    }
+    if settings.make_parent_dirs {
+        println!("  creating missing parent directories");
+    }
+
+    if exists_already {
+        println!(
+            "{} already has a backup for {repo}",
+            config.source.display()
+        );
+    } else {
+        println!("Would append to {}:", config.source.display());
+        print!("{}", backup_config_entry(repo));
+    }
+}
+
+fn backup_config_entry(repo: &crate::Repo) -> String {
+    use toml_edit::{value, ArrayOfTables, DocumentMut, Item, Table};
+
+    // Quotes and backslashes in the location have to be escaped
+    let mut backup = Table::new();
+    backup["repository"] = value(repo.to_string());
+    let mut backups = ArrayOfTables::new();
+    backups.push(backup);
+    let mut document = DocumentMut::new();
+    document.insert("backup", Item::ArrayOfTables(backups));
+    format!("\n{document}")
+}
+
+/// Append a backup of `repo` to the config at `path`, unless there is one already
+///
+/// Returns whether the config was changed.
 fn append_backup_config(
     path: &std::path::PathBuf,
     repo: &crate::Repo,
-) -> Result<(), std::io::Error> {
-    use std::fs::OpenOptions;
-    use std::io::Write;
-
-    let mut file = OpenOptions::new().append(true).open(path)?;
+) -> Result<bool, crate::Error> {
+    let config = Config::load(path)?;
+    if config.backups.iter().any(|b| &b.repo == repo) {
+        return Ok(false);
+    }
 
-    file.write_all(b"\n[[backup]]\nrepository = \"")?;
-    file.write_all(repo.to_string().as_bytes())?;
-    file.write_all(b"\"\n")?;
+    // Rewritten as a whole, a crash mustn't leave a half-written entry behind
+    let mut content = std::fs::read_to_string(path)?;
+    content.push_str(&backup_config_entry(repo));
+    crate::util::atomic_write(path, content)?;
 
-    Ok(())
+    Ok(true)
 }
 
 #[cfg(test)]
@@ -78,6 +543,20 @@ mod tests {
 
     use super::*;
 
+    fn args(backup: &str) -> super::Args {
+        super::Args {
+            encryption: Some(Encryption::None),
+            append_only: None,
+            storage_quota: None,
+            make_parent_dirs: false,
+            explain: false,
+            and_run: false,
+            create_exclude_file: false,
+            all_missing: false,
+            backup: Some(backup.to_owned()),
+        }
+    }
+
     fn borg_available() -> bool {
         std::process::Command::new("which")
             .arg("borg")
@@ -95,13 +574,7 @@ mod tests {
 
         std::fs::create_dir_all("./tmp").ok();
 
-        let args = super::Args {
-            encryption: Encryption::None,
-            append_only: false,
-            storage_quota: None,
-            make_parent_dirs: false,
-            repository: "./tmp/test-repo".parse().unwrap(),
-        };
+        let args = args("./tmp/test-repo");
 
         let config_path = std::path::PathBuf::from("./tmp/borrg.toml");
 
@@ -123,4 +596,241 @@ mod tests {
         std::fs::remove_file(&config_path).ok();
         std::fs::remove_dir_all("./tmp").ok();
     }
+
+    #[test]
+    fn test_create_exclude_file() {
+        let dir =
+            std::env::temp_dir().join(format!("borrg-test-exclude-file-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut archive = crate::Archive::new("test");
+        archive.path(dir.clone());
+        archive.set_exclude_file(".borgignore");
+
+        create_exclude_file(&archive).unwrap();
+        let path = dir.join(".borgignore");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            EXCLUDE_FILE_TEMPLATE
+        );
+
+        // Existing files are left alone
+        std::fs::write(&path, "*.iso\n").unwrap();
+        create_exclude_file(&archive).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "*.iso\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_init_dry_run() {
+        let dir =
+            std::env::temp_dir().join(format!("borrg-test-init-dry-run-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("borrg.toml");
+        std::fs::write(&config_path, "").unwrap();
+
+        let mut borg = Borg::default();
+        borg.dry_run();
+        let config = Config::load(&config_path).unwrap();
+        let repo = dir.join("repo");
+
+        let code = init(borg, config, args(repo.to_str().unwrap()));
+
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert!(!repo.exists());
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap(), "");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_append_backup_config_once() {
+        let dir =
+            std::env::temp_dir().join(format!("borrg-test-append-once-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("borrg.toml");
+        std::fs::write(&config_path, "").unwrap();
+
+        let repo: crate::Repo = "ssh://user@host/srv/backup".parse().unwrap();
+        assert!(append_backup_config(&config_path, &repo).unwrap());
+
+        // The same repository written differently
+        let same: crate::Repo = "user@host:/srv/backup".parse().unwrap();
+        assert!(!append_backup_config(&config_path, &same).unwrap());
+
+        let config = Config::load(&config_path).unwrap();
+        assert_eq!(config.backups.len(), 1);
+
+        let quoted: crate::Repo = r#"/srv/"my" \backup"#.parse().unwrap();
+        assert!(append_backup_config(&config_path, &quoted).unwrap());
+        let config = Config::load(&config_path).unwrap();
+        assert_eq!(config.backups[1].repo, quoted);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let dir =
+            std::env::temp_dir().join(format!("borrg-test-init-config-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("borrg.toml");
+        std::fs::write(
+            &config_path,
+            "[template.nas]\nencryption = \"repokey-blake2\"\nstorage_quota = \"500G\"\n\
+            make_parent_dirs = true\n\n\
+            [backup.docs]\ntemplate = \"nas\"\nrepository = \"/srv/docs\"\nappend_only = true\n",
+        )
+        .unwrap();
+        let config = Config::load(&config_path).unwrap();
+
+        let (repo, backup) = target(&config, "docs").unwrap();
+        assert_eq!(repo.to_string(), "/srv/docs");
+        let options = backup.unwrap().init.clone();
+        let no_flags = super::Args {
+            encryption: None,
+            ..args("docs")
+        };
+        assert_eq!(
+            Settings::resolve(&no_flags, &options).unwrap(),
+            Settings {
+                encryption: Encryption::RepoKeyBlake2,
+                append_only: true,
+                storage_quota: Some(500 << 30),
+                make_parent_dirs: true,
+            }
+        );
+
+        // Flags take precedence over the config
+        let flags = super::Args {
+            append_only: Some(false),
+            storage_quota: Some(1 << 30),
+            ..args("docs")
+        };
+        assert_eq!(
+            Settings::resolve(&flags, &options).unwrap(),
+            Settings {
+                encryption: Encryption::None,
+                append_only: false,
+                storage_quota: Some(1 << 30),
+                make_parent_dirs: true,
+            }
+        );
+
+        // A new repository only has the flags
+        let (repo, backup) = target(&config, "/srv/new").unwrap();
+        assert_eq!(repo.to_string(), "/srv/new");
+        assert!(backup.is_none());
+
+        // The name of one backup and the repository of another
+        std::fs::write(
+            &config_path,
+            "[backup.nas]\nrepository = \"/srv/nas\"\n\n\
+            [backup.docs]\nrepository = \"nas\"\n",
+        )
+        .unwrap();
+        let ambiguous = Config::load(&config_path).unwrap();
+        let err = target(&ambiguous, "nas").unwrap_err();
+        assert!(err.contains("several repositories"), "{err}");
+        let err = Settings::resolve(&no_flags, &InitOptions::default()).unwrap_err();
+        assert!(err.contains("--encryption"), "{err}");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_all_missing_dry_run() {
+        let dir =
+            std::env::temp_dir().join(format!("borrg-test-all-missing-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("borrg.toml");
+        let repo = |name: &str| dir.join(name).display().to_string();
+        std::fs::write(
+            &config_path,
+            format!(
+                "[backup.docs]\nrepository = {:?}\nencryption = \"repokey\"\n\n\
+                [backup.docs-again]\nrepository = {:?}\narchive_name = \"again\"\n\n\
+                [backup.photos]\nrepository = {:?}\n",
+                repo("docs"),
+                repo("docs"),
+                repo("photos"),
+            ),
+        )
+        .unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let mut borg = Borg::default();
+        borg.dry_run();
+        let args = super::Args {
+            encryption: None,
+            all_missing: true,
+            backup: None,
+            ..args("")
+        };
+
+        let docs = config.select(Some("docs")).unwrap()[0];
+        assert_eq!(
+            init_if_missing(&borg, docs, &args),
+            Outcome::WouldInitialize(Encryption::RepoKey)
+        );
+        // Without configured encryption nothing is guessed
+        let photos = config.select(Some("photos")).unwrap()[0];
+        assert!(init_if_missing(&borg, photos, &args).is_failure());
+        assert_eq!(init_all_missing(&borg, &config, &args), ExitCode::FAILURE);
+        assert!(!dir.join("docs").exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_is_missing_repo() {
+        let missing: crate::Error = "Repository /srv/docs does not exist.".into();
+        assert!(is_missing_repo(&missing));
+        assert!(is_missing_repo(&BorgError::Repository(13).into()));
+        assert!(!is_missing_repo(&BorgError::Connection(80).into()));
+        assert!(!is_missing_repo(&"Permission denied".into()));
+    }
+
+    #[test]
+    fn test_probing() {
+        let borg = Borg::default();
+        let probing = probing(&borg);
+        assert!(probing.interaction.is_batch());
+        assert!(!borg.interaction.is_batch());
+    }
+
+    #[test]
+    fn test_precheck_parent_dirs() {
+        let repo = std::env::temp_dir().join("borrg-test-missing/parent/repo");
+        let repo: crate::Repo = repo.to_str().unwrap().parse().unwrap();
+        let borg = Borg::default();
+
+        let err = precheck(&borg, &repo, false).unwrap_err();
+        assert!(err.contains("--make-parent-dirs"));
+        assert!(precheck(&borg, &repo, true).is_ok());
+    }
+
+    #[test]
+    fn test_batch_passphrase() {
+        let mut borg = Borg::default();
+        let mut repo: crate::Repo = "/srv/backup".parse().unwrap();
+        assert!(batch_passphrase(&borg, &repo, &Encryption::RepoKey).is_ok());
+
+        borg.interaction(crate::Interaction::Batch);
+        assert!(batch_passphrase(&borg, &repo, &Encryption::None).is_ok());
+        if std::env::var_os("BORG_PASSPHRASE").is_none()
+            && std::env::var_os("BORG_PASSCOMMAND").is_none()
+            && std::env::var_os("BORG_PASSPHRASE_FD").is_none()
+        {
+            let err = batch_passphrase(&borg, &repo, &Encryption::RepoKey).unwrap_err();
+            assert!(err.contains("batch mode"), "{err}");
+        }
+        repo.passphrase(crate::Passphrase::Command("pass borg".to_owned()));
+        assert!(batch_passphrase(&borg, &repo, &Encryption::RepoKey).is_ok());
+    }
 }
@@ -0,0 +1,255 @@
+use serde::Serialize;
+
+/// Version of the JSON output, increased on incompatible changes
+pub(super) const SCHEMA: u32 = 1;
+
+/// A JSON object tagged with the schema version
+#[derive(Serialize)]
+pub(super) struct Versioned<T: Serialize> {
+    borrg_schema: u32,
+    #[serde(flatten)]
+    inner: T,
+}
+
+impl<T: Serialize> Versioned<T> {
+    pub fn new(inner: T) -> Self {
+        Versioned {
+            borrg_schema: SCHEMA,
+            inner,
+        }
+    }
+}
+
+/// Print a versioned JSON object on a single line to stdout
+///
+/// Failing to print is only a warning, the result of the command doesn't change because of it.
+pub(super) fn print_json<T: Serialize>(value: T) {
+    use std::io::Write;
+
+    let json = serde_json::to_string(&Versioned::new(value)).expect("output is serializable");
+    if let Err(e) = writeln!(std::io::stdout(), "{json}") {
+        log::warn!("Failed to print the output: {e}");
+    }
+}
+
+/// Result of an operation on a single repository
+#[derive(Serialize)]
+pub(super) struct RepoResult<'a, T: Serialize> {
+    pub repository: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info: Option<&'a T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<'a, T: Serialize> RepoResult<'a, T> {
+    pub fn new(repository: &crate::Repo, result: &'a Result<T, crate::Error>) -> Self {
+        RepoResult {
+            repository: repository.to_string(),
+            info: result.as_ref().ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ArchiveInfo, ArchiveListEntry, Encryption, EntryKind, RepoInfo};
+    use serde_json::json;
+    use std::{path::PathBuf, time::SystemTime};
+
+    fn epoch() -> String {
+        chrono::DateTime::<chrono::Local>::from(SystemTime::UNIX_EPOCH).to_rfc3339()
+    }
+
+    fn snapshot<T: Serialize>(value: T) -> serde_json::Value {
+        serde_json::to_value(Versioned::new(value)).unwrap()
+    }
+
+    #[test]
+    fn test_repo_info() {
+        let info = RepoInfo {
+            total_chunks: 2,
+            total_csize: 30,
+            total_size: 40,
+            total_unique_chunks: 1,
+            unique_csize: 10,
+            unique_size: 20,
+            encryption: Encryption::RepoKeyBlake2,
+            ..crate::backend::mock::repo_info(&"/backup/repo".parse().unwrap())
+        };
+        assert_eq!(
+            snapshot(info),
+            json!({
+                "borrg_schema": 1,
+                "cache_path": "/root/.cache/borg/dd06",
+                "total_chunks": 2,
+                "total_csize": 30,
+                "total_size": 40,
+                "total_unique_chunks": 1,
+                "unique_csize": 10,
+                "unique_size": 20,
+                "encryption": "repokey-blake2",
+                "id": "dd06",
+                "location": "/backup/repo",
+                "last_modified": epoch(),
+                "keyfile": null,
+                "security_dir": "/root/.config/borg/security/dd06",
+            })
+        );
+    }
+
+    #[test]
+    fn test_archives() {
+        #[derive(Serialize)]
+        struct Archives {
+            archives: Vec<ArchiveInfo>,
+        }
+        let archives = Archives {
+            archives: vec![ArchiveInfo {
+                name: "2024-05-01".to_string(),
+                id: "f00d".to_string(),
+                time: SystemTime::UNIX_EPOCH,
+            }],
+        };
+        assert_eq!(
+            snapshot(archives),
+            json!({
+                "borrg_schema": 1,
+                "archives": [{ "name": "2024-05-01", "id": "f00d", "time": epoch() }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_archive_list_entry() {
+        let entry = ArchiveListEntry {
+            path: PathBuf::from("home/link"),
+            kind: EntryKind::Symlink,
+            size: 0,
+            mtime: SystemTime::UNIX_EPOCH,
+            mode: "lrwxrwxrwx".to_string(),
+            user: "seb".to_string(),
+            group: "users".to_string(),
+            link_target: Some(PathBuf::from("notes.txt")),
+        };
+        assert_eq!(
+            snapshot(entry),
+            json!({
+                "borrg_schema": 1,
+                "path": "home/link",
+                "type": "l",
+                "size": 0,
+                "mtime": epoch(),
+                "mode": "lrwxrwxrwx",
+                "user": "seb",
+                "group": "users",
+                "link_target": "notes.txt",
+            })
+        );
+    }
+
+    #[test]
+    fn test_repo_result() {
+        let repo: crate::Repo = "/backup/repo".parse().unwrap();
+        let err: Result<u32, crate::Error> = Err("unreachable".into());
+        assert_eq!(
+            snapshot(RepoResult::new(&repo, &err)),
+            json!({ "borrg_schema": 1, "repository": "/backup/repo", "error": "unreachable" })
+        );
+        assert_eq!(
+            snapshot(RepoResult::new(&repo, &Ok(1))),
+            json!({ "borrg_schema": 1, "repository": "/backup/repo", "info": 1 })
+        );
+    }
+
+    #[test]
+    fn test_backup_status() {
+        let status = crate::cli::status::BackupStatus {
+            id: "backup-repo-0123abcd".to_string(),
+            backup: "/backup/repo::2024-05-02".to_string(),
+            repository: "/backup/repo".to_string(),
+            encryption: Some(Encryption::None),
+            encryption_error: Some(
+                "The repository is not encrypted (none), but require_encryption is set".to_string(),
+            ),
+            archives: Some(1),
+            last_archive: Some(ArchiveInfo {
+                name: "2024-05-01".to_string(),
+                id: "f00d".to_string(),
+                time: SystemTime::UNIX_EPOCH,
+            }),
+            last_run: Some(crate::cli::state::LastRun {
+                time: SystemTime::UNIX_EPOCH,
+                success: false,
+                error: Some("failed: locked".to_string()),
+            }),
+            next_archive: Some("laptop-2024-05-02".to_string()),
+            next_archive_exists: false,
+            free_space: Some(5_000_000_000),
+            low_free_space: false,
+            dedup_ratio: Some(crate::Ratio(3.0)),
+            compression_ratio: Some(crate::Ratio(1.5)),
+            key_backup_warning: None,
+            error: None,
+            timed_out: false,
+        };
+        assert_eq!(
+            snapshot(status),
+            json!({
+                "borrg_schema": 1,
+                "id": "backup-repo-0123abcd",
+                "backup": "/backup/repo::2024-05-02",
+                "repository": "/backup/repo",
+                "encryption": "none",
+                "encryption_error":
+                    "The repository is not encrypted (none), but require_encryption is set",
+                "archives": 1,
+                "last_archive": { "name": "2024-05-01", "id": "f00d", "time": epoch() },
+                "last_run": { "time": epoch(), "success": false, "error": "failed: locked" },
+                "next_archive": "laptop-2024-05-02",
+                "free_space": 5_000_000_000_u64,
+                "dedup_ratio": 3.0,
+                "compression_ratio": 1.5,
+            })
+        );
+    }
+
+    #[test]
+    fn test_timed_out_status() {
+        let status = crate::cli::status::BackupStatus {
+            id: "backup-repo-0123abcd".to_string(),
+            backup: "/backup/repo::2024-05-02".to_string(),
+            repository: "/backup/repo".to_string(),
+            encryption: None,
+            encryption_error: None,
+            archives: None,
+            last_archive: None,
+            last_run: None,
+            next_archive: None,
+            next_archive_exists: false,
+            free_space: None,
+            low_free_space: false,
+            dedup_ratio: None,
+            compression_ratio: None,
+            key_backup_warning: None,
+            error: Some("borg didn't finish within 30s".to_string()),
+            timed_out: true,
+        };
+        assert_eq!(
+            snapshot(status),
+            json!({
+                "borrg_schema": 1,
+                "id": "backup-repo-0123abcd",
+                "backup": "/backup/repo::2024-05-02",
+                "repository": "/backup/repo",
+                "encryption": null,
+                "archives": null,
+                "last_archive": null,
+                "error": "borg didn't finish within 30s",
+                "timed_out": true,
+            })
+        );
+    }
+}
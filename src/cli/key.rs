@@ -0,0 +1,297 @@
+use super::*;
+use crate::{backend::borg::BorgWrapper, Borg, Repo, RepoInfo};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Export the key of every keyfile-mode repository to key_backup_dir
+    ///
+    /// Each key goes to a file named after the repository id, replacing an earlier export.
+    /// Repositories which store their key themselves are skipped.
+    BackupAll,
+}
+
+/// The export of a key in `key_backup_dir`, see [`KeyBackup::check`]
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum KeyBackup {
+    /// The repository stores its key itself, or isn't encrypted
+    NotNeeded,
+    /// There is no `key_backup_dir`
+    Unconfigured,
+    Missing(PathBuf),
+    /// Older than the key file, e.g. after the passphrase was changed
+    Outdated(PathBuf),
+    Current(PathBuf),
+}
+
+impl KeyBackup {
+    /// Look for the export of the key of the repository described by `info` in `dir`
+    ///
+    /// Only keyfile modes need one, without the key file on the client the repository can't be
+    /// read anymore.
+    pub(super) fn check(info: &RepoInfo, dir: Option<&Path>) -> Self {
+        if !info.encryption.is_keyfile() {
+            return KeyBackup::NotNeeded;
+        }
+        let Some(dir) = dir else {
+            return KeyBackup::Unconfigured;
+        };
+        let path = export_path(dir, &info.id);
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let Some(exported) = modified(&path) else {
+            return KeyBackup::Missing(path);
+        };
+        match info.keyfile.as_deref().and_then(modified) {
+            Some(changed) if changed > exported => KeyBackup::Outdated(path),
+            _ => KeyBackup::Current(path),
+        }
+    }
+
+    /// What to warn about, `None` if the key is safe
+    pub(super) fn warning(&self, info: &RepoInfo) -> Option<String> {
+        let keyfile = match &info.keyfile {
+            Some(keyfile) => keyfile.display().to_string(),
+            None => "the key file".to_owned(),
+        };
+        match self {
+            KeyBackup::NotNeeded | KeyBackup::Current(_) => None,
+            KeyBackup::Unconfigured => Some(format!(
+                "The key is only stored in {keyfile}, set key_backup_dir to keep a copy"
+            )),
+            KeyBackup::Missing(path) => Some(format!(
+                "The key isn't exported to {}, run `borrg key backup-all`",
+                path.display()
+            )),
+            KeyBackup::Outdated(path) => Some(format!(
+                "{} is older than {keyfile}, run `borrg key backup-all`",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Where the key of the repository with `id` is exported to
+fn export_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.key"))
+}
+
+pub fn key(borg: Borg, config: Config, args: Args) -> ExitCode {
+    match args.command {
+        Command::BackupAll => backup_all(&borg, &config),
+    }
+}
+
+/// Create `dir` and its missing parents, only accessible by the owner like the keys in it
+fn create_private_dir(dir: &std::path::Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+/// Mode of `dir` if other users can list or read it, `create_private_dir` leaves an existing
+/// directory as it is
+#[cfg(unix)]
+fn exposed_mode(dir: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(dir).ok()?.permissions().mode() & 0o777;
+    (mode & 0o077 != 0).then_some(mode)
+}
+
+#[cfg(not(unix))]
+fn exposed_mode(_dir: &Path) -> Option<u32> {
+    None
+}
+
+fn backup_all(borg: &Borg, config: &Config) -> ExitCode {
+    let Some(dir) = &config.key_backup_dir else {
+        let message = format!(
+            "No key_backup_dir in {}, set it to where the keys should be exported to",
+            config.source.display()
+        );
+        eprintln!("{}", style::error_stderr(message));
+        return ExitCode::FAILURE;
+    };
+    if !borg.dry_run {
+        if let Err(e) = create_private_dir(dir) {
+            let message = format!("Failed to create {}: {e}", dir.display());
+            eprintln!("{}", style::error_stderr(message));
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some(mode) = exposed_mode(dir) {
+        let message = format!(
+            "{} is accessible by other users (mode {mode:o}), `chmod 700` it to keep the keys private",
+            dir.display()
+        );
+        eprintln!("{}", style::warning_stderr(message));
+    }
+
+    // Several backups can share a repository and with it the key
+    let mut repos: Vec<&Repo> = vec![];
+    for backup in &config.backups {
+        if !repos.contains(&&backup.repo) {
+            repos.push(&backup.repo);
+        }
+    }
+    let infos = parallel_map(&repos, |repo| borg.repo_info::<BorgWrapper>(repo));
+
+    let (mut exported, mut skipped, mut failed) = (0, 0, 0);
+    for (repo, info) in repos.iter().zip(infos) {
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => {
+                let message = format!("[{repo}] Failed to get repository info: {e}");
+                eprintln!("{}", style::error_stderr(message));
+                failed += 1;
+                continue;
+            }
+        };
+        if !info.encryption.is_keyfile() {
+            log::info!(
+                "[{repo}] {} keeps the key in the repository",
+                info.encryption
+            );
+            skipped += 1;
+            continue;
+        }
+        let path = export_path(dir, &info.id);
+        if borg.dry_run {
+            println!("[{repo}] Would export the key to {}", path.display());
+            exported += 1;
+            continue;
+        }
+        match BorgWrapper::export_key(borg, repo, &path) {
+            Ok(()) => {
+                println!("[{repo}] Exported the key to {}", path.display());
+                exported += 1;
+            }
+            Err(e) => {
+                let message = format!("[{repo}] Failed to export the key: {e}");
+                eprintln!("{}", style::error_stderr(message));
+                failed += 1;
+            }
+        }
+    }
+
+    let verb = match borg.dry_run {
+        true => "would be exported",
+        false => "exported",
+    };
+    println!("{exported} {verb}, {skipped} not in keyfile mode, {failed} failed");
+
+    match failed {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Encryption;
+    use std::time::{Duration, SystemTime};
+
+    fn info(encryption: Encryption, keyfile: Option<PathBuf>) -> RepoInfo {
+        RepoInfo {
+            encryption,
+            keyfile,
+            ..crate::backend::mock::repo_info(&"/srv/backup".parse().unwrap())
+        }
+    }
+
+    fn touch(path: &Path, modified: SystemTime) {
+        std::fs::write(path, "BORG_KEY dd06\n").unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exposed_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("borrg-test-key-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_private_dir(&dir).unwrap();
+        assert_eq!(exposed_mode(&dir), None);
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(exposed_mode(&dir), Some(0o755));
+        // An existing directory is left alone
+        create_private_dir(&dir).unwrap();
+        assert_eq!(exposed_mode(&dir), Some(0o755));
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-key-{}", std::process::id()));
+        let keys = dir.join("keys");
+        std::fs::create_dir_all(&keys).unwrap();
+        let keyfile = dir.join("srv_backup");
+        let now = SystemTime::now();
+        touch(&keyfile, now);
+
+        let repokey = info(Encryption::RepoKey, None);
+        assert_eq!(
+            KeyBackup::check(&repokey, Some(&keys)),
+            KeyBackup::NotNeeded
+        );
+
+        let keyfile_repo = info(Encryption::KeyFileBlake2, Some(keyfile.clone()));
+        let unconfigured = KeyBackup::check(&keyfile_repo, None);
+        assert_eq!(unconfigured, KeyBackup::Unconfigured);
+        assert!(unconfigured
+            .warning(&keyfile_repo)
+            .unwrap()
+            .contains("key_backup_dir"));
+
+        let export = keys.join("dd06.key");
+        let missing = KeyBackup::check(&keyfile_repo, Some(&keys));
+        assert_eq!(missing, KeyBackup::Missing(export.clone()));
+        assert!(missing
+            .warning(&keyfile_repo)
+            .unwrap()
+            .contains("backup-all"));
+
+        // Exported before the passphrase was changed
+        touch(&export, now - Duration::from_secs(3600));
+        let outdated = KeyBackup::check(&keyfile_repo, Some(&keys));
+        assert_eq!(outdated, KeyBackup::Outdated(export.clone()));
+        assert_eq!(
+            outdated.warning(&keyfile_repo).unwrap(),
+            format!(
+                "{} is older than {}, run `borrg key backup-all`",
+                export.display(),
+                keyfile.display()
+            )
+        );
+
+        touch(&export, now + Duration::from_secs(1));
+        let current = KeyBackup::check(&keyfile_repo, Some(&keys));
+        assert_eq!(current, KeyBackup::Current(export.clone()));
+        assert_eq!(current.warning(&keyfile_repo), None);
+
+        // Without a known key file an export is all that can be checked
+        let unknown = info(Encryption::KeyFile, None);
+        assert_eq!(
+            KeyBackup::check(&unknown, Some(&keys)),
+            KeyBackup::Current(export)
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,61 @@
+use super::*;
+use crate::{backend, ArchiveInfo, Borg};
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup
+    backup: String,
+
+    /// Format of the line, e.g. "{name}\t{time}"
+    ///
+    /// Placeholders: {name}, {id}, {time}.
+    /// Use {{ and }} for literal braces.
+    #[arg(long, default_value = "{name}")]
+    format: String,
+}
+
+/// Print the newest archive of a backup, e.g. for `borg extract ::$(borrg latest home)`
+pub fn latest(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let template = match Template::parse::<ArchiveInfo>(&args.format) {
+        Ok(template) => template,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(format!("Invalid format: {e}")));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let repo = match config.select(Some(&args.backup)) {
+        Ok(backups) => &backups[0].repo,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let archives = match borg.list_archives::<backend::borg::BorgWrapper>(repo) {
+        Ok(archives) => archives,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                style::error_stderr(format!("Failed to list archives: {e}"))
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match latest_archive(&archives) {
+        Some(archive) => {
+            println!("{}", template.render(archive));
+            ExitCode::SUCCESS
+        }
+        None => {
+            let message = match archives.len() {
+                0 => format!("No archives in {repo}"),
+                n => format!("No archives in {repo}, only {n} checkpoint(s)"),
+            };
+            eprintln!("{}", style::error_stderr(message));
+            ExitCode::FAILURE
+        }
+    }
+}
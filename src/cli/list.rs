@@ -0,0 +1,208 @@
+use super::*;
+use crate::{backend, ArchiveInfo, ArchiveListEntry, Borg, ByteSize, Encryption};
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup, lists all backups if omitted
+    backup: Option<String>,
+
+    /// Archive to list the contents of, lists all archives if omitted
+    archive: Option<String>,
+
+    /// Only list paths matching this glob pattern
+    pattern: Option<String>,
+
+    /// Print JSON to stdout, one object per line for archive contents
+    #[arg(long, conflicts_with = "format")]
+    json: bool,
+
+    /// Format of each line, e.g. "{name}\t{time}"
+    ///
+    /// Placeholders for archives: {name}, {id}, {time}. borg doesn't list their number of files,
+    /// `borrg history --format "{nfiles}"` has it for the archives borrg created.
+    /// Placeholders for archive contents: {path}, {type}, {mode}, {user}, {group}, {size},
+    /// {bytes}, {mtime}, {linktarget}.
+    /// Use {{ and }} for literal braces.
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Exit successfully if no backups are configured
+    #[arg(long)]
+    allow_empty: bool,
+}
+
+pub fn list(borg: Borg, config: Config, args: Args) -> ExitCode {
+    if let Some(code) = no_backups(&config, args.allow_empty, ExitCode::FAILURE) {
+        return code;
+    }
+    let Some(query) = args.backup.as_deref() else {
+        if args.json {
+            #[derive(serde::Serialize)]
+            struct Backup {
+                id: String,
+                backup: String,
+                repository: String,
+                archive: String,
+                paths: Vec<std::path::PathBuf>,
+            }
+            #[derive(serde::Serialize)]
+            struct Output {
+                backups: Vec<Backup>,
+            }
+            print_json(Output {
+                backups: config
+                    .backups
+                    .iter()
+                    .map(|b| Backup {
+                        id: b.id().to_owned(),
+                        backup: b.to_string(),
+                        repository: b.repo.to_string(),
+                        archive: b.archive.name.to_owned(),
+                        paths: b.archive.paths.clone(),
+                    })
+                    .collect(),
+            });
+            return ExitCode::SUCCESS;
+        }
+        let rows: Vec<_> = config
+            .backups
+            .iter()
+            .map(|b| vec![b.id().to_owned(), b.to_string()])
+            .collect();
+        print_table(&["Id", "Backup"], &rows);
+        return ExitCode::SUCCESS;
+    };
+
+    let repo = match config.select(Some(query)) {
+        Ok(backups) => &backups[0].repo,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let Some(archive) = args.archive.as_deref() else {
+        let template = match args.format.as_deref().map(Template::parse::<ArchiveInfo>) {
+            Some(Ok(template)) => Some(template),
+            Some(Err(e)) => {
+                eprintln!("{}", style::error_stderr(format!("Invalid format: {e}")));
+                return ExitCode::FAILURE;
+            }
+            None => None,
+        };
+        // Lines of a custom format are only archives
+        let encryption = match template {
+            Some(_) => None,
+            None => match borg.repo_info::<backend::borg::BorgWrapper>(repo) {
+                Ok(info) => Some(info.encryption),
+                Err(e) => {
+                    log::warn!("Failed to determine the encryption of {repo}: {e}");
+                    None
+                }
+            },
+        };
+        return match borg.list_archives::<backend::borg::BorgWrapper>(repo) {
+            Ok(archives) if args.json => {
+                #[derive(serde::Serialize)]
+                struct Output {
+                    encryption: Option<Encryption>,
+                    archives: Vec<ArchiveInfo>,
+                }
+                print_json(Output {
+                    encryption,
+                    archives,
+                });
+                ExitCode::SUCCESS
+            }
+            Ok(archives) => {
+                if let Some(mode) = &encryption {
+                    println!("Encryption: {}", style::encryption(mode));
+                    if let Some(warning) = encryption_warning(repo, mode) {
+                        eprintln!("{}", style::warning_stderr(warning));
+                    }
+                }
+                for archive in archives {
+                    match &template {
+                        Some(template) => println!("{}", template.render(&archive)),
+                        None => println!(
+                            "{:<40} {} [{}]",
+                            archive.name,
+                            fmt_time(archive.time),
+                            archive.id
+                        ),
+                    }
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    style::error_stderr(format!("Failed to list archives: {e}"))
+                );
+                ExitCode::FAILURE
+            }
+        };
+    };
+
+    let pattern = match args.pattern.as_deref().map(glob::Pattern::new).transpose() {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(format!("Invalid pattern: {e}")));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let template = match args
+        .format
+        .as_deref()
+        .map(Template::parse::<ArchiveListEntry>)
+    {
+        Some(Ok(template)) => Some(template),
+        Some(Err(e)) => {
+            eprintln!("{}", style::error_stderr(format!("Invalid format: {e}")));
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+
+    let res = borg.list_archive::<backend::borg::BorgWrapper>(repo, archive, |entry| {
+        if let Some(pattern) = &pattern {
+            if !pattern.matches_path(&entry.path) {
+                return;
+            }
+        }
+        match (&template, args.json) {
+            (Some(template), _) => println!("{}", template.render(&entry)),
+            (None, true) => print_json(&entry),
+            (None, false) => println!("{}", fmt_entry(&entry)),
+        }
+    });
+
+    if let Err(e) = res {
+        eprintln!(
+            "{}",
+            style::error_stderr(format!("Failed to list archive: {e}"))
+        );
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Format an entry like `ls -l`
+fn fmt_entry(entry: &ArchiveListEntry) -> String {
+    let mut line = format!(
+        "{} {} {} {:>6} {} {}",
+        entry.mode,
+        entry.user,
+        entry.group,
+        ByteSize(entry.size),
+        fmt_time(entry.mtime),
+        entry.path.display()
+    );
+    if let Some(target) = &entry.link_target {
+        line.push_str(&format!(" -> {}", target.display()));
+    }
+    line
+}
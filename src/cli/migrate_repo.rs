@@ -0,0 +1,339 @@
+use super::*;
+use crate::{backend::borg::BorgWrapper, ArchiveInfo, Borg, Repo};
+use std::process::ExitCode;
+use toml_edit::{value, DocumentMut, Item, TableLike, Value};
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup
+    backup: String,
+
+    /// Where the repository is now, e.g. "ssh://new-nas/./backup"
+    #[arg(value_name = "NEW-LOCATION")]
+    location: String,
+
+    /// Migrate even if the repository at the new location doesn't seem to be the same one
+    #[arg(long)]
+    allow_different_id: bool,
+}
+
+/// Whether the repository at the new location is the one which moved
+#[derive(Debug, PartialEq, Eq)]
+enum Identity {
+    Same,
+    /// Why it is another one
+    Different(String),
+    /// Why it can't be told
+    Unknown(String),
+}
+
+/// Point the config at the new location of a repository
+///
+/// The new location has to answer `borg info` with the id of the old one. Borg accepts the
+/// relocation on this first contact and updates its security info, every backup and template
+/// with the repository gets the new location, and the states of backups whose ids change with
+/// it are moved along.
+pub fn migrate_repo(borg: Borg, config: Config, args: Args) -> ExitCode {
+    match migrate(&borg, &config, &args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn migrate(borg: &Borg, config: &Config, args: &Args) -> Result<(), crate::Error> {
+    let old = config.select(Some(&args.backup))?[0].repo.clone();
+    let mut new: Repo = args.location.parse()?;
+    if new.canonical_location() == old.canonical_location() {
+        return Err(format!("{old} is at {new} already").into());
+    }
+    new.passphrase.clone_from(&old.passphrase);
+    new.env.clone_from(&old.env);
+    // Otherwise borg asks whether a repository it knows from elsewhere may be used
+    new.set_env("BORG_RELOCATED_REPO_ACCESS_IS_OK", "yes");
+
+    let info = borg
+        .repo_info::<BorgWrapper>(&new)
+        .map_err(|e| format!("Failed to get the info of {new}: {e}"))?;
+    let old_id = match borg.repo_info::<BorgWrapper>(&old) {
+        Ok(info) => Some(info.id),
+        Err(e) => {
+            log::info!("Failed to get the info of {old}, comparing archives instead: {e}");
+            None
+        }
+    };
+    let recorded = match old_id {
+        Some(_) => vec![],
+        None => recorded_archives(&old),
+    };
+    let archives = match recorded.is_empty() {
+        true => vec![],
+        false => borg.list_archives::<BorgWrapper>(&new)?,
+    };
+    match identify(&info.id, old_id.as_deref(), &recorded, &archives) {
+        Identity::Same => {}
+        Identity::Different(why) | Identity::Unknown(why) if args.allow_different_id => {
+            let warning = format!("{new} may not be the repository of {old}: {why}");
+            eprintln!("{}", style::warning_stderr(warning));
+        }
+        Identity::Different(why) => {
+            return Err(format!(
+                "{new} is another repository than {old}: {why}, see --allow-different-id"
+            )
+            .into())
+        }
+        Identity::Unknown(why) => {
+            return Err(format!(
+                "Can't tell whether {new} is the repository of {old}: {why}, see \
+                --allow-different-id"
+            )
+            .into())
+        }
+    }
+
+    let source = std::fs::read_to_string(&config.source)
+        .map_err(|e| format!("{}: {e}", config.source.display()))?;
+    let mut document: DocumentMut = source.parse()?;
+    let replaced = rewrite(&mut document, &old, &args.location);
+    if replaced == 0 {
+        return Err(format!(
+            "{old} isn't written out in {}, its parts come from templates",
+            config.source.display()
+        )
+        .into());
+    }
+    if borg.dry_run {
+        println!(
+            "Would replace {old} by {new} in {replaced} place(s) of {} (dry run)",
+            config.source.display()
+        );
+        return Ok(());
+    }
+    crate::util::atomic_write(&config.source, document.to_string())?;
+    println!(
+        "Replaced {old} by {new} in {replaced} place(s) of {}",
+        config.source.display()
+    );
+
+    move_states(config)
+}
+
+/// Compare the repository with id `new_id` at the new location with the old one
+///
+/// If the old location still answers, its id has to match. Otherwise the new repository has
+/// to contain one of the `recorded` archives, the ids of those borrg created at the old one.
+fn identify(
+    new_id: &str,
+    old_id: Option<&str>,
+    recorded: &[String],
+    archives: &[ArchiveInfo],
+) -> Identity {
+    if let Some(old_id) = old_id {
+        return match old_id == new_id {
+            true => Identity::Same,
+            false => Identity::Different(format!("its id is {new_id} instead of {old_id}")),
+        };
+    }
+    if recorded.is_empty() {
+        return Identity::Unknown(
+            "the old location doesn't answer and borrg recorded no archives of it".to_owned(),
+        );
+    }
+    match archives.iter().any(|a| recorded.contains(&a.id)) {
+        true => Identity::Same,
+        false => Identity::Different(format!(
+            "it has none of the {} archives recorded for the old location",
+            recorded.len()
+        )),
+    }
+}
+
+/// Ids of the archives borrg created in `repo`, from the history
+fn recorded_archives(repo: &Repo) -> Vec<String> {
+    let Some(path) = history::history_path() else {
+        return vec![];
+    };
+    match history::read(&path) {
+        Ok(entries) => entries
+            .into_iter()
+            .filter(|entry| entry.repository == repo.to_string())
+            .map(|entry| entry.id)
+            .collect(),
+        Err(e) => {
+            log::warn!("Failed to read history: {e}");
+            vec![]
+        }
+    }
+}
+
+/// Replace the `repository` of every backup and template at `old` by `new`, returns how many
+/// were replaced
+fn rewrite(document: &mut DocumentMut, old: &Repo, new: &str) -> usize {
+    let old = old.canonical_location();
+    let mut replaced = 0;
+    let mut visit = |table: &mut dyn TableLike| {
+        let Some(item) = table.get_mut("repository") else {
+            return;
+        };
+        let location = location(item).and_then(|l| l.parse::<Repo>().ok());
+        if location.is_some_and(|repo| repo.canonical_location() == old) {
+            let mut replacement = value(new);
+            // Keeps the spacing and comments around the old value
+            if let (Some(old), Some(new)) = (item.as_value(), replacement.as_value_mut()) {
+                *new.decor_mut() = old.decor().clone();
+            }
+            *item = replacement;
+            replaced += 1;
+        }
+    };
+
+    for key in ["template", "backup"] {
+        let Some(item) = document.get_mut(key) else {
+            continue;
+        };
+        match item {
+            // `[[backup]]`
+            Item::ArrayOfTables(tables) => tables.iter_mut().for_each(|t| visit(t)),
+            // `backup = [{ ... }]`
+            Item::Value(Value::Array(values)) => values
+                .iter_mut()
+                .filter_map(Value::as_inline_table_mut)
+                .for_each(|t| visit(t)),
+            // A single backup, or named ones and templates
+            item => {
+                let Some(table) = item.as_table_like_mut() else {
+                    continue;
+                };
+                visit(table);
+                for (_, item) in table.iter_mut() {
+                    if let Some(table) = item.as_table_like_mut() {
+                        visit(table);
+                    }
+                }
+            }
+        }
+    }
+    replaced
+}
+
+/// The location of a `repository` item, see [`repository_location`]
+fn location(item: &Item) -> Option<String> {
+    let value = match item {
+        Item::Value(value) => value.clone(),
+        Item::Table(table) => Value::InlineTable(table.clone().into_inline_table()),
+        _ => return None,
+    };
+    let mut parsed: toml::Table = toml::from_str(&format!("repository = {value}")).ok()?;
+    repository_location(&parsed.remove("repository")?)
+}
+
+/// Move the states of backups whose ids changed with the new location, see [`Backup::id`]
+fn move_states(before: &Config) -> Result<(), crate::Error> {
+    let after = Config::load(&before.source)?;
+    let Some(dir) = state::StateStore::default_dir() else {
+        return Ok(());
+    };
+    let store = state::StateStore::open(dir)?;
+    // Rewriting the repositories doesn't add, remove or reorder backups
+    for (old, new) in before.backups.iter().zip(&after.backups) {
+        if old.id() == new.id() {
+            continue;
+        }
+        match store.rename(old.id(), new.id()) {
+            Ok(()) => println!("Moved the state of {} to {}", old.id(), new.id()),
+            Err(e) => {
+                let warning = format!("Failed to move the state of {}: {e}", old.id());
+                eprintln!("{}", style::warning_stderr(warning));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_rewrite() {
+        let mut document: DocumentMut = r#"
+[template.nas]
+repository = "ssh://nas/./backup" # the old one
+
+[[backup]]
+template = "nas"
+path = "/home"
+
+[[backup]]
+repository = { host = "nas", path = "./backup/" }
+path = "/etc"
+
+[[backup]]
+repository = "/mnt/usb/backup"
+"#
+        .parse()
+        .unwrap();
+
+        let old: Repo = "ssh://nas/./backup".parse().unwrap();
+        assert_eq!(rewrite(&mut document, &old, "ssh://new-nas/./backup"), 2);
+        assert_eq!(
+            document.to_string(),
+            r#"
+[template.nas]
+repository = "ssh://new-nas/./backup" # the old one
+
+[[backup]]
+template = "nas"
+path = "/home"
+
+[[backup]]
+repository = "ssh://new-nas/./backup"
+path = "/etc"
+
+[[backup]]
+repository = "/mnt/usb/backup"
+"#
+        );
+
+        let mut named: DocumentMut = "[backup.usb]\nrepository = \"/mnt/usb/backup\"\n"
+            .parse()
+            .unwrap();
+        let old: Repo = "/mnt/usb/backup".parse().unwrap();
+        assert_eq!(rewrite(&mut named, &old, "/mnt/usb2/backup"), 1);
+        assert_eq!(
+            named.to_string(),
+            "[backup.usb]\nrepository = \"/mnt/usb2/backup\"\n"
+        );
+    }
+
+    #[test]
+    fn test_identify() {
+        let archive = |id: &str| ArchiveInfo {
+            name: "2024-05-10".to_owned(),
+            id: id.to_owned(),
+            time: SystemTime::UNIX_EPOCH,
+        };
+
+        assert_eq!(identify("dd06", Some("dd06"), &[], &[]), Identity::Same);
+        assert_eq!(
+            identify("dd06", Some("f00d"), &[], &[]),
+            Identity::Different("its id is dd06 instead of f00d".to_owned())
+        );
+
+        // The old location is gone, the history decides
+        let recorded = ["a1".to_owned(), "b2".to_owned()];
+        let archives = [archive("c3"), archive("b2")];
+        assert_eq!(identify("dd06", None, &recorded, &archives), Identity::Same);
+        assert!(matches!(
+            identify("dd06", None, &recorded, &archives[..1]),
+            Identity::Different(_)
+        ));
+        assert!(matches!(
+            identify("dd06", None, &[], &archives),
+            Identity::Unknown(_)
+        ));
+    }
+}
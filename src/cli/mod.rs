@@ -1,7 +1,38 @@
+pub mod cache;
 mod config;
+pub mod configure;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+pub mod delete_repo;
+pub mod doctor;
+mod excludes;
+mod filter;
+mod format;
+pub mod history;
+pub mod info;
 pub mod init;
+mod json;
+pub mod key;
+pub mod latest;
+pub mod list;
+pub mod migrate_repo;
+mod paths_from;
+pub mod prune;
+pub mod raw;
+mod render;
 pub mod run;
-pub(crate) use clap::{arg, Args};
+mod state;
+pub mod status;
+pub mod style;
+pub mod verify_restore;
+pub mod with_lock;
+#[allow(unused_imports)]
+pub(crate) use clap::{arg, Args, Subcommand};
 pub use config::*;
 mod util;
+pub use crate::runner::Limits;
+pub use filter::Filter;
+use format::*;
+use json::*;
+pub use paths_from::{PathsDelimiter, PathsFrom};
 use util::*;
@@ -0,0 +1,234 @@
+use super::*;
+use crate::util::resolve_path;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// How the paths in a file are separated
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathsDelimiter {
+    /// One path per line
+    #[default]
+    Newline,
+    /// NUL bytes, e.g. from `find -print0`
+    Nul,
+}
+
+/// Read the paths to back up from files, generated by another tool
+#[derive(Args, Debug, Default, Clone)]
+pub struct PathsFrom {
+    /// Back up the paths listed in FILE instead of the configured ones, "-" reads stdin
+    #[arg(long, value_name = "FILE")]
+    paths_from: Option<PathBuf>,
+
+    /// Back up the paths of --paths-from in addition to the configured ones
+    #[arg(long, requires = "paths_from")]
+    keep_paths: bool,
+
+    /// How the paths of --paths-from are separated, `paths_from` files have one per line
+    #[arg(long, value_enum, value_name = "DELIMITER", default_value_t)]
+    paths_delimiter: PathsDelimiter,
+}
+
+impl PathsFrom {
+    /// Add the paths of `--paths-from` and of the `paths_from` file of each backup to its
+    /// archive
+    ///
+    /// Returns why a backup can't run, e.g. because its file lists no paths. Stdin is read at
+    /// most once, every backup reading it gets the same paths.
+    pub fn apply(&self, backups: &mut [Backup]) -> Vec<Option<String>> {
+        let mut reader = Reader { stdin: None };
+        backups
+            .iter_mut()
+            .map(|backup| self.apply_to(&mut reader, backup).err())
+            .collect()
+    }
+
+    fn apply_to(&self, reader: &mut Reader, backup: &mut Backup) -> Result<(), String> {
+        let replace = self.paths_from.is_some() && !self.keep_paths;
+        if replace {
+            backup.archive.paths.clear();
+        } else if let Some(file) = &backup.paths_from {
+            backup
+                .archive
+                .extend_paths(reader.read(file, PathsDelimiter::Newline)?);
+        }
+
+        if let Some(file) = &self.paths_from {
+            backup
+                .archive
+                .extend_paths(reader.read(file, self.paths_delimiter)?);
+        }
+        Ok(())
+    }
+}
+
+struct Reader {
+    /// What stdin contained, once it was read
+    stdin: Option<Result<Vec<u8>, String>>,
+}
+
+impl Reader {
+    /// Paths listed in `file`, an error if there are none
+    fn read(&mut self, file: &Path, delimiter: PathsDelimiter) -> Result<Vec<PathBuf>, String> {
+        let (name, paths) = if file == Path::new("-") {
+            let content = self.stdin.get_or_insert_with(|| {
+                let mut content = vec![];
+                std::io::stdin()
+                    .read_to_end(&mut content)
+                    .map_err(|e| format!("failed to read paths from stdin: {e}"))?;
+                Ok(content)
+            });
+            let content = content.as_ref().map_err(Clone::clone)?;
+            ("stdin".to_owned(), split(content, delimiter))
+        } else {
+            let content = std::fs::read(resolve_path(file))
+                .map_err(|e| format!("failed to read paths from {}: {e}", file.display()))?;
+            (file.display().to_string(), split(&content, delimiter))
+        };
+
+        if paths.is_empty() {
+            return Err(format!("no paths to back up in {name}"));
+        }
+        Ok(paths)
+    }
+}
+
+/// The non-empty paths in `content`, a trailing "\r" of a line is dropped
+fn split(content: &[u8], delimiter: PathsDelimiter) -> Vec<PathBuf> {
+    let separator = match delimiter {
+        PathsDelimiter::Newline => b'\n',
+        PathsDelimiter::Nul => b'\0',
+    };
+    content
+        .split(|&b| b == separator)
+        .map(|path| match delimiter {
+            PathsDelimiter::Newline => path.strip_suffix(b"\r").unwrap_or(path),
+            PathsDelimiter::Nul => path,
+        })
+        .filter(|path| !path.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configured(name: &str) -> Vec<Backup> {
+        let dir = std::env::temp_dir().join(format!("borrg-test-paths-from-{name}"));
+        ConfigProperty::parse(
+            &format!(
+                r#"
+                [[backup]]
+                repository = "/srv/a"
+                path = "/home"
+                [[backup]]
+                repository = "/srv/b"
+                path = "/etc"
+                paths_from = "{}"
+                "#,
+                dir.join("listed").display()
+            )
+            .parse()
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn paths(backup: &Backup) -> Vec<&str> {
+        backup
+            .archive
+            .paths()
+            .iter()
+            .map(|p| p.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split(b"/home\r\n\n/etc\n", PathsDelimiter::Newline),
+            [PathBuf::from("/home"), PathBuf::from("/etc")]
+        );
+        assert_eq!(
+            split(b"/my\nfiles\0/etc\0", PathsDelimiter::Nul),
+            [PathBuf::from("/my\nfiles"), PathBuf::from("/etc")]
+        );
+        assert!(split(b"\n\n", PathsDelimiter::Newline).is_empty());
+    }
+
+    #[test]
+    fn test_apply() {
+        let dir =
+            std::env::temp_dir().join(format!("borrg-test-paths-from-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("listed"), "/srv\n/opt\n").unwrap();
+        std::fs::write(dir.join("cli"), "/var/lib\0/root\0").unwrap();
+        std::fs::write(dir.join("empty"), "\n").unwrap();
+
+        // Only the configured paths_from
+        let mut backups = configured(&std::process::id().to_string());
+        let rejected = PathsFrom::default().apply(&mut backups);
+        assert_eq!(rejected, [None, None]);
+        assert_eq!(paths(&backups[0]), ["/home"]);
+        assert_eq!(paths(&backups[1]), ["/etc", "/srv", "/opt"]);
+
+        // --paths-from replaces everything
+        let cli = PathsFrom {
+            paths_from: Some(dir.join("cli")),
+            keep_paths: false,
+            paths_delimiter: PathsDelimiter::Nul,
+        };
+        let mut backups = configured(&std::process::id().to_string());
+        assert_eq!(cli.apply(&mut backups), [None, None]);
+        assert_eq!(paths(&backups[0]), ["/var/lib", "/root"]);
+        assert_eq!(paths(&backups[1]), ["/var/lib", "/root"]);
+
+        // --keep-paths extends them
+        let cli = PathsFrom {
+            keep_paths: true,
+            ..cli
+        };
+        let mut backups = configured(&std::process::id().to_string());
+        assert_eq!(cli.apply(&mut backups), [None, None]);
+        assert_eq!(paths(&backups[0]), ["/home", "/var/lib", "/root"]);
+        assert_eq!(
+            paths(&backups[1]),
+            ["/etc", "/srv", "/opt", "/var/lib", "/root"]
+        );
+
+        // An empty list fails the backups, naming the file
+        let empty = dir.join("empty");
+        let cli = PathsFrom {
+            paths_from: Some(empty.clone()),
+            ..PathsFrom::default()
+        };
+        let mut backups = configured(&std::process::id().to_string());
+        let rejected = cli.apply(&mut backups);
+        let expected = format!("no paths to back up in {}", empty.display());
+        assert_eq!(rejected, [Some(expected.clone()), Some(expected)]);
+
+        // So does a missing one
+        let mut backups = configured("missing");
+        let rejected = PathsFrom::default().apply(&mut backups);
+        assert_eq!(rejected[0], None);
+        assert!(rejected[1]
+            .as_ref()
+            .is_some_and(|e| e.starts_with("failed to read paths from")));
+    }
+}
@@ -0,0 +1,338 @@
+use super::*;
+use crate::{backend, ArchiveInfo, Borg, Event, PruneStats};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::process::ExitCode;
+use std::time::SystemTime;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup, all backups if omitted
+    backup: Option<String>,
+
+    /// Print every archive which is kept or pruned
+    #[arg(long, conflicts_with = "json")]
+    list: bool,
+
+    /// Print a report per backup as JSON to stdout, with the archives kept and pruned
+    #[arg(long)]
+    json: bool,
+}
+
+/// What pruning a backup did, printed by `borrg prune --json` and recorded in the prune history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct PruneReport {
+    pub backup: String,
+    pub repository: String,
+    /// When pruning finished
+    #[serde(
+        serialize_with = "crate::util::serialize_time",
+        deserialize_with = "crate::util::deserialize_time"
+    )]
+    pub time: SystemTime,
+    /// Names of the archives kept
+    pub kept: Vec<String>,
+    pub pruned: Vec<PrunedArchive>,
+    /// Whether the repository was compacted afterwards
+    pub compacted: bool,
+    /// Decrease of the compressed size of the unique chunks by compacting, if it was measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reclaimed_bytes: Option<u64>,
+    /// The repository is append-only, the archives are only removed by a later compaction
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deferred: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+}
+
+/// An archive removed by pruning
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct PrunedArchive {
+    pub name: String,
+    /// Seconds between the creation of the archive and pruning, if its time is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<u64>,
+}
+
+impl PruneReport {
+    /// Report of pruning `decisions`, the archives with their names and whether they were
+    /// pruned
+    ///
+    /// The ages come from `archives`, as listed before pruning.
+    fn new(
+        backup: &Backup,
+        decisions: &[(String, bool)],
+        archives: &[ArchiveInfo],
+        time: SystemTime,
+    ) -> Self {
+        let age = |name: &str| {
+            let archive = archives.iter().find(|a| a.name == name)?;
+            Some(time.duration_since(archive.time).ok()?.as_secs())
+        };
+        PruneReport {
+            backup: backup.to_string(),
+            repository: backup.repo.to_string(),
+            time,
+            kept: decisions
+                .iter()
+                .filter(|(_, pruned)| !pruned)
+                .map(|(name, _)| name.to_owned())
+                .collect(),
+            pruned: decisions
+                .iter()
+                .filter(|(_, pruned)| *pruned)
+                .map(|(name, _)| PrunedArchive {
+                    name: name.to_owned(),
+                    age: age(name),
+                })
+                .collect(),
+            compacted: false,
+            reclaimed_bytes: None,
+            deferred: false,
+            dry_run: false,
+        }
+    }
+}
+
+/// Output of `borrg prune --json`, a report per pruned backup or why it couldn't be pruned
+#[derive(Serialize)]
+struct PruneOutput<'a> {
+    reports: Vec<RepoResult<'a, PruneReport>>,
+}
+
+pub fn prune(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let backups = match config.select(args.backup.as_deref()) {
+        Ok(backups) => backups,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Each series only prunes its own archives
+    let backups: Vec<_> = backups
+        .into_iter()
+        .flat_map(|backup| backup.per_series(SystemTime::now()))
+        .collect();
+
+    let mut success = true;
+    let mut results = vec![];
+    // Several backups may share a repository
+    let mut append_only = HashMap::new();
+    for backup in &backups {
+        let Some(policy) = &backup.retention else {
+            if !args.json {
+                println!(
+                    "[{backup}] {}",
+                    style::warning("No retention policy, not pruning")
+                );
+            }
+            continue;
+        };
+
+        // The configured append_only if borg can't tell, e.g. for a remote repository
+        let deferred = !borg.dry_run
+            && append_only
+                .entry(backup.repo.canonical_location())
+                .or_insert_with(|| {
+                    borg.is_append_only::<backend::borg::BorgWrapper>(&backup.repo)
+                        .unwrap_or_else(|e| {
+                            log::warn!(
+                                "Failed to find out whether {} is append-only: {e}",
+                                backup.repo
+                            );
+                            None
+                        })
+                })
+                .or(backup.init.append_only)
+                .unwrap_or(false);
+        let compact = backup.compact && !borg.dry_run;
+
+        // For the ages of the pruned archives
+        let archives = borg
+            .list_archives::<backend::borg::BorgWrapper>(&backup.repo)
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to list the archives of {backup}: {e}");
+                vec![]
+            });
+        // The space compacting frees, from the sizes before and after
+        let unique_csize = || match borg.repo_info::<backend::borg::BorgWrapper>(&backup.repo) {
+            Ok(info) => Some(info.unique_csize),
+            Err(e) => {
+                log::warn!("Failed to get the size of {}: {e}", backup.repo);
+                None
+            }
+        };
+        let before = compact.then(unique_csize).flatten();
+
+        let stats = Cell::new(PruneStats::default());
+        let decisions = RefCell::new(vec![]);
+        let on_update = |event: Event| {
+            let mut s = stats.get();
+            s.update(&event);
+            stats.set(s);
+            match event {
+                Event::PruneDecision {
+                    ref archive,
+                    pruned,
+                    ..
+                } => {
+                    decisions.borrow_mut().push((archive.to_owned(), pruned));
+                    if args.list {
+                        println!("[{backup}] {event}");
+                    }
+                }
+                Event::Error(e) => eprintln!("{}", style::error_stderr(format!("[{backup}] {e}"))),
+                _ => {}
+            }
+            ControlFlow::Continue(())
+        };
+
+        let res = borg
+            .prune::<backend::borg::BorgWrapper>(&backup.repo, policy, on_update)
+            .and_then(|_| match compact {
+                true => borg.compact::<backend::borg::BorgWrapper>(&backup.repo, on_update),
+                false => Ok(()),
+            });
+        let res = res.map(|()| {
+            let after = before.and_then(|_| unique_csize());
+            PruneReport {
+                compacted: compact,
+                reclaimed_bytes: before.zip(after).map(|(b, a)| b.saturating_sub(a)),
+                deferred,
+                dry_run: borg.dry_run,
+                ..PruneReport::new(backup, &decisions.borrow(), &archives, SystemTime::now())
+            }
+        });
+        success &= res.is_ok();
+
+        if !args.json {
+            print_result(backup, &res, stats.get());
+        }
+        results.push((backup, res));
+    }
+
+    let reports: Vec<_> = results
+        .iter()
+        .filter_map(|(_, res)| res.as_ref().ok())
+        .filter(|report| !report.dry_run)
+        .cloned()
+        .collect();
+    record(&reports);
+
+    if args.json {
+        let reports = results
+            .iter()
+            .map(|(backup, res)| RepoResult::new(&backup.repo, res))
+            .collect();
+        print_json(PruneOutput { reports });
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn print_result(backup: &Backup, res: &Result<PruneReport, crate::Error>, stats: PruneStats) {
+    let report = match res {
+        Ok(report) => report,
+        Err(e) => {
+            println!(
+                "[{backup}] {}",
+                style::error(format!("pruning failed: {e}"))
+            );
+            return;
+        }
+    };
+    if report.dry_run {
+        println!("[{backup}] pruning (dry run): {stats}");
+    } else if report.deferred {
+        println!(
+            "[{backup}] {}",
+            style::warning(format!(
+                "pruning deferred (append-only repository): {stats}"
+            ))
+        );
+        println!(
+            "[{backup}] {}",
+            style::warning(
+                "Note: pruned archives are only removed once the repository is \
+                compacted without append-only mode"
+            )
+        );
+    } else if let Some(reclaimed) = report.reclaimed_bytes {
+        let summary = format!("pruning: {stats}, reclaimed {}", fmt_bytes(reclaimed));
+        println!("[{backup}] {}", style::success(summary));
+    } else {
+        println!("[{backup}] {}", style::success(format!("pruning: {stats}")));
+    }
+}
+
+/// Append the reports to the prune history, failing to do so only results in a warning
+fn record(reports: &[PruneReport]) {
+    if reports.is_empty() {
+        return;
+    }
+    let Some(path) = history::prune_history_path() else {
+        log::warn!("No state directory, not recording the prune history");
+        return;
+    };
+    if let Err(e) = history::append(&path, reports) {
+        log::warn!("Failed to record prune history in {}: {e}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_prune_report() {
+        let backups: Vec<Backup> = ConfigProperty::parse(
+            &"[backup.docs]\nrepository = \"/srv/backup\"\npath = \"/srv/docs\""
+                .parse()
+                .unwrap(),
+        )
+        .unwrap();
+        let backup = &backups[0];
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_715_310_000);
+        let archives = [ArchiveInfo {
+            name: "docs-2024-05-08".to_owned(),
+            id: "f00d".to_owned(),
+            time: time - Duration::from_secs(2 * 86400),
+        }];
+        let decisions = [
+            ("docs-2024-05-10".to_owned(), false),
+            ("docs-2024-05-08".to_owned(), true),
+            ("docs-2024-05-07".to_owned(), true),
+        ];
+
+        let report = PruneReport {
+            compacted: true,
+            reclaimed_bytes: Some(1024),
+            ..PruneReport::new(backup, &decisions, &archives, time)
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["backup"], backup.to_string());
+        assert_eq!(json["repository"], "/srv/backup");
+        assert_eq!(json["kept"], serde_json::json!(["docs-2024-05-10"]));
+        assert_eq!(
+            json["pruned"],
+            serde_json::json!([
+                { "name": "docs-2024-05-08", "age": 172800 },
+                { "name": "docs-2024-05-07" },
+            ])
+        );
+        assert_eq!(json["reclaimed_bytes"], 1024);
+        assert!(json.get("deferred").is_none());
+
+        // Read back from the prune history
+        let read: PruneReport = serde_json::from_value(json).unwrap();
+        assert_eq!(read, report);
+    }
+}
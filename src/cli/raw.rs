@@ -0,0 +1,82 @@
+use super::*;
+use crate::{backend, Borg};
+use std::ffi::OsString;
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of a backup, available to borg as `::`
+    #[arg(long)]
+    repo: Option<String>,
+
+    /// Arguments for borg, after `--`
+    #[arg(last = true, required = true, value_name = "ARGS")]
+    args: Vec<OsString>,
+}
+
+pub fn raw(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let repo = match args.repo.as_deref().map(|q| util::select_repo(&config, q)) {
+        Some(Ok(repo)) => Some(repo),
+        Some(Err(e)) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+
+    // Borg only has --dry-run for some commands, and then at different places
+    if borg.dry_run {
+        return match backend::borg::BorgWrapper::explain_raw(&borg, repo, &args.args) {
+            Ok(invocation) => {
+                println!("Would run {invocation} (dry run)");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{}", style::error_stderr(e));
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    match backend::borg::BorgWrapper::raw(&borg, repo, &args.args) {
+        Ok(status) => util::exit_code(status),
+        Err(e) => {
+            eprintln!(
+                "{}",
+                style::error_stderr(format!("Failed to run borg: {e}"))
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: Args,
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        let args = std::iter::once("raw").chain(args.iter().copied());
+        Cli::try_parse_from(args).map(|cli| cli.args)
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&["--repo", "home", "--", "key", "export", "::"]).unwrap();
+        assert_eq!(args.repo.as_deref(), Some("home"));
+        assert_eq!(args.args, ["key", "export", "::"]);
+
+        let args = parse(&["--", "--version"]).unwrap();
+        assert_eq!(args.repo, None);
+        assert_eq!(args.args, ["--version"]);
+
+        assert!(parse(&["--"]).is_err());
+        assert!(parse(&["list"]).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock,
+};
+
+/// Set when the user asks for a progress report by sending SIGUSR1
+pub(super) struct ReportRequest(Arc<AtomicBool>);
+
+impl ReportRequest {
+    /// Start listening for SIGUSR1, on platforms without it no report is ever requested
+    ///
+    /// The handler is registered once per process and shared, the daemon calls this for every
+    /// round. Requests from before the call are forgotten.
+    pub(super) fn register() -> Self {
+        static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+        let flag = FLAG.get_or_init(|| {
+            let flag = Arc::new(AtomicBool::new(false));
+            #[cfg(unix)]
+            if let Err(e) =
+                signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&flag))
+            {
+                log::warn!("Failed to register SIGUSR1 handler: {e}");
+            }
+            flag
+        });
+        flag.store(false, Ordering::Relaxed);
+        Self(Arc::clone(flag))
+    }
+
+    /// Whether a report was requested since the last call
+    pub(super) fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Warnings of borg which only differ in their path, e.g. thousands of "file changed while we
+/// backed it up", to print each kind only once
+#[derive(Debug, Default)]
+pub(super) struct Repeats {
+    /// Kind, its first message and how many more there were, in order of appearance
+    kinds: Vec<((Option<String>, String), String, u64)>,
+}
+
+impl Repeats {
+    /// Count a warning, returns whether it is the first of its kind and should be printed
+    pub(super) fn first(&mut self, msgid: Option<&str>, message: &str) -> bool {
+        let kind = (msgid.map(ToOwned::to_owned), template(message).to_owned());
+        match self.kinds.iter_mut().find(|(k, ..)| *k == kind) {
+            Some((_, _, more)) => {
+                *more += 1;
+                false
+            }
+            None => {
+                self.kinds.push((kind, message.to_owned(), 0));
+                true
+            }
+        }
+    }
+
+    /// Number of warnings which weren't printed
+    pub(super) fn more(&self) -> u64 {
+        self.kinds.iter().map(|(_, _, more)| more).sum()
+    }
+
+    /// A line per repeated kind, e.g. "/home/a: file changed while we backed it up … and 2,314
+    /// more"
+    pub(super) fn summary(&self) -> impl Iterator<Item = String> + '_ {
+        self.kinds
+            .iter()
+            .filter(|(_, _, more)| *more > 0)
+            .map(|(_, first, more)| format!("{first} … and {} more", super::fmt_count(*more)))
+    }
+}
+
+/// A message without the path it is about, "/home/a: file changed" is "file changed"
+fn template(message: &str) -> &str {
+    match message.split_once(": ") {
+        Some((path, rest)) if path.contains(['/', '\\']) => rest,
+        _ => message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats() {
+        let mut repeats = Repeats::default();
+        let changed = |path: &str| format!("{path}: file changed while we backed it up");
+
+        assert!(repeats.first(None, &changed("/mail/cur/1")));
+        assert!(repeats.first(None, "Warning: low disk space: 1 GB left"));
+        for i in 2..2317 {
+            assert!(!repeats.first(None, &changed(&format!("/mail/cur/{i}"))));
+        }
+        assert!(!repeats.first(None, "Warning: low disk space: 1 GB left"));
+        // Same text, but a different msgid
+        assert!(repeats.first(Some("FileChanged"), &changed("/mail/cur/1")));
+
+        assert_eq!(repeats.more(), 2316);
+        assert_eq!(
+            repeats.summary().collect::<Vec<_>>(),
+            [
+                "/mail/cur/1: file changed while we backed it up … and 2,315 more",
+                "Warning: low disk space: 1 GB left … and 1 more"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_report_request() {
+        let report = ReportRequest::register();
+        assert!(!report.take());
+        signal_hook::low_level::raise(signal_hook::consts::SIGUSR1).unwrap();
+        assert!(report.take());
+        assert!(!report.take());
+
+        // Every round of the daemon shares the one handler
+        let next = ReportRequest::register();
+        assert!(Arc::ptr_eq(&report.0, &next.0));
+    }
+}
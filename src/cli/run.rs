@@ -1,38 +1,391 @@
 use super::*;
-use crate::{backend, Borg};
-use std::{sync::mpsc, time::Duration};
+use crate::{
+    backend::{self, borg::BorgError},
+    runner::{BackupResult, EventSink, Job, RunEvent, Runner, Status},
+    Backend, Borg, CancellationToken, CreateStats, Event, ExcludeFile, FileChangeKind, FileFilter,
+    Phase, ProgressTracker, Repo,
+};
+use log::warn;
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Default)]
 pub struct Args {
     #[arg(short, long)]
     progress: bool,
 
     #[arg(short, long)]
     dry_run: bool,
+
+    /// With --dry-run, list the exclusion rules of each backup with what they excluded
+    #[arg(long)]
+    explain_excludes: bool,
+
+    /// Print every file borg processes with its status letter, e.g. "A" for added
+    #[arg(long)]
+    list_files: bool,
+
+    /// With --list-files, only print files with these status letters, like borg's --filter
+    ///
+    /// A: added, M: modified, U: unchanged, C: changed while reading, E: error, d: directory,
+    /// b: block device, c: character device, h: hard link, s: symlink, f: fifo, i: stdin,
+    /// -: dry run, x: excluded, ?: unknown.
+    #[arg(long = "filter", value_name = "LETTERS", requires = "list_files")]
+    file_filter: Option<FileFilter>,
+
+    /// Prune (and compact) repositories after successful backups according to their retention policy
+    #[arg(long)]
+    prune: bool,
+
+    /// Print the borg commands that would be executed instead of running them
+    #[arg(long)]
+    explain: bool,
+
+    /// Remove the progress bars when done instead of keeping a line per backup
+    #[arg(long)]
+    clear: bool,
+
+    /// Print a status line per running backup this often (e.g. 5m), 0 never
+    ///
+    /// Every 5 minutes by default when stdout isn't a terminal, e.g. under cron.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    progress_interval: Option<Duration>,
+
+    /// Fail backups without a passphrase instead of asking for it
+    #[arg(long)]
+    no_prompt: bool,
+
+    /// Run at most this many backups at the same time
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<std::num::NonZeroUsize>,
+
+    /// Try creating an archive up to this many more times if borg fails
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Cancel the backups still running after this long (e.g. 2h), like on Ctrl+C
+    ///
+    /// Borg is interrupted, so it can release the repository lock, and backups which didn't
+    /// start yet fail.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Format of the summary printed to stdout
+    #[arg(long, value_enum, default_value_t)]
+    output: OutputFormat,
+
+    /// Compare the size and number of files of each new archive with the previous one
+    #[arg(long)]
+    report: bool,
+
+    #[command(flatten)]
+    filter: Filter,
+
+    #[command(flatten)]
+    paths: PathsFrom,
+
+    /// Least severe messages of borg printed under the progress bars
+    ///
+    /// Borg logs at least its info messages, those are passed to the log of borrg (RUST_LOG)
+    /// instead of being printed. Less severe messages are only counted.
+    #[arg(long, value_enum, value_name = "LEVEL", default_value_t)]
+    borg_verbosity: BorgVerbosity,
+
+    /// Record this creation time in the new archives instead of now (RFC 3339, e.g.
+    /// 2024-05-01T03:00:00+02:00)
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    timestamp: Option<SystemTime>,
+
+    /// Only warn about timestamps in the future instead of failing the backups
+    #[arg(long)]
+    allow_future_timestamp: bool,
+
+    /// Only warn about local repositories with less than min_free_space free instead of
+    /// failing their backups
+    #[arg(long)]
+    force: bool,
+
+    /// Exit successfully if no backup ran, instead of with exit code 3
+    ///
+    /// For scheduled runs whose filters may leave out every backup, or before backups are
+    /// configured.
+    #[arg(long)]
+    allow_empty: bool,
+}
+
+/// Exit code of a run in which no backup ran, because filters left out all of them or all were
+/// skipped
+const NOTHING_RAN: u8 = 3;
+
+/// How often status lines are printed if stdout isn't a terminal, see `--progress-interval`
+const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often to print status lines, `None` for never
+fn progress_interval(interval: Option<Duration>, terminal: bool) -> Option<Duration> {
+    match interval {
+        Some(interval) if interval.is_zero() => None,
+        Some(interval) => Some(interval),
+        None if terminal => None,
+        None => Some(DEFAULT_PROGRESS_INTERVAL),
+    }
+}
+
+/// Cancel `token` on SIGINT and SIGTERM, on a second one exit right away
+///
+/// Borg gets the SIGINT of Ctrl+C itself, the token lets the runner stop starting backups.
+fn cancel_on_signals(token: &CancellationToken) {
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+
+        let mut signals = match signal_hook::iterator::Signals::new([SIGINT, SIGTERM]) {
+            Ok(signals) => signals,
+            Err(e) => {
+                log::warn!("Failed to register SIGINT and SIGTERM handlers: {e}");
+                return;
+            }
+        };
+        let token = token.clone();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                if token.is_cancelled() {
+                    std::process::exit(128 + signal);
+                }
+                eprintln!(
+                    "{}",
+                    style::warning_stderr("Cancelling the backups, interrupt again to quit")
+                );
+                // Ctrl+C reaches borg as well, a second SIGINT would make it quit right away
+                if signal == SIGINT {
+                    token.cancel_interrupted();
+                } else {
+                    token.cancel();
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = token;
+}
+
+/// Cancel `token` once `timeout` passed, see `--timeout`
+fn cancel_after(token: &CancellationToken, timeout: Duration) {
+    let token = token.clone();
+    std::thread::spawn(move || {
+        if !token.wait_timeout(timeout) {
+            let message = format!("Timeout of {} reached, cancelling", fmt_humantime(timeout));
+            eprintln!("{}", style::warning_stderr(message));
+            token.cancel();
+        }
+    });
+}
+
+fn parse_timestamp(s: &str) -> Result<SystemTime, chrono::ParseError> {
+    chrono::DateTime::parse_from_rfc3339(s).map(Into::into)
+}
+
+/// Severity of the messages of borg, see `--borg-verbosity`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum BorgVerbosity {
+    Error,
+    #[default]
+    Warning,
+    Info,
+    Debug,
+}
+
+impl BorgVerbosity {
+    fn level(self) -> log::Level {
+        match self {
+            BorgVerbosity::Error => log::Level::Error,
+            BorgVerbosity::Warning => log::Level::Warn,
+            BorgVerbosity::Info => log::Level::Info,
+            BorgVerbosity::Debug => log::Level::Debug,
+        }
+    }
+
+    /// The level borg logs at, info messages go to the log of borrg even if not printed
+    fn borg_level(self) -> log::Level {
+        self.level().max(log::Level::Info)
+    }
+
+    /// What to do with a message of borg, printing those without a level
+    fn show(self, level: Option<log::Level>) -> Shown {
+        match level {
+            None => Shown::Print,
+            Some(level) if level <= self.level() => Shown::Print,
+            Some(log::Level::Info) => Shown::Log,
+            Some(_) => Shown::Count,
+        }
+    }
+}
+
+/// See [`BorgVerbosity::show`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shown {
+    Print,
+    /// Only in the log of borrg
+    Log,
+    Count,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputFormat {
+    /// A line per backup
+    #[default]
+    Text,
+    /// A JSON object including the name and id of every new archive
+    Json,
 }
 
-pub fn run(mut borg: Borg, config: Config, args: Args) {
+/// What the outputs of one `borrg run`, or one round of the daemon, have in common
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct RunContext {
+    /// Short random id to find the run in the summary, history, state and logs, e.g.
+    /// "3f9a0c1e"
+    pub id: String,
+}
+
+impl RunContext {
+    pub fn new() -> Self {
+        RunContext {
+            id: format!("{:08x}", crate::util::random() as u32),
+        }
+    }
+
+    /// Pass the id to everything borg starts, e.g. a passcommand, as `BORRG_RUN_ID`
+    pub fn apply(&self, backups: &mut [Backup]) {
+        for backup in backups {
+            backup.repo.set_env("BORRG_RUN_ID", &self.id);
+        }
+    }
+}
+
+pub fn run(mut borg: Borg, mut config: Config, args: Args) -> ExitCode {
     if args.dry_run {
         borg.dry_run();
     }
+    if args.explain_excludes {
+        // Either `borrg --dry-run run` or `borrg run --dry-run`
+        if !borg.dry_run {
+            let error = "--explain-excludes requires --dry-run";
+            eprintln!("{}", style::error_stderr(error));
+            return ExitCode::FAILURE;
+        }
+        borg.list_excluded();
+    }
+    let listed = args
+        .list_files
+        .then(|| args.file_filter.clone().unwrap_or_default());
+    if let Some(filter) = &listed {
+        borg.list_files(filter.clone());
+    }
+    borg.log_level(args.borg_verbosity.borg_level());
+
+    if let Some(code) = no_backups(&config, args.allow_empty, ExitCode::from(NOTHING_RAN)) {
+        return code;
+    }
+    let configured = config.backups.len();
+    let mut filtered_out = args.filter.apply(&mut config.backups);
+
+    let prune = args.prune;
+    let clear = args.clear;
+
+    if let Some(timestamp) = args.timestamp {
+        for backup in &mut config.backups {
+            backup.archive.set_timestamp(timestamp);
+            backup.archive.name = backup.archive_name_at(timestamp);
+        }
+    }
+
+    let future = future_timestamps(&config.backups, SystemTime::now());
+    let unreadable: Vec<_> = args
+        .paths
+        .apply(&mut config.backups)
+        .into_iter()
+        .zip(future)
+        .map(|(unreadable, future)| match future {
+            Some(e) if args.allow_future_timestamp => {
+                warn!("{e}");
+                unreadable
+            }
+            Some(e) => unreadable.or(Some(format!("{e}, see --allow-future-timestamp"))),
+            None => unreadable,
+        })
+        .collect();
+
+    if args.explain {
+        return explain(&borg, &config, &unreadable, prune);
+    }
+
+    // Once here instead of once per backup, and before asking for passphrases
+    let mut version = None;
+    if !config.backups.is_empty() {
+        match borg.version::<backend::borg::BorgWrapper>() {
+            Ok(v) => version = Some(v),
+            Err(e) => {
+                if let Some(e @ BorgError::BinaryNotFound { .. }) = e.downcast_ref() {
+                    eprintln!("{}", style::error_stderr(e));
+                    return ExitCode::FAILURE;
+                }
+                warn!("Failed to determine the version of borg: {e}");
+            }
+        }
+    }
+    if let Some(version) = &version {
+        warn_remote_versions(&borg, &config.backups, version);
+    }
+
+    let ctx = RunContext::new();
+    log::info!("Starting run {}", ctx.id);
+    ctx.apply(&mut config.backups);
+
+    // Before the progress bars, they would draw over the prompts
+    let not_asking = match (borg.interaction.is_batch(), args.no_prompt) {
+        (true, _) => Some("--batch"),
+        (false, true) => Some("--no-prompt"),
+        (false, false) => None,
+    };
+    let mut rejected: Vec<_> = prompt_passphrases(&mut config.backups, not_asking)
+        .into_iter()
+        .zip(unreadable)
+        .map(|(passphrase, paths)| paths.or(passphrase))
+        .collect();
+    let time = args.timestamp.unwrap_or_else(SystemTime::now);
+    filtered_out.extend(expand_series(
+        &borg,
+        &mut config.backups,
+        &mut rejected,
+        time,
+    ));
+    let rejected = check_encryption(&borg, &config.backups, rejected);
+    let rejected = match borg.dry_run {
+        true => rejected,
+        false => check_free_space(&config.backups, rejected, args.force),
+    };
 
-    let borg = std::sync::Arc::new(borg);
-    let (tx, rx) = mpsc::channel();
     let mp = indicatif::MultiProgress::new();
+    if !borg
+        .interaction
+        .shows_progress(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+    {
+        mp.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+    }
     let multi = config.backups.len() > 1;
 
-    let mut handles = vec![];
-    for (idx, backup) in config.backups.into_iter().enumerate() {
+    let mut bars = vec![];
+    for backup in &config.backups {
+        if prune && backup.retention.is_none() {
+            warn!("No retention policy for {backup}, not pruning");
+        }
+
         let pb = mp.add(indicatif::ProgressBar::new(u64::MAX));
-        let prefix = if multi {
-            format!("[{}::{}] ", &backup.0, &backup.1)
-        } else {
-            String::new()
-        };
-        let template = format!(
-            "{}{}",
-            &prefix, "{elapsed:.dim} {spinner:.green} {prefix:.yellow} {wide_msg}"
-        );
+        let prefix = prefix(backup, multi, borg.dry_run);
+        let template = style::spinner_template(&prefix);
         let sty = indicatif::ProgressStyle::default_spinner()
             .template(&template)
             .unwrap()
@@ -45,64 +398,1748 @@ pub fn run(mut borg: Borg, config: Config, args: Args) {
         //     //.tick_strings(&vec!["▱▱▱▱", "▰▱▱▱", "▰▰▱▱", "▱▰▰▱", "▱▱▰▰", "▱▱▱▰"])
         //     .template(&template),
 
-        let backup = std::sync::Arc::new(backup);
-        let borg = borg.clone();
+        bars.push(Bar {
+            pb,
+            prefix,
+            totals: String::new(),
+            progress: ProgressTracker::new(),
+            verbosity: args.borg_verbosity,
+            hidden: 0,
+            repeats: render::Repeats::default(),
+            borg_log_level: backup.borg_log_level,
+            show_rc: backup.show_rc,
+        });
+    }
 
-        let tx = tx.clone();
-        let handle = std::thread::spawn(move || {
-            let res =
-                borg.create_archive::<backend::borg::BorgWrapper>(&backup.0, &backup.1, |e| {
-                    tx.send((idx, e)).unwrap();
-                });
+    let prefixes: Vec<_> = bars.iter().map(|bar| bar.prefix.clone()).collect();
+    let names: Vec<_> = config.backups.iter().map(ToString::to_string).collect();
+    let repos: Vec<_> = config.backups.iter().map(|b| b.repo.clone()).collect();
+    let ids: Vec<_> = config.backups.iter().map(|b| b.id().to_owned()).collect();
+    let archives: Vec<_> = config.backups.iter().map(|b| b.archive.clone()).collect();
+    let excluded = Arc::new(Mutex::new(vec![vec![]; config.backups.len()]));
+    let excluded_by_borg = Arc::clone(&excluded);
+    config.limits.jobs = args.jobs;
+    let explain_excludes = args.explain_excludes;
+    let cancellation = CancellationToken::new();
+    cancel_on_signals(&cancellation);
+    if let Some(timeout) = args.timeout {
+        cancel_after(&cancellation, timeout);
+    }
+    let interval = progress_interval(
+        args.progress_interval,
+        borg.interaction
+            .shows_progress(std::io::IsTerminal::is_terminal(&std::io::stdout())),
+    );
+    let mut runner = Runner::new(borg);
+    runner
+        .limits(config.limits)
+        .retries(args.retries)
+        .cancellation(cancellation);
 
-            if let Err(e) = res {
-                tx.send((idx, crate::Event::Error(e))).unwrap();
+    let results = run_backups::<backend::borg::BorgWrapper>(
+        &runner,
+        config.backups,
+        rejected,
+        prune,
+        interval,
+        move |idx, event| match event {
+            RunEvent::Event(event @ Event::FileStatus { .. }) => {
+                let kind = event.file_change().unwrap_or(FileChangeKind::Unknown);
+                // Borg lists the union of both filters
+                if kind == FileChangeKind::Excluded && explain_excludes {
+                    if let Event::FileStatus { path, .. } = &event {
+                        excluded_by_borg.lock().unwrap()[idx].push(path.to_owned());
+                    }
+                }
+                if listed.as_ref().is_some_and(|filter| filter.contains(kind)) {
+                    render(&mut bars[idx], event);
+                }
             }
-        });
+            RunEvent::Event(event) => render(&mut bars[idx], event),
+            RunEvent::Queued(reason) => bars[idx].pb.set_message(reason.unwrap_or_default()),
+            RunEvent::Finished(summary) => bars[idx].finish(summary, clear),
+        },
+    );
 
-        handles.push((handle, pb, prefix));
+    if clear {
+        if let Err(e) = mp.clear() {
+            warn!("Failed to clear the progress bars: {e}");
+        }
     }
-    // Drop original tx so that the receiver stops when all threads finish
-    drop(tx);
 
-    for (idx, event) in rx {
-        let (_, pb, prefix) = &mut handles[idx];
-        use crate::borrg::Event as E;
+    // Read before the new archives are recorded, they would be their own baseline
+    let baselines = if args.report {
+        read_baselines(&ids)
+    } else {
+        vec![]
+    };
+
+    record_history(&ctx, &repos, &ids, &results);
+    record_state(&ctx, &ids, &results);
+
+    // Printing can fail, e.g. on a closed pipe, the backups succeeded anyway
+    match args.output {
+        OutputFormat::Text => {
+            if let Err(e) = print_summary(&ctx, &results, &prefixes) {
+                warn!("Failed to print the summary: {e}");
+            }
+            if args.report {
+                print_report(&results, &baselines, &prefixes);
+            }
+            if args.explain_excludes {
+                let excluded = excluded.lock().unwrap();
+                print_excludes(&archives, &excluded, &prefixes);
+            }
+            if nothing_ran(&results) {
+                print_nothing_ran(configured, &filtered_out);
+            } else if !filtered_out.is_empty() {
+                println!("{} backup(s) filtered out", filtered_out.len());
+            }
+        }
+        OutputFormat::Json => {
+            let mut output = RunOutput::new(&ctx, &names, &repos, &results, &filtered_out);
+            if args.report {
+                output.add_reports(&results, &baselines);
+            }
+            print_json(output);
+        }
+    }
+    let success = !results.iter().any(BackupResult::is_failed);
+
+    if nothing_ran(&results) {
+        // Skipped backups aren't successful, but nothing failed either
+        return match args.allow_empty {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::from(NOTHING_RAN),
+        };
+    }
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Whether no backup ran, either none was left to run or all were skipped
+fn nothing_ran(results: &[BackupResult]) -> bool {
+    results.iter().all(|result| result.skipped)
+}
+
+/// E.g. "0 of 12 backups selected", with why each filtered out backup didn't match
+fn print_nothing_ran(configured: usize, filtered_out: &[(String, String)]) {
+    let summary = format!("0 of {configured} backups selected");
+    println!("{}", style::warning(summary));
+    for (backup, reason) in filtered_out {
+        println!("[{backup}] filtered out: {reason}");
+    }
+}
+
+/// Why the timestamp of each backup is suspicious, if it is later than `now`
+fn future_timestamps(backups: &[Backup], now: SystemTime) -> Vec<Option<String>> {
+    backups
+        .iter()
+        .map(|backup| {
+            let timestamp = backup.archive.timestamp().filter(|t| *t > now)?;
+            let timestamp = chrono::DateTime::<chrono::Local>::from(timestamp).to_rfc3339();
+            Some(format!(
+                "timestamp {timestamp} of {backup} is in the future"
+            ))
+        })
+        .collect()
+}
+
+/// Ask once per repository for the passphrases borg would otherwise prompt for in parallel
+///
+/// Only on a terminal. With the flag `not_asking`, e.g. "--no-prompt", these backups get a
+/// reason to fail instead.
+fn prompt_passphrases(backups: &mut [Backup], not_asking: Option<&str>) -> Vec<Option<String>> {
+    let missing = missing_passphrases(backups, |var| std::env::var_os(var).is_some());
+    if let Some(flag) = not_asking {
+        return missing
+            .into_iter()
+            .map(|m| m.then(|| format!("no passphrase configured, not asking ({flag})")))
+            .collect();
+    }
+
+    if std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        let mut entered: Vec<(Repo, String)> = vec![];
+        for (backup, _) in backups.iter_mut().zip(missing).filter(|(_, m)| *m) {
+            let passphrase = match entered.iter().find(|(repo, _)| *repo == backup.repo) {
+                Some((_, passphrase)) => passphrase.to_owned(),
+                None => {
+                    let prompt = format!(
+                        "Passphrase for {} (empty if it isn't encrypted): ",
+                        backup.repo
+                    );
+                    let Some(passphrase) = read_secret(&prompt) else {
+                        break;
+                    };
+                    entered.push((backup.repo.clone(), passphrase.clone()));
+                    passphrase
+                }
+            };
+            backup
+                .repo
+                .passphrase(crate::Passphrase::Passphrase(passphrase));
+        }
+    }
+    backups.iter().map(|_| None).collect()
+}
+
+/// Which backups have neither a configured passphrase nor one in the environment
+///
+/// Repositories configured with `encryption = "none"` don't need one.
+fn missing_passphrases(backups: &[Backup], is_set: impl Fn(&str) -> bool) -> Vec<bool> {
+    backups
+        .iter()
+        .map(|b| {
+            b.init.encryption != Some(crate::Encryption::None)
+                && !backend::borg::has_passphrase_source(b.repo.passphrase.as_ref(), &is_set)
+        })
+        .collect()
+}
+
+/// Prefix of the lines of a backup, its name if there are several and a dry run marker
+pub(super) fn prefix(backup: &Backup, with_name: bool, dry_run: bool) -> String {
+    let mut prefix = String::new();
+    if with_name {
+        prefix.push_str(&format!("[{backup}] "));
+    }
+    if dry_run {
+        prefix.push_str("(dry-run) ");
+    }
+    prefix
+}
+
+/// Print one line per backup and the run id
+pub(super) fn print_summary(
+    ctx: &RunContext,
+    results: &[BackupResult],
+    prefixes: &[String],
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut out = std::io::stdout().lock();
+    for (result, prefix) in results.iter().zip(prefixes) {
+        match result.status() {
+            Status::Skipped => writeln!(out, "{prefix}{}", style::warning(result))?,
+            Status::Failed => writeln!(out, "{prefix}{}", style::error(result))?,
+            Status::Success if matches!(result.prune, Some(Err(_))) => {
+                writeln!(out, "{prefix}{}", style::warning(result))?
+            }
+            Status::Success => writeln!(out, "{prefix}{}", style::success(result))?,
+        }
+        if result.prune_deferred {
+            let note = "Note: the repository is append-only, pruned archives are only removed \
+                once it is compacted without append-only mode";
+            writeln!(out, "{prefix}{}", style::warning(note))?;
+        }
+    }
+    writeln!(out, "run {}", ctx.id)
+}
+
+/// Warn about remote repositories of backups with `check_remote_version` whose host runs
+/// another version of borg than `local`
+fn warn_remote_versions(borg: &Borg, backups: &[Backup], local: &crate::Version) {
+    let repos = doctor::remote_repos(
+        backups
+            .iter()
+            .filter(|b| b.check_remote_version)
+            .map(|b| &b.repo),
+    );
+    let versions = parallel_map(&repos, |repo| {
+        doctor::RemoteVersion::check(borg, repo, local)
+    });
+    for (repo, version) in repos.iter().zip(versions) {
+        if let Some(warning) = version.warning(repo, local) {
+            eprintln!("{}", style::warning_stderr(format!("[{repo}] {warning}")));
+        }
+    }
+}
+
+/// Replace the backups with archive series by a backup per due series, see
+/// [`Backup::series_due`]
+///
+/// The archives are listed in parallel, every series is due if borg can't list them. Rejected
+/// backups aren't expanded, they fail once. Returns the backups without a due series with why
+/// they don't run, like [`Filter::apply`](super::filter::Filter::apply).
+pub(super) fn expand_series(
+    borg: &Borg,
+    backups: &mut Vec<Backup>,
+    rejected: &mut Vec<Option<String>>,
+    time: SystemTime,
+) -> Vec<(String, String)> {
+    let listed: Vec<_> = backups
+        .iter()
+        .zip(rejected.iter())
+        .filter(|(backup, rejected)| !backup.series.is_empty() && rejected.is_none())
+        .map(|(backup, _)| backup)
+        .collect();
+    let mut archives = parallel_map(&listed, |backup| {
+        borg.list_archives::<backend::borg::BorgWrapper>(&backup.repo)
+            .unwrap_or_else(|e| {
+                warn!("Failed to list the archives of {backup}, creating every series: {e}");
+                vec![]
+            })
+    })
+    .into_iter();
+
+    let mut expanded = vec![];
+    let mut expanded_rejected = vec![];
+    let mut not_due = vec![];
+    let mut series_ids = vec![];
+    for (backup, rejected) in backups.drain(..).zip(rejected.drain(..)) {
+        if backup.series.is_empty() || rejected.is_some() {
+            expanded.push(backup);
+            expanded_rejected.push(rejected);
+            continue;
+        }
+        let archives = archives.next().unwrap_or_default();
+        let ids: Vec<_> = backup.series.iter().map(|s| backup.series_id(s)).collect();
+        series_ids.push((backup.id().to_owned(), ids));
+
+        let due = backup.for_each_series(
+            backup
+                .series
+                .iter()
+                .filter(|series| backup.series_due(series, &archives, time)),
+            time,
+        );
+        if due.is_empty() {
+            not_due.push((backup.to_string(), "no archive series is due".to_owned()));
+        }
+        expanded_rejected.extend(due.iter().map(|_| None));
+        expanded.extend(due);
+    }
+
+    // Backups after one with series wait for all of them
+    for backup in &mut expanded {
+        backup.after = backup
+            .after
+            .iter()
+            .flat_map(
+                |after| match series_ids.iter().find(|(id, _)| id == after) {
+                    Some((_, ids)) => ids.clone(),
+                    None => vec![after.clone()],
+                },
+            )
+            .collect();
+    }
+
+    *backups = expanded;
+    *rejected = expanded_rejected;
+    not_due
+}
+
+/// Reject the backups whose repository doesn't use the encryption they require
+///
+/// Already rejected ones aren't checked, borg would only fail for the same reason.
+fn check_encryption(
+    borg: &Borg,
+    backups: &[Backup],
+    rejected: Vec<Option<String>>,
+) -> Vec<Option<String>> {
+    let checks: Vec<_> = backups.iter().zip(rejected).collect();
+    parallel_map(&checks, |(backup, rejected)| {
+        if rejected.is_some() || backup.require_encryption == RequiredEncryption::Any {
+            return rejected.clone();
+        }
+        match borg.repo_info::<backend::borg::BorgWrapper>(&backup.repo) {
+            Ok(info) => backup.require_encryption.check(&info.encryption).err(),
+            Err(e) => Some(format!(
+                "Failed to check the encryption of the repository: {e}"
+            )),
+        }
+    })
+}
+
+/// Reject the backups whose local repository is on a filesystem with less than their
+/// `min_free_space` free, only warn about them with `force`
+///
+/// Borg fails halfway through when it runs out of space, with an error not mentioning it.
+/// Remote repositories aren't checked.
+fn check_free_space(
+    backups: &[Backup],
+    rejected: Vec<Option<String>>,
+    force: bool,
+) -> Vec<Option<String>> {
+    backups
+        .iter()
+        .zip(rejected)
+        .map(|(backup, rejected)| {
+            let Some(min) = backup.min_free_space else {
+                return rejected;
+            };
+            if rejected.is_some() || backup.repo.is_remote() {
+                return rejected;
+            }
+            let free = match crate::util::free_space(backup.repo.borg_location()) {
+                Ok(free) => free,
+                Err(e) => {
+                    warn!("Failed to get the free space of {}: {e}", backup.repo);
+                    return None;
+                }
+            };
+            if free >= min.0 {
+                return None;
+            }
+            let problem = format!(
+                "Only {} free on the filesystem of the repository, min_free_space is {}",
+                fmt_bytes(free),
+                fmt_bytes(min.0)
+            );
+            if !force {
+                return Some(problem);
+            }
+            eprintln!("{}", style::warning_stderr(format!("[{backup}] {problem}")));
+            None
+        })
+        .collect()
+}
+
+/// Append the archives created by successful backups to the history file
+///
+/// Failing to do so only results in a warning, the backups themselves are done.
+pub(super) fn record_history(
+    ctx: &RunContext,
+    repos: &[Repo],
+    ids: &[String],
+    results: &[BackupResult],
+) {
+    let entries = history_entries(ctx, repos, ids, results);
+    if entries.is_empty() {
+        return;
+    }
+
+    let Some(path) = history::history_path() else {
+        warn!("No state directory, not recording history");
+        return;
+    };
+    if let Err(e) = history::append(&path, &entries) {
+        warn!("Failed to record history in {}: {e}", path.display());
+    }
+}
+
+/// History entries of the new archives, none for dry runs
+fn history_entries(
+    ctx: &RunContext,
+    repos: &[Repo],
+    ids: &[String],
+    results: &[BackupResult],
+) -> Vec<history::HistoryEntry> {
+    repos
+        .iter()
+        .zip(ids)
+        .zip(results)
+        .filter(|(_, result)| !result.dry_run)
+        .filter_map(|((repo, id), result)| {
+            let throughput = result.throughput.as_ref();
+            Some(history::HistoryEntry {
+                average_throughput: throughput.map(|t| t.average.round() as u64),
+                peak_throughput: throughput.map(|t| t.peak.round() as u64),
+                run_id: Some(ctx.id.to_owned()),
+                backup: Some(id.to_owned()),
+                ..history::HistoryEntry::new(repo, result.stats.as_ref()?)
+            })
+        })
+        .collect()
+}
+
+/// Remember the result of every backup in its state file, see [`Backup::id`]
+///
+/// Failing to do so only results in a warning, like for the history.
+pub(super) fn record_state(ctx: &RunContext, ids: &[String], results: &[BackupResult]) {
+    if results.iter().all(|o| o.dry_run) {
+        return;
+    }
+    let Some(dir) = state::StateStore::default_dir() else {
+        warn!("No state directory, not recording the state of backups");
+        return;
+    };
+    let store = match state::StateStore::open(dir) {
+        Ok(store) => store,
+        Err(e) => {
+            warn!("Failed to open state directory: {e}");
+            return;
+        }
+    };
+
+    record_state_in(ctx, &store, ids, results);
+}
+
+fn record_state_in(
+    ctx: &RunContext,
+    store: &state::StateStore,
+    ids: &[String],
+    results: &[BackupResult],
+) {
+    for (id, result) in ids.iter().zip(results) {
+        // Neither ran, a skipped backup keeps its last run
+        if result.dry_run || result.skipped {
+            continue;
+        }
+        let run = state::RunHistoryEntry {
+            time: std::time::SystemTime::now(),
+            duration: result.backup.as_ref().map_or(0.0, Duration::as_secs_f64),
+            success: result.is_success(),
+            archive: result.stats.as_ref().map(|s| s.name.to_owned()),
+            run_id: Some(ctx.id.to_owned()),
+        };
+        let error = (!result.is_success()).then(|| result.summary());
+        if let Err(e) = store.update(id, |state| state.record(run, error)) {
+            warn!("Failed to record the state of {id}: {e}");
+        }
+    }
+}
+
+/// Newest history entry of each backup, `None` for backups without one
+///
+/// Other backups and series can share a repository, so entries are looked up by backup id.
+fn read_baselines(ids: &[String]) -> Vec<Option<history::HistoryEntry>> {
+    let entries = match history::history_path().map(|path| history::read(&path)) {
+        Some(Ok(entries)) => entries,
+        Some(Err(e)) => {
+            warn!("Failed to read history: {e}");
+            vec![]
+        }
+        None => vec![],
+    };
+    ids.iter()
+        .map(|id| history::latest(&entries, id).cloned())
+        .collect()
+}
+
+/// Print the change of every new archive against its baseline
+fn print_report(
+    results: &[BackupResult],
+    baselines: &[Option<history::HistoryEntry>],
+    prefixes: &[String],
+) {
+    let now = chrono::Local::now();
+    for ((result, baseline), prefix) in results.iter().zip(baselines).zip(prefixes) {
+        if let Some(stats) = &result.stats {
+            println!("{prefix}{}", fmt_report(stats, baseline.as_ref(), now));
+        }
+    }
+}
+
+/// Print what the exclusion rules of every backup excluded, see `--explain-excludes`
+fn print_excludes(archives: &[crate::Archive], excluded: &[Vec<PathBuf>], prefixes: &[String]) {
+    for ((archive, excluded), prefix) in archives.iter().zip(excluded).zip(prefixes) {
+        for line in excludes::ExclusionReport::new(archive, excluded).lines() {
+            println!("{prefix}{line}");
+        }
+    }
+}
+
+/// Change of an archive against the previous one of its repository, e.g.
+/// "+2.10 GiB original, +1,204 files vs. yesterday"
+fn fmt_report(
+    stats: &CreateStats,
+    previous: Option<&history::HistoryEntry>,
+    now: chrono::DateTime<chrono::Local>,
+) -> String {
+    let Some(previous) = previous else {
+        return "first archive, nothing to compare with".to_owned();
+    };
+
+    let sign = |delta: i128| if delta < 0 { '-' } else { '+' };
+    let (size, files) = changes(stats, previous);
+
+    let days = (now.date_naive()
+        - chrono::DateTime::<chrono::Local>::from(previous.time).date_naive())
+    .num_days();
+    let since = match days {
+        ..=0 => "earlier today".to_owned(),
+        1 => "yesterday".to_owned(),
+        days => format!("{days} days ago"),
+    };
+
+    format!(
+        "{}{} original, {}{} files vs. {since}",
+        sign(size),
+        fmt_bytes(size.unsigned_abs() as u64),
+        sign(files),
+        fmt_count(files.unsigned_abs() as u64),
+    )
+}
+
+/// Change of the original size and of the number of files against the previous archive
+fn changes(stats: &CreateStats, previous: &history::HistoryEntry) -> (i128, i128) {
+    (
+        i128::from(stats.original_size) - i128::from(previous.original_size),
+        i128::from(stats.nfiles) - i128::from(previous.nfiles),
+    )
+}
+
+/// Summary of `borrg run --output json`
+#[derive(serde::Serialize)]
+struct RunOutput<'a> {
+    run_id: &'a str,
+    backups: Vec<BackupOutput<'a>>,
+    filtered_out: usize,
+    /// The filtered out backups with why they didn't match
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    excluded: Vec<ExcludedOutput<'a>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+}
+
+#[derive(serde::Serialize)]
+struct BackupOutput<'a> {
+    backup: &'a str,
+    repository: String,
+    success: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    skipped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive: Option<ArchiveOutput<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclude_file: Option<ExcludeFileOutput<'a>>,
+    /// Only with `--report` and a new archive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<ReportOutput<'a>>,
+    /// Pruned on an append-only repository, see [`BackupResult::prune_deferred`]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    prune_deferred: bool,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    warnings: &'a [String],
+}
+
+/// A backup left out by the filters
+#[derive(serde::Serialize)]
+struct ExcludedOutput<'a> {
+    backup: &'a str,
+    reason: &'a str,
+}
+
+/// Change of the new archive against the previous one of its repository, see `--report`
+#[derive(serde::Serialize)]
+struct ReportOutput<'a> {
+    /// `None` for the first archive in the history
+    previous: Option<PreviousOutput<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_size_change: Option<i128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nfiles_change: Option<i128>,
+}
+
+/// The archive a new one is compared with
+#[derive(serde::Serialize)]
+struct PreviousOutput<'a> {
+    name: &'a str,
+    id: &'a str,
+    #[serde(serialize_with = "crate::util::serialize_time")]
+    time: SystemTime,
+}
+
+/// The exclude file and whether borg used it
+#[derive(serde::Serialize)]
+struct ExcludeFileOutput<'a> {
+    path: &'a std::path::Path,
+    applied: bool,
+}
+
+/// The new archive, as reported by borg
+#[derive(serde::Serialize)]
+struct ArchiveOutput<'a> {
+    name: &'a str,
+    id: &'a str,
+    /// Seconds
+    duration: f64,
+    original_size: u64,
+    compressed_size: u64,
+    deduplicated_size: u64,
+    nfiles: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression_ratio: Option<crate::Ratio>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    space_saving: Option<crate::Percentage>,
+}
+
+impl<'a> RunOutput<'a> {
+    fn new(
+        ctx: &'a RunContext,
+        names: &'a [String],
+        repos: &[Repo],
+        results: &'a [BackupResult],
+        filtered_out: &'a [(String, String)],
+    ) -> Self {
+        let backups = names
+            .iter()
+            .zip(repos)
+            .zip(results)
+            .map(|((name, repo), result)| BackupOutput {
+                backup: name,
+                repository: repo.to_string(),
+                success: result.is_success(),
+                skipped: result.skipped,
+                error: result.error().map(ToString::to_string),
+                archive: result.stats.as_ref().map(|stats| ArchiveOutput {
+                    name: &stats.name,
+                    id: &stats.id,
+                    duration: stats.duration.as_secs_f64(),
+                    original_size: stats.original_size,
+                    compressed_size: stats.compressed_size,
+                    deduplicated_size: stats.deduplicated_size,
+                    nfiles: stats.nfiles,
+                    compression_ratio: stats.compression_ratio(),
+                    space_saving: stats.space_saving(),
+                }),
+                exclude_file: result.exclude_file.as_ref().map(|e| match e {
+                    ExcludeFile::Applied(path) => ExcludeFileOutput {
+                        path,
+                        applied: true,
+                    },
+                    ExcludeFile::Skipped(path, _) | ExcludeFile::Missing(path) => {
+                        ExcludeFileOutput {
+                            path,
+                            applied: false,
+                        }
+                    }
+                }),
+                report: None,
+                prune_deferred: result.prune_deferred,
+                warnings: &result.warnings,
+            })
+            .collect();
+        RunOutput {
+            run_id: &ctx.id,
+            backups,
+            filtered_out: filtered_out.len(),
+            excluded: filtered_out
+                .iter()
+                .map(|(backup, reason)| ExcludedOutput { backup, reason })
+                .collect(),
+            dry_run: results.iter().any(|o| o.dry_run),
+        }
+    }
+
+    /// Compare the new archives with their `baselines`, like [`print_report`]
+    fn add_reports(
+        &mut self,
+        results: &[BackupResult],
+        baselines: &'a [Option<history::HistoryEntry>],
+    ) {
+        for ((output, result), baseline) in self.backups.iter_mut().zip(results).zip(baselines) {
+            let Some(stats) = &result.stats else {
+                continue;
+            };
+            let changes = baseline.as_ref().map(|previous| changes(stats, previous));
+            output.report = Some(ReportOutput {
+                previous: baseline.as_ref().map(|previous| PreviousOutput {
+                    name: &previous.name,
+                    id: &previous.id,
+                    time: previous.time,
+                }),
+                original_size_change: changes.map(|(size, _)| size),
+                nfiles_change: changes.map(|(_, files)| files),
+            });
+        }
+    }
+}
+
+/// Run `backups` and pass their events to `on_event`, reporting on their progress on SIGUSR1
+/// and every `progress_interval`
+///
+/// Backups with a reason in `rejected` fail right away, see [`Job::rejected`].
+pub(super) fn run_backups<B>(
+    runner: &Runner,
+    backups: Vec<Backup>,
+    rejected: Vec<Option<String>>,
+    prune: bool,
+    progress_interval: Option<Duration>,
+    on_event: impl FnMut(usize, RunEvent) + Send + 'static,
+) -> Vec<BackupResult>
+where
+    B: Backend<Update = Event>,
+{
+    let names: Vec<_> = backups.iter().map(ToString::to_string).collect();
+    let sink = Reporting {
+        on_event,
+        snapshots: names.iter().map(|_| Snapshot::default()).collect(),
+        names,
+        request: render::ReportRequest::register(),
+        start: Instant::now(),
+        progress_interval,
+        last_status: Instant::now(),
+    };
+    runner
+        .run::<B>(jobs(backups, rejected, prune), sink)
+        .backups
+}
+
+/// What the runner needs to know of `backups`, with the ids in `after` turned into indices
+///
+/// Prerequisites which aren't part of the run are left out.
+fn jobs(backups: Vec<Backup>, rejected: Vec<Option<String>>, prune: bool) -> Vec<Job> {
+    let ids: Vec<_> = backups.iter().map(|b| b.id().to_owned()).collect();
+    backups
+        .into_iter()
+        .zip(rejected)
+        .map(|(backup, rejected)| {
+            let name = backup.id().to_owned();
+            let verify = backup.verify.check_options();
+            let after = backup
+                .after
+                .iter()
+                .filter_map(|id| ids.iter().position(|other| other == id))
+                .collect();
+            let prune_now = prune || backup.prune == Prune::Auto;
+            Job {
+                name,
+                verify,
+                prune: backup.retention.filter(|_| prune_now),
+                compact: backup.compact,
+                after,
+                allow_missing_paths: backup.allow_missing_paths,
+                allow_nested_paths: backup.allow_nested_paths,
+                rejected,
+                borg_log_level: backup.borg_log_level,
+                show_rc: backup.show_rc,
+                append_only: backup.init.append_only,
+                ..Job::from((backup.repo, backup.archive))
+            }
+        })
+        .collect()
+}
+
+/// Passes the events on and keeps track of every backup for reports on SIGUSR1 and periodic
+/// status lines
+struct Reporting<F> {
+    on_event: F,
+    names: Vec<String>,
+    snapshots: Vec<Snapshot>,
+    request: render::ReportRequest,
+    start: Instant,
+    /// See `--progress-interval`
+    progress_interval: Option<Duration>,
+    last_status: Instant,
+}
+
+impl<F> EventSink for Reporting<F>
+where
+    F: FnMut(usize, RunEvent) + Send + 'static,
+{
+    fn event(&mut self, idx: usize, event: RunEvent) {
+        self.snapshots[idx].update(&event);
+        (self.on_event)(idx, event);
+    }
+
+    fn tick(&mut self) {
+        if self.request.take() {
+            for (name, snapshot) in self.names.iter().zip(&self.snapshots) {
+                eprintln!("[{name}] {}", snapshot.report(self.start.elapsed()));
+            }
+        }
+        let Some(interval) = self.progress_interval else {
+            return;
+        };
+        if self.last_status.elapsed() >= interval {
+            self.last_status = Instant::now();
+            for (name, snapshot) in self.names.iter().zip(&self.snapshots) {
+                if let Some(status) = snapshot.status(self.last_status) {
+                    eprintln!("[{name}] {status}");
+                }
+            }
+        }
+    }
+}
+
+/// Latest known state of a backup, reported on SIGUSR1
+#[derive(Default)]
+struct Snapshot {
+    progress: ProgressTracker,
+    queued: Option<String>,
+    finished: Option<String>,
+    /// When the first event of borg arrived
+    started: Option<Instant>,
+}
+
+impl Snapshot {
+    fn update(&mut self, event: &RunEvent) {
         match event {
-            E::ArchiveProgress {
-                nfiles,
-                original_size,
-                compressed_size,
-                deduplicated_size,
-                path,
-                ..
-            } => {
-                let mut prefix = Vec::with_capacity(4);
-                prefix.push(format!("O {}", indicatif::HumanBytes(original_size)));
+            RunEvent::Event(event) => {
+                self.started.get_or_insert_with(Instant::now);
+                self.progress.update(event);
+            }
+            RunEvent::Queued(reason) => self.queued = reason.to_owned(),
+            RunEvent::Finished(summary) => self.finished = Some(summary.to_owned()),
+        }
+    }
 
-                prefix.push(format!("C {}", indicatif::HumanBytes(compressed_size)));
+    fn report(&self, elapsed: Duration) -> String {
+        if let Some(summary) = &self.finished {
+            return summary.to_owned();
+        }
+        if let Some(reason) = &self.queued {
+            return format!("{reason} after {}", fmt_duration(elapsed));
+        }
+        match self.progress.path() {
+            None => format!("no progress yet after {}", fmt_duration(elapsed)),
+            Some(path) => format!(
+                "{} files, {} after {}, at {}",
+                fmt_count(self.progress.nfiles()),
+                fmt_bytes(self.progress.original_size()),
+                fmt_duration(elapsed),
+                path.display()
+            ),
+        }
+    }
 
-                prefix.push(format!("D {}", indicatif::HumanBytes(deduplicated_size)));
+    /// Compact line for `--progress-interval`, e.g. "12m30s: 1,234 files, 1.50 GiB original,
+    /// 200.00 MiB deduplicated, creating", `None` unless the backup is running
+    fn status(&self, now: Instant) -> Option<String> {
+        if self.finished.is_some() {
+            return None;
+        }
+        if let Some(reason) = &self.queued {
+            return Some(reason.to_owned());
+        }
+        let elapsed = now.saturating_duration_since(self.started?);
+        let phase = match self.progress.stage() {
+            Some(stage) => stage.to_string(),
+            None => match self.progress.phase() {
+                Phase::Waiting => "starting",
+                Phase::Creating => "creating",
+                Phase::Checking => "checking",
+                Phase::Pruning => "pruning",
+                Phase::Compacting => "compacting",
+                Phase::Done => "done",
+            }
+            .to_owned(),
+        };
+        Some(format!(
+            "{}: {} files, {} original, {} deduplicated, {phase}",
+            fmt_duration(elapsed),
+            fmt_count(self.progress.nfiles()),
+            fmt_bytes(self.progress.original_size()),
+            fmt_bytes(self.progress.deduplicated_size()),
+        ))
+    }
+}
 
-                pb.set_position(nfiles);
-                prefix.push(format!("N {}", nfiles));
+/// Progress bar of a running backup
+struct Bar {
+    pb: indicatif::ProgressBar,
+    /// Before every line printed for the backup, see [`prefix`]
+    prefix: String,
+    /// Totals of the last archive progress, shown again once borg finishes a stage
+    totals: String,
+    /// For the current throughput
+    progress: ProgressTracker,
+    /// Which messages of borg are printed
+    verbosity: BorgVerbosity,
+    /// Messages of borg which were neither printed nor logged
+    hidden: usize,
+    /// Warnings of borg, only the first of each kind is printed
+    repeats: render::Repeats,
+    /// `borg_log_level` of the backup, its messages up to it go to the log of borrg
+    borg_log_level: Option<log::Level>,
+    /// `show_rc` of the backup, the exit code of borg goes to the log of borrg
+    show_rc: bool,
+}
 
-                pb.set_prefix(prefix.join(" "));
+impl Bar {
+    /// Print how often warnings repeated and how many messages of borg were hidden, then keep
+    /// the line of the backup with its summary or clear it
+    fn finish(&mut self, summary: String, clear: bool) {
+        for line in self.repeats.summary() {
+            self.pb
+                .println(format!("{}{}", self.prefix, style::warning(line)));
+        }
+        if self.hidden > 0 {
+            self.pb.println(format!(
+                "{}{} message(s) of borg not shown, see --borg-verbosity",
+                self.prefix, self.hidden
+            ));
+        }
+        if clear {
+            self.pb.finish_and_clear();
+        } else {
+            self.pb.finish_with_message(summary);
+        }
+    }
+}
+
+/// Show a single event on the progress bar of its backup
+fn render(bar: &mut Bar, event: Event) {
+    use crate::borrg::Event as E;
+    let Bar {
+        pb,
+        prefix,
+        totals,
+        progress,
+        verbosity,
+        hidden,
+        repeats,
+        borg_log_level,
+        show_rc,
+    } = bar;
+    progress.update(&event);
+    match event {
+        E::ArchiveProgress {
+            nfiles,
+            original_size,
+            compressed_size,
+            deduplicated_size,
+            path,
+            ..
+        } => {
+            let mut prefix = Vec::with_capacity(4);
+            prefix.push(format!("O {}", fmt_bytes(original_size)));
+
+            prefix.push(format!("C {}", fmt_bytes(compressed_size)));
 
-                pb.set_message(format!("{}", path.display()));
+            prefix.push(format!("D {}", fmt_bytes(deduplicated_size)));
+
+            pb.set_position(nfiles);
+            prefix.push(format!("N {}", fmt_count(nfiles)));
+
+            if repeats.more() > 0 {
+                prefix.push(format!("+{} warnings", fmt_count(repeats.more())));
             }
-            E::Error(e) => {
-                pb.println(format!("{prefix}Error: {e}"));
+
+            if let Some(throughput) = progress.throughput() {
+                prefix.push(fmt_throughput(throughput.current as u64));
             }
-            ev => {
-                pb.println(format!("{prefix}{ev}"));
+
+            *totals = prefix.join(" ");
+            pb.set_prefix(totals.to_owned());
+
+            pb.set_message(format!("{}", path.display()));
+        }
+        ev @ (E::ProgressMessage { .. } | E::ProgressPercent { .. }) => match stage_prefix(&ev) {
+            Some(stage) => pb.set_prefix(stage),
+            None => pb.set_prefix(totals.to_owned()),
+        },
+        // Only counted for the summary, there can be thousands of them
+        E::PruneDecision { .. } => {}
+        // The summary says the same, unless the backup asks for it
+        ev @ E::Termination { .. } => {
+            if *show_rc {
+                log::info!("{prefix}{ev}");
+            }
+        }
+        E::LogMessage { level, message, .. } if verbosity.show(level) != Shown::Print => {
+            let requested = level.zip(*borg_log_level).is_some_and(|(l, max)| l <= max);
+            // Only count the messages which end up nowhere
+            if verbosity.show(level) == Shown::Log || requested {
+                log::log!(level.unwrap_or(log::Level::Info), "{prefix}{message}");
+            } else {
+                *hidden += 1;
+            }
+        }
+        E::LogMessage {
+            level: Some(log::Level::Warn),
+            msgid,
+            message,
+            ..
+        } => {
+            if repeats.first(msgid.as_deref(), &message) {
+                pb.println(format!("{prefix}{message}"));
+            } else {
+                log::info!("{prefix}{message}");
+            }
+        }
+        E::Error(e) => {
+            pb.println(format!(
+                "{prefix}{}",
+                style::error_stderr(format!("Error: {e}"))
+            ));
+        }
+        ev => {
+            pb.println(format!("{prefix}{ev}"));
+        }
+    }
+}
+
+/// What borg is busy with for the bar prefix, e.g. "syncing chunks cache (34%)"
+///
+/// Stages borrg doesn't know by name are shown with borg's message. `None` once a stage is
+/// finished.
+fn stage_prefix(event: &Event) -> Option<String> {
+    let stage = event
+        .stage()
+        .filter(|s| !matches!(s, crate::Stage::Other(_)));
+    match (event, stage) {
+        (Event::ProgressPercent { finished: true, .. }, _) => None,
+        (Event::ProgressPercent { current, total, .. }, Some(stage)) if *total > 0 => {
+            Some(format!("{stage} ({}%)", current * 100 / total))
+        }
+        (Event::ProgressPercent { message, .. }, None) => Some(message.trim().to_owned()),
+        (Event::ProgressPercent { .. }, Some(stage)) => Some(stage.to_string()),
+        (
+            Event::ProgressMessage {
+                finished: Some(true),
+                ..
+            },
+            _,
+        ) => None,
+        (Event::ProgressMessage { .. }, Some(stage)) => Some(stage.to_string()),
+        (Event::ProgressMessage { message, .. }, None) => message.to_owned(),
+        _ => None,
+    }
+}
+
+/// Print every borg invocation `run` would perform, in order
+fn explain(borg: &Borg, config: &Config, unreadable: &[Option<String>], prune: bool) -> ExitCode {
+    let mut success = true;
+    // Every series, without asking borg which ones are due
+    let now = SystemTime::now();
+    for (backup, unreadable) in config.backups.iter().zip(unreadable) {
+        for backup in backup.per_series(now) {
+            println!("# {backup}");
+            if let Some(e) = unreadable {
+                eprintln!("{}", style::error_stderr(format!("Error: {e}")));
+                success = false;
+                continue;
+            }
+            for invocation in invocations(borg, &backup, prune) {
+                match invocation {
+                    Ok(invocation) => println!("{invocation}"),
+                    Err(e) => {
+                        eprintln!("{}", style::error_stderr(format!("Error: {e}")));
+                        success = false;
+                    }
+                }
+            }
+        }
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// The borg commands `run` executes for `backup`, in order
+pub(super) fn invocations(
+    borg: &Borg,
+    backup: &Backup,
+    prune: bool,
+) -> Vec<Result<backend::borg::Invocation, crate::Error>> {
+    use backend::borg::BorgWrapper;
+
+    let mut invocations = vec![BorgWrapper::explain_create(
+        borg,
+        &backup.repo,
+        &backup.archive,
+    )];
+
+    if !borg.dry_run {
+        if let Some(options) = backup.verify.check_options() {
+            invocations.push(BorgWrapper::explain_check(borg, &backup.repo, &options));
+        }
+
+        if let Some(policy) = &backup.retention {
+            if prune || backup.prune == Prune::Auto {
+                invocations.push(BorgWrapper::explain_prune(borg, &backup.repo, policy));
+                if backup.compact {
+                    invocations.push(BorgWrapper::explain_compact(borg, &backup.repo));
+                }
+            }
+        }
+    }
+
+    invocations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Archive, ByteSize, Interaction, Passphrase, PrunePolicy, Repo, Verify};
+
+    /// A successful backup, with `stats` unless it was a dry run
+    fn succeeded(stats: Option<CreateStats>, dry_run: bool) -> BackupResult {
+        BackupResult {
+            backup: Ok(Duration::from_secs(2)),
+            verify: None,
+            prune: None,
+            new_data: stats.as_ref().map(|s| s.deduplicated_size),
+            pruned: None,
+            prune_deferred: false,
+            stats,
+            throughput: None,
+            file_changes: Default::default(),
+            exclude_file: None,
+            warnings: vec![],
+            attempts: 1,
+            dry_run,
+            skipped: false,
+        }
+    }
+
+    fn backup(name: &str) -> Backup {
+        Backup {
+            name: None,
+            id: name.to_owned(),
+            repo: "/srv/backup".parse().unwrap(),
+            archive: Archive::new(name),
+            verify: Verify::Archive,
+            require_encryption: Default::default(),
+            check_remote_version: false,
+            borg_log_level: None,
+            show_rc: false,
+            min_free_space: None,
+            init: Default::default(),
+            retention: None,
+            prune: Prune::Manual,
+            compact: false,
+            schedule: None,
+            series: vec![],
+            after: vec![],
+            allow_missing_paths: false,
+            allow_nested_paths: false,
+            paths_from: None,
+            name_template: None,
+            name_placeholders: Default::default(),
+            name_suffix: None,
+        }
+    }
+
+    #[test]
+    fn test_future_timestamps() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_532_400);
+        let mut backups = vec![backup("docs"), backup("mail"), backup("photos")];
+        backups[0]
+            .archive
+            .set_timestamp(now - Duration::from_secs(86400));
+        backups[1]
+            .archive
+            .set_timestamp(now + Duration::from_secs(60));
+
+        let future = future_timestamps(&backups, now);
+        assert_eq!(future[0], None);
+        assert!(future[1]
+            .as_ref()
+            .is_some_and(|e| e.ends_with("is in the future")));
+        assert_eq!(future[2], None);
+    }
+
+    #[test]
+    fn test_missing_passphrases() {
+        let with = |passphrase| {
+            let mut backup = backup("passphrase");
+            if let Some(passphrase) = passphrase {
+                backup.repo.passphrase(passphrase);
             }
+            backup
+        };
+        let backups = [
+            with(None),
+            with(Some(Passphrase::Environment("BORG_PASSCOMMAND".to_owned()))),
+            with(Some(Passphrase::Command("pass borg".to_owned()))),
+            Backup {
+                init: config::InitOptions {
+                    encryption: Some(crate::Encryption::None),
+                    ..Default::default()
+                },
+                ..with(None)
+            },
+        ];
+        assert_eq!(
+            missing_passphrases(&backups, |_| false),
+            [true, true, false, false]
+        );
+        let exported = |var: &str| var == "BORG_PASSCOMMAND";
+        assert_eq!(
+            missing_passphrases(&backups, exported),
+            [false, false, false, false]
+        );
+
+        // Fails instead of asking, also on a terminal
+        let mut backups = backups;
+        let rejected = prompt_passphrases(&mut backups, Some("--batch"));
+        if std::env::var_os("BORG_PASSPHRASE").is_none() {
+            assert_eq!(
+                rejected[0].as_deref(),
+                Some("no passphrase configured, not asking (--batch)")
+            );
         }
+        assert_eq!(rejected[2], None);
+        assert_eq!(rejected[3], None);
+        assert!(backups[0].repo.passphrase.is_none());
+    }
+
+    #[test]
+    fn test_batch_progress() {
+        assert!(Interaction::Interactive.shows_progress(true));
+        assert!(!Interaction::Interactive.shows_progress(false));
+        assert!(!Interaction::Batch.shows_progress(true));
+        // Status lines instead, like under cron
+        assert_eq!(
+            progress_interval(None, Interaction::Batch.shows_progress(true)),
+            Some(DEFAULT_PROGRESS_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn test_jobs() {
+        let after = |name: &str, after: &[&str]| Backup {
+            after: after.iter().map(|id| id.to_string()).collect(),
+            retention: Some(PrunePolicy {
+                keep_daily: Some(7),
+                ..Default::default()
+            }),
+            ..backup(name)
+        };
+        let backups = vec![
+            after("first", &[]),
+            Backup {
+                prune: Prune::Auto,
+                verify: Verify::None,
+                ..after("second", &["first", "missing"])
+            },
+        ];
+        let rejected = vec![None, Some("no passphrase".to_owned())];
+
+        let jobs = jobs(backups.clone(), rejected.clone(), false);
+        assert_eq!(jobs[0].name, "first");
+        assert!(jobs[0].verify.is_some() && jobs[0].prune.is_none());
+        assert_eq!(jobs[1].after, [0]);
+        assert!(jobs[1].verify.is_none() && jobs[1].prune.is_some());
+        assert_eq!(jobs[1].rejected.as_deref(), Some("no passphrase"));
+
+        let jobs = super::jobs(backups, rejected, true);
+        assert!(jobs.iter().all(|job| job.prune.is_some()));
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        let dir =
+            std::env::temp_dir().join(format!("borrg-test-free-space-{}", std::process::id()));
+        let repo: Repo = dir.join("repo").display().to_string().parse().unwrap();
+        let with_min = |min: u64, repo: &Repo| Backup {
+            repo: repo.clone(),
+            min_free_space: Some(ByteSize(min)),
+            ..backup("docs")
+        };
+        let backups = [
+            with_min(u64::MAX, &repo),
+            with_min(1, &repo),
+            with_min(u64::MAX, &"ssh://nas/./backup".parse().unwrap()),
+            backup("unlimited"),
+            with_min(u64::MAX, &repo),
+        ];
+        let rejected = vec![None, None, None, None, Some("no passphrase".to_owned())];
+
+        let checked = check_free_space(&backups, rejected.clone(), false);
+        let problem = checked[0].as_deref().unwrap();
+        assert!(problem.starts_with("Only "), "{problem}");
+        assert!(problem.ends_with(&fmt_bytes(u64::MAX)), "{problem}");
+        assert_eq!(checked[1..4], [None, None, None]);
+        assert_eq!(checked[4].as_deref(), Some("no passphrase"));
+
+        // Forced, the backup only gets a warning
+        let forced = check_free_space(&backups, rejected, true);
+        assert_eq!(forced[0], None);
     }
 
-    mp.clear().unwrap();
+    #[test]
+    fn test_dry_run() {
+        let backups = [backup("first"), backup("second")];
+        let repos: Vec<_> = backups.iter().map(|b| b.repo.clone()).collect();
+        let ids: Vec<_> = backups.iter().map(|b| b.id().to_owned()).collect();
+        assert_eq!(
+            prefix(&backups[0], true, true),
+            "[/srv/backup::first] (dry-run) "
+        );
+        assert_eq!(prefix(&backups[0], false, true), "(dry-run) ");
+
+        let results = [succeeded(None, true), succeeded(None, true)];
+        let ctx = RunContext::new();
+        assert!(history_entries(&ctx, &repos, &ids, &results).is_empty());
+
+        let dir = std::env::temp_dir().join(format!("borrg-test-dry-run-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = state::StateStore::open(&dir).unwrap();
+        record_state_in(&ctx, &store, &ids, &results);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_report() {
+        use chrono::TimeZone;
+
+        let at = |day, hour| {
+            chrono::Local
+                .with_ymd_and_hms(2024, 5, day, hour, 0, 0)
+                .unwrap()
+        };
+        let stats = CreateStats {
+            name: "2024-05-10".to_owned(),
+            id: "0123abcd".to_owned(),
+            duration: Duration::from_secs(90),
+            original_size: 10 * 1024 * 1024 * 1024,
+            compressed_size: 0,
+            deduplicated_size: 0,
+            nfiles: 21_204,
+        };
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let mut previous = history::HistoryEntry::new(
+            &repo,
+            &CreateStats {
+                original_size: stats.original_size - 2254857830,
+                nfiles: 20_000,
+                ..stats.clone()
+            },
+        );
+        previous.time = at(9, 3).into();
+
+        assert_eq!(
+            fmt_report(&stats, None, at(10, 3)),
+            "first archive, nothing to compare with"
+        );
+        assert_eq!(
+            fmt_report(&stats, Some(&previous), at(10, 3)),
+            "+2.10 GiB original, +1,204 files vs. yesterday"
+        );
+        assert_eq!(
+            fmt_report(&stats, Some(&previous), at(9, 20)),
+            "+2.10 GiB original, +1,204 files vs. earlier today"
+        );
+
+        previous.original_size = stats.original_size + 512;
+        previous.nfiles = 1_021_204;
+        assert_eq!(
+            fmt_report(&stats, Some(&previous), at(13, 3)),
+            "-512 B original, -1,000,000 files vs. 4 days ago"
+        );
+
+        // Another backup to the same repository is no baseline, neither are old entries without
+        // a backup id
+        let entry = |backup: Option<&str>| history::HistoryEntry {
+            backup: backup.map(str::to_owned),
+            ..history::HistoryEntry::new(&repo, &stats)
+        };
+        let entries = [entry(Some("home")), entry(Some("etc")), entry(None)];
+        assert_eq!(history::latest(&entries, "home"), Some(&entries[0]));
+        assert_eq!(history::latest(&entries, "etc"), Some(&entries[1]));
+        assert_eq!(history::latest(&entries, "var"), None);
+    }
+
+    #[test]
+    fn test_json_output() {
+        let names = ["/srv/a::{now}".to_owned(), "/srv/b::{now}".to_owned()];
+        let repos: Vec<Repo> = ["/srv/a", "/srv/b"].map(|r| r.parse().unwrap()).into();
+        let stats = CreateStats {
+            name: "2024-05-10".to_owned(),
+            id: "0123abcd".to_owned(),
+            duration: Duration::from_millis(1500),
+            original_size: 2048,
+            compressed_size: 1024,
+            deduplicated_size: 512,
+            nfiles: 3,
+        };
+        let results = [
+            BackupResult {
+                warnings: vec!["pruning failed: locked".to_owned()],
+                ..succeeded(Some(stats), false)
+            },
+            BackupResult {
+                backup: Err("locked".into()),
+                ..succeeded(None, false)
+            },
+        ];
+
+        let ctx = RunContext {
+            id: "3f9a0c1e".to_owned(),
+        };
+        let filtered_out = [(
+            "/srv/c::{now}".to_owned(),
+            "repository is local, not on nas (--repo-host)".to_owned(),
+        )];
+        let output = RunOutput::new(&ctx, &names, &repos, &results, &filtered_out);
+        let json = serde_json::to_value(output).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "run_id": "3f9a0c1e",
+                "backups": [
+                    {
+                        "backup": "/srv/a::{now}",
+                        "repository": "/srv/a",
+                        "success": true,
+                        "archive": {
+                            "name": "2024-05-10",
+                            "id": "0123abcd",
+                            "duration": 1.5,
+                            "original_size": 2048,
+                            "compressed_size": 1024,
+                            "deduplicated_size": 512,
+                            "nfiles": 3,
+                            "compression_ratio": 2.0,
+                            "space_saving": 75.0
+                        },
+                        "warnings": ["pruning failed: locked"]
+                    },
+                    {
+                        "backup": "/srv/b::{now}",
+                        "repository": "/srv/b",
+                        "success": false,
+                        "error": "locked"
+                    }
+                ],
+                "filtered_out": 1,
+                "excluded": [
+                    {
+                        "backup": "/srv/c::{now}",
+                        "reason": "repository is local, not on nas (--repo-host)"
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_report() {
+        let names = ["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        let repos: Vec<Repo> = ["/srv/a", "/srv/b", "/srv/c"]
+            .map(|r| r.parse().unwrap())
+            .into();
+        let stats = CreateStats {
+            name: "2024-05-10".to_owned(),
+            id: "0123abcd".to_owned(),
+            duration: Duration::from_secs(1),
+            original_size: 2048,
+            compressed_size: 1024,
+            deduplicated_size: 512,
+            nfiles: 3,
+        };
+        let results = [
+            succeeded(Some(stats.clone()), false),
+            succeeded(Some(stats.clone()), false),
+            BackupResult {
+                backup: Err("locked".into()),
+                ..succeeded(None, false)
+            },
+        ];
+        let mut previous = history::HistoryEntry::new(
+            &repos[0],
+            &CreateStats {
+                name: "2024-05-09".to_owned(),
+                id: "4567ef01".to_owned(),
+                original_size: 4096,
+                nfiles: 1,
+                ..stats
+            },
+        );
+        previous.time = SystemTime::UNIX_EPOCH;
+        let baselines = [Some(previous), None, None];
+
+        let ctx = RunContext {
+            id: "3f9a0c1e".to_owned(),
+        };
+        let mut output = RunOutput::new(&ctx, &names, &repos, &results, &[]);
+        output.add_reports(&results, &baselines);
+        let json = serde_json::to_value(output).unwrap();
+
+        assert_eq!(
+            json["backups"][0]["report"],
+            serde_json::json!({
+                "previous": {
+                    "name": "2024-05-09",
+                    "id": "4567ef01",
+                    "time": chrono::DateTime::<chrono::Local>::from(SystemTime::UNIX_EPOCH)
+                        .to_rfc3339(),
+                },
+                "original_size_change": -2048,
+                "nfiles_change": 2,
+            })
+        );
+        assert_eq!(
+            json["backups"][1]["report"],
+            serde_json::json!({ "previous": null })
+        );
+        // No new archive, nothing to report
+        assert!(json["backups"][2].get("report").is_none());
+    }
+
+    #[test]
+    fn test_run_id() {
+        let ctx = RunContext::new();
+        assert_eq!(ctx.id.len(), 8);
+        assert_ne!(ctx, RunContext::new());
+
+        let mut backups = vec![backup("first")];
+        ctx.apply(&mut backups);
+        assert_eq!(backups[0].repo.env().get("BORRG_RUN_ID"), Some(&ctx.id));
+
+        let names = vec![backups[0].to_string()];
+        let repos = vec![backups[0].repo.clone()];
+        let ids = vec![format!("run-id-{}", std::process::id())];
+        let stats = CreateStats {
+            name: "2024-05-10".to_owned(),
+            id: "0123abcd".to_owned(),
+            duration: Duration::from_secs(1),
+            original_size: 2048,
+            compressed_size: 1024,
+            deduplicated_size: 512,
+            nfiles: 3,
+        };
+        let results = [succeeded(Some(stats), false)];
+
+        let json =
+            serde_json::to_value(RunOutput::new(&ctx, &names, &repos, &results, &[])).unwrap();
+        assert_eq!(json["run_id"], ctx.id);
+
+        let entries = history_entries(&ctx, &repos, &ids, &results);
+        assert_eq!(entries[0].run_id.as_ref(), Some(&ctx.id));
+        assert_eq!(entries[0].backup.as_ref(), Some(&ids[0]));
+
+        let dir = std::env::temp_dir().join(format!("borrg-test-run-id-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = state::StateStore::open(&dir).unwrap();
+        record_state_in(&ctx, &store, &ids, &results);
+        let runs = store.load(&ids[0]).runs;
+        assert_eq!(runs[0].run_id.as_ref(), Some(&ctx.id));
+    }
+
+    #[test]
+    fn test_nothing_ran() {
+        assert!(nothing_ran(&[]));
+        let skipped = BackupResult {
+            skipped: true,
+            ..BackupResult {
+                backup: Err("none of its paths exist (/srv/a)".into()),
+                ..succeeded(None, false)
+            }
+        };
+        assert!(nothing_ran(std::slice::from_ref(&skipped)));
+        assert!(!nothing_ran(&[skipped, succeeded(None, false)]));
+    }
+
+    #[test]
+    fn test_borg_verbosity() {
+        use log::Level;
+
+        let default = BorgVerbosity::default();
+        assert_eq!(default.borg_level(), Level::Info);
+        assert_eq!(default.show(Some(Level::Error)), Shown::Print);
+        assert_eq!(default.show(Some(Level::Warn)), Shown::Print);
+        assert_eq!(default.show(Some(Level::Info)), Shown::Log);
+        assert_eq!(default.show(Some(Level::Debug)), Shown::Count);
+        assert_eq!(default.show(None), Shown::Print);
+
+        assert_eq!(BorgVerbosity::Error.show(Some(Level::Warn)), Shown::Count);
+        assert_eq!(BorgVerbosity::Debug.borg_level(), Level::Debug);
+        assert_eq!(BorgVerbosity::Debug.show(Some(Level::Debug)), Shown::Print);
+    }
+
+    #[test]
+    fn test_hidden_messages() {
+        let mut bar = Bar {
+            pb: indicatif::ProgressBar::hidden(),
+            prefix: String::new(),
+            totals: String::new(),
+            progress: ProgressTracker::new(),
+            verbosity: BorgVerbosity::default(),
+            hidden: 0,
+            repeats: render::Repeats::default(),
+            borg_log_level: None,
+            show_rc: false,
+        };
+        let message = |level| Event::LogMessage {
+            level: Some(level),
+            message: "Remote: Starting repository check".to_owned(),
+            msgid: None,
+            name: Some("borg.repository".to_owned()),
+            time: None,
+        };
+        // Logged, so not hidden
+        render(&mut bar, message(log::Level::Info));
+        assert_eq!(bar.hidden, 0);
+        render(&mut bar, message(log::Level::Debug));
+        assert_eq!(bar.hidden, 1);
+    }
+
+    #[test]
+    fn test_stage_prefix() {
+        let percent = |msgid: &str, current, finished| Event::ProgressPercent {
+            current,
+            finished,
+            message: "Syncing chunks cache: 34.0%".to_owned(),
+            msgid: msgid.to_owned(),
+            operation: 1,
+            time: std::time::SystemTime::UNIX_EPOCH,
+            total: 200,
+        };
+        assert_eq!(
+            stage_prefix(&percent("cache.sync", 68, false)).as_deref(),
+            Some("syncing chunks cache (34%)")
+        );
+        assert_eq!(stage_prefix(&percent("cache.sync", 200, true)), None);
+        assert_eq!(
+            stage_prefix(&percent("cache.new", 68, false)).as_deref(),
+            Some("Syncing chunks cache: 34.0%")
+        );
+
+        let message = |msgid: Option<&str>| Event::ProgressMessage {
+            message: Some("Saving files cache".to_owned()),
+            finished: None,
+            msgid: msgid.map(ToOwned::to_owned),
+            operation: Some(2),
+            time: None,
+        };
+        assert_eq!(
+            stage_prefix(&message(Some("cache.commit"))).as_deref(),
+            Some("saving cache")
+        );
+        assert_eq!(
+            stage_prefix(&message(None)).as_deref(),
+            Some("Saving files cache")
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut snapshot = Snapshot::default();
+        let elapsed = Duration::from_secs(62);
+        assert_eq!(snapshot.report(elapsed), "no progress yet after 1m02s");
+
+        snapshot.update(&RunEvent::Event(Event::ArchiveProgress {
+            nfiles: 12,
+            compressed_size: 0,
+            deduplicated_size: 0,
+            original_size: 2048,
+            path: "/home/seb/notes.txt".into(),
+            time: None,
+        }));
+        snapshot.update(&RunEvent::Event(Event::Other("ignored".to_owned())));
+        assert_eq!(
+            snapshot.report(elapsed),
+            "12 files, 2.00 KiB after 1m02s, at /home/seb/notes.txt"
+        );
+
+        let now = snapshot.started.unwrap() + Duration::from_secs(750);
+        assert_eq!(
+            snapshot.status(now).unwrap(),
+            "12m30s: 12 files, 2.00 KiB original, 0 B deduplicated, creating"
+        );
+
+        snapshot.update(&RunEvent::Finished("done in 1m02s".to_owned()));
+        assert_eq!(snapshot.report(elapsed), "done in 1m02s");
+        assert_eq!(snapshot.status(now), None);
+
+        // Nothing to say before borg reports anything
+        assert_eq!(Snapshot::default().status(now), None);
+        let queued = Snapshot {
+            queued: Some("waiting for home".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(queued.status(now).unwrap(), "waiting for home");
+    }
 
-    for (handle, _, _) in handles {
-        handle.join().unwrap();
+    #[test]
+    fn test_progress_interval() {
+        let five = Some(Duration::from_secs(300));
+        assert_eq!(progress_interval(None, true), None);
+        assert_eq!(progress_interval(None, false), five);
+        assert_eq!(progress_interval(five, true), five);
+        assert_eq!(progress_interval(Some(Duration::ZERO), false), None);
     }
 }
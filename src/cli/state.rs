@@ -0,0 +1,271 @@
+//! State of each backup, stored as one JSON file per backup in the state directory
+//!
+//! Writes replace the whole file atomically while holding an advisory lock, so a daemon and a
+//! manual `borrg run` can update the same backup without losing each other's changes. Readers
+//! don't lock, they always see a complete file.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Number of runs kept per backup
+const MAX_RUNS: usize = 100;
+
+/// Everything borrg remembers about a backup
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(super) struct BackupState {
+    pub last_run: Option<LastRun>,
+    /// Oldest first, at most [`MAX_RUNS`]
+    pub runs: Vec<RunHistoryEntry>,
+}
+
+impl BackupState {
+    /// Remember a finished run, dropping the oldest ones beyond [`MAX_RUNS`]
+    pub fn record(&mut self, run: RunHistoryEntry, error: Option<String>) {
+        self.last_run = Some(LastRun {
+            time: run.time,
+            success: run.success,
+            error,
+        });
+        self.runs.push(run);
+        let excess = self.runs.len().saturating_sub(MAX_RUNS);
+        self.runs.drain(..excess);
+    }
+}
+
+/// The most recent run of a backup, successful or not
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct LastRun {
+    /// When the run finished
+    #[serde(
+        serialize_with = "crate::util::serialize_time",
+        deserialize_with = "crate::util::deserialize_time"
+    )]
+    pub time: SystemTime,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A single run of a backup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct RunHistoryEntry {
+    /// When the run finished
+    #[serde(
+        serialize_with = "crate::util::serialize_time",
+        deserialize_with = "crate::util::deserialize_time"
+    )]
+    pub time: SystemTime,
+    /// Seconds borg took to create the archive, 0 if it failed
+    pub duration: f64,
+    pub success: bool,
+    /// Name of the new archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<String>,
+    /// The `borrg run` or round of the daemon, see `borrg history`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
+
+/// Directory with the state files of all backups
+#[derive(Debug, Clone)]
+pub(super) struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    /// `borrg/backups` in the state directory of the user
+    pub fn default_dir() -> Option<PathBuf> {
+        let dir = dirs::state_dir().or_else(dirs::data_local_dir)?;
+        Some(dir.join("borrg").join("backups"))
+    }
+
+    /// Use `dir` for the state files, creating it if needed
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, crate::Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+        Ok(StateStore { dir })
+    }
+
+    /// State of the backup with the given id, see [`Backup::id`](super::Backup::id)
+    ///
+    /// A missing file is an empty state. A corrupt one is renamed aside with a warning and
+    /// treated as empty, so that it doesn't block new runs.
+    pub fn load(&self, id: &str) -> BackupState {
+        read(&self.path(id))
+    }
+
+    /// Modify the state of a backup and write it back
+    ///
+    /// Blocks while another process updates the same backup.
+    pub fn update(&self, id: &str, f: impl FnOnce(&mut BackupState)) -> Result<(), crate::Error> {
+        let path = self.path(id);
+
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+        lock.lock()?;
+
+        let mut state = read(&path);
+        f(&mut state);
+        crate::util::atomic_write(&path, serde_json::to_string_pretty(&state)?)?;
+        Ok(())
+    }
+
+    /// Move the state of a backup to another id, e.g. when a new repository changed its id
+    ///
+    /// Nothing to move is fine, a state under the new id is kept and the move fails.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), crate::Error> {
+        let (from, to) = (self.path(from), self.path(to));
+        if to.exists() {
+            return Err(format!("{} exists already", to.display()).into());
+        }
+        match fs::rename(&from, &to) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+}
+
+fn read(path: &Path) -> BackupState {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return BackupState::default(),
+        Err(e) => {
+            log::warn!("Failed to read {}: {e}", path.display());
+            return BackupState::default();
+        }
+    };
+    match serde_json::from_str(&content) {
+        Ok(state) => state,
+        Err(e) => {
+            let aside = path.with_extension("json.corrupt");
+            log::warn!(
+                "Corrupt state file {} ({e}), moving it to {}",
+                path.display(),
+                aside.display()
+            );
+            if let Err(e) = fs::rename(path, &aside) {
+                log::warn!("Failed to move {}: {e}", path.display());
+            }
+            BackupState::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn store(name: &str) -> StateStore {
+        let dir =
+            std::env::temp_dir().join(format!("borrg-test-state-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        StateStore::open(dir).unwrap()
+    }
+
+    fn run(success: bool) -> RunHistoryEntry {
+        RunHistoryEntry {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_715_000_000),
+            duration: 1.5,
+            success,
+            archive: success.then(|| "2024-05-06".to_owned()),
+            run_id: Some("3f9a0c1e".to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let store = store("update");
+        assert_eq!(store.load("srv-a"), BackupState::default());
+
+        store
+            .update("srv-a", |state| state.record(run(true), None))
+            .unwrap();
+        store
+            .update("srv-a", |state| {
+                state.record(run(false), Some("locked".to_owned()))
+            })
+            .unwrap();
+
+        let state = store.load("srv-a");
+        assert_eq!(state.runs, [run(true), run(false)]);
+        let last = state.last_run.unwrap();
+        assert!(!last.success);
+        assert_eq!(last.error.as_deref(), Some("locked"));
+
+        assert_eq!(store.load("srv-b"), BackupState::default());
+    }
+
+    #[test]
+    fn test_corrupt_file() {
+        let store = store("corrupt");
+        let path = store.path("srv-a");
+        fs::write(&path, "{\"runs\": [").unwrap();
+
+        assert_eq!(store.load("srv-a"), BackupState::default());
+        assert!(!path.exists());
+        assert!(path.with_extension("json.corrupt").exists());
+
+        store
+            .update("srv-a", |state| state.record(run(true), None))
+            .unwrap();
+        assert_eq!(store.load("srv-a").runs, [run(true)]);
+    }
+
+    #[test]
+    fn test_rename() {
+        let store = store("rename");
+        store
+            .update("srv-a", |state| state.record(run(true), None))
+            .unwrap();
+        store.rename("srv-a", "nas-a").unwrap();
+        assert_eq!(store.load("srv-a"), BackupState::default());
+        assert_eq!(store.load("nas-a").runs, [run(true)]);
+
+        // Nothing to move
+        store.rename("srv-b", "nas-b").unwrap();
+        store
+            .update("srv-a", |state| state.record(run(false), None))
+            .unwrap();
+        assert!(store.rename("srv-a", "nas-a").is_err());
+        assert_eq!(store.load("nas-a").runs, [run(true)]);
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let store = store("concurrent");
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..20 {
+                        store
+                            .update("srv-a", |state| state.record(run(true), None))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(store.load("srv-a").runs.len(), 80);
+
+        for _ in 0..MAX_RUNS {
+            store
+                .update("srv-a", |state| state.record(run(false), None))
+                .unwrap();
+        }
+        let runs = store.load("srv-a").runs;
+        assert_eq!(runs.len(), MAX_RUNS);
+        assert!(runs.iter().all(|r| !r.success));
+    }
+}
@@ -0,0 +1,356 @@
+use super::*;
+use crate::{
+    backend::{self, borg::BorgError},
+    ArchiveInfo, Borg, Encryption, Ratio, Repo,
+};
+use key::KeyBackup;
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup, all backups if omitted
+    backup: Option<String>,
+
+    /// Print JSON to stdout
+    #[arg(long, conflicts_with = "format")]
+    json: bool,
+
+    /// Format of each line, e.g. "{backup}\t{last_time}"
+    ///
+    /// Placeholders: {id}, {backup}, {repository}, {encryption}, {archives}, {last_archive},
+    /// {last_time}, {last_run}, {next_archive}, {free_space}, {dedup_ratio},
+    /// {compression_ratio}, {error}.
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Exit successfully if no backups are configured
+    #[arg(long)]
+    allow_empty: bool,
+}
+
+/// State of a single backup
+#[derive(Debug, serde::Serialize)]
+pub(super) struct BackupStatus {
+    pub id: String,
+    pub backup: String,
+    pub repository: String,
+    pub encryption: Option<Encryption>,
+    /// The encryption doesn't match the backup's `require_encryption`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption_error: Option<String>,
+    pub archives: Option<usize>,
+    pub last_archive: Option<ArchiveInfo>,
+    /// Last run of the backup by borrg on this machine, from its state file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<state::LastRun>,
+    /// Name of the archive if the backup ran now, unless only borg knows it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_archive: Option<String>,
+    /// The repository already has an archive called `next_archive`, so creating it would fail
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub next_archive_exists: bool,
+    /// Bytes free on the filesystem of a local repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_space: Option<u64>,
+    /// Less than the backup's `min_free_space` is free, so running it would fail
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub low_free_space: bool,
+    /// See [`RepoInfo::dedup_ratio`](crate::RepoInfo::dedup_ratio)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedup_ratio: Option<Ratio>,
+    /// See [`RepoInfo::compression_ratio`](crate::RepoInfo::compression_ratio)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression_ratio: Option<Ratio>,
+    /// The key of a keyfile-mode repository isn't exported to `key_backup_dir`, or the export
+    /// is outdated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_backup_warning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The repository didn't answer within `info_timeout`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+}
+
+impl Placeholders for BackupStatus {
+    const KEYS: &'static [&'static str] = &[
+        "id",
+        "backup",
+        "repository",
+        "encryption",
+        "archives",
+        "last_archive",
+        "last_time",
+        "last_run",
+        "next_archive",
+        "free_space",
+        "dedup_ratio",
+        "compression_ratio",
+        "error",
+    ];
+
+    fn value(&self, key: &str) -> String {
+        match key {
+            "id" => self.id.to_owned(),
+            "backup" => self.backup.to_owned(),
+            "repository" => self.repository.to_owned(),
+            "encryption" => self
+                .encryption
+                .as_ref()
+                .map(|e| e.to_string())
+                .unwrap_or_default(),
+            "archives" => self.archives.map(|a| a.to_string()).unwrap_or_default(),
+            "last_archive" => self
+                .last_archive
+                .as_ref()
+                .map(|a| a.name.to_owned())
+                .unwrap_or_default(),
+            "last_time" => self
+                .last_archive
+                .as_ref()
+                .map(|a| fmt_time(a.time))
+                .unwrap_or_default(),
+            "last_run" => self
+                .last_run
+                .as_ref()
+                .map(|r| fmt_time(r.time))
+                .unwrap_or_default(),
+            "next_archive" => self.next_archive.to_owned().unwrap_or_default(),
+            "free_space" => self.free_space.map(fmt_bytes).unwrap_or_default(),
+            "dedup_ratio" => self.dedup_ratio.map(|r| r.to_string()).unwrap_or_default(),
+            "compression_ratio" => self
+                .compression_ratio
+                .map(|r| r.to_string())
+                .unwrap_or_default(),
+            "error" => self.error.to_owned().unwrap_or_default(),
+            _ => unreachable!("unknown key {key}"),
+        }
+    }
+}
+
+pub fn status(mut borg: Borg, config: Config, args: Args) -> ExitCode {
+    // Only here, a slow repository mustn't fail a backup
+    borg.info_timeout(config.info_timeout);
+    if let Some(code) = no_backups(&config, args.allow_empty, ExitCode::FAILURE) {
+        return code;
+    }
+    let template = match args.format.as_deref().map(Template::parse::<BackupStatus>) {
+        Some(Ok(template)) => Some(template),
+        Some(Err(e)) => {
+            eprintln!("{}", style::error_stderr(format!("Invalid format: {e}")));
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+
+    let backups = match config.select(args.backup.as_deref()) {
+        Ok(backups) => backups,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Query every repository only once, even if several backups share it
+    let mut repos: Vec<&Repo> = vec![];
+    for backup in &backups {
+        if !repos.contains(&&backup.repo) {
+            repos.push(&backup.repo);
+        }
+    }
+    let store = state::StateStore::default_dir().and_then(|dir| state::StateStore::open(dir).ok());
+
+    let results = parallel_map(&repos, |repo| {
+        let info = borg.repo_info::<backend::borg::BorgWrapper>(repo)?;
+        let archives = borg.list_archives::<backend::borg::BorgWrapper>(repo)?;
+        Ok::<_, crate::Error>((info, archives))
+    });
+    let free_space: Vec<_> = repos
+        .iter()
+        .map(|repo| match repo.is_remote() {
+            true => None,
+            false => crate::util::free_space(repo.borg_location())
+                .inspect_err(|e| log::warn!("Failed to get the free space of {repo}: {e}"))
+                .ok(),
+        })
+        .collect();
+
+    let now = std::time::SystemTime::now();
+    let statuses: Vec<BackupStatus> = backups
+        .iter()
+        .map(|backup| {
+            let idx = repos.iter().position(|r| r == &&backup.repo).unwrap();
+            let mut status = BackupStatus {
+                id: backup.id().to_owned(),
+                backup: backup.to_string(),
+                repository: backup.repo.to_string(),
+                encryption: None,
+                encryption_error: None,
+                archives: None,
+                last_archive: None,
+                last_run: store
+                    .as_ref()
+                    .and_then(|store| store.load(backup.id()).last_run),
+                next_archive: backup.expected_archive_name(now),
+                next_archive_exists: false,
+                free_space: free_space[idx],
+                low_free_space: free_space[idx]
+                    .zip(backup.min_free_space)
+                    .is_some_and(|(free, min)| free < min.0),
+                dedup_ratio: None,
+                compression_ratio: None,
+                key_backup_warning: None,
+                error: None,
+                timed_out: false,
+            };
+            match &results[idx] {
+                Ok((info, archives)) => {
+                    status.encryption = Some(info.encryption.clone());
+                    status.dedup_ratio = info.dedup_ratio();
+                    status.compression_ratio = info.compression_ratio();
+                    status.encryption_error =
+                        backup.require_encryption.check(&info.encryption).err();
+                    // Only checked once there is somewhere to export keys to
+                    status.key_backup_warning =
+                        match KeyBackup::check(info, config.key_backup_dir.as_deref()) {
+                            KeyBackup::Unconfigured => None,
+                            key_backup => key_backup.warning(info),
+                        };
+                    status.archives = Some(archives.len());
+                    status.last_archive = latest_archive(archives).cloned();
+                    status.next_archive_exists = archives
+                        .iter()
+                        .any(|a| Some(&a.name) == status.next_archive.as_ref());
+                }
+                Err(e) => {
+                    status.error = Some(e.to_string());
+                    status.timed_out = matches!(e.downcast_ref(), Some(BorgError::Timeout(_)));
+                }
+            }
+            status
+        })
+        .collect();
+
+    if args.json {
+        #[derive(serde::Serialize)]
+        struct Output<'a> {
+            backups: &'a [BackupStatus],
+        }
+        print_json(Output { backups: &statuses });
+    } else if let Some(template) = template {
+        for status in &statuses {
+            println!("{}", template.render(status));
+        }
+    } else {
+        let header = [
+            "Id",
+            "Backup",
+            "Encryption",
+            "Archives",
+            "Dedup",
+            "Last archive",
+            "Last run",
+            "Free space",
+        ];
+        // The cells show the placeholders of --format, "-" if there is no value
+        let cell = |s: &BackupStatus, key| match s.value(key) {
+            value if value.is_empty() => "-".to_string(),
+            value => value,
+        };
+        let last_archive = Template::parse::<BackupStatus>("{last_archive} ({last_time})")
+            .expect("valid template");
+        let rows: Vec<_> = statuses
+            .iter()
+            .map(|s| match &s.error {
+                Some(e) if s.timed_out => vec![
+                    s.id.to_owned(),
+                    s.backup.to_owned(),
+                    style::warning(format!("timed out: {e}")).to_string(),
+                ],
+                Some(e) => vec![
+                    s.id.to_owned(),
+                    s.backup.to_owned(),
+                    style::error(format!("error: {e}")).to_string(),
+                ],
+                None => vec![
+                    cell(s, "id"),
+                    cell(s, "backup"),
+                    match (&s.encryption, &s.encryption_error) {
+                        (Some(mode), None) => style::encryption(mode),
+                        (Some(mode), Some(_)) => style::error(mode).to_string(),
+                        (None, _) => "-".to_string(),
+                    },
+                    cell(s, "archives"),
+                    cell(s, "dedup_ratio"),
+                    match &s.last_archive {
+                        Some(_) => last_archive.render(s),
+                        None => "-".to_string(),
+                    },
+                    match &s.last_run {
+                        Some(r) if r.success => cell(s, "last_run"),
+                        Some(_) => {
+                            style::error(format!("{} (failed)", cell(s, "last_run"))).to_string()
+                        }
+                        None => "-".to_string(),
+                    },
+                    match s.free_space {
+                        Some(_) if s.low_free_space => {
+                            style::error(cell(s, "free_space")).to_string()
+                        }
+                        _ => cell(s, "free_space"),
+                    },
+                ],
+            })
+            .collect();
+        print_table(&header, &rows);
+    }
+
+    for (backup, status) in backups.iter().zip(&statuses) {
+        let warning = match (&status.encryption_error, &status.encryption) {
+            (Some(e), _) => Some(e.to_owned()),
+            (None, Some(mode)) => encryption_warning(&backup.repo, mode),
+            (None, None) => None,
+        };
+        if let Some(warning) = warning {
+            let warning = format!("[{}] {warning}", status.id);
+            eprintln!("{}", style::warning_stderr(warning));
+        }
+    }
+    // Backups sharing a repository share its key
+    let mut warned: Vec<&str> = vec![];
+    for status in &statuses {
+        if let Some(warning) = &status.key_backup_warning {
+            if !warned.contains(&status.repository.as_str()) {
+                warned.push(&status.repository);
+                let warning = format!("[{}] {warning}", status.id);
+                eprintln!("{}", style::warning_stderr(warning));
+            }
+        }
+    }
+    for status in statuses.iter().filter(|s| s.next_archive_exists) {
+        let warning = format!(
+            "[{}] An archive called {} already exists, a backup now would fail",
+            status.id,
+            status.next_archive.as_deref().unwrap_or_default()
+        );
+        eprintln!("{}", style::warning_stderr(warning));
+    }
+    for (backup, status) in backups.iter().zip(&statuses) {
+        if let (true, Some(min)) = (status.low_free_space, backup.min_free_space) {
+            let warning = format!(
+                "[{}] Only {} free on the filesystem of the repository, less than min_free_space \
+                ({}), a backup now would be refused",
+                status.id,
+                fmt_bytes(status.free_space.unwrap_or_default()),
+                fmt_bytes(min.0)
+            );
+            eprintln!("{}", style::warning_stderr(warning));
+        }
+    }
+
+    if statuses.iter().all(|s| s.error.is_none()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,68 @@
+use console::{style, StyledObject};
+use std::fmt::Display;
+
+/// When to use colors in the output
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Use colors if the output is a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Decide once whether stdout and stderr are colored, every other function in here follows it
+pub fn init(choice: ColorChoice) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let (stdout, stderr) = match choice {
+        ColorChoice::Always => (true, true),
+        ColorChoice::Never => (false, false),
+        ColorChoice::Auto if no_color => (false, false),
+        ColorChoice::Auto => (
+            console::Term::stdout().features().colors_supported(),
+            console::Term::stderr().features().colors_supported(),
+        ),
+    };
+    console::set_colors_enabled(stdout);
+    console::set_colors_enabled_stderr(stderr);
+}
+
+pub(super) fn error<D: Display>(text: D) -> StyledObject<D> {
+    style(text).red()
+}
+
+pub(super) fn warning<D: Display>(text: D) -> StyledObject<D> {
+    style(text).yellow()
+}
+
+pub(super) fn success<D: Display>(text: D) -> StyledObject<D> {
+    style(text).green()
+}
+
+/// Encryption mode of a repository, red if it isn't encrypted
+pub(super) fn encryption(mode: &crate::Encryption) -> String {
+    match mode {
+        crate::Encryption::None => error(mode).to_string(),
+        mode if !mode.is_encrypted() => warning(mode).to_string(),
+        mode => mode.to_string(),
+    }
+}
+
+/// Same as [`error`] but for text printed to stderr
+pub fn error_stderr<D: Display>(text: D) -> StyledObject<D> {
+    style(text).red().for_stderr()
+}
+
+/// Same as [`warning`] but for text printed to stderr
+pub fn warning_stderr<D: Display>(text: D) -> StyledObject<D> {
+    style(text).yellow().for_stderr()
+}
+
+/// Template for the spinner of a running backup
+pub(super) fn spinner_template(prefix: &str) -> String {
+    if console::colors_enabled_stderr() {
+        format!("{prefix}{{elapsed:.dim}} {{spinner:.green}} {{prefix:.yellow}} {{wide_msg}}")
+    } else {
+        format!("{prefix}{{elapsed}} {{spinner}} {{prefix}} {{wide_msg}}")
+    }
+}
@@ -1,3 +1,11 @@
+pub(super) use crate::format::{
+    bytes as fmt_bytes, count as fmt_count, duration as fmt_duration, throughput as fmt_throughput,
+};
+use crate::{ArchiveInfo, Encryption, Interaction, Repo};
+use std::{
+    process::{ExitCode, ExitStatus},
+    time::Duration,
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -25,6 +33,196 @@ pub(super) fn parse_byte_size(size: &str) -> Result<u64, InvalidByteSize> {
     Ok(num * factor)
 }
 
+#[derive(Error, Debug)]
+#[error("Invalid duration \"{0}\", expected seconds or e.g. \"90s\", \"15m\", \"1h30m\", \"2d\"")]
+pub(super) struct InvalidDuration(pub String);
+
+/// Seconds or a humantime duration like "1h30m", the inverse of [`fmt_humantime`]
+pub(super) fn parse_duration(duration: &str) -> Result<Duration, InvalidDuration> {
+    let duration = duration.trim();
+    if let Ok(secs) = duration.parse() {
+        return Ok(Duration::from_secs(secs));
+    }
+    humantime::parse_duration(duration).map_err(|_| InvalidDuration(duration.to_owned()))
+}
+
+/// A configured duration as it could be written in the config, e.g. "1h 30m"
+pub(super) fn fmt_humantime(duration: Duration) -> String {
+    humantime::format_duration(duration).to_string()
+}
+
+/// Ask the user a yes/no question on the terminal, defaulting to no
+///
+/// `None` in batch mode, without asking. The caller has to fail then, unless it was told yes
+/// another way like `--yes`.
+pub(super) fn confirm(interaction: Interaction, question: &str) -> Option<bool> {
+    use std::io::Write;
+
+    if interaction.is_batch() {
+        return None;
+    }
+
+    eprint!("{question} [y/N] ");
+    std::io::stderr().flush().ok();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return Some(false);
+    }
+    Some(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Ask for a secret on the terminal without echoing it, `None` if it can't be read
+pub(super) fn read_secret(prompt: &str) -> Option<String> {
+    let term = console::Term::stderr();
+    term.write_str(prompt).ok()?;
+    term.read_secure_line().ok()
+}
+
+/// Warning about a repository anyone with access to can read, `None` if it's encrypted
+pub(super) fn encryption_warning(repo: &Repo, mode: &Encryption) -> Option<String> {
+    match mode {
+        _ if mode.is_encrypted() => None,
+        Encryption::None => Some(format!(
+            "{repo} is not encrypted, anyone with access to it can read the backups"
+        )),
+        _ => Some(format!(
+            "{repo} is only authenticated ({mode}), anyone with access to it can read the backups"
+        )),
+    }
+}
+
+/// The newest archive, leaving out checkpoints of interrupted backups
+pub(super) fn latest_archive(archives: &[ArchiveInfo]) -> Option<&ArchiveInfo> {
+    archives
+        .iter()
+        .filter(|a| !a.is_checkpoint())
+        .max_by_key(|a| a.time)
+}
+
+/// Apply `f` to every item on its own thread, keeping their order
+pub(super) fn parallel_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    std::thread::scope(|s| {
+        let f = &f;
+        let handles: Vec<_> = items.iter().map(|item| s.spawn(move || f(item))).collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("worker thread panicked"))
+            .collect()
+    })
+}
+
+/// Format a point in time in the local timezone
+pub(super) fn fmt_time(time: std::time::SystemTime) -> String {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+/// Lowercase ASCII letters and digits of `s` with a single "-" for everything in between, at
+/// most `max_len` long
+pub(super) fn slugify(s: &str, max_len: usize) -> String {
+    let mut slug = String::new();
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(max_len);
+    slug.trim_end_matches('-').to_owned()
+}
+
+/// FNV-1a hash, unlike `DefaultHasher` it is the same in every Rust version
+pub(super) fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Print rows as left-aligned columns
+///
+/// Rows may be shorter than the header, their last cell then isn't aligned.
+pub(super) fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate().take(row.len().saturating_sub(1)) {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    let header = header.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+    for row in std::iter::once(&header).chain(rows) {
+        let line = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| match i + 1 == row.len() {
+                true => cell.to_owned(),
+                false => format!("{cell:<0$}", widths[i]),
+            })
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{line}");
+    }
+}
+
+/// Tell that `config` has no backups, with the exit code for that, `None` if it has some
+///
+/// That's `failure` unless `allow_empty`.
+pub(super) fn no_backups(
+    config: &super::Config,
+    allow_empty: bool,
+    failure: ExitCode,
+) -> Option<ExitCode> {
+    let Err(e) = config.require_backups() else {
+        return None;
+    };
+    match allow_empty {
+        true => {
+            eprintln!("{}", super::style::warning_stderr(e));
+            Some(ExitCode::SUCCESS)
+        }
+        false => {
+            eprintln!("{}", super::style::error_stderr(e));
+            Some(failure)
+        }
+    }
+}
+
+/// The only repository of the backups matching `query`
+pub(super) fn select_repo<'a>(config: &'a super::Config, query: &str) -> Result<&'a Repo, String> {
+    let backups = config.select(Some(query)).map_err(|e| e.to_string())?;
+
+    let mut repos: Vec<&Repo> = vec![];
+    for backup in backups {
+        if !repos.contains(&&backup.repo) {
+            repos.push(&backup.repo);
+        }
+    }
+    match repos[..] {
+        [repo] => Ok(repo),
+        _ => Err(format!("\"{query}\" matches several repositories")),
+    }
+}
+
+/// Exit with the same code as the child, or 128 + signal like a shell if it was killed
+pub(super) fn exit_code(status: ExitStatus) -> ExitCode {
+    if let Some(code) = status.code() {
+        return ExitCode::from(code as u8);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return ExitCode::from(128 + signal as u8);
+        }
+    }
+
+    ExitCode::FAILURE
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -44,4 +242,69 @@ mod tests {
         assert!(parse_byte_size("1X").is_err());
         assert!(parse_byte_size("X").is_err());
     }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(
+            parse_duration("1h30m").unwrap(),
+            Duration::from_secs(90 * 60)
+        );
+        assert_eq!(
+            parse_duration("2d").unwrap(),
+            Duration::from_secs(2 * 86400)
+        );
+        assert_eq!(
+            parse_duration("soon").unwrap_err().to_string(),
+            "Invalid duration \"soon\", expected seconds or e.g. \"90s\", \"15m\", \"1h30m\", \"2d\""
+        );
+        assert!(parse_duration("-5").is_err());
+
+        for secs in [0, 45, 90 * 60, 2 * 86400 + 1] {
+            let duration = Duration::from_secs(secs);
+            assert_eq!(parse_duration(&fmt_humantime(duration)).unwrap(), duration);
+        }
+        assert_eq!(fmt_humantime(Duration::from_secs(90 * 60)), "1h 30m");
+    }
+
+    #[test]
+    fn test_confirm_batch() {
+        // Would block on stdin if it asked
+        assert_eq!(confirm(Interaction::Batch, "Delete everything?"), None);
+    }
+
+    #[test]
+    fn test_latest_archive() {
+        use std::time::{Duration, SystemTime};
+
+        let archive = |name: &str, secs| ArchiveInfo {
+            name: name.to_owned(),
+            id: format!("{name}-id"),
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        };
+        let archives = [
+            archive("monday", 1),
+            archive("wednesday.checkpoint", 3),
+            archive("tuesday", 2),
+        ];
+        assert_eq!(latest_archive(&archives), Some(&archives[2]));
+        assert_eq!(latest_archive(&archives[1..2]), None);
+        assert_eq!(latest_archive(&[]), None);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let status = |script: &str| {
+            std::process::Command::new("sh")
+                .args(["-c", script])
+                .status()
+                .unwrap()
+        };
+        assert_eq!(exit_code(status("exit 0")), ExitCode::SUCCESS);
+        assert_eq!(exit_code(status("exit 3")), ExitCode::from(3));
+        assert_eq!(exit_code(status("exit 255")), ExitCode::from(255));
+        assert_eq!(exit_code(status("kill -TERM $$")), ExitCode::from(143));
+    }
 }
@@ -0,0 +1,384 @@
+use super::*;
+use crate::{backend::borg::BorgWrapper, ArchiveListEntry, Borg, EntryKind};
+use std::{
+    fs,
+    io::Read,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::SystemTime,
+};
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup
+    backup: String,
+
+    /// Number of files to restore
+    #[arg(long, value_name = "N", default_value = "5")]
+    sample: NonZeroUsize,
+
+    /// Upper bound for the size of all restored files together (e.g. 64M)
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, default_value = "64M")]
+    max_size: u64,
+
+    /// Compare the content of restored files with the live ones, if they are unchanged
+    #[arg(long)]
+    checksum: bool,
+}
+
+/// Restore a few random files of the newest archive into a temporary directory and check them
+pub fn verify_restore(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let backup = match config.select(Some(&args.backup)) {
+        Ok(backups) => backups[0],
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+    let repo = &backup.repo;
+
+    let archive = match borg.list_archives::<BorgWrapper>(repo) {
+        Ok(archives) => match latest_archive(&archives) {
+            Some(archive) => archive.name.to_owned(),
+            None => {
+                eprintln!("{}", style::error_stderr(format!("No archives in {repo}")));
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(e) => {
+            let message = format!("Failed to list archives: {e}");
+            eprintln!("{}", style::error_stderr(message));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Every file may take its share of the limit, so the sample never exceeds it
+    let max_file_size = args.max_size / args.sample.get() as u64;
+    let mut sample = Sample::new(args.sample.get());
+    let listed = borg.list_archive::<BorgWrapper>(repo, &archive, |entry| {
+        if entry.kind == EntryKind::File && entry.size <= max_file_size {
+            sample.offer(entry, crate::util::random());
+        }
+    });
+    if let Err(e) = listed {
+        let message = format!("Failed to list archive {archive}: {e}");
+        eprintln!("{}", style::error_stderr(message));
+        return ExitCode::FAILURE;
+    }
+    if sample.files.is_empty() {
+        let message = format!(
+            "No files of at most {} in {archive}, raise --max-size",
+            fmt_bytes(max_file_size)
+        );
+        eprintln!("{}", style::error_stderr(message));
+        return ExitCode::FAILURE;
+    }
+
+    let dir = match TempDir::create(&archive) {
+        Ok(dir) => dir,
+        Err(e) => {
+            let message = format!("Failed to create a temporary directory: {e}");
+            eprintln!("{}", style::error_stderr(message));
+            return ExitCode::FAILURE;
+        }
+    };
+    let live_root = backup
+        .archive
+        .relative_to()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/"));
+
+    println!("Restoring {} file(s) of {archive}", sample.files.len());
+    let mut success = true;
+    for (i, entry) in sample.files.iter().enumerate() {
+        let outcome = restore(&borg, repo, &archive, entry, &dir.0.join(i.to_string()))
+            .map(|restored| check(entry, &restored, &live_root, args.checksum));
+        let path = entry.path.display();
+        match outcome {
+            Ok(Outcome::Restored) => println!("{}", style::success(format!("ok {path}"))),
+            Ok(Outcome::Changed) => println!(
+                "{}",
+                style::success(format!(
+                    "ok {path} (changed since the backup, not compared)"
+                ))
+            ),
+            Ok(Outcome::Mismatch(reason)) => {
+                success = false;
+                println!("{}", style::error(format!("FAILED {path}: {reason}")));
+            }
+            Err(e) => {
+                success = false;
+                println!("{}", style::error(format!("FAILED {path}: {e}")));
+            }
+        }
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Uniformly random files of an archive, holding at most `n` of them however many are offered
+struct Sample {
+    n: usize,
+    offered: u64,
+    files: Vec<ArchiveListEntry>,
+}
+
+impl Sample {
+    fn new(n: usize) -> Self {
+        Sample {
+            n,
+            offered: 0,
+            files: Vec::with_capacity(n),
+        }
+    }
+
+    /// Keep `entry` with the right probability, `random` decides which
+    fn offer(&mut self, entry: ArchiveListEntry, random: u64) {
+        self.offered += 1;
+        if self.files.len() < self.n {
+            self.files.push(entry);
+            return;
+        }
+        let slot = (random % self.offered) as usize;
+        if slot < self.n {
+            self.files[slot] = entry;
+        }
+    }
+}
+
+/// Removed again when dropped, also if restoring fails
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// A new directory only accessible by the owner, never one which exists already
+    ///
+    /// Anyone can create files in the temporary directory, a directory or symlink with the
+    /// same name could lead the extracted files elsewhere.
+    fn create(archive: &str) -> std::io::Result<Self> {
+        use std::io::ErrorKind;
+
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        for _ in 0..16 {
+            let name = format!("borrg-verify-{archive}-{:016x}", crate::util::random());
+            let dir = std::env::temp_dir().join(name);
+            match builder.create(&dir) {
+                Ok(()) => return Ok(TempDir(dir)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(std::io::Error::new(
+            ErrorKind::AlreadyExists,
+            "no unused name for a temporary directory",
+        ))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            log::warn!("Failed to remove {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// Extract a single file into its own directory `dest`, returns where it ended up
+fn restore(
+    borg: &Borg,
+    repo: &crate::Repo,
+    archive: &str,
+    entry: &ArchiveListEntry,
+    dest: &Path,
+) -> crate::Result<PathBuf> {
+    fs::create_dir_all(dest)?;
+    let name = entry.path.file_name().ok_or("path without a file name")?;
+    let parents = entry.path.components().count() - 1;
+    BorgWrapper::extract(borg, repo, archive, &entry.path, dest, parents)?;
+    Ok(dest.join(name))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Restored,
+    /// The live file was modified after the backup, so it can't be compared
+    Changed,
+    Mismatch(String),
+}
+
+/// Compare a restored file with its listing, and with the live file below `live_root`
+fn check(entry: &ArchiveListEntry, restored: &Path, live_root: &Path, checksum: bool) -> Outcome {
+    let size = match fs::metadata(restored) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return Outcome::Mismatch(format!("not restored ({e})")),
+    };
+    if size != entry.size {
+        return Outcome::Mismatch(format!("{size} bytes restored, {} archived", entry.size));
+    }
+    if !checksum {
+        return Outcome::Restored;
+    }
+
+    let live = live_root.join(&entry.path);
+    let modified = fs::metadata(&live).and_then(|m| m.modified());
+    match modified {
+        // Gone or unreadable, only the size can be checked
+        Err(_) => Outcome::Restored,
+        Ok(time) if !same_second(time, entry.mtime) => Outcome::Changed,
+        Ok(_) => match same_content(restored, &live) {
+            Ok(true) => Outcome::Restored,
+            Ok(false) => Outcome::Mismatch(format!("differs from {}", live.display())),
+            Err(e) => Outcome::Mismatch(format!("failed to compare with {}: {e}", live.display())),
+        },
+    }
+}
+
+/// Borg lists modification times with a precision of seconds
+fn same_second(a: SystemTime, b: SystemTime) -> bool {
+    let secs = |t: SystemTime| {
+        t.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok()
+    };
+    secs(a) == secs(b)
+}
+
+fn same_content(a: &Path, b: &Path) -> std::io::Result<bool> {
+    let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+        match b.read_exact(&mut buf_b[..n]) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: Args,
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parse = |args: &[&str]| {
+            let args = ["verify-restore", "home"]
+                .into_iter()
+                .chain(args.iter().copied());
+            Cli::try_parse_from(args).map(|cli| cli.args)
+        };
+        assert_eq!(parse(&[]).unwrap().sample.get(), 5);
+        assert_eq!(parse(&["--sample", "1"]).unwrap().sample.get(), 1);
+        // Nothing would be restored
+        assert!(parse(&["--sample", "0"]).is_err());
+    }
+
+    fn entry(path: &str, size: u64, mtime: SystemTime) -> ArchiveListEntry {
+        ArchiveListEntry {
+            path: path.into(),
+            kind: EntryKind::File,
+            size,
+            mtime,
+            mode: "-rw-r--r--".to_owned(),
+            user: "seb".to_owned(),
+            group: "seb".to_owned(),
+            link_target: None,
+        }
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut sample = Sample::new(2);
+        for (i, random) in [7, 7, 0, 5, 1].into_iter().enumerate() {
+            sample.offer(entry(&i.to_string(), 0, SystemTime::UNIX_EPOCH), random);
+        }
+        // 2 replaces slot 0 (0 % 3), 3 is dropped (5 % 4), 4 replaces slot 1 (1 % 5)
+        let paths: Vec<_> = sample.files.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, [PathBuf::from("2"), PathBuf::from("4")]);
+        assert_eq!(sample.offered, 5);
+    }
+
+    #[test]
+    fn test_temp_dir() {
+        let first = TempDir::create("2024-05-10").unwrap();
+        let second = TempDir::create("2024-05-10").unwrap();
+        assert_ne!(first.0, second.0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&first.0).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        let path = first.0.clone();
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-verify-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("live/docs")).unwrap();
+        fs::create_dir_all(dir.join("restored")).unwrap();
+        let live = dir.join("live/docs/notes.txt");
+        let restored = dir.join("restored/notes.txt");
+        fs::write(&live, "hello").unwrap();
+        fs::write(&restored, "hello").unwrap();
+        let mtime = fs::metadata(&live).unwrap().modified().unwrap();
+        let live_root = dir.join("live");
+
+        let archived = entry("docs/notes.txt", 5, mtime);
+        assert_eq!(
+            check(&archived, &restored, &live_root, false),
+            Outcome::Restored
+        );
+        assert_eq!(
+            check(&archived, &restored, &live_root, true),
+            Outcome::Restored
+        );
+
+        let truncated = entry("docs/notes.txt", 6, mtime);
+        assert!(matches!(
+            check(&truncated, &restored, &live_root, false),
+            Outcome::Mismatch(_)
+        ));
+
+        fs::write(&restored, "hallo").unwrap();
+        assert!(matches!(
+            check(&archived, &restored, &live_root, true),
+            Outcome::Mismatch(_)
+        ));
+
+        let older = entry("docs/notes.txt", 5, SystemTime::UNIX_EPOCH);
+        assert_eq!(check(&older, &restored, &live_root, true), Outcome::Changed);
+
+        // Without the live file only the size is checked
+        let gone = entry("docs/gone.txt", 5, mtime);
+        assert_eq!(check(&gone, &restored, &live_root, true), Outcome::Restored);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
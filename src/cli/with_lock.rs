@@ -0,0 +1,81 @@
+use super::*;
+use crate::{backend, Borg};
+use std::ffi::OsString;
+use std::process::ExitCode;
+
+#[derive(Args, Debug)]
+pub struct Args {
+    /// Id or repository of the backup
+    backup: String,
+
+    /// Command to run while the repository is locked, after `--`
+    #[arg(last = true, required = true, value_name = "COMMAND")]
+    command: Vec<OsString>,
+}
+
+pub fn with_lock(borg: Borg, config: Config, args: Args) -> ExitCode {
+    let repo = match util::select_repo(&config, &args.backup) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("{}", style::error_stderr(e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Borg has no --dry-run for with-lock, the command would run anyway
+    if borg.dry_run {
+        return match backend::borg::BorgWrapper::explain_with_lock(&borg, repo, &args.command) {
+            Ok(invocation) => {
+                println!("Would run {invocation} (dry run)");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{}", style::error_stderr(e));
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    match backend::borg::BorgWrapper::with_lock(&borg, repo, &args.command) {
+        Ok(status) => util::exit_code(status),
+        Err(e) => {
+            eprintln!(
+                "{}",
+                style::error_stderr(format!("Failed to run borg with-lock: {e}"))
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: Args,
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        let args = std::iter::once("with-lock").chain(args.iter().copied());
+        Cli::try_parse_from(args).map(|cli| cli.args)
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let args = parse(&["/srv/backup", "--", "rsync", "-av", "--delete", "a", "b"]).unwrap();
+        assert_eq!(args.backup, "/srv/backup");
+        assert_eq!(args.command, ["rsync", "-av", "--delete", "a", "b"]);
+
+        // Only the first separator belongs to borrg
+        let args = parse(&["/srv/backup", "--", "ls", "--", "-weird-name"]).unwrap();
+        assert_eq!(args.command, ["ls", "--", "-weird-name"]);
+
+        assert!(parse(&["/srv/backup"]).is_err());
+        assert!(parse(&["/srv/backup", "--"]).is_err());
+        assert!(parse(&["/srv/backup", "ls"]).is_err());
+    }
+}
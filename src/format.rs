@@ -0,0 +1,141 @@
+//! How sizes, durations and counts are shown to users
+//!
+//! The summaries and progress output of the cli go through the [`Formatter`] set with
+//! [`set_formatter`], [`DefaultFormatter`] if none is set. Applications embedding borrg can
+//! provide their own to localize them.
+//!
+//! ```rust
+//! use borrg::format::{self, DefaultFormatter, Formatter};
+//! use std::time::Duration;
+//!
+//! struct German;
+//!
+//! impl Formatter for German {
+//!     fn bytes(&self, bytes: u64) -> String {
+//!         DefaultFormatter.bytes(bytes).replace('.', ",")
+//!     }
+//!
+//!     fn duration(&self, duration: Duration) -> String {
+//!         format!("{} Sek.", duration.as_secs())
+//!     }
+//!
+//!     fn count(&self, count: u64) -> String {
+//!         DefaultFormatter.count(count).replace(',', ".")
+//!     }
+//! }
+//!
+//! assert!(format::set_formatter(German));
+//! assert_eq!(format::bytes(1536), "1,50 KiB");
+//! assert_eq!(format::throughput(1536), "1,50 KiB/s");
+//! assert_eq!(format::count(1_204), "1.204");
+//! assert_eq!(format::duration(Duration::from_secs(42)), "42 Sek.");
+//! ```
+
+use std::{sync::OnceLock, time::Duration};
+
+/// Formats the numbers of the output, see the [module docs](self)
+pub trait Formatter: Send + Sync {
+    /// A size, e.g. "1.50 GiB"
+    fn bytes(&self, bytes: u64) -> String;
+
+    /// How long something took, e.g. "3m12s"
+    fn duration(&self, duration: Duration) -> String;
+
+    /// A number of files or archives, e.g. "1,204"
+    fn count(&self, count: u64) -> String;
+
+    /// Bytes per second, e.g. "42.00 MiB/s"
+    fn throughput(&self, bytes_per_second: u64) -> String {
+        format!("{}/s", self.bytes(bytes_per_second))
+    }
+}
+
+/// English output with binary units
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultFormatter;
+
+impl Formatter for DefaultFormatter {
+    fn bytes(&self, bytes: u64) -> String {
+        indicatif::HumanBytes(bytes).to_string()
+    }
+
+    fn duration(&self, duration: Duration) -> String {
+        let secs = duration.as_secs();
+        let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+        match (h, m) {
+            (0, 0) => format!("{s}s"),
+            (0, _) => format!("{m}m{s:02}s"),
+            _ => format!("{h}h{m:02}m{s:02}s"),
+        }
+    }
+
+    fn count(&self, count: u64) -> String {
+        let digits = count.to_string();
+        let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                out.push(',');
+            }
+            out.push(digit);
+        }
+        out
+    }
+}
+
+static FORMATTER: OnceLock<Box<dyn Formatter>> = OnceLock::new();
+
+/// Use `formatter` for all further output
+///
+/// Only works once and before anything was formatted, returns whether it did.
+pub fn set_formatter(formatter: impl Formatter + 'static) -> bool {
+    FORMATTER.set(Box::new(formatter)).is_ok()
+}
+
+/// The formatter set with [`set_formatter`], [`DefaultFormatter`] if there is none
+pub fn formatter() -> &'static dyn Formatter {
+    FORMATTER
+        .get_or_init(|| Box::new(DefaultFormatter))
+        .as_ref()
+}
+
+/// [`Formatter::bytes`] of the current [`formatter`]
+pub fn bytes(bytes: u64) -> String {
+    formatter().bytes(bytes)
+}
+
+/// [`Formatter::duration`] of the current [`formatter`]
+pub fn duration(duration: Duration) -> String {
+    formatter().duration(duration)
+}
+
+/// [`Formatter::count`] of the current [`formatter`]
+pub fn count(count: u64) -> String {
+    formatter().count(count)
+}
+
+/// [`Formatter::throughput`] of the current [`formatter`]
+pub fn throughput(bytes_per_second: u64) -> String {
+    formatter().throughput(bytes_per_second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_formatter() {
+        let f = DefaultFormatter;
+        assert_eq!(f.duration(Duration::from_millis(500)), "0s");
+        assert_eq!(f.duration(Duration::from_secs(42)), "42s");
+        assert_eq!(f.duration(Duration::from_secs(192)), "3m12s");
+        assert_eq!(f.duration(Duration::from_secs(3600 + 5)), "1h00m05s");
+
+        assert_eq!(f.count(0), "0");
+        assert_eq!(f.count(999), "999");
+        assert_eq!(f.count(1_204), "1,204");
+        assert_eq!(f.count(12_345_678), "12,345,678");
+
+        assert_eq!(f.bytes(1536), "1.50 KiB");
+        assert_eq!(f.throughput(42 * 1024 * 1024), "42.00 MiB/s");
+    }
+}
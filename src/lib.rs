@@ -2,4 +2,6 @@ pub mod backend;
 mod borrg;
 pub use crate::borrg::*;
 pub mod cli;
-pub(crate) mod util;
+pub mod format;
+pub mod runner;
+pub mod util;
@@ -1,7 +1,9 @@
-use borrg::Borg;
+use borrg::backend::borg::BorgWrapper;
+use borrg::cli::style::{self, ColorChoice};
+use borrg::util;
+use borrg::{Borg, Interaction};
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
-mod util;
+use std::{path::PathBuf, process::ExitCode};
 
 /// Borrg wrapper
 #[derive(Parser, Debug)]
@@ -11,13 +13,48 @@ pub struct Cli {
     #[clap(subcommand)]
     command: Commands,
 
-    /// Path to config file
-    #[clap(short, long, default_value = "~/.config/borg/borrg.toml")]
-    config: PathBuf,
+    /// Path to config file [default: ~/.config/borg/borrg.toml, %APPDATA%\borrg\borrg.toml on
+    /// Windows]
+    #[clap(short, long)]
+    config: Option<PathBuf>,
 
     /// Run borg in dry run mode
     #[clap(long)]
     dry_run: bool,
+
+    /// Let borg report specific exit codes (needs borg 1.4 or newer)
+    #[clap(long)]
+    modern_exit_codes: bool,
+
+    /// When to use colors
+    #[clap(long, value_enum, default_value_t, global = true)]
+    color: ColorChoice,
+
+    /// Log more, -v info, -vv debug, -vvv trace messages (RUST_LOG takes precedence)
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Don't log anything, not even errors
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Never wait for input: fail instead of asking for passphrases or confirmations, answer
+    /// borg's questions with no and draw no progress bars
+    #[clap(long, global = true, env = "BORRG_BATCH")]
+    batch: bool,
+}
+
+impl Cli {
+    /// Level of the log of borrg, only errors by default
+    fn log_level(&self) -> log::LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => log::LevelFilter::Off,
+            (false, 0) => log::LevelFilter::Error,
+            (false, 1) => log::LevelFilter::Info,
+            (false, 2) => log::LevelFilter::Debug,
+            (false, _) => log::LevelFilter::Trace,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -26,34 +63,98 @@ enum Commands {
     Run(borrg::cli::run::Args),
     /// Initialize a new borg repository
     Init(borrg::cli::init::Args),
-    /// List backups
-    List,
+    /// List backups, their archives or the contents of an archive
+    List(borrg::cli::list::Args),
+    /// Print the newest archive of a backup
+    Latest(borrg::cli::latest::Args),
     /// Get info about a backup
-    Info { backup: String },
+    Info(borrg::cli::info::Args),
+    /// Delete archives according to the retention policy of backups
+    Prune(borrg::cli::prune::Args),
+    /// Show the state of all backups
+    Status(borrg::cli::status::Args),
+    /// Restore a few files of the newest archive into a temporary directory and check them
+    VerifyRestore(borrg::cli::verify_restore::Args),
+    /// Show archives created by borrg
+    History(borrg::cli::history::Args),
+    /// Keep running and start backups according to their schedule
+    #[cfg(feature = "daemon")]
+    Daemon(borrg::cli::daemon::Args),
+    /// Delete a repository including all archives
+    DeleteRepo(borrg::cli::delete_repo::Args),
+    /// Point the config at the new location of a repository which moved
+    MigrateRepo(borrg::cli::migrate_repo::Args),
+    /// Keep copies of the keys of keyfile-mode repositories
+    Key(borrg::cli::key::Args),
+    /// Run a command while the repository of a backup is locked
+    WithLock(borrg::cli::with_lock::Args),
+    /// Run borg directly, with the passphrase and settings of borrg
+    Raw(borrg::cli::raw::Args),
+    /// Check the setup of borg, e.g. the versions on the hosts of remote repositories
+    Doctor(borrg::cli::doctor::Args),
+    /// Manage the client-side cache
+    Cache(borrg::cli::cache::Args),
+    /// Import or export configurations
+    Config(borrg::cli::configure::Args),
     /// Validate config
     Debug,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-
+fn main() -> ExitCode {
     let cli = Cli::parse();
 
-    let config_path = util::resolve_path(&cli.config);
+    // Borg's log level is set separately, see `Borg::log_level`
+    match std::env::var_os("RUST_LOG") {
+        Some(_) => env_logger::init(),
+        None => env_logger::Builder::new()
+            .filter_level(cli.log_level())
+            .init(),
+    }
+    style::init(cli.color);
+
+    let config_path = match &cli.config {
+        Some(path) => util::resolve_path(path),
+        None => borrg::cli::Config::default_path(),
+    };
+
+    // Works without a (valid) config
+    if let Commands::Config(args) = cli.command {
+        return borrg::cli::configure::configure(&config_path, args);
+    }
+
     let config = borrg::cli::Config::load(&config_path);
 
     let config = match config {
         Ok(config) => config,
         Err(e) => {
-            eprintln!("Failed to load config ({}): {}", config_path.display(), e);
+            let message = format!("Failed to load config ({}): {}", config_path.display(), e);
+            eprintln!("{}", style::error_stderr(message));
             std::process::exit(1);
         }
     };
 
     let mut borg = Borg::default();
+    borg.cache(config.cache.clone());
+    // Every command asks borg about a repository only once
+    borg.info_cache(std::sync::Arc::new(borrg::RepoInfoCache::new()));
     if cli.dry_run {
         borg.dry_run();
     }
+    if cli.batch {
+        borg.interaction(Interaction::Batch);
+    }
+    if cli.modern_exit_codes {
+        let warning = match borg.modern_exit_codes::<BorgWrapper>() {
+            Ok(true) => None,
+            Ok(false) => Some("borg doesn't support modern exit codes, using legacy ones".into()),
+            Err(e) => Some(format!(
+                "Failed to get borg version, using legacy exit codes: {e}"
+            )),
+        };
+        if let Some(warning) = warning {
+            eprintln!("{}", style::warning_stderr(warning));
+        }
+    }
 
     match cli.command {
         Commands::Debug => {
@@ -62,13 +163,59 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             dbg!(borg);
         }
         Commands::Run(args) => {
-            borrg::cli::run::run(borg, config, args);
+            return borrg::cli::run::run(borg, config, args);
+        }
+        Commands::DeleteRepo(args) => {
+            return borrg::cli::delete_repo::delete_repo(borg, config, args);
+        }
+        Commands::MigrateRepo(args) => {
+            return borrg::cli::migrate_repo::migrate_repo(borg, config, args);
+        }
+        Commands::WithLock(args) => {
+            return borrg::cli::with_lock::with_lock(borg, config, args);
+        }
+        Commands::Raw(args) => {
+            return borrg::cli::raw::raw(borg, config, args);
+        }
+        Commands::Doctor(args) => {
+            return borrg::cli::doctor::doctor(borg, config, args);
+        }
+        Commands::Cache(args) => {
+            return borrg::cli::cache::cache(borg, config, args);
+        }
+        Commands::Key(args) => {
+            return borrg::cli::key::key(borg, config, args);
+        }
+        Commands::List(args) => {
+            return borrg::cli::list::list(borg, config, args);
+        }
+        Commands::VerifyRestore(args) => {
+            return borrg::cli::verify_restore::verify_restore(borg, config, args);
+        }
+        Commands::Latest(args) => {
+            return borrg::cli::latest::latest(borg, config, args);
+        }
+        Commands::Info(args) => {
+            return borrg::cli::info::info(borg, config, args);
+        }
+        Commands::Prune(args) => {
+            return borrg::cli::prune::prune(borg, config, args);
+        }
+        Commands::Status(args) => {
+            return borrg::cli::status::status(borg, config, args);
+        }
+        Commands::History(args) => {
+            return borrg::cli::history::history(config, args);
+        }
+        #[cfg(feature = "daemon")]
+        Commands::Daemon(args) => {
+            return borrg::cli::daemon::daemon(borg, config, args);
         }
         Commands::Init(args) => {
-            borrg::cli::init::init(borg, config, args);
+            return borrg::cli::init::init(borg, config, args);
         }
-        _ => unimplemented!(),
+        Commands::Config(_) => unreachable!(),
     }
 
-    Ok(())
+    ExitCode::SUCCESS
 }
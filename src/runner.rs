@@ -0,0 +1,1358 @@
+//! Running many backups at once, with their verification and pruning
+//!
+//! Every backup runs on a thread of its own, within the [`Limits`] of the run. Their events
+//! are passed to an [`EventSink`] on yet another thread, so a slow consumer doesn't hold up
+//! the backups.
+//!
+//! # Examples
+//! ```rust,no_run
+//! use borrg::{backend::borg::BorgWrapper, runner::Runner, Archive, Borg};
+//!
+//! let repo = "/srv/backup".parse().unwrap();
+//! let mut archive = Archive::new("home");
+//! archive.path("/home");
+//!
+//! let mut runner = Runner::new(Borg::default());
+//! runner.retries(2);
+//! let report = runner.run::<BorgWrapper>([(repo, archive)], |idx, event| {
+//!     println!("{idx}: {event:?}");
+//! });
+//! for result in &report.backups {
+//!     println!("{result}");
+//! }
+//! ```
+mod channel;
+mod limits;
+
+use crate::{
+    backend::borg::BorgError, Archive, Backend, Borg, CancellationToken, CheckOptions, CreateStats,
+    Error, Event, ExcludeFile, FileChanges, Phase, ProgressTracker, PrunePolicy, PruneStats, Repo,
+    Throughput,
+};
+pub use limits::Limits;
+use log::{error, warn};
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::Display,
+    num::NonZeroUsize,
+    ops::ControlFlow,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Runs backups in parallel, see the [module documentation](self)
+#[derive(Debug, Clone, Default)]
+pub struct Runner {
+    borg: Borg,
+    limits: Limits,
+    retries: u32,
+}
+
+impl Runner {
+    pub fn new(borg: Borg) -> Self {
+        Runner {
+            borg,
+            ..Runner::default()
+        }
+    }
+
+    /// Run at most this many backups at the same time
+    pub fn jobs(&mut self, jobs: NonZeroUsize) -> &mut Self {
+        self.limits.jobs = Some(jobs);
+        self
+    }
+
+    pub fn limits(&mut self, limits: Limits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Create an archive up to this many more times if borg fails
+    pub fn retries(&mut self, retries: u32) -> &mut Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Don't change any repository, see [`Borg::dry_run`]
+    pub fn dry_run(&mut self) -> &mut Self {
+        self.borg.dry_run();
+        self
+    }
+
+    /// Interrupt the running backups once `token` is cancelled and fail the ones which didn't
+    /// start yet, see [`Borg::cancellation`]
+    pub fn cancellation(&mut self, token: CancellationToken) -> &mut Self {
+        self.borg.cancellation(token);
+        self
+    }
+
+    /// Run all `backups` and wait for them, the results are in the same order
+    ///
+    /// Backups with a [`Job::rejected`] reason fail right away, the others wait for each other
+    /// according to the limits, and backups to the same repository never run at the same
+    /// time. Backups start after their prerequisites succeeded and are skipped if one of them
+    /// failed, backups which (indirectly) wait for themselves fail. A panicking backup doesn't affect the other backups, it only results in an
+    /// internal error for itself. A panicking sink only results in a warning.
+    pub fn run<B>(
+        &self,
+        backups: impl IntoIterator<Item = impl Into<Job>>,
+        mut sink: impl EventSink,
+    ) -> RunReport
+    where
+        B: Backend<Update = Event>,
+    {
+        let mut jobs: Vec<Job> = backups.into_iter().map(Into::into).collect();
+        let (tx, batches) = channel::channel();
+        let borg = Arc::new(self.borg.clone());
+        let dry_run = borg.dry_run;
+        let scheduler = Arc::new(limits::Scheduler::new(self.limits.clone()));
+        let completion = Arc::new(Completion::new(jobs.len()));
+        let append_only = Arc::new(AppendOnly::default());
+        // Prerequisites which aren't part of this run don't hold anything up
+        let names: Vec<_> = jobs.iter().map(|job| job.name.to_owned()).collect();
+        let prerequisites: Vec<Vec<usize>> = jobs
+            .iter()
+            .map(|job| {
+                job.after
+                    .iter()
+                    .copied()
+                    .filter(|&i| i < jobs.len())
+                    .collect()
+            })
+            .collect();
+        // They would wait for each other forever
+        for (job, cycle) in jobs.iter_mut().zip(dependency_cycles(&prerequisites)) {
+            if let Some(cycle) = cycle {
+                let cycle: Vec<_> = cycle.iter().map(|&i| names[i].as_str()).collect();
+                job.rejected.get_or_insert_with(|| {
+                    format!("backups depend on each other: {}", cycle.join(" -> "))
+                });
+            }
+        }
+
+        let handles: Vec<_> = jobs
+            .into_iter()
+            .enumerate()
+            .map(|(idx, job)| {
+                let borg = borg.clone();
+                let tx = tx.clone();
+                let scheduler = scheduler.clone();
+                let limits = self.limits.clone();
+                let retries = self.retries;
+                let completion = completion.clone();
+                let append_only = append_only.clone();
+                let prerequisites = prerequisites[idx].clone();
+                let names: Vec<_> = prerequisites.iter().map(|&i| names[i].clone()).collect();
+                std::thread::spawn(move || {
+                    let mut job = job;
+                    let mut done = Done::new(&completion, idx);
+                    if let Some(reason) = job.rejected.take() {
+                        let result = BackupResult::failed(reason.into(), dry_run);
+                        tx.finish(idx, result.summary()).ok();
+                        return result;
+                    }
+                    if !prerequisites.is_empty() {
+                        tx.queued(idx, Some(format!("waiting for {}", names.join(", "))))
+                            .ok();
+                    }
+                    if let Some(failed) = completion.wait(&prerequisites) {
+                        let position = prerequisites.iter().position(|&i| i == failed).unwrap();
+                        let reason = format!("{} failed", names[position]);
+                        let result = BackupResult::skipped(reason, dry_run);
+                        tx.finish(idx, result.summary()).ok();
+                        return result;
+                    }
+                    // After the prerequisites, they may create the paths
+                    let mut warnings = vec![];
+                    if let Some(result) = check_paths(&mut job, &mut warnings, dry_run) {
+                        tx.finish(idx, result.summary()).ok();
+                        return result;
+                    }
+
+                    let delay = limits.start_delay(idx);
+                    let mut waited = !delay.is_zero() || !prerequisites.is_empty();
+                    limits::countdown(delay, borg.cancellation.as_ref(), |left| {
+                        let message = format!("starting in {}…", crate::format::duration(left));
+                        tx.queued(idx, Some(message)).ok();
+                    });
+                    let slot = scheduler.acquire(&job.repo, |wait| {
+                        waited = true;
+                        tx.queued(idx, Some(wait.to_string())).ok();
+                    });
+                    if waited {
+                        tx.queued(idx, None).ok();
+                    }
+                    if cancelled(&borg) {
+                        let result = BackupResult::failed(BorgError::Cancelled.into(), dry_run);
+                        tx.finish(idx, result.summary()).ok();
+                        return result;
+                    }
+                    let mut result = run_backup::<B>(&borg, &job, retries, idx, &tx, &append_only);
+                    drop(slot);
+                    result.warnings.splice(0..0, warnings);
+                    tx.finish(idx, result.summary()).ok();
+                    done.success = result.is_success();
+                    result
+                })
+            })
+            .collect();
+
+        let consumer = std::thread::spawn(move || {
+            for batch in batches {
+                for (idx, event) in batch {
+                    sink.event(idx, event);
+                }
+                sink.tick();
+            }
+        });
+
+        let backups = handles
+            .into_iter()
+            .enumerate()
+            .map(|(idx, handle)| {
+                handle.join().unwrap_or_else(|panic| {
+                    let result = BackupResult::failed(internal_error(panic), dry_run);
+                    tx.finish(idx, result.summary()).ok();
+                    result
+                })
+            })
+            .collect();
+        // Drop original tx so that the receiver stops
+        drop(tx);
+
+        if let Err(panic) = consumer.join() {
+            error!("Event sink crashed: {}", internal_error(panic));
+        }
+
+        RunReport { backups }
+    }
+}
+
+/// A backup for the [`Runner`], with what to do after creating its archive
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub repo: Repo,
+    pub archive: Archive,
+    /// Shown when other backups wait for this one, `repo::archive` by default
+    pub name: String,
+    /// Check the repository after creating the archive, failing the backup if it fails
+    pub verify: Option<CheckOptions>,
+    /// Prune after a successful backup, failures are only warnings
+    pub prune: Option<PrunePolicy>,
+    /// Compact after pruning
+    pub compact: bool,
+    /// Indices of the backups which have to succeed before this one starts
+    pub after: Vec<usize>,
+    /// Leave out paths which don't exist instead of failing
+    pub allow_missing_paths: bool,
+    /// Don't warn about paths inside other paths, see [`Archive::nested_paths`]
+    pub allow_nested_paths: bool,
+    /// Fail right away for this reason, e.g. a missing passphrase
+    pub rejected: Option<String>,
+    /// Level borg logs at for this backup instead of the one of the runner's [`Borg`]
+    pub borg_log_level: Option<log::Level>,
+    /// Let borg log its exit code for this backup, see [`Borg::show_rc`]
+    pub show_rc: bool,
+    /// Whether the repository is configured append-only, for remote repositories whose
+    /// config borg can't read, see [`Borg::is_append_only`]
+    pub append_only: Option<bool>,
+}
+
+impl Job {
+    /// `borg` with the settings of this backup
+    fn borg<'a>(&self, borg: &'a Borg) -> Cow<'a, Borg> {
+        if self.borg_log_level.is_none() && !self.show_rc {
+            return Cow::Borrowed(borg);
+        }
+        let mut borg = borg.clone();
+        if let Some(level) = self.borg_log_level {
+            borg.log_level(level);
+        }
+        if self.show_rc {
+            borg.show_rc();
+        }
+        Cow::Owned(borg)
+    }
+}
+
+impl From<(Repo, Archive)> for Job {
+    fn from((repo, archive): (Repo, Archive)) -> Self {
+        Job {
+            name: format!("{repo}::{}", archive.name()),
+            repo,
+            archive,
+            verify: None,
+            prune: None,
+            compact: false,
+            after: vec![],
+            allow_missing_paths: false,
+            allow_nested_paths: false,
+            rejected: None,
+            borg_log_level: None,
+            show_rc: false,
+            append_only: None,
+        }
+    }
+}
+
+/// What happens during a run, passed to an [`EventSink`] with the index of the backup
+#[derive(Debug)]
+pub enum RunEvent {
+    /// Reported by borg
+    Event(Event),
+    /// Why the backup is waiting, `None` once it starts
+    Queued(Option<String>),
+    /// Summary of a finished backup, see [`BackupResult::summary`]
+    Finished(String),
+}
+
+/// Receives the events of a [run](Runner::run) on a thread of its own
+///
+/// Implemented by closures taking the index of the backup and the event.
+pub trait EventSink: Send + 'static {
+    fn event(&mut self, idx: usize, event: RunEvent);
+
+    /// Called after each batch of events, and at least once a second while the run goes on
+    fn tick(&mut self) {}
+}
+
+impl<F> EventSink for F
+where
+    F: FnMut(usize, RunEvent) + Send + 'static,
+{
+    fn event(&mut self, idx: usize, event: RunEvent) {
+        self(idx, event)
+    }
+}
+
+/// Results of a [run](Runner::run), in the order of its backups
+#[derive(Debug)]
+pub struct RunReport {
+    pub backups: Vec<BackupResult>,
+}
+
+impl RunReport {
+    /// Whether no backup failed, skipped backups don't count as failed
+    pub fn is_success(&self) -> bool {
+        !self.backups.iter().any(BackupResult::is_failed)
+    }
+}
+
+/// How a backup ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The archive was created and verified, pruning may still have failed
+    Success,
+    Failed,
+    /// The backup didn't start because a prerequisite failed or none of its paths exist
+    Skipped,
+}
+
+/// Result of a single backup including its follow-up operations
+#[derive(Debug)]
+pub struct BackupResult {
+    /// How long creating the archive took
+    pub backup: Result<Duration, Error>,
+    pub verify: Option<Result<Duration, Error>>,
+    /// Pruning and compaction, failures are only warnings
+    pub prune: Option<Result<Duration, Error>>,
+    /// Deduplicated size of the new archive as last reported by borg
+    pub new_data: Option<u64>,
+    /// Archives kept and removed, if pruning succeeded
+    pub pruned: Option<PruneStats>,
+    /// Pruning succeeded on an append-only repository, the archives are only removed once it
+    /// is compacted without append-only mode
+    pub prune_deferred: bool,
+    /// Stats of the new archive, unless it was a dry run
+    pub stats: Option<CreateStats>,
+    /// How fast borg read the files while creating the archive, unless it was a dry run
+    pub throughput: Option<Throughput>,
+    /// Files per kind of the last attempt, if borg listed them, see [`Borg::list_files`]
+    pub file_changes: FileChanges,
+    /// Whether borg got the exclude file, if the backup got that far
+    pub exclude_file: Option<ExcludeFile>,
+    /// Problems which didn't fail the backup, e.g. left out paths or failed attempts
+    pub warnings: Vec<String>,
+    /// How often borg was asked to create the archive, more than once after retries
+    pub attempts: u32,
+    /// Nothing was written, the result mustn't be recorded anywhere
+    pub dry_run: bool,
+    /// See [`Status::Skipped`]
+    pub skipped: bool,
+}
+
+impl BackupResult {
+    fn failed(error: Error, dry_run: bool) -> Self {
+        BackupResult {
+            backup: Err(error),
+            verify: None,
+            prune: None,
+            new_data: None,
+            pruned: None,
+            prune_deferred: false,
+            stats: None,
+            throughput: None,
+            file_changes: FileChanges::default(),
+            exclude_file: None,
+            warnings: vec![],
+            attempts: 0,
+            dry_run,
+            skipped: false,
+        }
+    }
+
+    fn skipped(reason: String, dry_run: bool) -> Self {
+        BackupResult {
+            skipped: true,
+            ..BackupResult::failed(reason.into(), dry_run)
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self.is_success() {
+            true => Status::Success,
+            false if self.skipped => Status::Skipped,
+            false => Status::Failed,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.backup.is_ok() && !matches!(self.verify, Some(Err(_)))
+    }
+
+    /// See [`Status::Failed`], a skipped backup neither succeeded nor failed
+    pub fn is_failed(&self) -> bool {
+        self.status() == Status::Failed
+    }
+
+    /// Why the backup failed or was skipped
+    pub fn error(&self) -> Option<&Error> {
+        match (&self.backup, &self.verify) {
+            (Err(e), _) | (Ok(_), Some(Err(e))) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Short description for a progress bar of the finished backup, e.g.
+    /// "done in 3m12s, 1.50 GiB new (98.2% saved)"
+    pub fn summary(&self) -> String {
+        use crate::format;
+
+        let backup_time = match &self.backup {
+            Ok(t) => t,
+            Err(e) if self.skipped => return format!("skipped: {e}"),
+            Err(e) => return format!("failed: {e}"),
+        };
+        if let Some(Err(e)) = &self.verify {
+            return format!("verification failed: {e}");
+        }
+
+        let total = [&self.verify, &self.prune]
+            .into_iter()
+            .flatten()
+            .filter_map(|res| res.as_ref().ok())
+            .sum::<Duration>()
+            + *backup_time;
+        let mut summary = format!("done in {}", format::duration(total));
+        if let Some(new) = self.new_data {
+            summary.push_str(&format!(", {} new", format::bytes(new)));
+            if let Some(saving) = self.stats.as_ref().and_then(CreateStats::space_saving) {
+                summary.push_str(&format!(" ({saving} saved)"));
+            }
+        }
+        if !self.file_changes.is_empty() {
+            summary.push_str(&format!(", {}", self.file_changes));
+        }
+        if self.prune_deferred {
+            summary.push_str(", prune deferred (append-only repository)");
+        } else if let Some(pruned) = self.pruned {
+            let pruned = format::count(pruned.pruned);
+            summary.push_str(&format!(", pruned {pruned} archive(s)"));
+        }
+        summary
+    }
+}
+
+impl Display for BackupResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use crate::format;
+
+        let backup_time = match &self.backup {
+            Ok(t) => t,
+            Err(e) if self.skipped => return write!(f, "skipped: {e}"),
+            Err(e) => return write!(f, "backup failed: {e}"),
+        };
+        write!(f, "backed up in {}", format::duration(*backup_time))?;
+        if let Some(throughput) = &self.throughput {
+            write!(f, " at {}", format::throughput(throughput.average as u64))?;
+        }
+        if !self.file_changes.is_empty() {
+            write!(f, " ({})", self.file_changes)?;
+        }
+        if let Some(stats) = &self.stats {
+            if let Some(ratio) = stats.compression_ratio() {
+                write!(f, ", compression {ratio}")?;
+            }
+            if let Some(saving) = stats.space_saving() {
+                write!(f, ", {saving} saved")?;
+            }
+        }
+
+        match &self.verify {
+            Some(Ok(t)) => write!(f, " and verified in {}", format::duration(*t))?,
+            Some(Err(e)) => write!(f, ", verification failed: {e}")?,
+            None => {}
+        }
+
+        match &self.prune {
+            Some(Ok(_)) if self.prune_deferred => {
+                write!(f, ", prune deferred (append-only repository)")?
+            }
+            Some(Ok(t)) => write!(f, ", pruned in {}", format::duration(*t))?,
+            Some(Err(e)) => write!(f, ", warning: pruning failed: {e}")?,
+            None => {}
+        }
+        match &self.pruned {
+            Some(pruned) if !self.prune_deferred => write!(f, " ({pruned})")?,
+            _ => {}
+        }
+        if let Some(exclude_file) = &self.exclude_file {
+            write!(f, ", {exclude_file}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Make sure the paths of a backup exist before running it, the result if it can't run
+///
+/// Missing paths fail the backup, unless `allow_missing_paths` is set. Then they are left out
+/// with a warning, and the backup is skipped if none are left. Empty directories are only
+/// warned about.
+fn check_paths(job: &mut Job, warnings: &mut Vec<String>, dry_run: bool) -> Option<BackupResult> {
+    if !job.allow_nested_paths {
+        for (outer, inner) in job.archive.nested_paths() {
+            let (outer, inner) = (outer.display(), inner.display());
+            warn!(
+                "{inner} of {} is inside {outer}, it is read twice",
+                job.name
+            );
+            warnings.push(format!(
+                "{inner} is inside {outer}, remove it to read it once"
+            ));
+        }
+    }
+
+    let mut missing = vec![];
+    for path in job.archive.paths() {
+        let resolved = job.archive.locate(path);
+        if !resolved.exists() {
+            missing.push(path.to_owned());
+        } else if std::fs::read_dir(&resolved).is_ok_and(|mut e| e.next().is_none()) {
+            warn!("{} of {} is empty", path.display(), job.name);
+            warnings.push(format!("{} is empty", path.display()));
+        }
+    }
+    if missing.is_empty() {
+        return None;
+    }
+
+    let list = missing
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if !job.allow_missing_paths {
+        let error = format!("path(s) don't exist: {list}");
+        return Some(BackupResult::failed(error.into(), dry_run));
+    }
+    if missing.len() == job.archive.paths().len() {
+        let reason = format!("none of its paths exist ({list})");
+        return Some(BackupResult::skipped(reason, dry_run));
+    }
+    warn!("Leaving out missing path(s) of {}: {list}", job.name);
+    warnings.push(format!("left out missing path(s): {list}"));
+    job.archive.paths.retain(|p| !missing.contains(p));
+    None
+}
+
+/// Which backups of a run are finished, for backups waiting on their prerequisites
+struct Completion {
+    /// Whether each backup succeeded, `None` while it runs
+    results: Mutex<Vec<Option<bool>>>,
+    changed: Condvar,
+}
+
+impl Completion {
+    fn new(len: usize) -> Self {
+        Completion {
+            results: Mutex::new(vec![None; len]),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Block until all of `backups` are finished, returns the first one which failed
+    fn wait(&self, backups: &[usize]) -> Option<usize> {
+        let mut results = self.results.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(&failed) = backups.iter().find(|&&i| results[i] == Some(false)) {
+                return Some(failed);
+            }
+            if backups.iter().all(|&i| results[i].is_some()) {
+                return None;
+            }
+            results = self
+                .changed
+                .wait(results)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Marks a backup as finished when dropped, as failed unless `success` was set, also if it
+/// panicked
+/// The cycle each backup is part of, e.g. `[0, 1, 0]` if 0 waits for 1 and 1 for 0, `None` for
+/// backups which aren't part of one
+fn dependency_cycles(prerequisites: &[Vec<usize>]) -> Vec<Option<Vec<usize>>> {
+    (0..prerequisites.len())
+        .map(|start| {
+            // Breadth-first through the prerequisites, until one of them waits for `start`
+            let mut waiting = vec![None; prerequisites.len()];
+            let mut seen = vec![false; prerequisites.len()];
+            let mut queue = std::collections::VecDeque::from([start]);
+            seen[start] = true;
+            while let Some(idx) = queue.pop_front() {
+                for &next in &prerequisites[idx] {
+                    if next == start {
+                        let mut cycle = vec![idx];
+                        while let Some(prev) = waiting[*cycle.last().unwrap()] {
+                            cycle.push(prev);
+                        }
+                        cycle.reverse();
+                        cycle.push(start);
+                        return Some(cycle);
+                    }
+                    if !seen[next] {
+                        seen[next] = true;
+                        waiting[next] = Some(idx);
+                        queue.push_back(next);
+                    }
+                }
+            }
+            None
+        })
+        .collect()
+}
+
+struct Done<'a> {
+    completion: &'a Completion,
+    idx: usize,
+    success: bool,
+}
+
+impl<'a> Done<'a> {
+    fn new(completion: &'a Completion, idx: usize) -> Self {
+        Done {
+            completion,
+            idx,
+            success: false,
+        }
+    }
+}
+
+impl Drop for Done<'_> {
+    fn drop(&mut self) {
+        let mut results = self
+            .completion
+            .results
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        results[self.idx] = Some(self.success);
+        self.completion.changed.notify_all();
+    }
+}
+
+/// Whether repositories are append-only, asked once per repository during a run
+#[derive(Debug, Default)]
+struct AppendOnly(Mutex<HashMap<String, Option<bool>>>);
+
+impl AppendOnly {
+    /// Borg's answer, or [`Job::append_only`] if borg can't tell, e.g. for a remote
+    /// repository. Failing to find out counts as not append-only, with a warning.
+    fn get<B: Backend>(&self, borg: &Borg, job: &Job) -> bool {
+        let location = job.repo.canonical_location();
+        let known = self
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&location)
+            .copied();
+        let answer = match known {
+            Some(answer) => answer,
+            None => {
+                // Not locked while borg runs, backups to other repositories would wait for it
+                let answer = borg.is_append_only::<B>(&job.repo).unwrap_or_else(|e| {
+                    warn!(
+                        "Failed to find out whether {} is append-only: {e}",
+                        job.repo
+                    );
+                    None
+                });
+                self.0
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(location, answer);
+                answer
+            }
+        };
+        answer.or(job.append_only).unwrap_or(false)
+    }
+}
+
+/// Create the archive of a single backup and run its follow-up operations
+fn run_backup<B>(
+    borg: &Borg,
+    job: &Job,
+    retries: u32,
+    idx: usize,
+    tx: &channel::EventSender,
+    append_only: &AppendOnly,
+) -> BackupResult
+where
+    B: Backend<Update = Event>,
+{
+    let borg = &*job.borg(borg);
+    let disconnected = Cell::new(false);
+    let progress = RefCell::new(ProgressTracker::new());
+    let file_changes = RefCell::new(FileChanges::default());
+    // Borg's questions and the answers it took from the environment, e.g. in batch mode
+    let questions = RefCell::new(vec![]);
+    let on_update = |e| {
+        progress.borrow_mut().update(&e);
+        file_changes.borrow_mut().update(&e);
+        match &e {
+            Event::Prompt { prompt, .. } => {
+                questions.borrow_mut().push(format!("borg asked: {prompt}"))
+            }
+            Event::Answer { answer, .. } => questions
+                .borrow_mut()
+                .push(format!("borg answered: {answer}")),
+            _ => {}
+        }
+        // Only the display of the backup is affected, not its result
+        if tx.send(idx, e).is_err() && !disconnected.replace(true) {
+            warn!("Can't report progress of {} anymore", job.name);
+        }
+        ControlFlow::Continue(())
+    };
+
+    // Decided by borg.create_archive the same way, it fails for a missing one
+    let exclude_file = job.archive.exclude_file_status();
+    let mut warnings = vec![];
+    let mut attempts = 0;
+    let (backup_time, stats) = loop {
+        attempts += 1;
+        let start = Instant::now();
+        progress.borrow_mut().begin(Phase::Creating);
+        file_changes.take();
+        match borg.create_archive::<B>(&job.repo, &job.archive, on_update) {
+            Ok(stats) => break (start.elapsed(), stats),
+            Err(e) if attempts <= retries && !cancelled(borg) => {
+                warn!("Attempt {attempts} of {} failed, retrying: {e}", job.name);
+                warnings.push(format!("attempt {attempts} failed: {e}"));
+            }
+            Err(e) => {
+                warnings.extend(questions.take());
+                return BackupResult {
+                    warnings,
+                    attempts,
+                    ..BackupResult::failed(e, borg.dry_run)
+                };
+            }
+        }
+    };
+    let throughput = progress.borrow().throughput().filter(|_| !borg.dry_run);
+    // Borg's final stats are more accurate than the last progress, a dry run has neither
+    let new_data = match &stats {
+        Some(stats) => Some(stats.deduplicated_size),
+        None if borg.dry_run => None,
+        None => {
+            let progress = progress.borrow();
+            progress.path().map(|_| progress.deduplicated_size())
+        }
+    };
+
+    // Don't verify archives which weren't actually created
+    let verify = match &job.verify {
+        Some(options) if !borg.dry_run => {
+            let start = Instant::now();
+            progress.borrow_mut().begin(Phase::Checking);
+            let res = borg.check::<B>(&job.repo, options, on_update);
+            Some(res.map(|_| start.elapsed()))
+        }
+        _ => None,
+    };
+
+    // Don't prune when the new archive can't be trusted
+    let mut prune_deferred = false;
+    let prune = match &job.prune {
+        Some(policy) if !borg.dry_run && !matches!(verify, Some(Err(_))) => {
+            let start = Instant::now();
+            prune_deferred = append_only.get::<B>(borg, job);
+            progress.borrow_mut().begin(Phase::Pruning);
+            let res = borg
+                .prune::<B>(&job.repo, policy, on_update)
+                .and_then(|_| match job.compact {
+                    true => {
+                        progress.borrow_mut().begin(Phase::Compacting);
+                        borg.compact::<B>(&job.repo, on_update)
+                    }
+                    false => Ok(()),
+                });
+            Some(res.map(|_| start.elapsed()))
+        }
+        _ => None,
+    };
+    if let Some(Err(e)) = &prune {
+        warnings.push(format!("pruning failed: {e}"));
+    }
+    warnings.extend(questions.take());
+    if disconnected.get() {
+        warnings.push("the event sink crashed, progress wasn't reported".to_owned());
+    }
+
+    progress.borrow_mut().begin(Phase::Done);
+    let pruned = progress.borrow().pruned();
+
+    BackupResult {
+        backup: Ok(backup_time),
+        verify,
+        new_data,
+        pruned: matches!(prune, Some(Ok(_))).then_some(pruned),
+        prune_deferred: prune_deferred && matches!(prune, Some(Ok(_))),
+        prune,
+        stats,
+        throughput,
+        file_changes: file_changes.take(),
+        exclude_file,
+        warnings,
+        attempts,
+        dry_run: borg.dry_run,
+        skipped: false,
+    }
+}
+
+/// Whether the run was cancelled, see [`Runner::cancellation`]
+fn cancelled(borg: &Borg) -> bool {
+    borg.cancellation
+        .as_ref()
+        .is_some_and(CancellationToken::is_cancelled)
+}
+
+/// Turn the payload of a panicked thread into an error
+fn internal_error(panic: Box<dyn Any + Send>) -> Error {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned());
+    format!("internal error: {message}").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{self, mock::Mock};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Backend which succeeds immediately, except for archives named "panic" and "fail"
+    ///
+    /// Archives named "relocated" fail after borg answered its question from the environment,
+    /// like in batch mode. Archives named "flaky" fail on every other attempt. Like borg it reports no stats for
+    /// dry runs, and it panics when asked to modify a repository in one. Listing files lists
+    /// a single added one.
+    struct MockBackend;
+
+    static FLAKY_ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+    static APPEND_ONLY_QUERIES: AtomicU32 = AtomicU32::new(0);
+
+    impl Mock for MockBackend {
+        type Update = Event;
+
+        fn create_archive(
+            borg: &Borg,
+            _: &Repo,
+            archive: &Archive,
+            mut on_update: impl FnMut(Event) -> ControlFlow<()>,
+        ) -> crate::Result<Option<CreateStats>> {
+            if on_update(Event::Other(format!("creating {}", archive.name))).is_break() {
+                return Err(backend::borg::BorgError::Cancelled.into());
+            }
+            if borg.list_files.is_some() {
+                let _ = on_update(Event::FileStatus {
+                    status: "A".to_owned(),
+                    path: "/home/a".into(),
+                });
+            }
+            if archive.name == "panic" {
+                panic!("mock backend panicked");
+            }
+            if archive.name == "fail" {
+                return Err("mock backup failed".into());
+            }
+            if archive.name == "relocated" {
+                let _ = on_update(Event::Answer {
+                    answer: "BORG_RELOCATED_REPO_ACCESS_IS_OK=no".to_owned(),
+                    env_var: Some("BORG_RELOCATED_REPO_ACCESS_IS_OK".to_owned()),
+                    msgid: "BORG_RELOCATED_REPO_ACCESS_IS_OK".to_owned(),
+                });
+                return Err("Repository access aborted".into());
+            }
+            if archive.name == "flaky"
+                && FLAKY_ATTEMPTS
+                    .fetch_add(1, Ordering::SeqCst)
+                    .is_multiple_of(2)
+            {
+                return Err("connection closed".into());
+            }
+            if borg.dry_run {
+                let _ = on_update(Event::ArchiveProgress {
+                    nfiles: 0,
+                    compressed_size: 0,
+                    deduplicated_size: 0,
+                    original_size: 0,
+                    path: "/home".into(),
+                    time: None,
+                });
+                return Ok(None);
+            }
+            Ok(Some(CreateStats {
+                name: archive.name.to_owned(),
+                id: "0123abcd".to_owned(),
+                duration: Duration::from_secs(1),
+                original_size: 2048,
+                compressed_size: 1024,
+                deduplicated_size: 512,
+                nfiles: 3,
+            }))
+        }
+
+        fn check(
+            borg: &Borg,
+            _: &Repo,
+            _: &CheckOptions,
+            _: impl FnMut(Event) -> ControlFlow<()>,
+        ) -> crate::Result<()> {
+            assert!(!borg.dry_run, "check in a dry run");
+            Ok(())
+        }
+
+        fn prune(
+            borg: &Borg,
+            _: &Repo,
+            _: &PrunePolicy,
+            _: impl FnMut(Event) -> ControlFlow<()>,
+        ) -> crate::Result<()> {
+            assert!(!borg.dry_run, "prune in a dry run");
+            Ok(())
+        }
+
+        fn compact(
+            borg: &Borg,
+            _: &Repo,
+            _: impl FnMut(Event) -> ControlFlow<()>,
+        ) -> crate::Result<()> {
+            assert!(!borg.dry_run, "compact in a dry run");
+            Ok(())
+        }
+
+        fn repo_config(_: &Borg, repository: &Repo, key: &str) -> crate::Result<String> {
+            assert_eq!(key, "append_only");
+            let append_only = repository.path().ends_with("append-only");
+            if append_only {
+                APPEND_ONLY_QUERIES.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(if append_only { "1" } else { "0" }.to_owned())
+        }
+    }
+
+    fn job(name: &str) -> Job {
+        let repo = "/srv/backup".parse().unwrap();
+        Job {
+            name: name.to_owned(),
+            verify: Some(CheckOptions::default()),
+            ..Job::from((repo, Archive::new(name)))
+        }
+    }
+
+    #[test]
+    fn test_panicking_backup() {
+        let jobs = [job("first"), job("panic"), job("last")];
+        let report = Runner::default().run::<MockBackend>(jobs, |_, _| {});
+        let results = &report.backups;
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_success());
+        assert!(results[0].verify.is_some());
+        assert_eq!(results[1].status(), Status::Failed);
+        assert!(results[1].to_string().contains("internal error"));
+        assert!(results[1].summary().starts_with("failed: internal error"));
+        assert!(results[2].is_success());
+        assert!(!report.is_success());
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let after = |name: &str, after: &[usize]| Job {
+            repo: format!("/srv/{name}").parse().unwrap(),
+            after: after.to_vec(),
+            ..job(name)
+        };
+        let jobs = vec![
+            after("second", &[2]),
+            after("skipped", &[3]),
+            after("first", &[]),
+            after("fail", &[]),
+            // Not part of the run
+            after("third", &[42]),
+            Job {
+                rejected: Some("no passphrase".to_owned()),
+                ..after("locked", &[])
+            },
+            after("unlocked", &[5]),
+        ];
+        let finished = Arc::new(Mutex::new(vec![]));
+        let report = Runner::default().run::<MockBackend>(jobs, {
+            let finished = finished.clone();
+            move |idx, event| {
+                if let RunEvent::Finished(_) = event {
+                    finished.lock().unwrap().push(idx);
+                }
+            }
+        });
+        let results = report.backups;
+
+        assert!(results[0].is_success());
+        let finished = finished.lock().unwrap();
+        let position = |idx| finished.iter().position(|&i| i == idx).unwrap();
+        assert!(position(2) < position(0));
+
+        assert_eq!(results[1].status(), Status::Skipped);
+        assert_eq!(results[1].summary(), "skipped: fail failed");
+        assert_eq!(results[3].status(), Status::Failed);
+        assert!(results[4].is_success());
+        assert_eq!(results[5].summary(), "failed: no passphrase");
+        assert!(results[6].skipped);
+    }
+
+    #[test]
+    fn test_dependency_cycles() {
+        assert_eq!(
+            dependency_cycles(&[vec![1], vec![2], vec![0], vec![0], vec![4]]),
+            [
+                Some(vec![0, 1, 2, 0]),
+                Some(vec![1, 2, 0, 1]),
+                Some(vec![2, 0, 1, 2]),
+                None,
+                Some(vec![4, 4]),
+            ]
+        );
+
+        let after = |name: &str, after: &[usize]| Job {
+            repo: format!("/srv/{name}").parse().unwrap(),
+            after: after.to_vec(),
+            ..job(name)
+        };
+        let jobs = vec![
+            after("self", &[0]),
+            after("a", &[2]),
+            after("b", &[1]),
+            after("after-a", &[1]),
+            after("free", &[]),
+        ];
+        // Finishes instead of waiting forever
+        let results = Runner::default()
+            .run::<MockBackend>(jobs, |_, _| {})
+            .backups;
+
+        assert_eq!(
+            results[0].summary(),
+            "failed: backups depend on each other: self -> self"
+        );
+        assert_eq!(
+            results[1].summary(),
+            "failed: backups depend on each other: a -> b -> a"
+        );
+        assert_eq!(results[2].status(), Status::Failed);
+        assert_eq!(results[3].status(), Status::Skipped);
+        assert!(results[4].is_success());
+    }
+
+    #[test]
+    fn test_retries() {
+        let jobs = [job("flaky"), job("fail")];
+        let mut borg = Borg::default();
+        borg.list_files(Default::default());
+        let mut runner = Runner::new(borg);
+        runner.retries(2);
+        let results = runner.run::<MockBackend>(jobs, |_, _| {}).backups;
+
+        assert!(results[0].is_success());
+        assert_eq!(results[0].attempts, 2);
+        assert_eq!(results[0].warnings, ["attempt 1 failed: connection closed"]);
+        // Only the files of the last attempt are counted
+        assert_eq!(results[0].file_changes.to_string(), "1 added");
+        assert!(results[0].summary().ends_with(", 1 added"));
+        assert!(!results[1].is_success());
+        assert_eq!(results[1].attempts, 3);
+        assert_eq!(results[1].warnings.len(), 2);
+    }
+
+    #[test]
+    fn test_questions() {
+        let results = Runner::default()
+            .run::<MockBackend>([job("relocated")], |_, _| {})
+            .backups;
+
+        assert_eq!(results[0].status(), Status::Failed);
+        assert_eq!(
+            results[0].warnings,
+            ["borg answered: BORG_RELOCATED_REPO_ACCESS_IS_OK=no"]
+        );
+    }
+
+    #[test]
+    fn test_missing_paths() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-paths-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("full")).unwrap();
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        std::fs::write(dir.join("full/file"), "").unwrap();
+        let with_paths = |paths: &[&str], allow_missing_paths| {
+            let mut job = Job {
+                allow_missing_paths,
+                ..job("paths")
+            };
+            job.archive.extend_paths(paths.iter().map(|p| dir.join(p)));
+            job
+        };
+
+        let mut warnings = vec![];
+        let mut job = with_paths(&["full", "full/file"], false);
+        assert!(check_paths(&mut job, &mut warnings, false).is_none());
+        assert_eq!(job.archive.paths().len(), 2);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("full/file is inside "));
+
+        let mut warnings = vec![];
+        let mut job = Job {
+            allow_nested_paths: true,
+            ..with_paths(&["full", "full/file"], false)
+        };
+        assert!(check_paths(&mut job, &mut warnings, false).is_none());
+        assert!(warnings.is_empty());
+
+        let mut job = with_paths(&["full", "typo"], false);
+        let result = check_paths(&mut job, &mut warnings, false).unwrap();
+        assert_eq!(result.status(), Status::Failed);
+        assert!(result.summary().contains("don't exist"));
+
+        let mut job = with_paths(&["full", "empty", "typo"], true);
+        assert!(check_paths(&mut job, &mut warnings, false).is_none());
+        assert_eq!(job.archive.paths(), [dir.join("full"), dir.join("empty")]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].ends_with("empty is empty"));
+        assert!(warnings[1].starts_with("left out missing path(s): "));
+
+        let mut job = with_paths(&["usb", "typo"], true);
+        let result = check_paths(&mut job, &mut vec![], false).unwrap();
+        assert_eq!(result.status(), Status::Skipped);
+        assert!(!result.is_failed());
+        assert!(RunReport {
+            backups: vec![result]
+        }
+        .is_success());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let jobs = ["first", "second"].map(|name| Job {
+            prune: Some(PrunePolicy {
+                keep_daily: Some(7),
+                ..Default::default()
+            }),
+            compact: true,
+            ..job(name)
+        });
+        let mut runner = Runner::default();
+        runner.dry_run();
+        let report = runner.run::<MockBackend>(jobs, |_, _| {});
+
+        for result in &report.backups {
+            assert!(result.is_success(), "{result}");
+            assert!(result.dry_run);
+            assert!(result.verify.is_none() && result.prune.is_none());
+            assert!(result.stats.is_none());
+            // No zeros from the progress events
+            assert_eq!(result.new_data, None);
+            assert!(!result.summary().contains(" B"), "{}", result.summary());
+        }
+    }
+
+    #[test]
+    fn test_append_only() {
+        let pruned = |name: &str, repo: &str| Job {
+            repo: repo.parse().unwrap(),
+            prune: Some(PrunePolicy::default()),
+            ..job(name)
+        };
+        let jobs = [
+            pruned("first", "/srv/append-only"),
+            pruned("second", "/srv/append-only/"),
+            pruned("other", "/srv/backup"),
+            // Borg can't read the config of a remote repository
+            Job {
+                append_only: Some(true),
+                ..pruned("remote", "ssh://nas/./backup")
+            },
+        ];
+        let results = Runner::default()
+            .run::<MockBackend>(jobs, |_, _| {})
+            .backups;
+
+        for result in [&results[0], &results[1], &results[3]] {
+            assert!(result.prune_deferred);
+            assert!(result
+                .summary()
+                .ends_with(", prune deferred (append-only repository)"));
+            assert!(result
+                .to_string()
+                .ends_with(", prune deferred (append-only repository)"));
+        }
+        assert!(!results[2].prune_deferred);
+        assert!(results[2].summary().ends_with(", pruned 0 archive(s)"));
+        // Asked once for both backups
+        assert_eq!(APPEND_ONLY_QUERIES.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_panicking_sink() {
+        let jobs = [job("first"), job("last")];
+        let report = Runner::default().run::<MockBackend>(jobs, |_, _| panic!("sink panicked"));
+
+        // Only the progress is lost
+        assert_eq!(report.backups.len(), 2);
+        assert!(report.is_success());
+        for result in report.backups {
+            assert!(result.verify.is_some());
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let result = BackupResult {
+            backup: Ok(Duration::from_secs(150)),
+            verify: Some(Ok(Duration::from_secs(42))),
+            prune: Some(Err("locked".into())),
+            new_data: Some(1536 * 1024 * 1024),
+            ..BackupResult::failed("unused".into(), false)
+        };
+        assert_eq!(result.summary(), "done in 3m12s, 1.50 GiB new");
+        assert_eq!(result.status(), Status::Success);
+
+        let result = BackupResult {
+            prune: Some(Ok(Duration::from_secs(8))),
+            pruned: Some(PruneStats {
+                kept: 30,
+                pruned: 12,
+            }),
+            ..result
+        };
+        assert_eq!(
+            result.summary(),
+            "done in 3m20s, 1.50 GiB new, pruned 12 archive(s)"
+        );
+        assert_eq!(
+            result.to_string(),
+            "backed up in 2m30s and verified in 42s, pruned in 8s (removed 12 archive(s), kept 30)"
+        );
+        let fast = BackupResult {
+            backup: Ok(Duration::from_secs(150)),
+            throughput: Some(Throughput {
+                current: 0.0,
+                average: 42.0 * 1024.0 * 1024.0,
+                peak: 50.0 * 1024.0 * 1024.0,
+            }),
+            ..BackupResult::failed("unused".into(), false)
+        };
+        assert_eq!(fast.to_string(), "backed up in 2m30s at 42.00 MiB/s");
+
+        let created = BackupResult {
+            new_data: Some(512),
+            stats: Some(CreateStats {
+                name: "2024-05-10".to_owned(),
+                id: "0123abcd".to_owned(),
+                duration: Duration::from_secs(150),
+                original_size: 2048,
+                compressed_size: 1024,
+                deduplicated_size: 512,
+                nfiles: 3,
+            }),
+            ..fast
+        };
+        assert_eq!(created.summary(), "done in 2m30s, 512 B new (75.0% saved)");
+        assert_eq!(
+            created.to_string(),
+            "backed up in 2m30s at 42.00 MiB/s, compression 2.00×, 75.0% saved"
+        );
+
+        let result = BackupResult {
+            verify: Some(Err("corrupted".into())),
+            ..result
+        };
+        assert_eq!(result.summary(), "verification failed: corrupted");
+        assert_eq!(result.error().unwrap().to_string(), "corrupted");
+    }
+
+    #[test]
+    fn test_closure_sink() {
+        let repo: Repo = "/srv/backup".parse().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let report = Runner::default()
+            .run::<MockBackend>([(repo, Archive::new("only"))], move |idx, event| {
+                tx.send((idx, event)).unwrap()
+            });
+
+        assert!(report.is_success());
+        let events: Vec<_> = rx.iter().collect();
+        assert!(matches!(events[0], (0, RunEvent::Event(Event::Other(_)))));
+        assert!(matches!(events.last(), Some((0, RunEvent::Finished(_)))));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut runner = Runner::default();
+        runner.cancellation(token).retries(2);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let report = runner.run::<MockBackend>([job("first"), job("second")], move |_, event| {
+            tx.send(event).unwrap()
+        });
+
+        // Nothing started
+        for result in &report.backups {
+            assert!(matches!(
+                result.error().unwrap().downcast_ref(),
+                Some(BorgError::Cancelled)
+            ));
+            assert_eq!(result.attempts, 0);
+        }
+        assert!(rx
+            .iter()
+            .all(|event| matches!(event, RunEvent::Finished(_))));
+    }
+}
@@ -0,0 +1,182 @@
+//! Channel from the backup threads to the thread calling the [`EventSink`](super::EventSink)
+
+use super::RunEvent;
+use crate::Event;
+use std::{
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    time::Duration,
+};
+
+/// Number of events that may be queued for the sink thread
+pub(super) const CAPACITY: usize = 1024;
+
+/// Sending half of the channel from the backup threads to the sink thread
+///
+/// Progress updates are dropped while the channel is full, everything else blocks until there is room.
+#[derive(Clone)]
+pub(super) struct EventSender(SyncSender<(usize, RunEvent)>);
+
+impl EventSender {
+    /// Fails only if the sink thread has stopped
+    pub(super) fn send(&self, idx: usize, event: Event) -> Result<(), Disconnected> {
+        match event {
+            Event::ArchiveProgress { .. } => match self.0.try_send((idx, RunEvent::Event(event))) {
+                Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+                Err(TrySendError::Disconnected(_)) => Err(Disconnected),
+            },
+            event => self
+                .0
+                .send((idx, RunEvent::Event(event)))
+                .map_err(|_| Disconnected),
+        }
+    }
+
+    /// Tell the sink thread why a backup is waiting, `None` once it starts
+    pub(super) fn queued(&self, idx: usize, reason: Option<String>) -> Result<(), Disconnected> {
+        self.0
+            .send((idx, RunEvent::Queued(reason)))
+            .map_err(|_| Disconnected)
+    }
+
+    /// Tell the sink thread that a backup is done, no more events follow for it
+    pub(super) fn finish(&self, idx: usize, summary: String) -> Result<(), Disconnected> {
+        self.0
+            .send((idx, RunEvent::Finished(summary)))
+            .map_err(|_| Disconnected)
+    }
+}
+
+impl RunEvent {
+    fn is_progress(&self) -> bool {
+        matches!(self, RunEvent::Event(Event::ArchiveProgress { .. }))
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Disconnected;
+
+pub(super) fn channel() -> (EventSender, Batches) {
+    let (tx, rx) = mpsc::sync_channel(CAPACITY);
+    (EventSender(tx), Batches(rx))
+}
+
+/// Receiving half which yields all queued events at once
+///
+/// Within a batch only the most recent progress update of each backup is kept.
+/// If nothing happens for a while an empty batch is yielded, so the receiver can do other work.
+pub(super) struct Batches(Receiver<(usize, RunEvent)>);
+
+impl Iterator for Batches {
+    type Item = Vec<(usize, RunEvent)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = match self.0.recv_timeout(Duration::from_secs(1)) {
+            Ok(first) => first,
+            Err(RecvTimeoutError::Timeout) => return Some(vec![]),
+            Err(RecvTimeoutError::Disconnected) => return None,
+        };
+        let mut batch = vec![first];
+        while batch.len() < CAPACITY {
+            match self.0.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        Some(coalesce(batch))
+    }
+}
+
+/// Remove progress updates which are superseded by a later one of the same backup
+fn coalesce(batch: Vec<(usize, RunEvent)>) -> Vec<(usize, RunEvent)> {
+    let mut superseded = vec![false; batch.len()];
+    let mut seen = std::collections::HashSet::new();
+    for (i, (idx, message)) in batch.iter().enumerate().rev() {
+        if message.is_progress() && !seen.insert(*idx) {
+            superseded[i] = true;
+        }
+    }
+
+    batch
+        .into_iter()
+        .zip(superseded)
+        .filter_map(|(event, superseded)| (!superseded).then_some(event))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn progress(nfiles: u64) -> Event {
+        Event::ArchiveProgress {
+            nfiles,
+            compressed_size: 0,
+            deduplicated_size: 0,
+            original_size: 0,
+            path: PathBuf::new(),
+            time: None,
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let batch = vec![
+            (0, RunEvent::Event(progress(1))),
+            (1, RunEvent::Event(progress(1))),
+            (0, RunEvent::Event(Event::Other("a".to_owned()))),
+            (0, RunEvent::Event(progress(2))),
+            (0, RunEvent::Event(progress(3))),
+            (1, RunEvent::Finished("done".to_owned())),
+        ];
+        let batch = coalesce(batch);
+        let summary: Vec<_> = batch
+            .iter()
+            .map(|(idx, m)| match m {
+                RunEvent::Event(Event::ArchiveProgress { nfiles, .. }) => format!("{idx}:{nfiles}"),
+                RunEvent::Event(e) => format!("{idx}:{e}"),
+                RunEvent::Queued(reason) => format!("{idx}:{reason:?}"),
+                RunEvent::Finished(s) => format!("{idx}:{s}"),
+            })
+            .collect();
+        assert_eq!(summary, ["1:1", "0:a", "0:3", "1:done"]);
+    }
+
+    #[test]
+    fn test_million_events() {
+        const EVENTS: u64 = 1_000_000;
+        let (tx, batches) = channel();
+
+        let producer = std::thread::spawn(move || {
+            for i in 0..EVENTS {
+                if i % 1000 == 0 {
+                    tx.send(0, Event::Other(i.to_string())).unwrap();
+                } else {
+                    tx.send(0, progress(i)).unwrap();
+                }
+            }
+        });
+
+        let mut messages = vec![];
+        let mut last_progress = 0;
+        for batch in batches {
+            assert!(batch.len() <= CAPACITY);
+            for (_, message) in batch {
+                match message {
+                    RunEvent::Event(Event::ArchiveProgress { nfiles, .. }) => {
+                        assert!(nfiles > last_progress);
+                        last_progress = nfiles;
+                    }
+                    RunEvent::Event(Event::Other(message)) => messages.push(message),
+                    RunEvent::Event(e) => panic!("unexpected event {e}"),
+                    RunEvent::Queued(_) => panic!("unexpected queueing"),
+                    RunEvent::Finished(_) => panic!("unexpected finish"),
+                }
+            }
+        }
+        producer.join().unwrap();
+
+        let expected: Vec<_> = (0..EVENTS).step_by(1000).map(|i| i.to_string()).collect();
+        assert_eq!(messages, expected);
+    }
+}
@@ -0,0 +1,266 @@
+//! How many backups may run at the same time
+
+use crate::util::random_delay;
+use crate::{CancellationToken, Repo};
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Limits on concurrent backups, on top of running only one backup per repository at a time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Backups in total, from `--jobs`
+    pub jobs: Option<NonZeroUsize>,
+    /// Backups per repository host, from the `[limits]` table
+    pub hosts: BTreeMap<String, NonZeroUsize>,
+    /// Delay between starting successive backups
+    pub stagger: Duration,
+    /// Random delay before starting each backup, up to this
+    pub jitter: Duration,
+}
+
+impl Limits {
+    /// How long the backup at `position` waits before it starts
+    pub(super) fn start_delay(&self, position: usize) -> Duration {
+        self.stagger.saturating_mul(position as u32) + random_delay(self.jitter)
+    }
+}
+
+/// Sleep for `delay`, calling `on_tick` with the time left (rounded up) every second
+///
+/// Returns early once `cancellation` is cancelled.
+pub(super) fn countdown(
+    delay: Duration,
+    cancellation: Option<&CancellationToken>,
+    mut on_tick: impl FnMut(Duration),
+) {
+    let end = Instant::now() + delay;
+    loop {
+        let left = end.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        let secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+        on_tick(Duration::from_secs(secs));
+        let tick = left - Duration::from_secs(secs - 1);
+        match cancellation {
+            Some(token) if token.wait_timeout(tick) => return,
+            Some(_) => {}
+            None => std::thread::sleep(tick),
+        }
+    }
+}
+
+/// Why a backup can't start yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Wait {
+    Jobs(NonZeroUsize),
+    Repo,
+    Host(String, NonZeroUsize),
+}
+
+impl std::fmt::Display for Wait {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Wait::Jobs(max) => write!(f, "waiting for one of {max} jobs"),
+            Wait::Repo => write!(f, "waiting for another backup to the repository"),
+            Wait::Host(host, max) => write!(f, "waiting for {host} (at most {max} at a time)"),
+        }
+    }
+}
+
+/// Hands out slots to backups according to [`Limits`]
+pub(super) struct Scheduler {
+    limits: Limits,
+    running: Mutex<Running>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct Running {
+    repos: Vec<Repo>,
+    hosts: BTreeMap<String, usize>,
+}
+
+impl Running {
+    fn wait(&self, limits: &Limits, repo: &Repo) -> Option<Wait> {
+        if self.repos.contains(repo) {
+            return Some(Wait::Repo);
+        }
+        if let Some(max) = limits.jobs.filter(|max| self.repos.len() >= max.get()) {
+            return Some(Wait::Jobs(max));
+        }
+        let host = repo.host()?;
+        let max = *limits.hosts.get(host)?;
+        let running = self.hosts.get(host).copied().unwrap_or_default();
+        (running >= max.get()).then(|| Wait::Host(host.to_owned(), max))
+    }
+}
+
+impl Scheduler {
+    pub fn new(limits: Limits) -> Self {
+        Scheduler {
+            limits,
+            running: Mutex::default(),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until a backup to `repo` may run, it may until the slot is dropped
+    ///
+    /// `on_wait` is called whenever the reason for waiting changes.
+    pub fn acquire(&self, repo: &Repo, mut on_wait: impl FnMut(&Wait)) -> Slot<'_> {
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        let mut waiting = None;
+        while let Some(wait) = running.wait(&self.limits, repo) {
+            if waiting.as_ref() != Some(&wait) {
+                on_wait(&wait);
+                waiting = Some(wait);
+            }
+            running = self
+                .released
+                .wait(running)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+
+        running.repos.push(repo.clone());
+        if let Some(host) = repo.host() {
+            *running.hosts.entry(host.to_owned()).or_default() += 1;
+        }
+        Slot {
+            scheduler: self,
+            repo: repo.clone(),
+        }
+    }
+}
+
+/// Permission to run a backup, released on drop
+pub(super) struct Slot<'a> {
+    scheduler: &'a Scheduler,
+    repo: Repo,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut running = self
+            .scheduler
+            .running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(idx) = running.repos.iter().position(|r| r == &self.repo) {
+            running.repos.remove(idx);
+        }
+        if let Some(count) = self.repo.host().and_then(|h| running.hosts.get_mut(h)) {
+            *count -= 1;
+        }
+        self.scheduler.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Most backups running at the same time when each of `repos` is backed up once
+    fn max_concurrency(limits: Limits, repos: &[&str]) -> usize {
+        let scheduler = Scheduler::new(limits);
+        let running = AtomicUsize::new(0);
+        let max = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for repo in repos {
+                let repo: Repo = repo.parse().unwrap();
+                let (scheduler, running, max) = (&scheduler, &running, &max);
+                s.spawn(move || {
+                    let _slot = scheduler.acquire(&repo, |_| {});
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        max.into_inner()
+    }
+
+    #[test]
+    fn test_limits() {
+        let nas = ["ssh://nas/a", "ssh://nas/b", "ssh://nas/c", "ssh://nas/d"];
+        let hosts = |max| Limits {
+            hosts: [("nas".to_owned(), NonZeroUsize::new(max).unwrap())].into(),
+            ..Limits::default()
+        };
+
+        assert_eq!(max_concurrency(Limits::default(), &nas), 4);
+        assert_eq!(max_concurrency(hosts(1), &nas), 1);
+        assert!(max_concurrency(hosts(2), &nas) <= 2);
+        // Other hosts and local repositories aren't limited
+        assert_eq!(
+            max_concurrency(hosts(1), &["ssh://nas/a", "ssh://offsite/a", "/srv/a"]),
+            3
+        );
+
+        let jobs = Limits {
+            jobs: NonZeroUsize::new(1),
+            ..Limits::default()
+        };
+        assert_eq!(max_concurrency(jobs, &["/srv/a", "/srv/b", "/srv/c"]), 1);
+        // The same repository is never used twice at the same time
+        assert_eq!(max_concurrency(Limits::default(), &["/srv/a"; 3]), 1);
+    }
+
+    #[test]
+    fn test_start_delay() {
+        let limits = Limits {
+            stagger: Duration::from_secs(5),
+            ..Limits::default()
+        };
+        assert_eq!(limits.start_delay(0), Duration::ZERO);
+        assert_eq!(limits.start_delay(2), Duration::from_secs(10));
+
+        let limits = Limits {
+            jitter: Duration::from_secs(30),
+            ..limits
+        };
+        let delay = limits.start_delay(1);
+        assert!(delay >= Duration::from_secs(5) && delay < Duration::from_secs(35));
+
+        let mut ticks = vec![];
+        countdown(Duration::from_millis(1200), None, |left| {
+            ticks.push(left.as_secs())
+        });
+        assert_eq!(ticks, [2, 1]);
+        countdown(Duration::ZERO, None, |_| unreachable!());
+
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        let start = Instant::now();
+        countdown(Duration::from_secs(30), Some(&cancelled), |_| {});
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_wait_reason() {
+        let scheduler = Scheduler::new(Limits {
+            jobs: NonZeroUsize::new(3),
+            hosts: [("nas".to_owned(), NonZeroUsize::new(1).unwrap())].into(),
+            ..Limits::default()
+        });
+        let first = scheduler.acquire(&"ssh://nas/a".parse().unwrap(), |_| unreachable!());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let repo = "ssh://nas/b".parse().unwrap();
+                drop(scheduler.acquire(&repo, |wait| tx.send(wait.to_string()).unwrap()));
+            });
+            let reason = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(reason, "waiting for nas (at most 1 at a time)");
+            drop(first);
+        });
+    }
+}
@@ -1,21 +1,310 @@
-use std::path::PathBuf;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
+/// Expand a leading `~` to the home directory, and `~user` to the one of `user` on Unix
+///
+/// `~` has to be a whole component, so `~/backup` and `~\backup` on Windows are expanded.
+/// Paths of unknown users are left alone.
 #[inline]
-pub fn resolve_path(path: &PathBuf) -> PathBuf {
-    if path == &PathBuf::from("~") {
-        return dirs::home_dir().unwrap();
+pub fn resolve_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    let mut components = path.components();
+    let home = match components.next() {
+        Some(Component::Normal(first)) => match first.to_str().and_then(|f| f.strip_prefix('~')) {
+            Some("") => dirs::home_dir(),
+            #[cfg(unix)]
+            Some(user) => user_home(user),
+            _ => None,
+        },
+        _ => None,
+    };
+    let Some(home) = home else {
+        return path.to_owned();
+    };
+
+    // Joining an empty path would add a trailing separator
+    let rest = components.as_path();
+    if rest.as_os_str().is_empty() {
+        return home;
+    }
+    home.join(rest)
+}
+
+/// Home directory of `user` from the user database
+#[cfg(unix)]
+fn user_home(user: &str) -> Option<PathBuf> {
+    use std::{ffi::CStr, os::unix::ffi::OsStrExt};
+
+    let name = std::ffi::CString::new(user).ok()?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: an all-zero passwd is valid, getpwnam_r only fills it in
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: all pointers are valid for the call, the strings of passwd point into buf
+    let rc = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 || result.is_null() || passwd.pw_dir.is_null() {
+        return None;
+    }
+    // SAFETY: not null, and NUL-terminated inside of buf, which is still alive
+    let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(dir.to_bytes())))
+}
+
+/// `path` with `~` expanded, made absolute and its parent directory canonicalized
+///
+/// The path itself stays as it is, a symlink isn't replaced by its target. A parent which
+/// doesn't exist is left alone.
+pub fn absolute_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = resolve_path(path);
+    let path = std::path::absolute(&path).unwrap_or(path);
+    let canonical = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent.canonicalize().map(|parent| parent.join(name)),
+        // The root, or a path ending in ".."
+        _ => path.canonicalize(),
+    };
+    canonical.unwrap_or(path)
+}
+
+/// Replace the contents of `path`, readers see either the old or the new contents
+///
+/// The contents are written and synced to a temporary file next to the file, which then
+/// replaces it. Symlinks are followed, so the file they point to is replaced instead of the
+/// link, and the permissions of an existing file are kept. If the file can't be replaced
+/// because it is on another filesystem than its directory, e.g. a bind mount, it is
+/// overwritten in place instead.
+pub fn atomic_write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    atomic_write_with(path.as_ref(), contents.as_ref(), |_| Ok(()))
+}
+
+/// [`atomic_write`], calling `before_rename` with the temporary file once it is written
+fn atomic_write_with(
+    path: &Path,
+    contents: &[u8],
+    before_rename: impl FnOnce(&Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    // Unique per call, threads may write the same file
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let tmp = dir.join(format!(".{name}.{}.{n}.tmp", std::process::id()));
+
+    let written = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?;
+        if let Ok(metadata) = fs::metadata(&path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        before_rename(&tmp)?;
+        fs::rename(&tmp, &path)
+    })();
+
+    let result = match written {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => fs::write(&path, contents),
+        result => result,
+    };
+    if result.is_err() || tmp.exists() {
+        let _ = fs::remove_file(&tmp);
+    }
+    // Make the rename itself durable, not every platform can open directories
+    if result.is_ok() {
+        if let Ok(dir) = fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
     }
+    result
+}
+
+/// A random duration up to `max`, good enough to spread out backups
+pub(crate) fn random_delay(max: std::time::Duration) -> std::time::Duration {
+    match max.as_millis() as u64 {
+        0 => std::time::Duration::ZERO,
+        max => std::time::Duration::from_millis(random() % max),
+    }
+}
+
+/// Random enough to spread out backups or tell runs apart, not for anything secret
+pub(crate) fn random() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// Name of this machine, without its domain like borg's `{hostname}`
+pub(crate) fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    let name = {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for its length, the name is NUL-terminated if it fits
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0)?;
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    };
+    #[cfg(not(unix))]
+    let name = std::env::var("COMPUTERNAME").ok()?;
 
-    match path.strip_prefix("~/") {
-        Ok(path) => dirs::home_dir().unwrap().join(path),
-        Err(_) => path.to_owned(),
+    let name = name.split('.').next().unwrap_or_default();
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// Login name of the user, from the same variables as borg's `{user}`
+pub(crate) fn username() -> Option<String> {
+    ["LOGNAME", "USER", "LNAME", "USERNAME"]
+        .into_iter()
+        .find_map(|var| std::env::var(var).ok().filter(|name| !name.is_empty()))
+}
+
+/// Bytes available to unprivileged users on the filesystem of `path`
+///
+/// A path which doesn't exist yet is looked up by its nearest existing ancestor.
+pub(crate) fn free_space(path: impl AsRef<Path>) -> io::Result<u64> {
+    let path = absolute_path(path);
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::CString::new(existing.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: the path is NUL-terminated and the struct is only read if the call succeeded
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stat = unsafe { stat.assume_init() };
+        #[allow(clippy::unnecessary_cast)]
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = existing;
+        Err(io::Error::from(io::ErrorKind::Unsupported))
     }
 }
 
+/// Serialize a point in time as RFC 3339 string in the local timezone
+pub(crate) fn serialize_time<S: serde::Serializer>(
+    time: &std::time::SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&chrono::DateTime::<chrono::Local>::from(*time).to_rfc3339())
+}
+
+/// An optional point in time as an RFC 3339 string in UTC, `null` if unset
+#[cfg(feature = "serde")]
+pub(crate) mod optional_time {
+    use std::time::SystemTime;
+
+    pub(crate) fn serialize<S: serde::Serializer>(
+        time: &Option<SystemTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => {
+                serializer.collect_str(&chrono::DateTime::<chrono::Utc>::from(*time).to_rfc3339())
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        let s = <Option<String> as serde::Deserialize>::deserialize(deserializer)?;
+        s.map(|s| chrono::DateTime::parse_from_rfc3339(&s).map(Into::into))
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Deserialize a point in time from an RFC 3339 string
+pub(crate) fn deserialize_time<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<std::time::SystemTime, D::Error> {
+    let s = <std::borrow::Cow<str> as serde::Deserialize>::deserialize(deserializer)?;
+    chrono::DateTime::parse_from_rfc3339(&s)
+        .map(Into::into)
+        .map_err(serde::de::Error::custom)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_atomic_write() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-atomic-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("borrg.toml");
+
+        atomic_write(&path, "first").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+
+        // A failure before the rename leaves the old contents and no temporary file
+        let failed = atomic_write_with(&path, b"second", |tmp| {
+            assert_eq!(fs::read_to_string(tmp).unwrap(), "second");
+            Err(io::Error::other("crashed"))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+            let link = dir.join("link.toml");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            atomic_write(&link, "third").unwrap();
+            assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "third");
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_free_space() {
+        assert!(free_space(std::env::temp_dir()).unwrap() > 0);
+        // Missing paths are on the filesystem of their parent
+        let missing =
+            std::env::temp_dir().join(format!("borrg-test-free-{}/repo", std::process::id()));
+        assert!(free_space(missing).unwrap() > 0);
+    }
+
+    #[cfg(unix)]
     #[test]
     fn test_resolve_path() {
         let should_resolve = PathBuf::from("~/test");
@@ -24,10 +313,53 @@ mod tests {
         let should_not_resolve = PathBuf::from("/test");
         assert_eq!(should_not_resolve, resolve_path(&should_not_resolve));
 
-        let should_not_resolve = PathBuf::from("~test");
-        assert_eq!(should_not_resolve, resolve_path(&should_not_resolve));
+        let unknown_user = PathBuf::from("~no-such-user-borrg/test");
+        assert_eq!(unknown_user, resolve_path(&unknown_user));
+
+        let not_leading = PathBuf::from("a/~/test");
+        assert_eq!(not_leading, resolve_path(&not_leading));
+
+        #[cfg(target_os = "linux")]
+        assert_eq!(resolve_path("~root/test"), PathBuf::from("/root/test"));
 
         let home_only = PathBuf::from("~");
-        assert_ne!(home_only, resolve_path(&home_only));
+        assert_eq!(resolve_path(&home_only), dirs::home_dir().unwrap());
+
+        let nested = PathBuf::from("~/a/b");
+        assert_eq!(resolve_path(&nested), dirs::home_dir().unwrap().join("a/b"));
+
+        #[cfg(windows)]
+        {
+            let backslash = PathBuf::from(r"~\test");
+            assert_eq!(
+                resolve_path(&backslash),
+                dirs::home_dir().unwrap().join("test")
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_absolute_path() {
+        let dir = std::env::temp_dir().join(format!("borrg-test-absolute-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real/data")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real/data"), dir.join("real/data-link")).unwrap();
+        let real = dir.canonicalize().unwrap().join("real");
+
+        // The parent is canonicalized, the path itself isn't
+        assert_eq!(absolute_path(dir.join("link/data")), real.join("data"));
+        assert_eq!(
+            absolute_path(dir.join("link/data-link")),
+            real.join("data-link")
+        );
+        assert_eq!(
+            absolute_path(dir.join("link/missing/x")),
+            dir.join("link/missing/x")
+        );
+        assert!(absolute_path("relative").is_absolute());
+        assert_eq!(absolute_path("/"), Path::new("/"));
+        fs::remove_dir_all(&dir).ok();
     }
 }
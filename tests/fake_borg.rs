@@ -0,0 +1,349 @@
+//! The borg backend against a fake borg, see [`support::FakeBorg`]
+#![cfg(unix)]
+
+mod support;
+
+use borrg::backend::borg::{BorgError, BorgWrapper};
+use borrg::{Archive, Borg, CancellationToken, Encryption, Event};
+use std::ops::ControlFlow;
+use std::time::Duration;
+use support::{Answer, FakeBorg};
+
+const PROGRESS: &str = r#"{"type": "archive_progress", "original_size": 2048, "compressed_size": 1024, "deduplicated_size": 512, "nfiles": 3, "path": "home/seb/notes.txt", "time": 1715310005.5}"#;
+
+const WARNING: &str = r#"{"type": "log_message", "time": 1715310012.5, "levelname": "WARNING", "name": "borg.archiver", "message": "home/seb/locked.db: open: [Errno 13] Permission denied"}"#;
+
+const CREATE: &str = r#"{
+    "archive": {
+        "duration": 12.5,
+        "id": "9d4d1cd3e0c7bd5bf7f12f1ba9e6c1c53e1b4fe4b1cbb77e1c2d1aa0ec3b45e1",
+        "name": "2024-05-10",
+        "stats": {
+            "compressed_size": 1024,
+            "deduplicated_size": 512,
+            "nfiles": 3,
+            "original_size": 2048
+        }
+    }
+}"#;
+
+const INFO: &str = r#"{
+    "cache": {
+        "path": "/home/seb/.cache/borg/dd06",
+        "stats": {
+            "total_chunks": 236619767,
+            "total_csize": 26289835627221,
+            "total_size": 38449962381221,
+            "total_unique_chunks": 1621026,
+            "unique_csize": 300958014008,
+            "unique_size": 477242905022
+        }
+    },
+    "encryption": {
+        "mode": "repokey-blake2"
+    },
+    "repository": {
+        "id": "dd06",
+        "last_modified": "2022-04-07T15:44:37.000000",
+        "location": "/srv/backup"
+    },
+    "security_dir": "/home/seb/.config/borg/security/dd06"
+}"#;
+
+fn terminating(status: &str, rc: i32) -> String {
+    let level = match status {
+        "success" => "INFO",
+        "warning" => "WARNING",
+        _ => "ERROR",
+    };
+    format!(
+        r#"{{"type": "log_message", "time": 1715310012.5, "levelname": "{level}", "name": "borg.archiver", "message": "terminating with {status} status, rc {rc}"}}"#
+    )
+}
+
+/// Create an archive of the directory of `fake`, with the events borg reported
+fn create(fake: &FakeBorg) -> (borrg::Result<Option<borrg::CreateStats>>, Vec<Event>) {
+    create_with(&Borg::default(), fake)
+}
+
+fn create_with(
+    borg: &Borg,
+    fake: &FakeBorg,
+) -> (borrg::Result<Option<borrg::CreateStats>>, Vec<Event>) {
+    let mut archive = Archive::new("2024-05-10");
+    archive.path(fake.dir());
+    let mut events = vec![];
+    let res = borg.create_archive::<BorgWrapper>(&fake.repo(), &archive, |event| {
+        events.push(event);
+        ControlFlow::Continue(())
+    });
+    (res, events)
+}
+
+#[test]
+fn create_archive() {
+    let fake = FakeBorg::new("create");
+    fake.answer(
+        "create",
+        Answer::default()
+            .stderr(PROGRESS)
+            .stderr(&terminating("success", 0))
+            .stdout(CREATE),
+    );
+
+    let (res, events) = create(&fake);
+    let stats = res.unwrap().unwrap();
+    assert_eq!(stats.name, "2024-05-10");
+    assert_eq!(stats.duration, Duration::from_millis(12500));
+    assert_eq!(stats.original_size, 2048);
+    assert_eq!(stats.deduplicated_size, 512);
+    assert_eq!(stats.nfiles, 3);
+
+    assert!(matches!(
+        events[0],
+        Event::ArchiveProgress { nfiles: 3, .. }
+    ));
+    assert!(matches!(events[1], Event::Termination { rc: 0, .. }));
+
+    let calls = fake.calls();
+    assert_eq!(calls.len(), 1);
+    assert!(calls[0].starts_with("create --progress --stats --log-json --json "));
+    assert!(calls[0].contains("/repo::2024-05-10 "), "{}", calls[0]);
+}
+
+#[test]
+fn create_archive_warning() {
+    let fake = FakeBorg::new("create-warning");
+    fake.answer(
+        "create",
+        Answer::default()
+            .stderr(WARNING)
+            .stderr(&terminating("warning", 1))
+            .stdout(CREATE)
+            .exit(1),
+    );
+
+    // Borg still created the archive
+    let (res, events) = create(&fake);
+    assert_eq!(res.unwrap().unwrap().nfiles, 3);
+    assert!(matches!(
+        &events[0],
+        Event::LogMessage { level: Some(log::Level::Warn), message, .. }
+            if message.ends_with("Permission denied")
+    ));
+    assert!(matches!(events[1], Event::Termination { rc: 1, .. }));
+}
+
+#[test]
+fn create_archive_failure() {
+    let fake = FakeBorg::new("create-failure");
+    fake.answer(
+        "create",
+        Answer::default()
+            .stderr(r#"{"type": "log_message", "time": 1715310001.0, "levelname": "ERROR", "name": "borg.archiver", "message": "Repository /srv/backup does not exist."}"#)
+            .stderr(&terminating("error", 2))
+            .exit(2),
+    );
+
+    let (res, events) = create(&fake);
+    let err = res.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<BorgError>(),
+        Some(BorgError::Failed(2))
+    ));
+    assert_eq!(events.len(), 2);
+
+    // The exit code alone is enough
+    fake.answer("create", Answer::default().exit(2));
+    let (res, events) = create(&fake);
+    assert!(res.is_err());
+    assert!(events.is_empty());
+}
+
+#[test]
+fn create_archive_malformed_events() {
+    let fake = FakeBorg::new("create-malformed");
+    fake.answer(
+        "create",
+        Answer::default()
+            .stderr("Remote: Warning: Permanently added 'nas' to the list of known hosts.")
+            .stderr(r#"{"type": "archive_progress", "nfiles": "#)
+            .stderr(r#"{"type": "no_such_event"}"#)
+            .stderr(PROGRESS)
+            .stdout(CREATE),
+    );
+
+    // Lines borg didn't write are passed on as they are, they don't fail the backup
+    let (res, events) = create(&fake);
+    assert!(res.unwrap().is_some());
+    assert_eq!(events.len(), 4);
+    assert!(matches!(
+        &events[0],
+        Event::Other(line) if line.starts_with("Remote: Warning")
+    ));
+    assert!(matches!(&events[1], Event::Other(_)));
+    assert!(matches!(&events[2], Event::Other(_)));
+    assert!(matches!(events[3], Event::ArchiveProgress { .. }));
+
+    // But the stats have to be JSON
+    fake.answer(
+        "create",
+        Answer::default().stdout("Archive name: 2024-05-10"),
+    );
+    assert!(create(&fake).0.is_err());
+}
+
+#[test]
+fn create_archive_cancelled() {
+    let fake = FakeBorg::new("create-cancelled");
+    fake.answer(
+        "create",
+        Answer::default()
+            .stderr(PROGRESS)
+            .sleep(Duration::from_secs(10))
+            .stdout(CREATE),
+    );
+
+    let token = CancellationToken::new();
+    let mut borg = Borg::default();
+    borg.cancellation(token.clone());
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        token.cancel();
+    });
+
+    let start = std::time::Instant::now();
+    let (res, _) = create_with(&borg, &fake);
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<BorgError>(),
+        Some(BorgError::Cancelled)
+    ));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    canceller.join().unwrap();
+
+    // Nothing else runs afterwards
+    let info = borg.repo_info::<BorgWrapper>(&fake.repo()).unwrap_err();
+    assert!(matches!(
+        info.downcast_ref::<BorgError>(),
+        Some(BorgError::Cancelled)
+    ));
+    assert_eq!(fake.calls().len(), 1);
+}
+
+#[test]
+fn init_repository() {
+    let fake = FakeBorg::new("init");
+    let mut repo = fake.repo();
+    Borg::default()
+        .init_repository::<BorgWrapper>(
+            &mut repo,
+            Encryption::RepoKeyBlake2,
+            true,
+            Some(500 << 30),
+            true,
+            |_| ControlFlow::Continue(()),
+        )
+        .unwrap();
+    let calls = fake.calls();
+    assert_eq!(
+        calls,
+        [format!(
+            "init --log-json --append-only --make-parent-dirs --storage-quota 536870912000 \
+            --encryption repokey-blake2 {}",
+            repo.location()
+        )]
+    );
+
+    fake.answer(
+        "init",
+        Answer::default()
+            .stderr(r#"{"type": "log_message", "time": 1715310001.0, "levelname": "ERROR", "name": "borg.archiver", "message": "A repository already exists at /srv/backup."}"#)
+            .exit(2),
+    );
+    let mut messages = vec![];
+    let res = Borg::default().init_repository::<BorgWrapper>(
+        &mut repo,
+        Encryption::RepoKeyBlake2,
+        false,
+        None,
+        false,
+        |event| {
+            if let Event::LogMessage { message, .. } = event {
+                messages.push(message);
+            }
+            ControlFlow::Continue(())
+        },
+    );
+    assert!(res.is_err());
+    assert_eq!(messages, ["A repository already exists at /srv/backup."]);
+}
+
+#[test]
+fn repo_info() {
+    let fake = FakeBorg::new("info");
+    fake.answer("info", Answer::default().stdout(INFO));
+
+    let info = Borg::default()
+        .repo_info::<BorgWrapper>(&fake.repo())
+        .unwrap();
+    assert_eq!(info.id, "dd06");
+    assert_eq!(info.location, "/srv/backup");
+    assert_eq!(info.encryption, Encryption::RepoKeyBlake2);
+    assert_eq!(info.unique_csize, 300958014008);
+    assert!(fake.calls()[0].starts_with("info --json "));
+
+    // Borg's message rather than just the exit code
+    fake.answer(
+        "info",
+        Answer::default()
+            .stderr("Repository /srv/backup does not exist.")
+            .exit(2),
+    );
+    let err = Borg::default()
+        .repo_info::<BorgWrapper>(&fake.repo())
+        .unwrap_err();
+    assert_eq!(
+        err.to_string().trim(),
+        "Repository /srv/backup does not exist."
+    );
+}
+
+#[test]
+fn repo_info_timeout() {
+    let fake = FakeBorg::new("info-timeout");
+    fake.answer(
+        "info",
+        Answer::default().sleep(Duration::from_secs(2)).stdout(INFO),
+    );
+
+    let mut borg = Borg::default();
+    borg.info_timeout(Duration::from_millis(200));
+    let err = borg.repo_info::<BorgWrapper>(&fake.repo()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<BorgError>(),
+        Some(BorgError::Timeout(_))
+    ));
+}
+
+#[test]
+fn export_key() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeBorg::new("export-key");
+    let path = fake.dir().join("dd06.key");
+    BorgWrapper::export_key(&Borg::default(), &fake.repo(), &path).unwrap();
+
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "BORG_KEY dd06\n");
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // Not even readable by others while borg writes it
+    let mode = std::fs::read_to_string(fake.dir().join("key.mode")).unwrap();
+    assert_eq!(mode, "-rw-------\n");
+
+    // Nothing is left behind if borg fails
+    std::fs::remove_file(&path).unwrap();
+    fake.answer("key", Answer::default().exit(2));
+    assert!(BorgWrapper::export_key(&Borg::default(), &fake.repo(), &path).is_err());
+    assert!(!path.exists());
+    assert!(!fake.dir().join("dd06.key.partial").exists());
+}
@@ -0,0 +1,160 @@
+//! A fake borg for the integration tests, answering every command as scripted by the test
+//!
+//! `BORG_PATH` points to a single shell script for the whole test binary. Each [`FakeBorg`] is
+//! a directory with the answers of one test, which the script finds through the environment of
+//! the repository, so tests running in parallel don't see each other's answers.
+
+use borrg::Repo;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::time::Duration;
+
+/// Looks up the answer to the command in `$FAKE_BORG_DIR`: `<command>.sleep` seconds to sleep,
+/// `<command>.stderr` and `<command>.stdout` to print and `<command>.rc` to exit with. The
+/// arguments of every call are appended to `calls`.
+///
+/// Like borg it stops on SIGINT, also while sleeping.
+const SCRIPT: &str = r#"#!/bin/sh
+if [ "$1" = "--version" ]; then
+    echo "borg ${FAKE_BORG_VERSION:-1.2.8}"
+    exit 0
+fi
+dir="${FAKE_BORG_DIR:?not a repository of a FakeBorg}"
+command=
+for arg in "$@"; do
+    case "$arg" in
+        init|create|info|list|config|check|prune|compact|delete|extract|break-lock|key)
+            command="$arg"
+            break
+            ;;
+    esac
+done
+printf '%s\n' "$*" >> "$dir/calls"
+if [ -f "$dir/$command.sleep" ]; then
+    sleep "$(cat "$dir/$command.sleep")" &
+    sleeping=$!
+    trap 'kill $sleeping; exit 130' INT
+    wait $sleeping
+    trap - INT
+fi
+if [ -f "$dir/$command.stderr" ]; then cat "$dir/$command.stderr" >&2; fi
+if [ -f "$dir/$command.stdout" ]; then cat "$dir/$command.stdout"; fi
+if [ -f "$dir/$command.rc" ]; then exit "$(cat "$dir/$command.rc")"; fi
+# `key export REPO PATH` writes the key to its last argument, `key.mode` is its mode then
+if [ "$command" = key ]; then
+    for path; do :; done
+    echo "BORG_KEY dd06" > "$path"
+    ls -l "$path" | cut -c 1-10 > "$dir/key.mode"
+fi
+exit 0
+"#;
+
+/// Write the script and point `BORG_PATH` to it, once per test binary
+fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("borrg-fake-borg-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("borg");
+        std::fs::write(&path, SCRIPT).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("BORG_PATH", &path);
+    });
+}
+
+/// What the fake answers to a command, by default nothing and exit code 0
+#[derive(Debug, Default, Clone)]
+pub struct Answer {
+    stderr: Vec<String>,
+    stdout: String,
+    sleep: Option<Duration>,
+    rc: i32,
+}
+
+impl Answer {
+    /// Print a line to stderr, borg's `--log-json` events are one JSON object per line
+    pub fn stderr(mut self, line: &str) -> Self {
+        self.stderr.push(line.to_owned());
+        self
+    }
+
+    /// Print `output` to stdout, e.g. the JSON of `borg info --json`
+    pub fn stdout(mut self, output: &str) -> Self {
+        self.stdout = output.to_owned();
+        self
+    }
+
+    /// Sleep before printing anything
+    pub fn sleep(mut self, duration: Duration) -> Self {
+        self.sleep = Some(duration);
+        self
+    }
+
+    /// Exit with `rc`
+    pub fn exit(mut self, rc: i32) -> Self {
+        self.rc = rc;
+        self
+    }
+}
+
+/// The answers of one test, in a directory of their own
+pub struct FakeBorg {
+    dir: PathBuf,
+}
+
+impl FakeBorg {
+    /// A fake without any answers, `name` has to be unique among the tests
+    pub fn new(name: &str) -> Self {
+        install();
+        let dir = std::env::temp_dir().join(format!(
+            "borrg-test-fake-borg-{name}-{}",
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        FakeBorg { dir }
+    }
+
+    /// Answer `command`, e.g. "create", with `answer` from now on
+    pub fn answer(&self, command: &str, answer: Answer) -> &Self {
+        let file = |extension: &str| self.dir.join(format!("{command}.{extension}"));
+        let mut stderr = answer.stderr.join("\n");
+        if !stderr.is_empty() {
+            stderr.push('\n');
+        }
+        std::fs::write(file("stderr"), stderr).unwrap();
+        std::fs::write(file("stdout"), answer.stdout).unwrap();
+        std::fs::write(file("rc"), answer.rc.to_string()).unwrap();
+        match answer.sleep {
+            Some(sleep) => std::fs::write(file("sleep"), sleep.as_secs_f64().to_string()).unwrap(),
+            None => drop(std::fs::remove_file(file("sleep"))),
+        }
+        self
+    }
+
+    /// A repository whose borg commands are answered by this fake
+    pub fn repo(&self) -> Repo {
+        let mut repo: Repo = self.dir.join("repo").display().to_string().parse().unwrap();
+        repo.set_env("FAKE_BORG_DIR", self.dir.display().to_string());
+        repo
+    }
+
+    /// Directory of the fake, for paths to back up
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The arguments of every call so far, separated by spaces
+    pub fn calls(&self) -> Vec<String> {
+        let calls = std::fs::read_to_string(self.dir.join("calls")).unwrap_or_default();
+        calls.lines().map(str::to_owned).collect()
+    }
+}
+
+impl Drop for FakeBorg {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}